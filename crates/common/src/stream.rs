@@ -1,20 +1,67 @@
-use std::io::{Read, Write};
+use std::{
+    io::{self, Read, Write},
+    net::{Shutdown, TcpStream},
+};
 
 #[cfg(debug_assertions)]
 pub mod mock;
 
-pub trait UnsafeRead: Read {
-    /// # Safety
-    /// Function must be externally synchronized, calling function from two places same time will make undefinied behavior.
-    unsafe fn read_unsafe(&self, buf: &mut [u8]) -> std::io::Result<usize>;
+/// Stream which is split into an owned read half and an owned write half, so each of them can be moved to its own
+/// thread without external synchronization.
+pub trait SplitStream: Send + 'static {
+    type ReadHalf: Read + Send + 'static;
+    type WriteHalf: Write + Send + 'static;
+    type Shutdown: StreamShutdown + Send + Sync + 'static;
+
+    /// Splits the stream into read half, write half and a handle which shuts down both of them.
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf, Self::Shutdown);
+
+    /// Returns true when the stream preserves message boundaries, like `SOCK_SEQPACKET` sockets. Every vectored write
+    /// is then received as a whole by a single vectored read.
+    fn is_message_based(&self) -> bool {
+        false
+    }
+}
+
+pub trait StreamShutdown {
+    /// Shuts down both directions of the stream, blocked and future reads and writes of its halves return immediately.
+    fn shutdown(&self) -> io::Result<()>;
+}
+
+impl SplitStream for TcpStream {
+    type ReadHalf = TcpStream;
+    type WriteHalf = TcpStream;
+    type Shutdown = TcpStream;
+
+    fn split(self) -> (TcpStream, TcpStream, TcpStream) {
+        let read = self.try_clone().expect("failed to clone TCP socket");
+        let shutdown = self.try_clone().expect("failed to clone TCP socket");
+        (read, self, shutdown)
+    }
+}
+
+impl StreamShutdown for TcpStream {
+    fn shutdown(&self) -> io::Result<()> {
+        TcpStream::shutdown(self, Shutdown::Both)
+    }
+}
+
+#[cfg(unix)]
+impl StreamShutdown for std::os::unix::net::UnixStream {
+    fn shutdown(&self) -> io::Result<()> {
+        std::os::unix::net::UnixStream::shutdown(self, Shutdown::Both)
+    }
 }
 
-pub trait UnsafeWrite: Write {
-    /// # Safety
-    /// Function must be externally synchronized, calling function from two places same time will make undefinied behavior.
-    unsafe fn write_unsafe(&self, buf: &[u8]) -> std::io::Result<usize>;
+#[cfg(unix)]
+impl SplitStream for std::os::unix::net::UnixStream {
+    type ReadHalf = Self;
+    type WriteHalf = Self;
+    type Shutdown = Self;
 
-    /// # Safety
-    /// Function must be externally synchronized, calling function from two places same time will make undefinied behavior.
-    unsafe fn flush_unsafe(&self) -> std::io::Result<()>;
+    fn split(self) -> (Self, Self, Self) {
+        let read = self.try_clone().expect("failed to clone Unix socket");
+        let shutdown = self.try_clone().expect("failed to clone Unix socket");
+        (read, self, shutdown)
+    }
 }
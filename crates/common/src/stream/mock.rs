@@ -1,14 +1,13 @@
 use std::{
     io::{Read, Write},
     net::{TcpListener, TcpStream},
-    sync::Mutex,
 };
 
-use super::{UnsafeRead, UnsafeWrite};
+use super::SplitStream;
 
 pub struct MockStream {
-    read: Mutex<TcpStream>,
-    write: Mutex<TcpStream>,
+    read: TcpStream,
+    write: TcpStream,
 }
 
 impl Default for MockStream {
@@ -18,8 +17,8 @@ impl Default for MockStream {
         let stream = TcpStream::connect(addr).unwrap();
 
         Self {
-            read: Mutex::new(stream),
-            write: Mutex::new(listener.accept().unwrap().0),
+            read: stream,
+            write: listener.accept().unwrap().0,
         }
     }
 }
@@ -27,40 +26,108 @@ impl Default for MockStream {
 impl From<TcpStream> for MockStream {
     fn from(inner: TcpStream) -> Self {
         Self {
-            read: inner.try_clone().unwrap().into(),
-            write: inner.into(),
+            read: inner.try_clone().unwrap(),
+            write: inner,
         }
     }
 }
 
 impl Read for MockStream {
     fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.read.lock().unwrap().read(buf)
+        self.read.read(buf)
     }
 }
 
-impl UnsafeRead for MockStream {
-    unsafe fn read_unsafe(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        self.read.lock().unwrap().read(buf)
+impl Write for MockStream {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        self.write.write(buf)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.write.flush()
     }
 }
 
-impl Write for MockStream {
+impl SplitStream for MockStream {
+    type ReadHalf = TcpStream;
+    type WriteHalf = TcpStream;
+    type Shutdown = TcpStream;
+
+    fn split(self) -> (TcpStream, TcpStream, TcpStream) {
+        // Read stream shares socket with the write one when created from `TcpStream`, or it is the other end of a
+        // loopback connection, so shutting down the write one stops both.
+        let shutdown = self.write.try_clone().unwrap();
+        (self.read, self.write, shutdown)
+    }
+}
+
+/// Message based stream over a `SOCK_SEQPACKET` Unix socket pair, which behaves like a vsock seqpacket stream.
+#[cfg(unix)]
+pub struct MockSeqPacketStream {
+    inner: std::os::unix::net::UnixStream,
+}
+
+#[cfg(unix)]
+impl MockSeqPacketStream {
+    pub fn pair() -> (Self, Self) {
+        use std::os::fd::FromRawFd;
+
+        let mut fds = [0; 2];
+        let result = unsafe {
+            libc::socketpair(
+                libc::AF_UNIX,
+                libc::SOCK_SEQPACKET | libc::SOCK_CLOEXEC,
+                0,
+                fds.as_mut_ptr(),
+            )
+        };
+        assert_eq!(0, result, "{}", std::io::Error::last_os_error());
+
+        // Unix stream reads and writes whole messages of seqpacket sockets, so it can wrap them.
+        unsafe {
+            (
+                Self {
+                    inner: std::os::unix::net::UnixStream::from_raw_fd(fds[0]),
+                },
+                Self {
+                    inner: std::os::unix::net::UnixStream::from_raw_fd(fds[1]),
+                },
+            )
+        }
+    }
+}
+
+#[cfg(unix)]
+impl Read for MockSeqPacketStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        self.inner.read(buf)
+    }
+}
+
+#[cfg(unix)]
+impl Write for MockSeqPacketStream {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        self.write.lock().unwrap().write(buf)
+        self.inner.write(buf)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
-        self.write.lock().unwrap().flush()
+        Ok(())
     }
 }
 
-impl UnsafeWrite for MockStream {
-    unsafe fn write_unsafe(&self, buf: &[u8]) -> std::io::Result<usize> {
-        self.write.lock().unwrap().write(buf)
+#[cfg(unix)]
+impl SplitStream for MockSeqPacketStream {
+    type ReadHalf = std::os::unix::net::UnixStream;
+    type WriteHalf = std::os::unix::net::UnixStream;
+    type Shutdown = std::os::unix::net::UnixStream;
+
+    fn split(self) -> (Self::ReadHalf, Self::WriteHalf, Self::Shutdown) {
+        let write = self.inner.try_clone().unwrap();
+        let shutdown = self.inner.try_clone().unwrap();
+        (self.inner, write, shutdown)
     }
 
-    unsafe fn flush_unsafe(&self) -> std::io::Result<()> {
-        self.write.lock().unwrap().flush()
+    fn is_message_based(&self) -> bool {
+        true
     }
 }
@@ -1,4 +1,4 @@
-use std::env;
+use std::{env, str::FromStr};
 
 pub fn is_active(name: &str) -> bool {
     match env::var(name) {
@@ -6,3 +6,7 @@ pub fn is_active(name: &str) -> bool {
         Err(_) => false,
     }
 }
+
+pub fn parse<T: FromStr>(name: &str) -> Option<T> {
+    env::var(name).ok().and_then(|v| v.parse().ok())
+}
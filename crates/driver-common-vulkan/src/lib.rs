@@ -1,4 +1,7 @@
-use std::ffi::{c_char, c_void, CStr};
+use std::{
+    ffi::{c_char, c_void, CStr},
+    mem,
+};
 
 use cdump::{CDumpReader, CDumpWriter};
 
@@ -7,6 +10,12 @@ pub mod generated;
 #[doc = "https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/VK_DEFINE_NON_DISPATCHABLE_HANDLE.html"]
 pub type NonDisposableHandle = u64;
 
+/// Ids of handlers registered by the guest driver, which host calls to invoke guest callbacks.
+pub mod guest_handlers {
+    pub const DEBUG_UTILS_MESSENGER_CALLBACK: u64 = 2000000000;
+    pub const DEBUG_REPORT_CALLBACK: u64 = 2000000001;
+}
+
 /// # Safety
 /// Ptr must be a null or valid pointer to C array of T with len elements.
 pub unsafe fn unpack_vk_array<T>(ptr: *const T, len: usize) -> Option<&'static [T]> {
@@ -30,3 +39,17 @@ pub(crate) unsafe fn unimplemented_deserializer<T: CDumpReader>(_buf: &mut T) ->
 pub(crate) unsafe fn unimplemented_deserializer_mut<T: CDumpReader>(_buf: &mut T) -> *mut c_void {
     unimplemented!("unimplemented_deserializer_mut");
 }
+
+/// Writes value of the pointer which is opaque for the other side, like user data of callbacks, it is never
+/// dereferenced.
+pub(crate) unsafe fn opaque_serializer<T: CDumpWriter>(buf: &mut T, obj: *const c_void) {
+    buf.align::<u64>();
+    buf.push_slice(&(obj as u64).to_ne_bytes());
+}
+
+pub(crate) unsafe fn opaque_deserializer_mut<T: CDumpReader>(buf: &mut T) -> *mut c_void {
+    buf.align::<u64>();
+    let value = *buf.as_mut_ptr_at::<u64>(buf.get_read());
+    buf.add_read(mem::size_of::<u64>());
+    value as *mut c_void
+}
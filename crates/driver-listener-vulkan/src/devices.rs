@@ -0,0 +1,330 @@
+//! Policy of physical devices which guests see. Host can hide devices, or expose only some of them in its own order,
+//! so every guest can get its own GPU or a software device.
+
+use std::{fmt, str::FromStr};
+
+use ash::vk;
+
+/// Selects physical devices by their properties.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum DeviceSelector {
+    /// PCI vendor ID, like `vendor:10de`.
+    Vendor(u32),
+    /// PCI vendor and device ID, like `device:10de:2684`.
+    Device { vendor_id: u32, device_id: u32 },
+    /// Name pattern where `*` matches any text and `?` a single character, like `name:*RTX 4090*`. Case is ignored.
+    Name(String),
+    /// UUID of the device, like `uuid:6f8c5e2a-...`. It is known only for devices which support Vulkan 1.1.
+    Uuid([u8; vk::UUID_SIZE]),
+    /// Type of the device, like `type:cpu` for software rasterizers such as lavapipe.
+    Type(vk::PhysicalDeviceType),
+}
+
+impl DeviceSelector {
+    pub fn matches(&self, device: &DeviceInfo) -> bool {
+        match self {
+            DeviceSelector::Vendor(vendor_id) => device.vendor_id == *vendor_id,
+            DeviceSelector::Device {
+                vendor_id,
+                device_id,
+            } => device.vendor_id == *vendor_id && device.device_id == *device_id,
+            DeviceSelector::Name(pattern) => {
+                let pattern: Vec<_> = pattern.to_lowercase().chars().collect();
+                let name: Vec<_> = device.name.to_lowercase().chars().collect();
+                glob(&pattern, &name)
+            }
+            DeviceSelector::Uuid(uuid) => device.uuid.as_ref() == Some(uuid),
+            DeviceSelector::Type(device_type) => device.device_type == *device_type,
+        }
+    }
+}
+
+impl fmt::Display for DeviceSelector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            DeviceSelector::Vendor(vendor_id) => write!(f, "vendor:{vendor_id:04x}"),
+            DeviceSelector::Device {
+                vendor_id,
+                device_id,
+            } => write!(f, "device:{vendor_id:04x}:{device_id:04x}"),
+            DeviceSelector::Name(pattern) => write!(f, "name:{pattern}"),
+            DeviceSelector::Uuid(uuid) => {
+                f.write_str("uuid:")?;
+                for (index, byte) in uuid.iter().enumerate() {
+                    if matches!(index, 4 | 6 | 8 | 10) {
+                        f.write_str("-")?;
+                    }
+                    write!(f, "{byte:02x}")?;
+                }
+                Ok(())
+            }
+            DeviceSelector::Type(device_type) => f.write_str(match *device_type {
+                vk::PhysicalDeviceType::INTEGRATED_GPU => "type:integrated",
+                vk::PhysicalDeviceType::DISCRETE_GPU => "type:discrete",
+                vk::PhysicalDeviceType::VIRTUAL_GPU => "type:virtual",
+                vk::PhysicalDeviceType::CPU => "type:cpu",
+                _ => "type:other",
+            }),
+        }
+    }
+}
+
+impl FromStr for DeviceSelector {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (kind, value) = s
+            .split_once(':')
+            .ok_or_else(|| format!("device selector must be in `kind:value` format, got `{s}`"))?;
+        let id = |value: &str| {
+            u32::from_str_radix(value.trim_start_matches("0x"), 16)
+                .map_err(|_| format!("invalid hexadecimal ID `{value}` in device selector `{s}`"))
+        };
+
+        match kind {
+            "vendor" => Ok(DeviceSelector::Vendor(id(value)?)),
+            "device" => {
+                let (vendor_id, device_id) = value.split_once(':').ok_or_else(|| {
+                    format!("device selector must be in `device:vendor:device` format, got `{s}`")
+                })?;
+                Ok(DeviceSelector::Device {
+                    vendor_id: id(vendor_id)?,
+                    device_id: id(device_id)?,
+                })
+            }
+            "name" => Ok(DeviceSelector::Name(value.to_owned())),
+            "uuid" => {
+                let digits = value.replace('-', "");
+                let mut uuid = [0; vk::UUID_SIZE];
+                if digits.len() != uuid.len() * 2 || !digits.is_ascii() {
+                    return Err(format!("invalid UUID `{value}` in device selector `{s}`"));
+                }
+                for (index, byte) in uuid.iter_mut().enumerate() {
+                    *byte = u8::from_str_radix(&digits[index * 2..index * 2 + 2], 16)
+                        .map_err(|_| format!("invalid UUID `{value}` in device selector `{s}`"))?;
+                }
+                Ok(DeviceSelector::Uuid(uuid))
+            }
+            "type" => Ok(DeviceSelector::Type(match value {
+                "integrated" => vk::PhysicalDeviceType::INTEGRATED_GPU,
+                "discrete" => vk::PhysicalDeviceType::DISCRETE_GPU,
+                "virtual" => vk::PhysicalDeviceType::VIRTUAL_GPU,
+                "cpu" => vk::PhysicalDeviceType::CPU,
+                "other" => vk::PhysicalDeviceType::OTHER,
+                _ => {
+                    return Err(format!(
+                        "invalid device type `{value}`, expected `integrated`, `discrete`, `virtual`, `cpu` or `other`"
+                    ))
+                }
+            })),
+            _ => Err(format!(
+                "invalid device selector kind `{kind}`, expected `vendor`, `device`, `name`, `uuid` or `type`"
+            )),
+        }
+    }
+}
+
+/// Properties of a physical device which selectors match.
+#[derive(Clone, Debug)]
+pub struct DeviceInfo {
+    pub vendor_id: u32,
+    pub device_id: u32,
+    pub name: String,
+    pub device_type: vk::PhysicalDeviceType,
+    pub uuid: Option<[u8; vk::UUID_SIZE]>,
+}
+
+/// Physical devices which a guest sees, every device is exposed in the host order by default.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct DevicePolicy {
+    /// When not empty, only devices which match any of the selectors are exposed, ordered by the first selector which
+    /// they match. Devices matched by the same selector keep the host order.
+    pub expose: Vec<DeviceSelector>,
+    /// Devices which match any of the selectors are hidden, even when they are exposed.
+    pub hide: Vec<DeviceSelector>,
+}
+
+impl DevicePolicy {
+    /// Tells if the guest sees devices just like the host does.
+    pub fn is_unrestricted(&self) -> bool {
+        self.expose.is_empty() && self.hide.is_empty()
+    }
+
+    /// Tells if device UUIDs must be queried, as they need Vulkan 1.1.
+    pub(crate) fn needs_uuid(&self) -> bool {
+        self.expose
+            .iter()
+            .chain(&self.hide)
+            .any(|selector| matches!(selector, DeviceSelector::Uuid(_)))
+    }
+
+    /// Returns indices of exposed devices, in the order in which the guest sees them.
+    pub(crate) fn select(&self, devices: &[DeviceInfo]) -> Vec<usize> {
+        let mut exposed: Vec<_> = devices
+            .iter()
+            .enumerate()
+            .filter(|(_, device)| !self.hide.iter().any(|selector| selector.matches(device)))
+            .filter_map(|(index, device)| match self.expose.is_empty() {
+                true => Some((0, index)),
+                false => self
+                    .expose
+                    .iter()
+                    .position(|selector| selector.matches(device))
+                    .map(|rank| (rank, index)),
+            })
+            .collect();
+        exposed.sort_unstable();
+        exposed.into_iter().map(|(_, index)| index).collect()
+    }
+}
+
+impl fmt::Display for DevicePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |selectors: &[DeviceSelector]| {
+            selectors
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>()
+                .join(", ")
+        };
+        match (self.expose.is_empty(), self.hide.is_empty()) {
+            (true, true) => f.write_str("all devices"),
+            (false, true) => write!(f, "expose [{}]", join(&self.expose)),
+            (true, false) => write!(f, "hide [{}]", join(&self.hide)),
+            (false, false) => write!(
+                f,
+                "expose [{}], hide [{}]",
+                join(&self.expose),
+                join(&self.hide)
+            ),
+        }
+    }
+}
+
+/// Matches the text against the pattern, where `*` matches any text and `?` a single character.
+fn glob(pattern: &[char], text: &[char]) -> bool {
+    let (mut p, mut t) = (0, 0);
+    // Position after the last `*` and the text position which it matched up to.
+    let mut star = None;
+    while t < text.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p + 1, t));
+                p += 1;
+            }
+            Some(c) if *c == '?' || *c == text[t] => {
+                p += 1;
+                t += 1;
+            }
+            _ => match star {
+                Some((after, matched)) => {
+                    p = after;
+                    t = matched + 1;
+                    star = Some((after, matched + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+    pattern[p..].iter().all(|c| *c == '*')
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use ash::vk;
+    use rstest::rstest;
+
+    use super::{DeviceInfo, DevicePolicy, DeviceSelector};
+
+    fn devices() -> Vec<DeviceInfo> {
+        let device = |vendor_id, device_id, name: &str, device_type, uuid| DeviceInfo {
+            vendor_id,
+            device_id,
+            name: name.to_owned(),
+            device_type,
+            uuid,
+        };
+        vec![
+            device(
+                0x8086,
+                0x56a0,
+                "Intel(R) Arc(tm) A770 Graphics",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                Some([1; 16]),
+            ),
+            device(
+                0x10de,
+                0x2684,
+                "NVIDIA GeForce RTX 4090",
+                vk::PhysicalDeviceType::DISCRETE_GPU,
+                Some([2; 16]),
+            ),
+            device(
+                0x10005,
+                0,
+                "llvmpipe (LLVM 17.0.6, 256 bits)",
+                vk::PhysicalDeviceType::CPU,
+                None,
+            ),
+        ]
+    }
+
+    fn policy(expose: &[&str], hide: &[&str]) -> DevicePolicy {
+        DevicePolicy {
+            expose: expose.iter().map(|x| x.parse().unwrap()).collect(),
+            hide: hide.iter().map(|x| x.parse().unwrap()).collect(),
+        }
+    }
+
+    #[rstest]
+    #[case::unrestricted(&[], &[], &[0, 1, 2])]
+    #[case::vendor(&["vendor:10de"], &[], &[1])]
+    #[case::device(&["device:0x8086:0x56a0"], &[], &[0])]
+    #[case::name(&["name:*geforce*"], &[], &[1])]
+    #[case::uuid(&["uuid:02020202-0202-0202-0202-020202020202"], &[], &[1])]
+    #[case::order(&["type:cpu", "type:discrete"], &[], &[2, 0, 1])]
+    #[case::hide(&[], &["type:cpu"], &[0, 1])]
+    #[case::hide_exposed(&["type:discrete"], &["vendor:8086"], &[1])]
+    #[case::none(&["vendor:1002"], &[], &[])]
+    fn select(#[case] expose: &[&str], #[case] hide: &[&str], #[case] expected: &[usize]) {
+        assert_eq!(expected, policy(expose, hide).select(&devices()));
+    }
+
+    #[rstest]
+    #[case("vendor:10de")]
+    #[case("device:10de:2684")]
+    #[case("name:NVIDIA *")]
+    #[case("uuid:00112233-4455-6677-8899-aabbccddeeff")]
+    #[case("type:cpu")]
+    fn selector_round_trip(#[case] selector: &str) {
+        assert_eq!(
+            selector,
+            selector.parse::<DeviceSelector>().unwrap().to_string()
+        );
+    }
+
+    #[rstest]
+    #[case("10de")]
+    #[case("vendor:nvidia")]
+    #[case("device:10de")]
+    #[case("uuid:0011")]
+    #[case("type:gpu")]
+    #[case("pci:10de")]
+    fn invalid_selector(#[case] selector: &str) {
+        assert!(selector.parse::<DeviceSelector>().is_err());
+    }
+
+    #[rstest]
+    #[case("*", "anything", true)]
+    #[case("nvidia*", "nvidia geforce", true)]
+    #[case("*rtx ?090", "nvidia geforce rtx 4090", true)]
+    #[case("*rtx ?090", "nvidia geforce rtx 4090 ti", false)]
+    #[case("a*b*c", "aXbYbZc", true)]
+    #[case("a*b*c", "aXcYb", false)]
+    #[case("", "", true)]
+    fn glob(#[case] pattern: &str, #[case] text: &str, #[case] expected: bool) {
+        let pattern: Vec<_> = pattern.chars().collect();
+        let text: Vec<_> = text.chars().collect();
+        assert_eq!(expected, super::glob(&pattern, &text));
+    }
+}
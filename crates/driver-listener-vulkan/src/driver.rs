@@ -0,0 +1,135 @@
+//! Vulkan implementation which serves a session. Sessions can load their own Vulkan library, like a loader built with
+//! other drivers or a driver which is loaded directly, such as SwiftShader.
+//!
+//! ICD manifests are read by the Vulkan loader from environment of the process, so every session of a process uses the
+//! same ones. Guests which need their own ICDs are served by isolated workers.
+
+use std::{
+    collections::BTreeMap,
+    env, fmt,
+    path::PathBuf,
+    sync::{Mutex, OnceLock},
+};
+
+use ash::LoadingError;
+
+/// Vulkan loader of the system, which serves sessions without their own library.
+static SYSTEM_ENTRY: OnceLock<ash::Entry> = OnceLock::new();
+
+/// Libraries loaded by sessions, keyed by their path. They are never unloaded, as host Vulkan may keep their functions.
+static LIBRARIES: Mutex<BTreeMap<PathBuf, &'static ash::Entry>> = Mutex::new(BTreeMap::new());
+
+/// Environment variables of the Vulkan loader with ICD manifests which replace the installed ones. Loaders older than
+/// 1.3.207 read only `VK_ICD_FILENAMES`.
+const ICD_VARIABLES: &[&str] = &["VK_DRIVER_FILES", "VK_ICD_FILENAMES"];
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Driver {
+    /// Vulkan library which is loaded instead of the system loader.
+    pub library: Option<PathBuf>,
+    /// ICD manifests which the Vulkan loader uses instead of the installed ones.
+    pub icd_files: Vec<PathBuf>,
+}
+
+impl Driver {
+    #[inline]
+    pub fn is_default(&self) -> bool {
+        self.library.is_none() && self.icd_files.is_empty()
+    }
+
+    /// Loads Vulkan library of the driver, or returns the one which is already loaded.
+    ///
+    /// # Safety
+    /// Loaded libraries cannot be simply dropped, and run their initialization code.
+    pub unsafe fn load(&self) -> Result<&'static ash::Entry, LoadingError> {
+        let Some(library) = &self.library else {
+            if let Some(entry) = SYSTEM_ENTRY.get() {
+                return Ok(entry);
+            }
+            let entry = ash::Entry::load()?;
+            return Ok(SYSTEM_ENTRY.get_or_init(|| entry));
+        };
+
+        let mut libraries = LIBRARIES.lock().unwrap();
+        if let Some(entry) = libraries.get(library) {
+            return Ok(entry);
+        }
+        let entry: &'static ash::Entry = Box::leak(Box::new(ash::Entry::load_from(library)?));
+        info!("Loaded Vulkan library {}", library.display());
+        libraries.insert(library.clone(), entry);
+        Ok(entry)
+    }
+
+    /// Makes the Vulkan loader of this process use ICD manifests of the driver, installed ICDs are used when it has
+    /// none. It must be called before other threads are started, as they could read the environment meanwhile.
+    pub fn use_icd_files(&self) {
+        if self.icd_files.is_empty() {
+            return;
+        }
+        let files = match env::join_paths(&self.icd_files) {
+            Ok(files) => files,
+            Err(e) => {
+                error!(
+                    "Unable to use ICD files, installed ICDs are used instead: {}",
+                    e
+                );
+                return;
+            }
+        };
+        for variable in ICD_VARIABLES {
+            env::set_var(variable, &files);
+        }
+    }
+}
+
+impl fmt::Display for Driver {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.library {
+            Some(library) => write!(f, "library {}", library.display())?,
+            None => f.write_str("system loader")?,
+        }
+        if !self.icd_files.is_empty() {
+            f.write_str(" with ICD files ")?;
+            for (index, file) in self.icd_files.iter().enumerate() {
+                if index > 0 {
+                    f.write_str(", ")?;
+                }
+                write!(f, "{}", file.display())?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::path::PathBuf;
+
+    use super::Driver;
+
+    #[test]
+    fn missing_library() {
+        let driver = Driver {
+            library: Some(PathBuf::from("/nonexistent/libvulkan.so.1")),
+            icd_files: Vec::new(),
+        };
+        assert!(unsafe { driver.load() }.is_err());
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("system loader", Driver::default().to_string());
+        let driver = Driver {
+            library: Some(PathBuf::from("/opt/swiftshader/libvk_swiftshader.so")),
+            icd_files: vec![
+                PathBuf::from("/usr/share/vulkan/icd.d/lvp_icd.x86_64.json"),
+                PathBuf::from("/opt/icd.json"),
+            ],
+        };
+        assert_eq!(
+            "library /opt/swiftshader/libvk_swiftshader.so with ICD files \
+             /usr/share/vulkan/icd.d/lvp_icd.x86_64.json, /opt/icd.json",
+            driver.to_string()
+        );
+    }
+}
@@ -21,12 +21,14 @@ pub fn request_address_for_function(
 ) -> bool {
     // NOTE: For different instance driver can return different addresses, but for now we just use the same address for
     //       all instances. This can be a problem in the future.
+    let session = crate::session::current();
     let address = unsafe {
-        crate::get_or_init_entry()
+        session
+            .entry()
             .get_instance_proc_addr(vk::Instance::from_raw(instance), c_name.as_ptr())
     };
     if address.is_some() {
-        unsafe { crate::FUNCTION_ADDRESS_TABLE.set_address(str_name, address) }
+        session.set_address(str_name, address);
         return true;
     }
     false
@@ -37,14 +39,23 @@ pub fn register_handlers_to(map: &mut HandlerMap) {
 }
 
 fn vk_icd_get_instance_proc_addr(mut packet: Packet) {
-    let instance = packet.read_shallow::<vk::Instance>();
+    let mut instance = packet.read_shallow::<NonDisposableHandle>();
     let p_name = packet.read_null_str();
     let c_name = unsafe { CStr::from_ptr(p_name) };
 
     let str_name = c_name.to_str().expect("UTF-8 valid name");
     trace!("requested address for function `{str_name}`");
 
-    let address = request_address_for_function(instance.as_raw(), c_name, str_name);
+    let address = match crate::session::current()
+        .handles
+        .to_host(&mut instance, vk::ObjectType::INSTANCE)
+    {
+        true => request_address_for_function(instance, c_name, str_name),
+        false => {
+            warn!("vkGetInstanceProcAddr rejected, guest passed unknown handle");
+            false
+        }
+    };
 
     let mut response = packet.write_response(None);
     response.write_shallow(address);
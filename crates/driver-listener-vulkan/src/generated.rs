@@ -1,8 +1,13 @@
+pub(crate) mod destroyers;
 #[allow(
     unused_variables,
     non_snake_case,
     clippy::missing_transmute_annotations
 )]
 pub(crate) mod function_address_table;
+#[allow(dead_code)]
+pub(crate) mod handle_walkers;
 #[allow(unused_variables)]
 pub(crate) mod handlers;
+#[allow(dead_code, clippy::too_many_arguments)]
+pub(crate) mod validators;
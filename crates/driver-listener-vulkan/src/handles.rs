@@ -0,0 +1,283 @@
+//! Opaque IDs which the guest gets instead of host handles.
+//!
+//! Every session has its own IDs, so a guest cannot use objects of another session, and handles passed by the guest
+//! are never given to host Vulkan without being found in the table first. IDs remember the type of their object, so
+//! the guest cannot pass an object where a different type is expected either.
+
+use std::{collections::HashMap, slice, sync::RwLock};
+
+use ash::vk;
+use wie_driver_common_vulkan::NonDisposableHandle;
+
+/// Callback which is called for every handle field of a structure with the type of its object, see
+/// `generated::handle_walkers`.
+pub(crate) type Map<'m> = dyn FnMut(&mut NonDisposableHandle, vk::ObjectType) -> bool + 'm;
+
+#[derive(Default)]
+pub(crate) struct HandleTable {
+    inner: RwLock<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    hosts: HashMap<NonDisposableHandle, (NonDisposableHandle, vk::ObjectType)>,
+    /// Host Vulkan can return the same value for handles of different types, and for multiple objects of the same type
+    /// which are equal, so IDs are looked up by both and count objects which were created with them.
+    ids: HashMap<(NonDisposableHandle, vk::ObjectType), Id>,
+    last_id: NonDisposableHandle,
+}
+
+struct Id {
+    id: NonDisposableHandle,
+    /// Created objects which have the ID. Handles which are only queried, like queues, have none and are forgotten by
+    /// the first removal.
+    references: usize,
+}
+
+impl HandleTable {
+    /// Replaces the guest ID with the host handle. Unknown IDs and IDs of a different object type are replaced with
+    /// null handle and false is returned.
+    ///
+    /// `vk::ObjectType::UNKNOWN` accepts objects of any type, it is used for object handles which are passed as
+    /// `uint64_t` together with their type.
+    pub fn to_host(&self, handle: &mut NonDisposableHandle, ty: vk::ObjectType) -> bool {
+        if *handle == 0 {
+            return true;
+        }
+
+        match self.inner.read().unwrap().hosts.get(handle) {
+            Some((host, host_ty)) if ty == vk::ObjectType::UNKNOWN || ty == *host_ty => {
+                *handle = *host;
+                true
+            }
+            _ => {
+                *handle = 0;
+                false
+            }
+        }
+    }
+
+    /// Replaces the host handle with its guest ID, the ID is created when the guest sees the handle for the first time.
+    /// Always returns true, so it can be passed to walkers.
+    pub fn to_guest(&self, handle: &mut NonDisposableHandle, ty: vk::ObjectType) -> bool {
+        if *handle == 0 {
+            return true;
+        }
+
+        if let Some(id) = self.inner.read().unwrap().ids.get(&(*handle, ty)) {
+            *handle = id.id;
+            return true;
+        }
+
+        // Other thread could create the ID in the meantime.
+        *handle = self.inner.write().unwrap().id(*handle, ty).id;
+        true
+    }
+
+    /// Counts the object created by host Vulkan, so its ID is kept until every object with the same handle is removed.
+    pub fn retain(&self, handle: NonDisposableHandle, ty: vk::ObjectType) {
+        if handle != 0 {
+            self.inner.write().unwrap().id(handle, ty).references += 1;
+        }
+    }
+
+    /// Returns ID of the host handle, without creating a new one. Used for handles reported by host Vulkan, like
+    /// objects of debug messages, which may be unknown to the guest. `vk::ObjectType::UNKNOWN` finds object of any
+    /// type.
+    pub fn find_guest(
+        &self,
+        handle: NonDisposableHandle,
+        ty: vk::ObjectType,
+    ) -> Option<NonDisposableHandle> {
+        if handle == 0 {
+            return Some(0);
+        }
+
+        let inner = self.inner.read().unwrap();
+        match ty {
+            vk::ObjectType::UNKNOWN => inner
+                .ids
+                .iter()
+                .find(|((host, _), _)| *host == handle)
+                .map(|(_, id)| id.id),
+            _ => inner.ids.get(&(handle, ty)).map(|id| id.id),
+        }
+    }
+
+    /// Forgets destroyed host handles, so their IDs are rejected from now on. IDs shared by other live objects are
+    /// kept.
+    pub fn remove(&self, handles: &[(NonDisposableHandle, vk::ObjectType)]) {
+        let mut inner = self.inner.write().unwrap();
+        for key in handles {
+            let Some(id) = inner.ids.get_mut(key) else {
+                continue;
+            };
+            if id.references > 1 {
+                id.references -= 1;
+                continue;
+            }
+
+            let id = id.id;
+            inner.ids.remove(key);
+            inner.hosts.remove(&id);
+        }
+    }
+}
+
+impl Inner {
+    fn id(&mut self, handle: NonDisposableHandle, ty: vk::ObjectType) -> &mut Id {
+        self.ids.entry((handle, ty)).or_insert_with(|| {
+            self.last_id += 1;
+            self.hosts.insert(self.last_id, (handle, ty));
+            Id {
+                id: self.last_id,
+                references: 0,
+            }
+        })
+    }
+}
+
+/// Returns elements of the array for translating them in place, arrays in packets are mutable even when they are
+/// passed as const.
+///
+/// # Safety
+/// Pointer must be null or point to `len` elements, which are not referenced elsewhere.
+#[inline]
+pub(crate) unsafe fn slice_mut<'s, T>(ptr: *const T, len: usize) -> &'s mut [T] {
+    match ptr.is_null() || len == 0 {
+        true => &mut [],
+        false => slice::from_raw_parts_mut(ptr as *mut T, len),
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use ash::vk;
+
+    use super::HandleTable;
+
+    #[test]
+    fn ids_are_translated_back() {
+        let table = HandleTable::default();
+        let mut first = 0x7f00_1000;
+        let mut second = 0x7f00_2000;
+        assert!(table.to_guest(&mut first, vk::ObjectType::BUFFER));
+        assert!(table.to_guest(&mut second, vk::ObjectType::BUFFER));
+        assert_ne!(0x7f00_1000, first);
+        assert_ne!(first, second);
+
+        let mut again = 0x7f00_1000;
+        table.to_guest(&mut again, vk::ObjectType::BUFFER);
+        assert_eq!(first, again);
+
+        assert!(table.to_host(&mut first, vk::ObjectType::BUFFER));
+        assert_eq!(0x7f00_1000, first);
+        assert!(table.to_host(&mut second, vk::ObjectType::BUFFER));
+        assert_eq!(0x7f00_2000, second);
+    }
+
+    #[test]
+    fn unknown_id_is_rejected() {
+        let table = HandleTable::default();
+        let mut host = 0x7f00_1000;
+        table.to_guest(&mut host, vk::ObjectType::BUFFER);
+
+        // Host handle itself is not a valid ID.
+        let mut forged = 0x7f00_1000;
+        assert!(!table.to_host(&mut forged, vk::ObjectType::BUFFER));
+        assert_eq!(0, forged);
+    }
+
+    #[test]
+    fn null_handle_is_kept() {
+        let table = HandleTable::default();
+        let mut handle = 0;
+        assert!(table.to_host(&mut handle, vk::ObjectType::BUFFER));
+        assert!(table.to_guest(&mut handle, vk::ObjectType::BUFFER));
+        assert_eq!(0, handle);
+        assert_eq!(Some(0), table.find_guest(0, vk::ObjectType::BUFFER));
+    }
+
+    #[test]
+    fn removed_handle_is_rejected() {
+        let table = HandleTable::default();
+        let mut id = 0x7f00_1000;
+        table.to_guest(&mut id, vk::ObjectType::BUFFER);
+        assert_eq!(
+            Some(id),
+            table.find_guest(0x7f00_1000, vk::ObjectType::BUFFER)
+        );
+
+        table.remove(&[(0x7f00_1000, vk::ObjectType::BUFFER)]);
+        assert_eq!(None, table.find_guest(0x7f00_1000, vk::ObjectType::BUFFER));
+        assert!(!table.to_host(&mut id, vk::ObjectType::BUFFER));
+    }
+
+    #[test]
+    fn id_of_other_type_is_rejected() {
+        let table = HandleTable::default();
+        let mut id = 0x7f00_1000;
+        table.to_guest(&mut id, vk::ObjectType::BUFFER);
+
+        let mut image = id;
+        assert!(!table.to_host(&mut image, vk::ObjectType::IMAGE));
+        assert_eq!(0, image);
+
+        // Object handles passed with their type accept any object.
+        let mut object = id;
+        assert!(table.to_host(&mut object, vk::ObjectType::UNKNOWN));
+        assert_eq!(0x7f00_1000, object);
+    }
+
+    #[test]
+    fn types_with_same_host_handle_have_own_ids() {
+        let table = HandleTable::default();
+        let mut buffer = 0x7f00_1000;
+        let mut image = 0x7f00_1000;
+        table.to_guest(&mut buffer, vk::ObjectType::BUFFER);
+        table.to_guest(&mut image, vk::ObjectType::IMAGE);
+        assert_ne!(buffer, image);
+        assert_eq!(
+            Some(buffer),
+            table.find_guest(0x7f00_1000, vk::ObjectType::BUFFER)
+        );
+        assert_eq!(
+            Some(image),
+            table.find_guest(0x7f00_1000, vk::ObjectType::IMAGE)
+        );
+
+        let mut host = buffer;
+        assert!(table.to_host(&mut host, vk::ObjectType::BUFFER));
+        assert_eq!(0x7f00_1000, host);
+        let mut host = image;
+        assert!(!table.to_host(&mut host, vk::ObjectType::BUFFER));
+
+        // Destroying the buffer keeps the image.
+        table.remove(&[(0x7f00_1000, vk::ObjectType::BUFFER)]);
+        let mut host = buffer;
+        assert!(!table.to_host(&mut host, vk::ObjectType::BUFFER));
+        let mut host = image;
+        assert!(table.to_host(&mut host, vk::ObjectType::IMAGE));
+        assert_eq!(0x7f00_1000, host);
+    }
+
+    #[test]
+    fn id_is_kept_until_every_duplicate_is_removed() {
+        let table = HandleTable::default();
+        let (mut first, mut second) = (0x7f00_1000, 0x7f00_1000);
+        for handle in [&mut first, &mut second] {
+            table.retain(*handle, vk::ObjectType::SAMPLER);
+            table.to_guest(handle, vk::ObjectType::SAMPLER);
+        }
+        assert_eq!(first, second);
+
+        table.remove(&[(0x7f00_1000, vk::ObjectType::SAMPLER)]);
+        let mut host = first;
+        assert!(table.to_host(&mut host, vk::ObjectType::SAMPLER));
+        assert_eq!(0x7f00_1000, host);
+
+        table.remove(&[(0x7f00_1000, vk::ObjectType::SAMPLER)]);
+        let mut host = first;
+        assert!(!table.to_host(&mut host, vk::ObjectType::SAMPLER));
+    }
+}
@@ -0,0 +1,373 @@
+//! Host objects created by a guest. They are destroyed when its session ends, so a crashed guest does not leak them
+//! until the host exits. Live objects can be dumped, and objects left when the guest destroys its instance are
+//! reported as leaks of the guest application.
+
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::CStr,
+    fmt::{self, Display, Formatter},
+    sync::Mutex,
+    thread::ThreadId,
+    time::{Duration, Instant},
+};
+
+use ash::vk;
+use wie_driver_common_vulkan::NonDisposableHandle;
+
+use crate::generated::function_address_table::FunctionAddressTable;
+
+/// Command which destroys a single object, generated for every handle type which has one.
+#[derive(Clone, Copy)]
+pub(crate) struct Destroyer {
+    pub name: &'static CStr,
+    /// Calls the command with the owner and the object. Instance and device are destroyed without the owner.
+    pub call: unsafe fn(&FunctionAddressTable, NonDisposableHandle, NonDisposableHandle),
+}
+
+pub(crate) struct Object {
+    pub type_name: &'static str,
+    /// Type which the guest ID of the object has, see [`HandleTable`].
+    ///
+    /// [`HandleTable`]: crate::handles::HandleTable
+    pub object_type: vk::ObjectType,
+    /// Command which created the object.
+    pub command: &'static str,
+    /// Instance or device which is passed to the destroy command.
+    pub owner: NonDisposableHandle,
+    /// Object which this one was created from, like its device or pool.
+    pub parent: NonDisposableHandle,
+    /// Objects without a destroyer are freed together with their parent, like command buffers with their pool.
+    pub destroyer: Option<Destroyer>,
+}
+
+#[derive(Default)]
+pub(crate) struct ObjectRegistry {
+    inner: Mutex<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    objects: HashMap<NonDisposableHandle, Entry>,
+    next_order: u64,
+}
+
+struct Entry {
+    object: Object,
+    /// Creation order, parents are always created before their children.
+    order: u64,
+    created_at: Instant,
+    /// Guest thread which called the create command.
+    thread: Option<ThreadId>,
+    /// Name set with `vkSetDebugUtilsObjectNameEXT` or `vkDebugMarkerSetObjectNameEXT`.
+    name: Option<String>,
+}
+
+/// Description of a live object, for dumps and leak reports.
+pub(crate) struct LiveObject {
+    pub handle: NonDisposableHandle,
+    pub type_name: &'static str,
+    pub command: &'static str,
+    pub thread: Option<ThreadId>,
+    pub age: Duration,
+    pub name: Option<String>,
+}
+
+impl Display for LiveObject {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:#x}", self.type_name, self.handle)?;
+        if let Some(name) = &self.name {
+            write!(f, " \"{}\"", name)?;
+        }
+        write!(f, " created by {}", self.command)?;
+        if let Some(thread) = self.thread {
+            write!(f, " on guest {:?}", thread)?;
+        }
+        write!(f, " {:.1?} ago", self.age)
+    }
+}
+
+impl ObjectRegistry {
+    pub fn insert(&self, handle: NonDisposableHandle, object: Object) {
+        if handle == 0 {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap();
+        let entry = Entry {
+            object,
+            order: inner.next_order,
+            created_at: Instant::now(),
+            thread: crate::session::guest_thread(),
+            name: None,
+        };
+        inner.next_order += 1;
+        inner.objects.insert(handle, entry);
+    }
+
+    /// Sets debug name of the object, empty name removes it.
+    pub fn set_name(&self, handle: NonDisposableHandle, name: Option<&str>) {
+        if let Some(entry) = self.inner.lock().unwrap().objects.get_mut(&handle) {
+            entry.name = name.filter(|x| !x.is_empty()).map(str::to_owned);
+        }
+    }
+
+    /// Forgets the object and objects created from it, which are destroyed implicitly or are invalid after it. Returns
+    /// all removed objects with their types.
+    pub fn remove(
+        &self,
+        handle: NonDisposableHandle,
+    ) -> Vec<(NonDisposableHandle, vk::ObjectType)> {
+        let mut inner = self.inner.lock().unwrap();
+        let Some(entry) = inner.objects.remove(&handle) else {
+            return Vec::new();
+        };
+
+        let mut removed = inner.remove_descendants(handle);
+        removed.push((handle, entry.object.object_type));
+        removed
+    }
+
+    /// Forgets objects created from the object, like descriptor sets of a reset pool. Returns all removed objects with
+    /// their types.
+    pub fn remove_children(
+        &self,
+        handle: NonDisposableHandle,
+    ) -> Vec<(NonDisposableHandle, vk::ObjectType)> {
+        self.inner.lock().unwrap().remove_descendants(handle)
+    }
+
+    /// Returns object which the object was created from, like physical device of a device.
+    pub fn parent(&self, handle: NonDisposableHandle) -> Option<NonDisposableHandle> {
+        let inner = self.inner.lock().unwrap();
+        inner
+            .objects
+            .get(&handle)
+            .map(|entry| entry.object.parent)
+            .filter(|parent| *parent != 0)
+    }
+
+    pub fn len(&self) -> usize {
+        self.inner.lock().unwrap().objects.len()
+    }
+
+    /// Returns every live object, in creation order.
+    pub fn live(&self) -> Vec<LiveObject> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries: Vec<_> = inner.objects.iter().collect();
+        entries.sort_by_key(|(_, entry)| entry.order);
+        entries
+            .into_iter()
+            .map(|(handle, entry)| entry.describe(*handle))
+            .collect()
+    }
+
+    /// Returns live objects created from the object, directly or through other objects, which must be destroyed before
+    /// it. Objects freed together with their parent, like command buffers, are covered by the parent.
+    pub fn leaked_by(&self, handle: NonDisposableHandle) -> Vec<LiveObject> {
+        let inner = self.inner.lock().unwrap();
+        let mut entries: Vec<_> = inner
+            .descendants(handle)
+            .into_iter()
+            .filter_map(|handle| Some((handle, inner.objects.get(&handle)?)))
+            .filter(|(_, entry)| entry.object.destroyer.is_some())
+            .collect();
+        entries.sort_by_key(|(_, entry)| entry.order);
+        entries
+            .into_iter()
+            .map(|(handle, entry)| entry.describe(handle))
+            .collect()
+    }
+
+    /// Removes every object, children are ordered before their parents, so they can be destroyed in this order.
+    pub fn take_all(&self) -> Vec<(NonDisposableHandle, Object)> {
+        let mut entries: Vec<_> = self.inner.lock().unwrap().objects.drain().collect();
+        entries.sort_by_key(|(_, entry)| std::cmp::Reverse(entry.order));
+        entries
+            .into_iter()
+            .map(|(handle, entry)| (handle, entry.object))
+            .collect()
+    }
+}
+
+impl Inner {
+    fn descendants(&self, handle: NonDisposableHandle) -> HashSet<NonDisposableHandle> {
+        let mut found = HashSet::from([handle]);
+        loop {
+            let children: Vec<_> = self
+                .objects
+                .iter()
+                .filter(|(child, entry)| {
+                    !found.contains(*child) && found.contains(&entry.object.parent)
+                })
+                .map(|(child, _)| *child)
+                .collect();
+            if children.is_empty() {
+                found.remove(&handle);
+                return found;
+            }
+            found.extend(children);
+        }
+    }
+
+    fn remove_descendants(
+        &mut self,
+        handle: NonDisposableHandle,
+    ) -> Vec<(NonDisposableHandle, vk::ObjectType)> {
+        self.descendants(handle)
+            .into_iter()
+            .filter_map(|child| Some((child, self.objects.remove(&child)?.object.object_type)))
+            .collect()
+    }
+}
+
+impl Entry {
+    fn describe(&self, handle: NonDisposableHandle) -> LiveObject {
+        LiveObject {
+            handle,
+            type_name: self.object.type_name,
+            command: self.object.command,
+            thread: self.thread,
+            age: self.created_at.elapsed(),
+            name: self.name.clone(),
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use ash::vk;
+
+    use super::{Destroyer, Object, ObjectRegistry};
+    use crate::generated::function_address_table::FunctionAddressTable;
+
+    const DESTROYER: Destroyer = Destroyer {
+        name: c"vkDestroy",
+        call: destroy,
+    };
+
+    unsafe fn destroy(_table: &FunctionAddressTable, _owner: u64, _handle: u64) {}
+
+    fn object(type_name: &'static str, parent: u64) -> Object {
+        Object {
+            type_name,
+            object_type: vk::ObjectType::UNKNOWN,
+            command: "vkCreate",
+            owner: parent,
+            parent,
+            destroyer: None,
+        }
+    }
+
+    fn registry() -> ObjectRegistry {
+        let registry = ObjectRegistry::default();
+        registry.insert(1, object("VkInstance", 0));
+        registry.insert(2, object("VkDevice", 100));
+        registry.insert(3, object("VkCommandPool", 2));
+        registry.insert(4, object("VkCommandBuffer", 3));
+        registry.insert(5, object("VkBuffer", 2));
+        registry.insert(6, object("VkSurfaceKHR", 1));
+        registry
+    }
+
+    #[test]
+    fn take_all_orders_children_first() {
+        let registry = registry();
+        let order: Vec<_> = registry
+            .take_all()
+            .into_iter()
+            .map(|(_, object)| object.type_name)
+            .collect();
+        assert_eq!(
+            vec![
+                "VkSurfaceKHR",
+                "VkBuffer",
+                "VkCommandBuffer",
+                "VkCommandPool",
+                "VkDevice",
+                "VkInstance"
+            ],
+            order
+        );
+        assert_eq!(0, registry.len());
+    }
+
+    #[test]
+    fn remove_forgets_descendants() {
+        let registry = registry();
+        let mut removed: Vec<_> = registry
+            .remove(2)
+            .into_iter()
+            .map(|(handle, _)| handle)
+            .collect();
+        removed.sort();
+        assert_eq!(vec![2, 3, 4, 5], removed);
+        let mut handles: Vec<_> = registry
+            .take_all()
+            .into_iter()
+            .map(|(handle, _)| handle)
+            .collect();
+        handles.sort();
+        assert_eq!(vec![1, 6], handles);
+    }
+
+    #[test]
+    fn remove_children_keeps_parent() {
+        let registry = registry();
+        assert_eq!(
+            vec![(4, vk::ObjectType::UNKNOWN)],
+            registry.remove_children(3)
+        );
+        assert_eq!(5, registry.len());
+        assert!(registry.remove(4).is_empty());
+        assert_eq!(5, registry.len());
+    }
+
+    #[test]
+    fn null_handle_is_ignored() {
+        let registry = ObjectRegistry::default();
+        registry.insert(0, object("VkBuffer", 1));
+        assert_eq!(0, registry.len());
+    }
+
+    #[test]
+    fn leaked_by_reports_objects_created_from_instance() {
+        let registry = ObjectRegistry::default();
+        registry.insert(1, object("VkInstance", 0));
+        registry.insert(2, object("VkPhysicalDevice", 1));
+        for (handle, type_name, parent) in [
+            (3, "VkDevice", 2),
+            (4, "VkBuffer", 3),
+            (5, "VkCommandPool", 3),
+        ] {
+            registry.insert(
+                handle,
+                Object {
+                    destroyer: Some(DESTROYER),
+                    ..object(type_name, parent)
+                },
+            );
+        }
+        registry.insert(6, object("VkCommandBuffer", 5));
+        registry.set_name(4, Some("vertices"));
+
+        let leaked = registry.leaked_by(1);
+        let handles: Vec<_> = leaked.iter().map(|x| x.handle).collect();
+        assert_eq!(vec![3, 4, 5], handles);
+        assert_eq!(Some("vertices"), leaked[1].name.as_deref());
+        assert!(leaked[1]
+            .to_string()
+            .starts_with("VkBuffer 0x4 \"vertices\" created by vkCreate"));
+        assert!(registry.leaked_by(6).is_empty());
+    }
+
+    #[test]
+    fn live_is_in_creation_order() {
+        let registry = registry();
+        registry.set_name(5, Some("staging"));
+        registry.set_name(5, Some(""));
+        let live = registry.live();
+        let handles: Vec<_> = live.iter().map(|x| x.handle).collect();
+        assert_eq!(vec![1, 2, 3, 4, 5, 6], handles);
+        assert_eq!(None, live[4].name);
+    }
+}
@@ -5,10 +5,67 @@ use log::Level;
 use wie_driver_common_vulkan::{
     generated::vulkan_types::{
         VkAllocationCallbacks, VkDebugReportCallbackCreateInfoEXT,
-        VkDebugUtilsMessengerCreateInfoEXT,
+        VkDebugUtilsMessengerCallbackDataEXT, VkDebugUtilsMessengerCreateInfoEXT,
     },
-    NonDisposableHandle,
+    guest_handlers, NonDisposableHandle,
 };
+use wie_transport::{packet::PacketWriter, stream::Stream};
+
+/// Callback which lives in the guest process, its pointers are never dereferenced on host.
+pub(crate) enum GuestCallback {
+    Messenger {
+        pfn: u64,
+        user_data: u64,
+        message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
+        message_type: vk::DebugUtilsMessageTypeFlagsEXT,
+    },
+    Report {
+        pfn: u64,
+        user_data: u64,
+        flags: vk::DebugReportFlagsEXT,
+    },
+}
+
+impl GuestCallback {
+    /// Replaces guest callback in create info with the host one, which calls the guest.
+    ///
+    /// Returned box is the user data of the host callback, it must outlive the messenger.
+    pub(crate) unsafe fn redirect_messenger(
+        create_info: &mut VkDebugUtilsMessengerCreateInfoEXT,
+    ) -> Box<Self> {
+        let callback = Box::new(Self::Messenger {
+            pfn: create_info
+                .pfn_user_callback
+                .map_or(0, |x| x as usize as u64),
+            user_data: create_info.p_user_data as u64,
+            message_severity: vk::DebugUtilsMessageSeverityFlagsEXT::from_raw(
+                create_info.message_severity,
+            ),
+            message_type: vk::DebugUtilsMessageTypeFlagsEXT::from_raw(create_info.message_type),
+        });
+
+        create_info.message_severity = u32::MAX;
+        create_info.message_type = u32::MAX;
+        create_info.pfn_user_callback = Some(utils_callback);
+        create_info.p_user_data = &*callback as *const Self as *mut c_void;
+        callback
+    }
+
+    /// Same as [`GuestCallback::redirect_messenger`], but for report callbacks.
+    pub(crate) unsafe fn redirect_report(
+        create_info: &mut VkDebugReportCallbackCreateInfoEXT,
+    ) -> Box<Self> {
+        let callback = Box::new(Self::Report {
+            pfn: create_info.pfn_callback.map_or(0, |x| x as usize as u64),
+            user_data: create_info.p_user_data as u64,
+            flags: vk::DebugReportFlagsEXT::from_raw(create_info.flags),
+        });
+
+        create_info.pfn_callback = Some(report_callback);
+        create_info.p_user_data = &*callback as *const Self as *mut c_void;
+        callback
+    }
+}
 
 pub unsafe fn process_log(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
@@ -64,16 +121,36 @@ pub unsafe fn vk_create_debug_utils_messenger_ext(
         error!("Field `p_next` is not supported yet for `VkDebugUtilsMessengerCreateInfoEXT`.");
     }
 
-    create_info.message_severity = u32::MAX;
-    create_info.message_type = u32::MAX;
-    create_info.pfn_user_callback = Some(utils_callback);
-
-    (crate::FUNCTION_ADDRESS_TABLE.vk_create_debug_utils_messenger_ext)(
+    let callback = GuestCallback::redirect_messenger(create_info);
+    let session = crate::session::current();
+    let result = (session.function_table().vk_create_debug_utils_messenger_ext)(
         instance,
         p_create_info,
         p_allocator,
         p_messenger,
-    )
+    );
+
+    if result == vk::Result::SUCCESS.as_raw() as u32 {
+        session
+            .guest_callbacks
+            .lock()
+            .unwrap()
+            .insert(*p_messenger, callback);
+    }
+    result
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkDestroyDebugUtilsMessengerEXT.html>"]
+pub unsafe fn vk_destroy_debug_utils_messenger_ext(
+    instance: NonDisposableHandle,
+    messenger: NonDisposableHandle,
+    p_allocator: *const VkAllocationCallbacks,
+) {
+    let session = crate::session::current();
+    (session
+        .function_table()
+        .vk_destroy_debug_utils_messenger_ext)(instance, messenger, p_allocator);
+    session.guest_callbacks.lock().unwrap().remove(&messenger);
 }
 
 #[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkCreateDebugReportCallbackEXT.html>"]
@@ -87,39 +164,203 @@ pub unsafe fn vk_create_debug_report_callback_ext(
         p_create_info as *mut VkDebugReportCallbackCreateInfoEXT;
     let create_info = &mut *p_create_info;
 
-    create_info.pfn_callback = Some(report_callback);
-
-    (crate::FUNCTION_ADDRESS_TABLE.vk_create_debug_report_callback_ext)(
+    let callback = GuestCallback::redirect_report(create_info);
+    let session = crate::session::current();
+    let result = (session.function_table().vk_create_debug_report_callback_ext)(
         instance,
         p_create_info,
         p_allocator,
         p_callback,
-    )
+    );
+
+    if result == vk::Result::SUCCESS.as_raw() as u32 {
+        session
+            .guest_callbacks
+            .lock()
+            .unwrap()
+            .insert(*p_callback, callback);
+    }
+    result
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkDestroyDebugReportCallbackEXT.html>"]
+pub unsafe fn vk_destroy_debug_report_callback_ext(
+    instance: NonDisposableHandle,
+    callback: NonDisposableHandle,
+    p_allocator: *const VkAllocationCallbacks,
+) {
+    let session = crate::session::current();
+    (session
+        .function_table()
+        .vk_destroy_debug_report_callback_ext)(instance, callback, p_allocator);
+    session.guest_callbacks.lock().unwrap().remove(&callback);
 }
 
 unsafe extern "system" fn utils_callback(
     message_severity: vk::DebugUtilsMessageSeverityFlagsEXT,
     message_type: vk::DebugUtilsMessageTypeFlagsEXT,
     p_callback_data: *const vk::DebugUtilsMessengerCallbackDataEXT,
-    _p_user_data: *mut c_void,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
     process_log(message_severity, message_type, p_callback_data);
 
-    // TODO: Implement calling the original callback.
+    let GuestCallback::Messenger {
+        pfn,
+        user_data,
+        message_severity: severity_mask,
+        message_type: type_mask,
+    } = *(p_user_data as *const GuestCallback)
+    else {
+        unreachable!("user data of messenger is not a messenger callback");
+    };
 
-    vk::FALSE
+    if pfn == 0
+        || !severity_mask.intersects(message_severity)
+        || !type_mask.intersects(message_type)
+    {
+        return vk::FALSE;
+    }
+
+    // Objects are reported with host handles, the guest knows them by their IDs.
+    let callback_data = &*p_callback_data;
+    let objects: Vec<_> =
+        crate::handles::slice_mut(callback_data.p_objects, callback_data.object_count as usize)
+            .iter()
+            .map(|object| vk::DebugUtilsObjectNameInfoEXT {
+                object_handle: guest_handle(object.object_handle, object.object_type),
+                ..*object
+            })
+            .collect();
+    let callback_data = vk::DebugUtilsMessengerCallbackDataEXT {
+        p_objects: objects.as_ptr(),
+        ..*callback_data
+    };
+
+    call_guest(guest_handlers::DEBUG_UTILS_MESSENGER_CALLBACK, |packet| {
+        packet.write_shallow(pfn);
+        packet.write_shallow(user_data);
+        packet.write_shallow(message_severity);
+        packet.write_shallow(message_type);
+        packet.write_deep(
+            &callback_data as *const vk::DebugUtilsMessengerCallbackDataEXT
+                as *const VkDebugUtilsMessengerCallbackDataEXT,
+        );
+    })
 }
 
+#[allow(clippy::too_many_arguments)]
 unsafe extern "system" fn report_callback(
-    _flags: vk::DebugReportFlagsEXT,
-    _object_type: vk::DebugReportObjectTypeEXT,
-    _object: u64,
-    _location: usize,
-    _message_code: i32,
-    _p_layer_prefix: *const c_char,
-    _p_message: *const c_char,
-    _p_user_data: *mut c_void,
+    flags: vk::DebugReportFlagsEXT,
+    object_type: vk::DebugReportObjectTypeEXT,
+    object: u64,
+    location: usize,
+    message_code: i32,
+    p_layer_prefix: *const c_char,
+    p_message: *const c_char,
+    p_user_data: *mut c_void,
 ) -> vk::Bool32 {
-    // TODO: Implement calling the original callback.
-    vk::FALSE
+    let GuestCallback::Report {
+        pfn,
+        user_data,
+        flags: flags_mask,
+    } = *(p_user_data as *const GuestCallback)
+    else {
+        unreachable!("user data of report callback is not a report callback");
+    };
+
+    if pfn == 0 || !flags_mask.intersects(flags) {
+        return vk::FALSE;
+    }
+
+    call_guest(guest_handlers::DEBUG_REPORT_CALLBACK, |packet| {
+        packet.write_shallow(pfn);
+        packet.write_shallow(user_data);
+        packet.write_shallow(flags);
+        packet.write_shallow(object_type);
+        // Types of debug reports match object types only for core objects.
+        packet.write_shallow(guest_handle(object, vk::ObjectType::UNKNOWN));
+        packet.write_shallow(location);
+        packet.write_shallow(message_code);
+        packet.write_null_str(p_layer_prefix);
+        packet.write_null_str(p_message);
+    })
+}
+
+/// Returns ID of the host handle reported by host Vulkan, handles unknown to the guest are reported as null.
+fn guest_handle(handle: u64, ty: vk::ObjectType) -> u64 {
+    crate::session::try_current()
+        .and_then(|session| session.handles.find_guest(handle, ty))
+        .unwrap_or(0)
+}
+
+/// Calls guest handler and waits for the result of the guest callback.
+///
+/// Guest handles it on its own handler thread, so it works also when the guest thread which caused the callback is
+/// blocked, waiting for the response of the current command.
+unsafe fn call_guest<F>(handler: u64, write: F) -> vk::Bool32
+where
+    F: FnOnce(&mut PacketWriter<'_, Stream>),
+{
+    // Host Vulkan calls callbacks on the thread of the command which caused them, so it runs in the guest session.
+    let Some(connection) = crate::session::try_current().and_then(|session| session.connection())
+    else {
+        warn!("Guest callback skipped, connection is not established.");
+        return vk::FALSE;
+    };
+
+    let mut packet = connection.new_packet(handler);
+    write(&mut packet);
+    match packet.try_send_with_response() {
+        Ok(mut response) => response.read_shallow(),
+        Err(e) => {
+            warn!("Guest callback skipped: {}", e);
+            vk::FALSE
+        }
+    }
+}
+
+/// Redirects guest callbacks chained to the instance create info, see [`GuestCallback::redirect_messenger`].
+pub(crate) unsafe fn redirect_chained_callbacks(
+    mut p_next: *const c_void,
+) -> Vec<Box<GuestCallback>> {
+    let mut callbacks = Vec::new();
+    while !p_next.is_null() {
+        let base = &*(p_next as *const vk::BaseInStructure);
+        match base.s_type {
+            vk::StructureType::DEBUG_UTILS_MESSENGER_CREATE_INFO_EXT => {
+                callbacks.push(GuestCallback::redirect_messenger(
+                    &mut *(p_next as *mut VkDebugUtilsMessengerCreateInfoEXT),
+                ))
+            }
+            vk::StructureType::DEBUG_REPORT_CALLBACK_CREATE_INFO_EXT => {
+                callbacks.push(GuestCallback::redirect_report(
+                    &mut *(p_next as *mut VkDebugReportCallbackCreateInfoEXT),
+                ))
+            }
+            _ => {}
+        }
+        p_next = base.p_next as *const c_void;
+    }
+    callbacks
+}
+
+pub(crate) fn set_instance_callbacks(
+    instance: NonDisposableHandle,
+    callbacks: Vec<Box<GuestCallback>>,
+) {
+    if !callbacks.is_empty() {
+        crate::session::current()
+            .instance_callbacks
+            .lock()
+            .unwrap()
+            .insert(instance, callbacks);
+    }
+}
+
+pub(crate) fn remove_instance_callbacks(instance: NonDisposableHandle) {
+    crate::session::current()
+        .instance_callbacks
+        .lock()
+        .unwrap()
+        .remove(&instance);
 }
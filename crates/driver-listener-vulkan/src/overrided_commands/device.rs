@@ -0,0 +1,17 @@
+use wie_driver_common_vulkan::{
+    generated::vulkan_types::VkAllocationCallbacks, NonDisposableHandle,
+};
+
+use crate::scheduler;
+
+/// Queues of the device stop being measured by the scheduler before it is destroyed, as their fences are destroyed
+/// with them.
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkDestroyDevice.html>"]
+pub unsafe fn vk_destroy_device(
+    device: NonDisposableHandle,
+    p_allocator: *const VkAllocationCallbacks,
+) {
+    let session = crate::session::current();
+    scheduler::release_device(&session, device);
+    (session.function_table().vk_destroy_device)(device, p_allocator);
+}
@@ -4,7 +4,7 @@ use std::{
 };
 
 use ash::vk;
-use wie_common::utils::{cstr, env};
+use wie_common::utils::cstr;
 use wie_driver_common_vulkan::{
     generated::vulkan_types::{
         VkAllocationCallbacks, VkDebugUtilsMessengerCreateInfoEXT, VkInstanceCreateInfo,
@@ -12,9 +12,11 @@ use wie_driver_common_vulkan::{
     NonDisposableHandle,
 };
 
-use crate::{entry, utils};
+use super::debug;
+use crate::{entry, settings, utils};
 
 const VALIDATION_LAYER_NAME: &CStr = c"VK_LAYER_KHRONOS_validation";
+const CAPTURE_LAYER_NAME: &CStr = c"VK_LAYER_LUNARG_gfxreconstruct";
 
 #[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkCreateInstance.html>"]
 pub unsafe fn vk_create_instance(
@@ -22,9 +24,14 @@ pub unsafe fn vk_create_instance(
     p_allocator: *const VkAllocationCallbacks,
     p_instance: *mut NonDisposableHandle,
 ) -> u32 {
-    let p_create_info = p_create_info as *mut _;
+    let p_create_info = p_create_info as *mut VkInstanceCreateInfo;
+    let settings = settings::get();
+    let _outlive_buf3 = match settings.capture.is_some() {
+        true => turn_on_capture_layer(p_create_info),
+        false => None,
+    };
     let ((_outlive_buf, layers), (_outlive_buf2, extensions)) =
-        match crate::ENABLE_VALIDATION_LAYERS || env::is_active("VK_VALIDATION_LAYERS") {
+        match settings.validation_layers.is_enabled() {
             true => (
                 turn_on_validation_layers(p_create_info),
                 turn_on_debug_utils_extension(p_create_info),
@@ -32,10 +39,18 @@ pub unsafe fn vk_create_instance(
             false => ((None, false), (None, false)),
         };
 
+    let callbacks = debug::redirect_chained_callbacks((*p_create_info).p_next);
+
     trace!("updated data {:?}", p_create_info.as_ref());
+    let session = crate::session::current();
     let result =
-        (crate::FUNCTION_ADDRESS_TABLE.vk_create_instance)(p_create_info, p_allocator, p_instance);
+        (session.function_table().vk_create_instance)(p_create_info, p_allocator, p_instance);
 
+    if result != vk::Result::SUCCESS.as_raw() as u32 {
+        return result;
+    }
+
+    debug::set_instance_callbacks(*p_instance, callbacks);
     if layers && extensions {
         create_log_callback(*p_instance);
     }
@@ -43,6 +58,16 @@ pub unsafe fn vk_create_instance(
     result
 }
 
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkDestroyInstance.html>"]
+pub unsafe fn vk_destroy_instance(
+    instance: NonDisposableHandle,
+    p_allocator: *const VkAllocationCallbacks,
+) {
+    let session = crate::session::current();
+    (session.function_table().vk_destroy_instance)(instance, p_allocator);
+    debug::remove_instance_callbacks(instance);
+}
+
 unsafe fn turn_on_validation_layers(
     create_info: *mut VkInstanceCreateInfo,
 ) -> (Option<Vec<*const c_char>>, bool) {
@@ -82,6 +107,35 @@ Make sure to have it properly installed in system.
     (None, false)
 }
 
+unsafe fn turn_on_capture_layer(
+    create_info: *mut VkInstanceCreateInfo,
+) -> Option<Vec<*const c_char>> {
+    let create_info = &mut *create_info;
+    if cstr::contains(
+        CAPTURE_LAYER_NAME,
+        create_info.pp_enabled_layer_names,
+        create_info.enabled_layer_count as usize,
+    ) {
+        return None;
+    }
+
+    let layers = utils::instance::get_layer_properties();
+    if !layers
+        .iter()
+        .any(|layer| cstr::eq_inline(CAPTURE_LAYER_NAME, &layer.layer_name))
+    {
+        error!("GFXReconstruct capture layer is not available on host, capture is disabled.");
+        return None;
+    }
+
+    info!("Enabled GFXReconstruct capture layer.");
+    Some(cstr::extend_array(
+        CAPTURE_LAYER_NAME,
+        &mut create_info.pp_enabled_layer_names,
+        &mut create_info.enabled_layer_count,
+    ))
+}
+
 unsafe fn turn_on_debug_utils_extension(
     create_info: *mut VkInstanceCreateInfo,
 ) -> (Option<Vec<*const c_char>>, bool) {
@@ -143,7 +197,8 @@ unsafe fn create_log_callback(instance: NonDisposableHandle) {
     });
 
     let mut p_messenger = 0;
-    let result = (crate::FUNCTION_ADDRESS_TABLE.vk_create_debug_utils_messenger_ext)(
+    let session = crate::session::current();
+    let result = (session.function_table().vk_create_debug_utils_messenger_ext)(
         instance,
         &create_info,
         ptr::null(),
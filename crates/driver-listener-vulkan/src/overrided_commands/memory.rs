@@ -0,0 +1,145 @@
+use ash::vk;
+use wie_driver_common_vulkan::{
+    generated::vulkan_types::{
+        VkAllocationCallbacks, VkMemoryAllocateInfo, VkPhysicalDeviceMemoryProperties2,
+    },
+    NonDisposableHandle,
+};
+
+use crate::{entry, quotas::Heap, session::Session};
+
+/// Allocations which would exceed the memory quota of the guest fail, like the heap is full.
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkAllocateMemory.html>"]
+pub unsafe fn vk_allocate_memory(
+    device: NonDisposableHandle,
+    p_allocate_info: *const VkMemoryAllocateInfo,
+    p_allocator: *const VkAllocationCallbacks,
+    p_memory: *mut NonDisposableHandle,
+) -> u32 {
+    let session = crate::session::current();
+    let allocate = || {
+        (session.function_table().vk_allocate_memory)(
+            device,
+            p_allocate_info,
+            p_allocator,
+            p_memory,
+        )
+    };
+    if session.quotas.quotas().memory_per_heap.is_none() {
+        return allocate();
+    }
+
+    let size = (*p_allocate_info).allocation_size;
+    let Some(heap) = heap_of(&session, device, (*p_allocate_info).memory_type_index) else {
+        warn!(
+            "Session {}: unable to find heap of memory type {}, allocation is rejected",
+            session.id(),
+            (*p_allocate_info).memory_type_index
+        );
+        return vk::Result::ERROR_OUT_OF_DEVICE_MEMORY.as_raw() as u32;
+    };
+    if !session.quotas.reserve_memory(heap, size) {
+        debug!(
+            "Session {}: allocation of {} bytes from heap {} is over the quota",
+            session.id(),
+            size,
+            heap.1
+        );
+        return vk::Result::ERROR_OUT_OF_DEVICE_MEMORY.as_raw() as u32;
+    }
+
+    let result = allocate();
+    match result == vk::Result::SUCCESS.as_raw() as u32 {
+        true => session.quotas.commit_memory(*p_memory, heap, size),
+        false => session.quotas.cancel_memory(heap, size),
+    }
+    result
+}
+
+/// Budget of every heap is limited by the memory quota of the guest, and its usage is the memory allocated by the guest,
+/// not by the whole host.
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkGetPhysicalDeviceMemoryProperties2.html>"]
+pub unsafe fn vk_get_physical_device_memory_properties2(
+    physical_device: NonDisposableHandle,
+    p_memory_properties: *mut VkPhysicalDeviceMemoryProperties2,
+) {
+    let session = crate::session::current();
+    (session
+        .function_table()
+        .vk_get_physical_device_memory_properties2)(physical_device, p_memory_properties);
+    let Some(quota) = session.quotas.quotas().memory_per_heap else {
+        return;
+    };
+
+    let properties = &*(p_memory_properties as *const vk::PhysicalDeviceMemoryProperties2);
+    let heap_count = properties.memory_properties.memory_heap_count as usize;
+    let mut next = properties.p_next as *mut vk::BaseOutStructure;
+    while let Some(structure) = next.as_mut() {
+        if structure.s_type == vk::StructureType::PHYSICAL_DEVICE_MEMORY_BUDGET_PROPERTIES_EXT {
+            let budget = &mut *(structure as *mut vk::BaseOutStructure
+                as *mut vk::PhysicalDeviceMemoryBudgetPropertiesEXT);
+            for heap in 0..heap_count.min(vk::MAX_MEMORY_HEAPS) {
+                budget.heap_budget[heap] = budget.heap_budget[heap].min(quota);
+                budget.heap_usage[heap] =
+                    session.quotas.memory_usage((physical_device, heap as u32));
+            }
+        }
+        next = structure.p_next;
+    }
+}
+
+/// Remembers heaps of memory types of the physical devices, when the session has the memory quota. Called when the
+/// devices are enumerated, so allocations do not query them again.
+pub(crate) unsafe fn cache_memory_heaps(
+    session: &Session,
+    instance: NonDisposableHandle,
+    physical_devices: &[NonDisposableHandle],
+) {
+    if session.quotas.quotas().memory_per_heap.is_none()
+        || !entry::make_sure_function_is_loaded(instance, c"vkGetPhysicalDeviceMemoryProperties")
+    {
+        return;
+    }
+
+    let table = session.function_table();
+    let mut heaps = session.memory_heaps.lock().unwrap();
+    for physical_device in physical_devices {
+        heaps.entry(*physical_device).or_insert_with(|| {
+            let mut properties = vk::PhysicalDeviceMemoryProperties::default();
+            (table.vk_get_physical_device_memory_properties)(
+                *physical_device,
+                &mut properties as *mut vk::PhysicalDeviceMemoryProperties as *mut _,
+            );
+            properties
+                .memory_types_as_slice()
+                .iter()
+                .map(|memory_type| memory_type.heap_index)
+                .collect()
+        });
+    }
+}
+
+/// Returns heap of the memory type, on the physical device which the device was created from. Devices which were not
+/// enumerated by the guest, only their groups, are cached by the first allocation.
+unsafe fn heap_of(
+    session: &Session,
+    device: NonDisposableHandle,
+    memory_type_index: u32,
+) -> Option<Heap> {
+    let physical_device = session.objects.parent(device)?;
+    if !session
+        .memory_heaps
+        .lock()
+        .unwrap()
+        .contains_key(&physical_device)
+    {
+        let instance = session.objects.parent(physical_device)?;
+        cache_memory_heaps(session, instance, &[physical_device]);
+    }
+
+    let heaps = session.memory_heaps.lock().unwrap();
+    let heap = heaps
+        .get(&physical_device)?
+        .get(memory_type_index as usize)?;
+    Some((physical_device, *heap))
+}
@@ -1,9 +1,26 @@
 pub mod debug;
+pub mod device;
 pub mod instance;
+pub mod memory;
+pub mod physical_device;
+pub mod queue;
 
 // Functions must be public used directly, without ::* syntax.
 // Sort alphabetically.
 
 pub use debug::vk_create_debug_report_callback_ext;
 pub use debug::vk_create_debug_utils_messenger_ext;
+pub use debug::vk_destroy_debug_report_callback_ext;
+pub use debug::vk_destroy_debug_utils_messenger_ext;
+pub use device::vk_destroy_device;
 pub use instance::vk_create_instance;
+pub use instance::vk_destroy_instance;
+pub use memory::vk_allocate_memory;
+pub use memory::vk_get_physical_device_memory_properties2;
+pub use physical_device::vk_enumerate_physical_device_groups;
+pub use physical_device::vk_enumerate_physical_devices;
+pub use queue::vk_get_device_queue;
+pub use queue::vk_get_device_queue2;
+pub use queue::vk_queue_present_khr;
+pub use queue::vk_queue_submit;
+pub use queue::vk_queue_submit2;
@@ -0,0 +1,240 @@
+use std::{collections::HashMap, ffi::c_void, mem, ptr, slice};
+
+use ash::vk;
+use wie_driver_common_vulkan::{
+    generated::vulkan_types::VkPhysicalDeviceGroupProperties, NonDisposableHandle,
+};
+
+use super::memory;
+use crate::{devices::DeviceInfo, entry, session::Session};
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkEnumeratePhysicalDevices.html>"]
+pub unsafe fn vk_enumerate_physical_devices(
+    instance: NonDisposableHandle,
+    p_physical_device_count: *mut u32,
+    p_physical_devices: *mut NonDisposableHandle,
+) -> u32 {
+    let session = crate::session::current();
+    if session.devices().is_unrestricted() {
+        let result = (session.function_table().vk_enumerate_physical_devices)(
+            instance,
+            p_physical_device_count,
+            p_physical_devices,
+        );
+        if (result as i32) >= 0 && !p_physical_devices.is_null() {
+            let devices =
+                slice::from_raw_parts(p_physical_devices, *p_physical_device_count as usize);
+            memory::cache_memory_heaps(&session, instance, devices);
+        }
+        return result;
+    }
+
+    let exposed = match exposed_devices(&session, instance) {
+        Ok(exposed) => exposed,
+        Err(result) => return result,
+    };
+    memory::cache_memory_heaps(&session, instance, &exposed);
+    write_elements(
+        exposed.len(),
+        p_physical_device_count,
+        p_physical_devices,
+        |index, device| *device = exposed[index],
+    )
+}
+
+/// Groups keep only exposed devices, groups without any are hidden. They are ordered by their first exposed device.
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkEnumeratePhysicalDeviceGroups.html>"]
+pub unsafe fn vk_enumerate_physical_device_groups(
+    instance: NonDisposableHandle,
+    p_physical_device_group_count: *mut u32,
+    p_physical_device_group_properties: *mut VkPhysicalDeviceGroupProperties,
+) -> u32 {
+    let session = crate::session::current();
+    if session.devices().is_unrestricted() {
+        return (session.function_table().vk_enumerate_physical_device_groups)(
+            instance,
+            p_physical_device_group_count,
+            p_physical_device_group_properties,
+        );
+    }
+
+    let exposed = match exposed_devices(&session, instance) {
+        Ok(exposed) => exposed,
+        Err(result) => return result,
+    };
+    let positions: HashMap<_, _> = exposed
+        .iter()
+        .enumerate()
+        .map(|(position, device)| (*device, position))
+        .collect();
+
+    let groups = enumerate(
+        || {
+            let mut group: VkPhysicalDeviceGroupProperties = mem::zeroed();
+            group.s_type = vk::StructureType::PHYSICAL_DEVICE_GROUP_PROPERTIES.as_raw() as _;
+            group
+        },
+        |count, groups| {
+            (session.function_table().vk_enumerate_physical_device_groups)(instance, count, groups)
+        },
+    );
+    let groups = match groups {
+        Ok(groups) => groups,
+        Err(result) => return result,
+    };
+
+    let mut exposed_groups: Vec<_> = groups
+        .iter()
+        .filter_map(|group| {
+            let devices: Vec<_> = group
+                .physical_devices
+                .iter()
+                .take(group.physical_device_count as usize)
+                .copied()
+                .filter(|device| positions.contains_key(device))
+                .collect();
+            let first = devices.iter().map(|device| positions[device]).min()?;
+            Some((first, devices, group.subset_allocation))
+        })
+        .collect();
+    exposed_groups.sort_unstable_by_key(|(first, _, _)| *first);
+
+    // Structure type and chain of the guest are kept.
+    write_elements(
+        exposed_groups.len(),
+        p_physical_device_group_count,
+        p_physical_device_group_properties,
+        |index, group| {
+            let (_, devices, subset_allocation) = &exposed_groups[index];
+            group.physical_device_count = devices.len() as u32;
+            for (slot, device) in group.physical_devices.iter_mut().enumerate() {
+                *slot = devices.get(slot).copied().unwrap_or_default();
+            }
+            group.subset_allocation = *subset_allocation;
+        },
+    )
+}
+
+/// Returns host physical devices which are exposed by the device policy of the session, in the guest order.
+unsafe fn exposed_devices(
+    session: &Session,
+    instance: NonDisposableHandle,
+) -> Result<Vec<NonDisposableHandle>, u32> {
+    if !entry::make_sure_function_is_loaded(instance, c"vkGetPhysicalDeviceProperties") {
+        return Err(vk::Result::ERROR_INITIALIZATION_FAILED.as_raw() as u32);
+    }
+    let policy = session.devices();
+    // UUID is a property of Vulkan 1.1, which is not needed by other selectors.
+    let uuid = policy.needs_uuid()
+        && entry::make_sure_function_is_loaded(instance, c"vkGetPhysicalDeviceProperties2");
+
+    let devices = enumerate(
+        || 0,
+        |count, devices| {
+            (session.function_table().vk_enumerate_physical_devices)(instance, count, devices)
+        },
+    )?;
+    let infos: Vec<_> = devices
+        .iter()
+        .map(|device| device_info(session, *device, uuid))
+        .collect();
+
+    let exposed: Vec<_> = policy
+        .select(&infos)
+        .into_iter()
+        .map(|index| devices[index])
+        .collect();
+    debug!(
+        "Session {}: exposing {} of {} physical device(s) with policy {}",
+        session.id(),
+        exposed.len(),
+        devices.len(),
+        policy
+    );
+    Ok(exposed)
+}
+
+unsafe fn device_info(session: &Session, device: NonDisposableHandle, uuid: bool) -> DeviceInfo {
+    let mut properties = vk::PhysicalDeviceProperties::default();
+    (session.function_table().vk_get_physical_device_properties)(
+        device,
+        &mut properties as *mut vk::PhysicalDeviceProperties as *mut _,
+    );
+
+    let uuid = (uuid && properties.api_version >= vk::API_VERSION_1_1).then(|| {
+        let mut id = vk::PhysicalDeviceIDProperties::default();
+        let mut properties2 = vk::PhysicalDeviceProperties2 {
+            p_next: &mut id as *mut vk::PhysicalDeviceIDProperties as *mut c_void,
+            ..Default::default()
+        };
+        (session.function_table().vk_get_physical_device_properties2)(
+            device,
+            &mut properties2 as *mut vk::PhysicalDeviceProperties2 as *mut _,
+        );
+        id.device_uuid
+    });
+
+    DeviceInfo {
+        vendor_id: properties.vendor_id,
+        device_id: properties.device_id,
+        name: properties
+            .device_name_as_c_str()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned(),
+        device_type: properties.device_type,
+        uuid,
+    }
+}
+
+/// Calls enumeration command of host Vulkan for the count and then for the elements, which start as `new` returns
+/// them. Enumeration is repeated when elements are added between these calls.
+unsafe fn enumerate<T>(
+    new: impl Fn() -> T,
+    mut command: impl FnMut(*mut u32, *mut T) -> u32,
+) -> Result<Vec<T>, u32> {
+    loop {
+        let mut count = 0;
+        let result = command(&mut count, ptr::null_mut());
+        if result != vk::Result::SUCCESS.as_raw() as u32 {
+            return Err(result);
+        }
+
+        let mut elements: Vec<_> = (0..count).map(|_| new()).collect();
+        let result = command(&mut count, elements.as_mut_ptr());
+        if result == vk::Result::SUCCESS.as_raw() as u32 {
+            elements.truncate(count as usize);
+            return Ok(elements);
+        }
+        if result != vk::Result::INCOMPLETE.as_raw() as u32 {
+            return Err(result);
+        }
+    }
+}
+
+/// Writes `len` elements to the array of the guest, like Vulkan enumeration commands do. Only the count is written
+/// when the array is null, and `VK_INCOMPLETE` is returned when the array is too small.
+unsafe fn write_elements<T>(
+    len: usize,
+    p_count: *mut u32,
+    p_elements: *mut T,
+    mut write: impl FnMut(usize, &mut T),
+) -> u32 {
+    if p_elements.is_null() {
+        *p_count = len as u32;
+        return vk::Result::SUCCESS.as_raw() as u32;
+    }
+
+    let written = len.min(*p_count as usize);
+    for (index, element) in crate::handles::slice_mut(p_elements, written)
+        .iter_mut()
+        .enumerate()
+    {
+        write(index, element);
+    }
+    *p_count = written as u32;
+    match written < len {
+        true => vk::Result::INCOMPLETE.as_raw() as u32,
+        false => vk::Result::SUCCESS.as_raw() as u32,
+    }
+}
@@ -0,0 +1,79 @@
+//! Submissions over the rate quota of the guest are delayed until they are allowed. Submissions and presents run on
+//! the queue thread of their session, so the delay does not hold handler threads of other sessions.
+//! Submissions and presents also wait for their turn in the [`scheduler`](crate::scheduler), which shares GPU queues
+//! fairly between sessions. Queues are measured by the scheduler from when the guest gets them until their device is
+//! destroyed.
+
+use wie_driver_common_vulkan::{
+    generated::vulkan_types::{VkDeviceQueueInfo2, VkPresentInfoKHR, VkSubmitInfo, VkSubmitInfo2},
+    NonDisposableHandle,
+};
+
+use crate::scheduler;
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkGetDeviceQueue.html>"]
+pub unsafe fn vk_get_device_queue(
+    device: NonDisposableHandle,
+    queue_family_index: u32,
+    queue_index: u32,
+    p_queue: *mut NonDisposableHandle,
+) {
+    let session = crate::session::current();
+    (session.function_table().vk_get_device_queue)(
+        device,
+        queue_family_index,
+        queue_index,
+        p_queue,
+    );
+    scheduler::add_queue(&session, device, *p_queue);
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkGetDeviceQueue2.html>"]
+pub unsafe fn vk_get_device_queue2(
+    device: NonDisposableHandle,
+    p_queue_info: *const VkDeviceQueueInfo2,
+    p_queue: *mut NonDisposableHandle,
+) {
+    let session = crate::session::current();
+    (session.function_table().vk_get_device_queue2)(device, p_queue_info, p_queue);
+    scheduler::add_queue(&session, device, *p_queue);
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkQueueSubmit.html>"]
+pub unsafe fn vk_queue_submit(
+    queue: NonDisposableHandle,
+    submit_count: u32,
+    p_submits: *const VkSubmitInfo,
+    fence: NonDisposableHandle,
+) -> u32 {
+    let session = crate::session::current();
+    session.quotas.throttle_submission();
+    scheduler::submit(&session, queue, fence, |fence| {
+        (session.function_table().vk_queue_submit)(queue, submit_count, p_submits, fence)
+    })
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkQueueSubmit2.html>"]
+pub unsafe fn vk_queue_submit2(
+    queue: NonDisposableHandle,
+    submit_count: u32,
+    p_submits: *const VkSubmitInfo2,
+    fence: NonDisposableHandle,
+) -> u32 {
+    let session = crate::session::current();
+    session.quotas.throttle_submission();
+    scheduler::submit(&session, queue, fence, |fence| {
+        (session.function_table().vk_queue_submit2)(queue, submit_count, p_submits, fence)
+    })
+}
+
+#[doc = "<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/vkQueuePresentKHR.html>"]
+pub unsafe fn vk_queue_present_khr(
+    queue: NonDisposableHandle,
+    p_present_info: *const VkPresentInfoKHR,
+) -> u32 {
+    let session = crate::session::current();
+    scheduler::present(&session, || {
+        (session.function_table().vk_queue_present_khr)(queue, p_present_info)
+    })
+}
@@ -0,0 +1,258 @@
+//! Limits of host resources which a guest can use, so a noisy guest does not starve others on a shared host GPU.
+//!
+//! Quotas are checked before commands are called, so concurrent commands of the guest can exceed the object quota by
+//! the number of handler threads.
+
+use std::{
+    collections::HashMap,
+    fmt,
+    num::NonZeroU32,
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+use wie_driver_common_vulkan::NonDisposableHandle;
+
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Quotas {
+    /// Bytes of device memory which the guest can allocate from every memory heap.
+    pub memory_per_heap: Option<u64>,
+    /// Host objects which the guest can have alive.
+    pub objects: Option<usize>,
+    /// Queue submissions per second, the guest can submit this many at once, later submissions are delayed.
+    pub submissions_per_second: Option<NonZeroU32>,
+}
+
+impl Quotas {
+    #[inline]
+    pub fn is_unlimited(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl fmt::Display for Quotas {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut limits = Vec::new();
+        if let Some(memory) = self.memory_per_heap {
+            limits.push(format!("{} MiB per heap", memory / MIB));
+        }
+        if let Some(objects) = self.objects {
+            limits.push(format!("{} objects", objects));
+        }
+        if let Some(submissions) = self.submissions_per_second {
+            limits.push(format!("{} submissions/s", submissions));
+        }
+        match limits.is_empty() {
+            true => f.write_str("unlimited"),
+            false => f.write_str(&limits.join(", ")),
+        }
+    }
+}
+
+const MIB: u64 = 1024 * 1024;
+
+/// Memory heap of a physical device, memory quota applies to each one separately.
+pub(crate) type Heap = (NonDisposableHandle, u32);
+
+/// Resources used by a session, which are limited by its quotas.
+#[derive(Default)]
+pub(crate) struct QuotaUsage {
+    quotas: Quotas,
+    memory: Mutex<MemoryUsage>,
+    submissions: Mutex<Option<RateLimiter>>,
+}
+
+#[derive(Default)]
+struct MemoryUsage {
+    /// Allocated and reserved bytes.
+    heaps: HashMap<Heap, u64>,
+    /// Allocations which are not freed yet, keyed by their memory object.
+    allocations: HashMap<NonDisposableHandle, (Heap, u64)>,
+}
+
+impl QuotaUsage {
+    pub fn new(quotas: Quotas) -> Self {
+        Self {
+            submissions: Mutex::new(quotas.submissions_per_second.map(RateLimiter::new)),
+            quotas,
+            memory: Mutex::default(),
+        }
+    }
+
+    #[inline]
+    pub fn quotas(&self) -> &Quotas {
+        &self.quotas
+    }
+
+    /// Reserves memory of the heap for an allocation, returns false when it is over the quota. Reservation is either
+    /// [`committed`](Self::commit_memory) or [`cancelled`](Self::cancel_memory) once the allocation finishes.
+    pub fn reserve_memory(&self, heap: Heap, size: u64) -> bool {
+        let mut memory = self.memory.lock().unwrap();
+        let used = memory.heaps.entry(heap).or_default();
+        if self
+            .quotas
+            .memory_per_heap
+            .is_some_and(|quota| used.saturating_add(size) > quota)
+        {
+            return false;
+        }
+        *used += size;
+        true
+    }
+
+    pub fn commit_memory(&self, memory_object: NonDisposableHandle, heap: Heap, size: u64) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.allocations.insert(memory_object, (heap, size));
+    }
+
+    pub fn cancel_memory(&self, heap: Heap, size: u64) {
+        let mut memory = self.memory.lock().unwrap();
+        memory.release(heap, size);
+    }
+
+    /// Releases memory of destroyed objects, other objects are skipped.
+    pub fn release(&self, objects: impl IntoIterator<Item = NonDisposableHandle>) {
+        let mut memory = self.memory.lock().unwrap();
+        for object in objects {
+            if let Some((heap, size)) = memory.allocations.remove(&object) {
+                memory.release(heap, size);
+            }
+        }
+    }
+
+    /// Returns bytes which the guest allocated from the heap.
+    pub fn memory_usage(&self, heap: Heap) -> u64 {
+        let memory = self.memory.lock().unwrap();
+        memory.heaps.get(&heap).copied().unwrap_or_default()
+    }
+
+    pub fn within_object_quota(&self, live: usize) -> bool {
+        self.quotas.objects.is_none_or(|quota| live < quota)
+    }
+
+    /// Waits until the guest can submit to a queue again. It is called on the queue thread of the session, so only
+    /// the session itself is delayed.
+    pub fn throttle_submission(&self) {
+        let delay = match self.submissions.lock().unwrap().as_mut() {
+            Some(limiter) => limiter.reserve(Instant::now()),
+            None => return,
+        };
+        if !delay.is_zero() {
+            trace!("delaying queue submission by {:?}", delay);
+            thread::sleep(delay);
+        }
+    }
+}
+
+impl MemoryUsage {
+    fn release(&mut self, heap: Heap, size: u64) {
+        if let Some(used) = self.heaps.get_mut(&heap) {
+            *used = used.saturating_sub(size);
+        }
+    }
+}
+
+/// Spaces events evenly to a rate, allowing bursts of one second worth of them.
+struct RateLimiter {
+    interval: Duration,
+    /// Burst which can happen before events are delayed, less the interval of the event itself.
+    tolerance: Duration,
+    /// Time when the next event happens, if there was no burst.
+    next: Option<Instant>,
+}
+
+impl RateLimiter {
+    fn new(per_second: NonZeroU32) -> Self {
+        let interval = Duration::from_secs(1) / per_second.get();
+        Self {
+            interval,
+            tolerance: Duration::from_secs(1) - interval,
+            next: None,
+        }
+    }
+
+    /// Reserves time of an event and returns how long it must wait for it.
+    fn reserve(&mut self, now: Instant) -> Duration {
+        let next = self.next.map_or(now, |next| next.max(now));
+        self.next = Some(next + self.interval);
+        next.checked_sub(self.tolerance)
+            .map_or(Duration::ZERO, |at| at.saturating_duration_since(now))
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{
+        num::NonZeroU32,
+        time::{Duration, Instant},
+    };
+
+    use super::{QuotaUsage, Quotas, RateLimiter};
+
+    #[test]
+    fn memory_per_heap() {
+        let usage = QuotaUsage::new(Quotas {
+            memory_per_heap: Some(100),
+            ..Default::default()
+        });
+        let heap = (1, 0);
+
+        assert!(usage.reserve_memory(heap, 60));
+        usage.commit_memory(10, heap, 60);
+        assert!(!usage.reserve_memory(heap, 50));
+        // Other heaps have their own quota.
+        assert!(usage.reserve_memory((1, 1), 50));
+        usage.cancel_memory((1, 1), 50);
+
+        assert!(usage.reserve_memory(heap, 40));
+        assert_eq!(100, usage.memory_usage(heap));
+        usage.cancel_memory(heap, 40);
+
+        usage.release([10, 11]);
+        assert_eq!(0, usage.memory_usage(heap));
+        assert!(usage.reserve_memory(heap, 100));
+    }
+
+    #[test]
+    fn objects() {
+        let usage = QuotaUsage::new(Quotas {
+            objects: Some(2),
+            ..Default::default()
+        });
+        assert!(usage.within_object_quota(1));
+        assert!(!usage.within_object_quota(2));
+        assert!(QuotaUsage::default().within_object_quota(usize::MAX));
+    }
+
+    #[test]
+    fn rate_limiter() {
+        let mut limiter = RateLimiter::new(NonZeroU32::new(4).unwrap());
+        let start = Instant::now();
+
+        // Burst of one second worth of events is not delayed.
+        for _ in 0..4 {
+            assert_eq!(Duration::ZERO, limiter.reserve(start));
+        }
+        assert_eq!(Duration::from_millis(250), limiter.reserve(start));
+        assert_eq!(Duration::from_millis(500), limiter.reserve(start));
+
+        // Idle time lets the burst happen again.
+        let later = start + Duration::from_secs(10);
+        assert_eq!(Duration::ZERO, limiter.reserve(later));
+    }
+
+    #[test]
+    fn display() {
+        assert_eq!("unlimited", Quotas::default().to_string());
+        let quotas = Quotas {
+            memory_per_heap: Some(512 * 1024 * 1024),
+            objects: Some(1000),
+            submissions_per_second: NonZeroU32::new(60),
+        };
+        assert_eq!(
+            "512 MiB per heap, 1000 objects, 60 submissions/s",
+            quotas.to_string()
+        );
+    }
+}
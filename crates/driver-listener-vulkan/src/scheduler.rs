@@ -0,0 +1,640 @@
+//! Fair scheduling of queue submissions of sessions which share the host GPU. Sessions get time of GPU queues in
+//! proportion to their weights, while other sessions have work to do. Submissions of a session which is ahead of
+//! another active session by more than [`SLICE`] wait, until the other one catches up or becomes idle. They wait on
+//! the queue thread of their session, see [`Session::register_handlers_to`], so other sessions keep being served.
+//!
+//! Time of GPU queues is measured with fences, which are submitted with the submissions of guests, or after them when
+//! guests pass their own fences. Queues execute their work in order, so a submission occupies the queue from when it is
+//! submitted, or the previous one finished, until its fence is signaled.
+//!
+//! Only sessions of the same process are scheduled together, isolated sessions share the GPU through its driver. Host
+//! does not accept weights of guests together with isolated sessions for this reason.
+
+use std::{
+    collections::{btree_map::Entry, BTreeMap},
+    ffi::CStr,
+    fmt, mem,
+    num::NonZeroU32,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        mpsc::{self, Receiver, Sender},
+        Arc, Condvar, Mutex, MutexGuard,
+    },
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use ash::vk;
+use wie_driver_common_vulkan::NonDisposableHandle;
+
+use crate::{entry, session::Session};
+
+/// Lead in weighted GPU time which a session can have over other active sessions, before its submissions wait.
+const SLICE: Duration = Duration::from_millis(10);
+/// Longest wait of a submission, so sessions are not stalled by work which never finishes, like on a hung GPU.
+const MAX_DELAY: Duration = Duration::from_millis(100);
+/// Time after which monitors of released queues stop waiting for their fences.
+const FENCE_TIMEOUT: Duration = Duration::from_secs(1);
+
+static SCHEDULER: Scheduler = Scheduler {
+    state: Mutex::new(State {
+        sessions: BTreeMap::new(),
+        queues: BTreeMap::new(),
+    }),
+    changed: Condvar::new(),
+};
+
+/// Device functions which are used for fences of queues.
+const FENCE_FUNCTIONS: &[&CStr] = &[
+    c"vkCreateFence",
+    c"vkDestroyFence",
+    c"vkWaitForFences",
+    c"vkResetFences",
+    c"vkQueueSubmit",
+];
+
+/// Statistics of queue submissions of a session.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct SchedulerMetrics {
+    pub weight: u32,
+    pub submissions: u64,
+    pub presents: u64,
+    /// Time of GPU queues which submissions of the session occupied.
+    pub occupancy: Duration,
+    /// Time which submissions and presents waited for other sessions.
+    pub delay: Duration,
+}
+
+impl fmt::Display for SchedulerMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "weight {}, {} submission(s), {} present(s), GPU occupancy {:.1?}, delayed {:.1?}",
+            self.weight, self.submissions, self.presents, self.occupancy, self.delay
+        )
+    }
+}
+
+struct Scheduler {
+    state: Mutex<State>,
+    /// Notified when sessions do work or become idle, so waiting submissions check their turn.
+    changed: Condvar,
+}
+
+struct State {
+    sessions: BTreeMap<u64, SessionState>,
+    /// Queues which are measured, keyed by their host handle. Queues are reserved by `None` while they are being set up.
+    queues: BTreeMap<NonDisposableHandle, Option<Queue>>,
+}
+
+#[derive(Default)]
+struct SessionState {
+    /// GPU time divided by the weight.
+    virtual_time: Duration,
+    /// Submissions which are executed by queues.
+    in_flight: usize,
+    /// Submissions and presents which wait for their turn.
+    waiting: usize,
+    metrics: SchedulerMetrics,
+}
+
+impl SessionState {
+    #[inline]
+    fn is_active(&self) -> bool {
+        self.in_flight > 0 || self.waiting > 0
+    }
+}
+
+struct Queue {
+    session: u64,
+    device: NonDisposableHandle,
+    fences: Arc<Fences>,
+    /// Sends fences of submissions to the monitor, it stops once this is dropped.
+    sender: Sender<Pending>,
+    monitor: JoinHandle<()>,
+}
+
+#[derive(Default)]
+struct Fences {
+    /// Every fence created for the queue, they are destroyed with it.
+    created: Mutex<Vec<NonDisposableHandle>>,
+    /// Signaled fences which were reset, so they can be submitted again.
+    free: Mutex<Vec<NonDisposableHandle>>,
+    /// Set when the queue is released, the monitor waits for its remaining fences only for [`FENCE_TIMEOUT`].
+    released: AtomicBool,
+}
+
+/// Fence submitted after work of the session.
+struct Pending {
+    session: u64,
+    fence: NonDisposableHandle,
+    submitted: Instant,
+}
+
+/// Starts scheduling of the session, every session is registered when it is created.
+pub(crate) fn register(session: u64, weight: NonZeroU32) {
+    SCHEDULER.register(session, weight);
+}
+
+/// Stops scheduling of the session, its queues must be released first.
+pub(crate) fn unregister(session: u64) {
+    SCHEDULER.lock().sessions.remove(&session);
+    SCHEDULER.changed.notify_all();
+}
+
+pub(crate) fn metrics(session: u64) -> SchedulerMetrics {
+    SCHEDULER.metrics(session)
+}
+
+/// Starts measuring the queue of the device, which the guest got from the device. Queues which are already measured are
+/// skipped.
+pub(crate) unsafe fn add_queue(
+    session: &Arc<Session>,
+    device: NonDisposableHandle,
+    queue: NonDisposableHandle,
+) {
+    if queue == 0 {
+        return;
+    }
+    // Queue is reserved first, so concurrent calls for it do not start their own monitors.
+    match SCHEDULER.lock().queues.entry(queue) {
+        Entry::Occupied(_) => return,
+        Entry::Vacant(entry) => _ = entry.insert(None),
+    }
+    // Device functions are resolved through the instance of the device.
+    let instance = session
+        .objects
+        .parent(device)
+        .and_then(|physical_device| session.objects.parent(physical_device))
+        .unwrap_or_default();
+    if !FENCE_FUNCTIONS
+        .iter()
+        .all(|name| entry::make_sure_function_is_loaded(instance, name))
+    {
+        warn!(
+            "Session {}: GPU occupancy of queue {:#x} is not measured",
+            session.id(),
+            queue
+        );
+        SCHEDULER.lock().queues.remove(&queue);
+        return;
+    }
+
+    let fences = Arc::new(Fences::default());
+    let (sender, receiver) = mpsc::channel();
+    let monitor = {
+        let session = session.clone();
+        let fences = fences.clone();
+        thread::spawn(move || monitor(&session, device, receiver, &fences))
+    };
+    SCHEDULER.lock().queues.insert(
+        queue,
+        Some(Queue {
+            session: session.id(),
+            device,
+            fences,
+            sender,
+            monitor,
+        }),
+    );
+}
+
+/// Stops measuring queues of the device and destroys their fences. It must be called before the device is destroyed,
+/// when its work is finished.
+pub(crate) unsafe fn release_device(session: &Session, device: NonDisposableHandle) {
+    release_queues(session, |queue| queue.device == device);
+}
+
+/// Same as [`release_device`], but for every device of the session.
+pub(crate) unsafe fn release_session(session: &Session) {
+    release_queues(session, |queue| queue.session == session.id());
+}
+
+unsafe fn release_queues(session: &Session, mut released: impl FnMut(&Queue) -> bool) {
+    let queues = {
+        let mut state = SCHEDULER.lock();
+        let (released, kept) = mem::take(&mut state.queues)
+            .into_iter()
+            .partition(|(_, queue)| queue.as_ref().is_some_and(&mut released));
+        state.queues = kept;
+        released
+    };
+
+    for queue in queues.into_values().flatten() {
+        queue.fences.released.store(true, Ordering::Relaxed);
+        drop(queue.sender);
+        if queue.monitor.join().is_err() {
+            error!("Session {}: queue monitor panicked", session.id());
+        }
+        for fence in queue.fences.created.lock().unwrap().drain(..) {
+            (session.function_table().vk_destroy_fence)(queue.device, fence, ptr::null());
+        }
+    }
+}
+
+/// Submits work of the session to the queue in its turn, and measures how long the queue executes it. `submit` gets
+/// the fence to submit with, it is a fence of the scheduler when the guest passed none, otherwise the guest fence and an
+/// empty submission with a fence of the scheduler follows.
+pub(crate) unsafe fn submit(
+    session: &Session,
+    queue: NonDisposableHandle,
+    fence: NonDisposableHandle,
+    submit: impl FnOnce(NonDisposableHandle) -> u32,
+) -> u32 {
+    let _turn = SCHEDULER.wait_for_turn(session.id());
+    let measured = SCHEDULER
+        .lock()
+        .queues
+        .get(&queue)
+        .and_then(Option::as_ref)
+        .map(|queue| (queue.device, queue.fences.clone(), queue.sender.clone()));
+    let own_fence = match (&measured, fence) {
+        (Some((device, fences, _)), 0) => acquire_fence(session, *device, fences),
+        _ => None,
+    };
+
+    let submitted = Instant::now();
+    let result = submit(own_fence.unwrap_or(fence));
+    if result != vk::Result::SUCCESS.as_raw() as u32 {
+        if let (Some(own_fence), Some((_, fences, _))) = (own_fence, &measured) {
+            fences.free.lock().unwrap().push(own_fence);
+        }
+        return result;
+    }
+
+    if let Some(session) = SCHEDULER.lock().sessions.get_mut(&session.id()) {
+        session.metrics.submissions += 1;
+    }
+    match (measured, own_fence) {
+        (Some((_, _, sender)), Some(own_fence)) => {
+            track_fence(session, own_fence, submitted, &sender)
+        }
+        (Some((device, fences, sender)), None) if fence != 0 => {
+            submit_fence(session, device, queue, &fences, &sender)
+        }
+        _ => {}
+    }
+    result
+}
+
+/// Presents images of the session in its turn.
+pub(crate) fn present(session: &Session, present: impl FnOnce() -> u32) -> u32 {
+    let _turn = SCHEDULER.wait_for_turn(session.id());
+    let result = present();
+    if let Some(session) = SCHEDULER.lock().sessions.get_mut(&session.id()) {
+        session.metrics.presents += 1;
+    }
+    result
+}
+
+/// Submits a fence which is signaled once previous work of the queue finished.
+unsafe fn submit_fence(
+    session: &Session,
+    device: NonDisposableHandle,
+    queue: NonDisposableHandle,
+    fences: &Fences,
+    sender: &Sender<Pending>,
+) {
+    let Some(fence) = acquire_fence(session, device, fences) else {
+        return;
+    };
+    let submitted = Instant::now();
+    if (session.function_table().vk_queue_submit)(queue, 0, ptr::null(), fence)
+        != vk::Result::SUCCESS.as_raw() as u32
+    {
+        fences.free.lock().unwrap().push(fence);
+        return;
+    }
+    track_fence(session, fence, submitted, sender);
+}
+
+/// Returns a free fence of the queue, or creates a new one.
+unsafe fn acquire_fence(
+    session: &Session,
+    device: NonDisposableHandle,
+    fences: &Fences,
+) -> Option<NonDisposableHandle> {
+    let free = fences.free.lock().unwrap().pop();
+    if free.is_some() {
+        return free;
+    }
+
+    let create_info = vk::FenceCreateInfo::default();
+    let mut fence = 0;
+    let result = (session.function_table().vk_create_fence)(
+        device,
+        &create_info as *const vk::FenceCreateInfo as *const _,
+        ptr::null(),
+        &mut fence,
+    );
+    if result != vk::Result::SUCCESS.as_raw() as u32 {
+        return None;
+    }
+    fences.created.lock().unwrap().push(fence);
+    Some(fence)
+}
+
+/// Passes the submitted fence to the monitor of the queue, work of the session is in flight until it is signaled.
+fn track_fence(
+    session: &Session,
+    fence: NonDisposableHandle,
+    submitted: Instant,
+    sender: &Sender<Pending>,
+) {
+    let mut state = SCHEDULER.lock();
+    if let Some(state) = state.sessions.get_mut(&session.id()) {
+        state.in_flight += 1;
+    }
+    drop(state);
+    _ = sender.send(Pending {
+        session: session.id(),
+        fence,
+        submitted,
+    });
+}
+
+/// Waits for fences of the queue in submission order, and charges sessions for the time which their work took.
+fn monitor(
+    session: &Session,
+    device: NonDisposableHandle,
+    receiver: Receiver<Pending>,
+    fences: &Fences,
+) {
+    let table = session.function_table();
+    let mut last_finished: Option<Instant> = None;
+    for pending in receiver {
+        let waited = Instant::now();
+        let signaled = loop {
+            let result = unsafe {
+                (table.vk_wait_for_fences)(
+                    device,
+                    1,
+                    &pending.fence,
+                    vk::TRUE,
+                    FENCE_TIMEOUT.as_nanos() as u64,
+                )
+            };
+            match vk::Result::from_raw(result as i32) {
+                vk::Result::SUCCESS => break true,
+                vk::Result::TIMEOUT
+                    if !fences.released.load(Ordering::Relaxed)
+                        || waited.elapsed() < FENCE_TIMEOUT => {}
+                _ => break false,
+            }
+        };
+
+        let occupancy = match signaled {
+            true => {
+                let finished = Instant::now();
+                let started =
+                    last_finished.map_or(pending.submitted, |last| last.max(pending.submitted));
+                last_finished = Some(finished);
+                unsafe { (table.vk_reset_fences)(device, 1, &pending.fence) };
+                fences.free.lock().unwrap().push(pending.fence);
+                finished.saturating_duration_since(started)
+            }
+            false => Duration::ZERO,
+        };
+        SCHEDULER.finish(pending.session, occupancy);
+    }
+}
+
+/// Marks the session as waiting for its turn, until it is dropped.
+struct Turn<'s> {
+    scheduler: &'s Scheduler,
+    session: u64,
+}
+
+impl Drop for Turn<'_> {
+    fn drop(&mut self) {
+        if let Some(session) = self.scheduler.lock().sessions.get_mut(&self.session) {
+            session.waiting -= 1;
+        }
+        self.scheduler.changed.notify_all();
+    }
+}
+
+impl Scheduler {
+    #[inline]
+    fn lock(&self) -> MutexGuard<'_, State> {
+        self.state.lock().unwrap()
+    }
+
+    fn register(&self, session: u64, weight: NonZeroU32) {
+        self.lock().sessions.insert(
+            session,
+            SessionState {
+                metrics: SchedulerMetrics {
+                    weight: weight.get(),
+                    ..Default::default()
+                },
+                ..Default::default()
+            },
+        );
+    }
+
+    fn metrics(&self, session: u64) -> SchedulerMetrics {
+        self.lock()
+            .sessions
+            .get(&session)
+            .map(|state| state.metrics)
+            .unwrap_or_default()
+    }
+
+    /// Waits while the session is ahead of other active sessions, for at most [`MAX_DELAY`]. Sessions which were idle
+    /// catch up with the active ones first, so they do not save up time for bursts.
+    ///
+    /// It blocks the calling thread, which is the queue thread of the session.
+    fn wait_for_turn(&self, session: u64) -> Turn<'_> {
+        let started = Instant::now();
+        let mut state = self.lock();
+        let turn = Turn {
+            scheduler: self,
+            session,
+        };
+        let Some(current) = state.sessions.get(&session) else {
+            return turn;
+        };
+        if !current.is_active() {
+            if let Some(least) = state.least_active_time(session) {
+                let current = state.sessions.get_mut(&session).unwrap();
+                current.virtual_time = current.virtual_time.max(least);
+            }
+        }
+        state.sessions.get_mut(&session).unwrap().waiting += 1;
+
+        loop {
+            let waited = started.elapsed();
+            if !state.is_ahead(session) || waited >= MAX_DELAY {
+                break;
+            }
+            state = self
+                .changed
+                .wait_timeout(state, MAX_DELAY - waited)
+                .unwrap()
+                .0;
+        }
+        if let Some(current) = state.sessions.get_mut(&session) {
+            current.metrics.delay += started.elapsed();
+        }
+        turn
+    }
+
+    /// Charges the session for work which finished.
+    fn finish(&self, session: u64, occupancy: Duration) {
+        if let Some(state) = self.lock().sessions.get_mut(&session) {
+            state.in_flight -= 1;
+            state.metrics.occupancy += occupancy;
+            state.virtual_time += occupancy / state.metrics.weight;
+        }
+        self.changed.notify_all();
+    }
+}
+
+impl State {
+    /// Returns least virtual time of active sessions other than the session.
+    fn least_active_time(&self, session: u64) -> Option<Duration> {
+        self.sessions
+            .iter()
+            .filter(|(id, state)| **id != session && state.is_active())
+            .map(|(_, state)| state.virtual_time)
+            .min()
+    }
+
+    fn is_ahead(&self, session: u64) -> bool {
+        match (self.sessions.get(&session), self.least_active_time(session)) {
+            (Some(current), Some(least)) => current.virtual_time > least + SLICE,
+            _ => false,
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{
+        collections::BTreeMap,
+        num::NonZeroU32,
+        sync::{Condvar, Mutex},
+        thread,
+        time::{Duration, Instant},
+    };
+
+    use super::{Scheduler, SessionState, State, MAX_DELAY, SLICE};
+
+    fn state(sessions: &[(u64, Duration, usize)]) -> State {
+        State {
+            sessions: sessions
+                .iter()
+                .map(|(id, virtual_time, in_flight)| {
+                    let state = SessionState {
+                        virtual_time: *virtual_time,
+                        in_flight: *in_flight,
+                        ..Default::default()
+                    };
+                    (*id, state)
+                })
+                .collect(),
+            queues: BTreeMap::new(),
+        }
+    }
+
+    /// Scheduler of sessions with the weights, each of them has the number of submissions in flight.
+    fn scheduler(sessions: &[(u64, u32, usize)]) -> Scheduler {
+        let scheduler = Scheduler {
+            state: Mutex::new(state(&[])),
+            changed: Condvar::new(),
+        };
+        for (id, weight, in_flight) in sessions {
+            scheduler.register(*id, NonZeroU32::new(*weight).unwrap());
+            scheduler.lock().sessions.get_mut(id).unwrap().in_flight = *in_flight;
+        }
+        scheduler
+    }
+
+    #[test]
+    fn session_ahead_of_active_one_waits() {
+        let state = state(&[(1, SLICE * 3, 1), (2, Duration::ZERO, 1)]);
+        assert!(state.is_ahead(1));
+        assert!(!state.is_ahead(2));
+    }
+
+    #[test]
+    fn idle_sessions_do_not_hold_others() {
+        let state = state(&[(1, SLICE * 3, 1), (2, Duration::ZERO, 0)]);
+        assert!(!state.is_ahead(1));
+        assert_eq!(None, state.least_active_time(1));
+    }
+
+    #[test]
+    fn lead_within_slice_is_allowed() {
+        let state = state(&[(1, SLICE, 1), (2, Duration::ZERO, 1), (3, SLICE * 2, 1)]);
+        assert!(!state.is_ahead(1));
+        assert!(state.is_ahead(3));
+        assert_eq!(Some(Duration::ZERO), state.least_active_time(3));
+    }
+
+    #[test]
+    fn wait_for_turn_delays_leading_session() {
+        const DELAY: Duration = Duration::from_millis(20);
+
+        let scheduler = scheduler(&[(1, 1, 2), (2, 1, 1)]);
+        scheduler.finish(1, SLICE * 3);
+
+        let started = Instant::now();
+        drop(scheduler.wait_for_turn(2));
+        assert!(started.elapsed() < MAX_DELAY);
+
+        thread::scope(|scope| {
+            let waiting = scope.spawn(|| {
+                let started = Instant::now();
+                let _turn = scheduler.wait_for_turn(1);
+                started.elapsed()
+            });
+            while scheduler.lock().sessions[&1].waiting == 0 {
+                thread::yield_now();
+            }
+
+            // Session which holds the leading one back becomes idle.
+            thread::sleep(DELAY);
+            scheduler.finish(2, Duration::ZERO);
+            let waited = waiting.join().unwrap();
+            assert!(waited >= DELAY && waited < MAX_DELAY, "waited {waited:?}");
+        });
+        assert!(scheduler.metrics(1).delay >= DELAY);
+        assert_eq!(0, scheduler.lock().sessions[&1].waiting);
+    }
+
+    #[test]
+    fn wait_for_turn_respects_weights() {
+        let scheduler = scheduler(&[(1, 1, 2), (2, 4, 2)]);
+        scheduler.finish(1, SLICE * 2);
+        scheduler.finish(2, SLICE * 2);
+
+        // Same GPU time is a quarter for the heavier session, so it keeps its turn while the lighter one waits.
+        let started = Instant::now();
+        drop(scheduler.wait_for_turn(2));
+        assert!(started.elapsed() < MAX_DELAY);
+
+        let started = Instant::now();
+        drop(scheduler.wait_for_turn(1));
+        assert!(started.elapsed() >= MAX_DELAY);
+
+        let (light, heavy) = (scheduler.metrics(1), scheduler.metrics(2));
+        assert_eq!(light.occupancy, heavy.occupancy);
+        assert!(light.delay >= MAX_DELAY);
+        assert!(heavy.delay < MAX_DELAY);
+    }
+
+    #[test]
+    fn sessions_with_same_weight_take_turns() {
+        let scheduler = scheduler(&[(1, 2, 2), (2, 2, 2)]);
+        scheduler.finish(1, SLICE * 2);
+        scheduler.finish(2, SLICE * 2);
+
+        let started = Instant::now();
+        drop(scheduler.wait_for_turn(1));
+        drop(scheduler.wait_for_turn(2));
+        assert!(started.elapsed() < MAX_DELAY);
+    }
+}
@@ -0,0 +1,407 @@
+//! State of a single guest process. Every connection gets its own session, so guests never share function tables,
+//! callbacks or objects.
+
+use std::{
+    cell::{Cell, RefCell},
+    collections::HashMap,
+    num::NonZeroU32,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc, Arc, Condvar, Mutex, RwLock, Weak,
+    },
+    thread::{self, ThreadId},
+    time::Duration,
+};
+
+use arc_swap::{ArcSwap, Guard};
+use ash::vk;
+use wie_driver_common_vulkan::NonDisposableHandle;
+use wie_transport::{packet::DetachedPacket, stream::Stream, Connection, Handler};
+
+use crate::{
+    devices::DevicePolicy,
+    entry,
+    generated::{function_address_table::FunctionAddressTable, handlers::QUEUE_HANDLERS},
+    handles::HandleTable,
+    objects::ObjectRegistry,
+    overrided_commands::debug::GuestCallback,
+    quotas::{QuotaUsage, Quotas},
+    scheduler::{self, SchedulerMetrics},
+};
+
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
+thread_local! {
+    /// Session of the handler which runs on this thread.
+    static CURRENT: RefCell<Option<Arc<Session>>> = const { RefCell::new(None) };
+    /// Guest thread which sent the packet of the running handler.
+    static GUEST_THREAD: Cell<Option<ThreadId>> = const { Cell::new(None) };
+}
+
+pub struct Session {
+    id: u64,
+    /// Vulkan library which the guest commands are executed by.
+    entry: &'static ash::Entry,
+    /// Functions which the guest requested addresses of. Handlers load the table without locking, while a request of
+    /// another function replaces it with an updated copy.
+    function_table: ArcSwap<FunctionAddressTable>,
+    connection: RwLock<Weak<Connection<Stream>>>,
+    /// Number of handlers which are running.
+    active_handlers: Mutex<usize>,
+    handlers_finished: Condvar,
+    /// Queue of the thread which runs queue submissions and presents, it is started by the first of them. They can be
+    /// delayed by quotas and the scheduler, which must not hold handler threads shared with other sessions.
+    queue: Mutex<Option<mpsc::Sender<QueuedHandler>>>,
+    /// Guest callbacks of created messengers and report callbacks, their addresses are passed as user data to the host
+    /// callbacks.
+    pub(crate) guest_callbacks: Mutex<HashMap<NonDisposableHandle, Box<GuestCallback>>>,
+    /// Guest callbacks chained to `VkInstanceCreateInfo`, they live until the instance is destroyed. They are boxed, as
+    /// their addresses are passed to host callbacks.
+    #[allow(clippy::vec_box)]
+    pub(crate) instance_callbacks: Mutex<HashMap<NonDisposableHandle, Vec<Box<GuestCallback>>>>,
+    /// Objects which are not destroyed by the guest yet.
+    pub(crate) objects: ObjectRegistry,
+    /// IDs of host handles which the guest has seen.
+    pub(crate) handles: HandleTable,
+    /// Physical devices which the guest sees.
+    devices: DevicePolicy,
+    /// Resources which the guest uses, within its quotas.
+    pub(crate) quotas: QuotaUsage,
+    /// Heaps of memory types of physical devices, keyed by the device, see
+    /// [`cache_memory_heaps`](crate::overrided_commands::memory::cache_memory_heaps).
+    pub(crate) memory_heaps: Mutex<HashMap<NonDisposableHandle, Vec<u32>>>,
+}
+
+impl Session {
+    /// Creates session which executes commands of the guest with Vulkan of the entry, see [`Driver::load`]. Weight
+    /// sets share of GPU queue time which the session gets, while other sessions use them too.
+    ///
+    /// [`Driver::load`]: crate::driver::Driver::load
+    pub fn new(
+        devices: DevicePolicy,
+        quotas: Quotas,
+        weight: NonZeroU32,
+        entry: &'static ash::Entry,
+    ) -> Arc<Self> {
+        let id = NEXT_ID.fetch_add(1, Ordering::Relaxed);
+        scheduler::register(id, weight);
+        Arc::new(Self {
+            id,
+            entry,
+            function_table: ArcSwap::from_pointee(FunctionAddressTable::new()),
+            connection: RwLock::new(Weak::new()),
+            active_handlers: Mutex::new(0),
+            handlers_finished: Condvar::new(),
+            queue: Mutex::new(None),
+            guest_callbacks: Mutex::new(HashMap::new()),
+            instance_callbacks: Mutex::new(HashMap::new()),
+            objects: ObjectRegistry::default(),
+            handles: HandleTable::default(),
+            devices,
+            quotas: QuotaUsage::new(quotas),
+            memory_heaps: Mutex::new(HashMap::new()),
+        })
+    }
+
+    #[inline]
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub(crate) fn entry(&self) -> &'static ash::Entry {
+        self.entry
+    }
+
+    /// Registers handlers of the session, they run with the session set as [`current`]. Queue submissions and
+    /// presents run one by one on the queue thread of the session, other handlers run on the thread which received
+    /// their packet.
+    pub fn register_handlers_to(self: &Arc<Self>, map: &mut HashMap<u64, Handler<Stream>>) {
+        let mut handlers = crate::HandlerMap::new();
+        crate::entry::register_handlers_to(&mut handlers);
+        crate::generated::handlers::register_handlers_to(&mut handlers);
+
+        for (destination, handler) in handlers {
+            let session = self.clone();
+            let handler: Handler<Stream> = if QUEUE_HANDLERS.contains(&destination) {
+                let handler = Arc::new(handler);
+                Box::new(move |packet| {
+                    session.push_to_queue(QueuedHandler {
+                        handler: handler.clone(),
+                        guest_thread: packet.sender_thread_id(),
+                        packet: packet.detach(),
+                        active: session.start_handler(),
+                    })
+                })
+            } else {
+                Box::new(move |packet| {
+                    let _active = session.start_handler();
+                    let guest_thread = GUEST_THREAD.replace(packet.sender_thread_id());
+                    session.enter(|| handler(packet));
+                    GUEST_THREAD.set(guest_thread);
+                })
+            };
+            map.insert(destination, handler);
+        }
+    }
+
+    /// Sets connection with the guest, which is used to invoke guest callbacks from host Vulkan. Session can outlive
+    /// the connection, when the guest reconnects.
+    pub fn set_connection(&self, connection: &Arc<Connection<Stream>>) {
+        *self.connection.write().unwrap() = Arc::downgrade(connection);
+    }
+
+    #[inline]
+    pub(crate) fn connection(&self) -> Option<Arc<Connection<Stream>>> {
+        self.connection.read().unwrap().upgrade()
+    }
+
+    #[inline]
+    pub(crate) fn devices(&self) -> &DevicePolicy {
+        &self.devices
+    }
+
+    #[inline]
+    pub(crate) fn function_table(&self) -> Guard<Arc<FunctionAddressTable>> {
+        self.function_table.load()
+    }
+
+    pub(crate) fn set_address(&self, name: &str, address: vk::PFN_vkVoidFunction) {
+        self.function_table.rcu(|table| {
+            let mut table = FunctionAddressTable::clone(table);
+            table.set_address(name, address);
+            table
+        });
+    }
+
+    /// Forgets the destroyed object and objects destroyed with it, so the guest cannot use their IDs anymore.
+    pub(crate) fn forget(&self, handle: NonDisposableHandle, ty: vk::ObjectType) {
+        let mut removed = self.objects.remove(handle);
+        self.quotas.release(removed.iter().map(|(x, _)| *x));
+        // Host can reuse handles of physical devices of a destroyed instance for other devices.
+        self.memory_heaps
+            .lock()
+            .unwrap()
+            .retain(|device, _| removed.iter().all(|(x, _)| x != device));
+        // Registry knows a single object of the host handle, while other objects can share it, so the destroyed one is
+        // removed with its own type, and also when an equal handle was destroyed before.
+        removed.retain(|(x, _)| *x != handle);
+        removed.push((handle, ty));
+        self.handles.remove(&removed);
+    }
+
+    /// Same as [`Session::forget`], but for objects freed by resetting their parent, like descriptor sets of a pool.
+    pub(crate) fn forget_children(&self, handle: NonDisposableHandle) {
+        self.handles.remove(&self.objects.remove_children(handle));
+    }
+
+    /// Tells if the guest can create another object, commands which would exceed the quota are rejected.
+    pub(crate) fn within_object_quota(&self) -> bool {
+        self.quotas.within_object_quota(self.objects.len())
+    }
+
+    /// Returns statistics of queue submissions of the guest.
+    pub fn metrics(&self) -> SchedulerMetrics {
+        scheduler::metrics(self.id)
+    }
+
+    /// Logs every host object which the guest did not destroy yet.
+    pub fn dump_objects(&self) {
+        let objects = self.objects.live();
+        info!("Session {}: {} live object(s)", self.id, objects.len());
+        for object in objects {
+            info!("Session {}:   {}", self.id, object);
+        }
+    }
+
+    /// Reports objects which are still alive while their instance or device is being destroyed. They are leaks of the
+    /// guest application, Vulkan requires all child objects to be destroyed first.
+    pub(crate) fn report_leaks(&self, handle: NonDisposableHandle, type_name: &str) {
+        let leaked = self.objects.leaked_by(handle);
+        if leaked.is_empty() {
+            return;
+        }
+
+        warn!(
+            "Session {}: guest destroyed {} {:#x} with {} live object(s):",
+            self.id,
+            type_name,
+            handle,
+            leaked.len()
+        );
+        for object in leaked {
+            warn!("Session {}:   {}", self.id, object);
+        }
+    }
+
+    /// Waits for running handlers and destroys host objects which the guest did not destroy. Connection of the
+    /// session should be closed first, so no new handlers are started.
+    ///
+    /// Objects are destroyed also when handlers do not finish within the timeout, as the process is going to exit or
+    /// the guest is gone anyway.
+    pub fn close(self: &Arc<Self>, timeout: Duration) {
+        let active = self.active_handlers.lock().unwrap();
+        let (active, result) = self
+            .handlers_finished
+            .wait_timeout_while(active, timeout, |active| *active > 0)
+            .unwrap();
+        if result.timed_out() {
+            warn!(
+                "Session {}: {} handler(s) did not finish in {:?}",
+                self.id, *active, timeout
+            );
+        }
+        drop(active);
+        // Queue thread exits when it is not used anymore.
+        self.queue.lock().unwrap().take();
+
+        self.enter(|| unsafe { self.destroy_objects() });
+        scheduler::unregister(self.id);
+    }
+
+    unsafe fn destroy_objects(&self) {
+        let objects = self.objects.take_all();
+        if objects.is_empty() {
+            return;
+        }
+
+        // Device functions are resolved through any instance, like in `entry::request_address_for_function`. All
+        // functions are loaded before anything is destroyed, as that needs the instance.
+        let instance = objects
+            .iter()
+            .find(|(_, object)| object.type_name == "VkInstance")
+            .map(|(handle, _)| *handle)
+            .unwrap_or_default();
+        let loaded = entry::make_sure_function_is_loaded(instance, c"vkDeviceWaitIdle");
+        let mut destroyers = HashMap::new();
+        for (_, object) in &objects {
+            if let Some(destroyer) = object.destroyer {
+                destroyers.entry(destroyer.name).or_insert_with(|| {
+                    entry::make_sure_function_is_loaded(instance, destroyer.name)
+                });
+            }
+        }
+
+        if loaded {
+            for (handle, _) in objects.iter().filter(|(_, x)| x.type_name == "VkDevice") {
+                (self.function_table().vk_device_wait_idle)(*handle);
+            }
+        }
+        scheduler::release_session(self);
+
+        let mut destroyed = 0;
+        for (handle, object) in &objects {
+            let Some(destroyer) = object.destroyer else {
+                continue;
+            };
+            if destroyers[destroyer.name] {
+                trace!(
+                    "Session {}: destroying {} {:#x}",
+                    self.id,
+                    object.type_name,
+                    handle
+                );
+                (destroyer.call)(&self.function_table(), object.owner, *handle);
+                destroyed += 1;
+            }
+        }
+
+        self.guest_callbacks.lock().unwrap().clear();
+        self.instance_callbacks.lock().unwrap().clear();
+        info!(
+            "Session {}: destroyed {} object(s) left by the guest",
+            self.id, destroyed
+        );
+    }
+
+    fn start_handler(self: &Arc<Self>) -> ActiveHandler {
+        *self.active_handlers.lock().unwrap() += 1;
+        ActiveHandler(self.clone())
+    }
+
+    fn push_to_queue(&self, handler: QueuedHandler) {
+        let mut queue = self.queue.lock().unwrap();
+        let sender = queue.get_or_insert_with(|| {
+            let (sender, receiver) = mpsc::channel();
+            thread::spawn(move || run_queue(receiver));
+            sender
+        });
+        sender
+            .send(handler)
+            .expect("queue thread of the session panicked");
+    }
+
+    fn enter<R>(self: &Arc<Self>, f: impl FnOnce() -> R) -> R {
+        struct Restore(Option<Arc<Session>>);
+
+        impl Drop for Restore {
+            fn drop(&mut self) {
+                CURRENT.with(|current| *current.borrow_mut() = self.0.take());
+            }
+        }
+
+        let _restore = Restore(CURRENT.with(|current| current.replace(Some(self.clone()))));
+        f()
+    }
+}
+
+struct ActiveHandler(Arc<Session>);
+
+impl Drop for ActiveHandler {
+    fn drop(&mut self) {
+        let mut active = self.0.active_handlers.lock().unwrap();
+        *active -= 1;
+        if *active == 0 {
+            self.0.handlers_finished.notify_all();
+        }
+    }
+}
+
+/// Handler which waits in the queue of its session.
+struct QueuedHandler {
+    handler: Arc<Handler<Stream>>,
+    packet: DetachedPacket,
+    guest_thread: Option<ThreadId>,
+    active: ActiveHandler,
+}
+
+/// Runs queued handlers until the session is closed. Handlers whose connection is gone are dropped, as the guest does
+/// not wait for them anymore.
+fn run_queue(receiver: mpsc::Receiver<QueuedHandler>) {
+    for queued in receiver {
+        let QueuedHandler {
+            handler,
+            packet,
+            guest_thread,
+            active,
+        } = queued;
+        let session = &active.0;
+        let Some(connection) = session.connection() else {
+            continue;
+        };
+
+        let guest_thread = GUEST_THREAD.replace(guest_thread);
+        session.enter(|| connection.handle_detached(packet, &handler));
+        GUEST_THREAD.set(guest_thread);
+    }
+}
+
+/// Returns session of the running handler.
+///
+/// # Panics
+/// When it is called outside of a handler.
+#[inline]
+pub(crate) fn current() -> Arc<Session> {
+    try_current().expect("called outside of a session handler")
+}
+
+#[inline]
+pub(crate) fn try_current() -> Option<Arc<Session>> {
+    CURRENT.with(|current| current.borrow().clone())
+}
+
+/// Returns guest thread which waits for the running handler, it is missing for commands sent without response.
+#[inline]
+pub(crate) fn guest_thread() -> Option<ThreadId> {
+    GUEST_THREAD.get()
+}
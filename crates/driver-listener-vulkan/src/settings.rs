@@ -0,0 +1,90 @@
+use std::{fmt, path::PathBuf, str::FromStr, sync::OnceLock};
+
+use wie_common::utils::env;
+
+static SETTINGS: OnceLock<Settings> = OnceLock::new();
+
+/// Settings of host Vulkan, they apply to instances created after [`configure`].
+#[derive(Clone, Debug, Default)]
+pub struct Settings {
+    pub validation_layers: ValidationLayers,
+    /// Checks commands of guests against rules of the Vulkan registry before they are executed, see `validation`.
+    pub hardened_validation: bool,
+    pub capture: Option<Capture>,
+}
+
+/// Policy of enabling Khronos validation layers on instances created by guests.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ValidationLayers {
+    /// Enabled in debug builds, or when `VK_VALIDATION_LAYERS` is set to `1` or `true`.
+    #[default]
+    Auto,
+    Enabled,
+    Disabled,
+}
+
+impl ValidationLayers {
+    pub fn is_enabled(self) -> bool {
+        match self {
+            ValidationLayers::Auto => {
+                cfg!(debug_assertions) || env::is_active("VK_VALIDATION_LAYERS")
+            }
+            ValidationLayers::Enabled => true,
+            ValidationLayers::Disabled => false,
+        }
+    }
+}
+
+impl fmt::Display for ValidationLayers {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            ValidationLayers::Auto => "auto",
+            ValidationLayers::Enabled => "enabled",
+            ValidationLayers::Disabled => "disabled",
+        })
+    }
+}
+
+impl FromStr for ValidationLayers {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(ValidationLayers::Auto),
+            "enabled" => Ok(ValidationLayers::Enabled),
+            "disabled" => Ok(ValidationLayers::Disabled),
+            _ => Err(format!(
+                "invalid validation layers policy `{s}`, expected `auto`, `enabled` or `disabled`"
+            )),
+        }
+    }
+}
+
+/// Capture of Vulkan calls with GFXReconstruct layer, which must be installed on the host.
+#[derive(Clone, Debug)]
+pub struct Capture {
+    /// Capture file, the layer appends timestamp to its name.
+    pub file: PathBuf,
+    /// Frame ranges to capture, like `1-10,20`, all frames are captured when not set.
+    pub frames: Option<String>,
+}
+
+/// Sets settings of host Vulkan, it must be called before the first connection is accepted.
+pub fn configure(settings: Settings) {
+    // GFXReconstruct reads its options from environment when the instance is created.
+    if let Some(capture) = &settings.capture {
+        std::env::set_var("GFXRECON_CAPTURE_FILE", &capture.file);
+        if let Some(frames) = &capture.frames {
+            std::env::set_var("GFXRECON_CAPTURE_FRAMES", frames);
+        }
+    }
+
+    if SETTINGS.set(settings).is_err() {
+        panic!("host Vulkan settings are already configured");
+    }
+}
+
+#[inline]
+pub(crate) fn get() -> &'static Settings {
+    SETTINGS.get_or_init(Settings::default)
+}
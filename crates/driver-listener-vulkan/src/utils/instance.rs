@@ -10,17 +10,16 @@ pub fn get_layer_properties() -> Vec<VkLayerProperties> {
             return Vec::new();
         }
 
+        let session = crate::session::current();
         let mut layer_count = 0;
-        (crate::FUNCTION_ADDRESS_TABLE.vk_enumerate_instance_layer_properties)(
-            &mut layer_count,
-            ptr::null_mut(),
-        );
+        (session
+            .function_table()
+            .vk_enumerate_instance_layer_properties)(&mut layer_count, ptr::null_mut());
 
         let mut layers = Vec::with_capacity(layer_count as usize);
-        (crate::FUNCTION_ADDRESS_TABLE.vk_enumerate_instance_layer_properties)(
-            &mut layer_count,
-            layers.as_mut_ptr(),
-        );
+        (session
+            .function_table()
+            .vk_enumerate_instance_layer_properties)(&mut layer_count, layers.as_mut_ptr());
         layers.set_len(layer_count as usize);
         layers
     }
@@ -32,15 +31,20 @@ pub fn get_extension_properties(p_layer_name: *const c_char) -> Vec<VkExtensionP
             return Vec::new();
         }
 
+        let session = crate::session::current();
         let mut extension_count = 0;
-        (crate::FUNCTION_ADDRESS_TABLE.vk_enumerate_instance_extension_properties)(
+        (session
+            .function_table()
+            .vk_enumerate_instance_extension_properties)(
             p_layer_name,
             &mut extension_count,
             ptr::null_mut(),
         );
 
         let mut extensions = Vec::with_capacity(extension_count as usize);
-        (crate::FUNCTION_ADDRESS_TABLE.vk_enumerate_instance_extension_properties)(
+        (session
+            .function_table()
+            .vk_enumerate_instance_extension_properties)(
             p_layer_name,
             &mut extension_count,
             extensions.as_mut_ptr(),
@@ -0,0 +1,260 @@
+//! Hardened validation of commands of guests, enabled by [`Settings::hardened_validation`].
+//!
+//! The transport rejects packets which are read beyond their end, but host drivers also rely on rules of the registry
+//! which a broken or hostile guest can break, like `sType` values, structures chained to ones which they do not extend
+//! and required pointers. Validators of every command are generated from `vk.xml`, see `generated::validators`.
+//!
+//! [`Settings::hardened_validation`]: crate::settings::Settings::hardened_validation
+
+use std::{
+    ffi::c_void,
+    fmt::{self, Display, Formatter},
+};
+
+use ash::vk;
+use wie_driver_common_vulkan::NonDisposableHandle;
+
+/// Longest `pNext` chain which is accepted, chains of applications are much shorter.
+const MAX_CHAIN_LENGTH: usize = 64;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Invalid {
+    NullHandle(&'static str),
+    NullPointer(&'static str),
+    /// Packets carry a single element of arrays which are not preceded by count pointer, longer arrays would be read
+    /// beyond the packet.
+    ArrayLength {
+        name: &'static str,
+        len: u64,
+    },
+    StructureType {
+        structure: &'static str,
+        found: vk::StructureType,
+    },
+    /// Structure is chained to the one which it does not extend.
+    Chain {
+        structure: &'static str,
+        found: vk::StructureType,
+    },
+    ChainTooLong(&'static str),
+}
+
+impl Display for Invalid {
+    fn fmt(&self, f: &mut Formatter<'_>) -> fmt::Result {
+        match self {
+            Invalid::NullHandle(name) => write!(f, "required handle `{}` is null", name),
+            Invalid::NullPointer(name) => write!(f, "required pointer `{}` is null", name),
+            Invalid::ArrayLength { name, len } => write!(
+                f,
+                "array `{}` has {} elements, but packet carries a single one",
+                name, len
+            ),
+            Invalid::StructureType { structure, found } => {
+                write!(f, "`{}` has wrong sType {:?}", structure, found)
+            }
+            Invalid::Chain { structure, found } => {
+                write!(
+                    f,
+                    "{:?} is chained to `{}` which it does not extend",
+                    found, structure
+                )
+            }
+            Invalid::ChainTooLong(structure) => write!(
+                f,
+                "pNext chain of `{}` is longer than {} structures",
+                structure, MAX_CHAIN_LENGTH
+            ),
+        }
+    }
+}
+
+/// Runs the validator of the command, when hardened validation is enabled. Returns false and logs the reason, when
+/// the command must not be called.
+pub(crate) fn check(command: &str, validate: impl FnOnce() -> Result<(), Invalid>) -> bool {
+    if !crate::settings::get().hardened_validation {
+        return true;
+    }
+
+    match validate() {
+        Ok(()) => true,
+        Err(e) => {
+            warn!("{} rejected, {}", command, e);
+            false
+        }
+    }
+}
+
+#[inline]
+pub(crate) fn handle(handle: NonDisposableHandle, name: &'static str) -> Result<(), Invalid> {
+    match handle {
+        0 => Err(Invalid::NullHandle(name)),
+        _ => Ok(()),
+    }
+}
+
+#[inline]
+pub(crate) fn pointer<T>(ptr: *const T, name: &'static str) -> Result<(), Invalid> {
+    match ptr.is_null() {
+        true => Err(Invalid::NullPointer(name)),
+        false => Ok(()),
+    }
+}
+
+/// Checks pointer to the array of a structure, which must not be null when the array is not empty.
+#[inline]
+pub(crate) fn elements<T>(ptr: *const T, len: u64, name: &'static str) -> Result<(), Invalid> {
+    match len {
+        0 => Ok(()),
+        _ => pointer(ptr, name),
+    }
+}
+
+/// Checks array parameter of the command, see [`Invalid::ArrayLength`].
+#[inline]
+pub(crate) fn array<T>(
+    ptr: *const T,
+    len: u64,
+    name: &'static str,
+    optional: bool,
+) -> Result<(), Invalid> {
+    match (ptr.is_null(), len) {
+        (_, 0) => Ok(()),
+        (true, _) if optional => Ok(()),
+        (true, _) => Err(Invalid::NullPointer(name)),
+        (false, 1) => Ok(()),
+        (false, len) => Err(Invalid::ArrayLength { name, len }),
+    }
+}
+
+#[inline]
+pub(crate) fn structure_type(
+    found: u32,
+    expected: vk::StructureType,
+    structure: &'static str,
+) -> Result<(), Invalid> {
+    let found = vk::StructureType::from_raw(found as i32);
+    match found == expected {
+        true => Ok(()),
+        false => Err(Invalid::StructureType { structure, found }),
+    }
+}
+
+/// Checks that every structure chained to the one extends it, and members of chained structures.
+///
+/// # Safety
+/// Chain must be null or point to valid structures, which is guaranteed by deserialization of the packet.
+pub(crate) unsafe fn chain(
+    p_next: *const c_void,
+    structure: &'static str,
+    extends: fn(vk::StructureType) -> bool,
+    validate: unsafe fn(*const c_void) -> Result<(), Invalid>,
+) -> Result<(), Invalid> {
+    let mut next = p_next as *const vk::BaseInStructure;
+    for _ in 0..MAX_CHAIN_LENGTH {
+        let Some(element) = next.as_ref() else {
+            return Ok(());
+        };
+        if !extends(element.s_type) {
+            return Err(Invalid::Chain {
+                structure,
+                found: element.s_type,
+            });
+        }
+
+        validate(next as *const c_void)?;
+        next = element.p_next;
+    }
+    Err(Invalid::ChainTooLong(structure))
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{ffi::c_void, ptr};
+
+    use ash::vk;
+
+    use super::{array, chain, Invalid};
+
+    unsafe fn accept(_element: *const c_void) -> Result<(), Invalid> {
+        Ok(())
+    }
+
+    fn extends(s_type: vk::StructureType) -> bool {
+        s_type == vk::StructureType::VALIDATION_FEATURES_EXT
+    }
+
+    #[test]
+    fn array_longer_than_packet_is_rejected() {
+        let element = 1u32;
+        assert_eq!(Ok(()), array(&element, 1, "pValues", false));
+        assert_eq!(Ok(()), array(ptr::null::<u32>(), 0, "pValues", false));
+        assert_eq!(Ok(()), array(ptr::null::<u32>(), 3, "pValues", true));
+        assert_eq!(
+            Err(Invalid::NullPointer("pValues")),
+            array(ptr::null::<u32>(), 3, "pValues", false)
+        );
+        assert_eq!(
+            Err(Invalid::ArrayLength {
+                name: "pValues",
+                len: 3
+            }),
+            array(&element, 3, "pValues", false)
+        );
+    }
+
+    #[test]
+    fn chain_rejects_structure_which_does_not_extend() {
+        let foreign = vk::BaseInStructure {
+            s_type: vk::StructureType::APPLICATION_INFO,
+            ..Default::default()
+        };
+        let features = vk::BaseInStructure {
+            s_type: vk::StructureType::VALIDATION_FEATURES_EXT,
+            p_next: &foreign,
+            ..Default::default()
+        };
+
+        let result = unsafe {
+            chain(
+                &features as *const _ as *const c_void,
+                "VkInstanceCreateInfo",
+                extends,
+                accept,
+            )
+        };
+        assert_eq!(
+            Err(Invalid::Chain {
+                structure: "VkInstanceCreateInfo",
+                found: vk::StructureType::APPLICATION_INFO
+            }),
+            result
+        );
+        assert_eq!(Ok(()), unsafe {
+            chain(ptr::null(), "VkInstanceCreateInfo", extends, accept)
+        });
+    }
+
+    #[test]
+    fn chain_with_cycle_is_rejected() {
+        let mut first = vk::BaseInStructure {
+            s_type: vk::StructureType::VALIDATION_FEATURES_EXT,
+            ..Default::default()
+        };
+        let second = vk::BaseInStructure {
+            s_type: vk::StructureType::VALIDATION_FEATURES_EXT,
+            p_next: &first,
+            ..Default::default()
+        };
+        first.p_next = &second;
+
+        let result = unsafe {
+            chain(
+                &first as *const _ as *const c_void,
+                "VkInstanceCreateInfo",
+                extends,
+                accept,
+            )
+        };
+        assert_eq!(Err(Invalid::ChainTooLong("VkInstanceCreateInfo")), result);
+    }
+}
@@ -1,12 +1,48 @@
-use std::env;
+use std::{env, fs, path::PathBuf};
+
+/// Vulkan version reported by manifests, it must match `vk_wie_icd.json`.
+const API_VERSION: &str = "1.3.285";
 
 fn main() {
     let target_os = env::var("CARGO_CFG_TARGET_OS");
     match target_os.as_ref().map(|x| &**x) {
-        Ok("macos") | Ok("linux") => {}
+        Ok("linux") => write_manifest("so"),
+        Ok("macos") => write_manifest("dylib"),
         Ok("windows") => {
             println!("cargo:rustc-cdylib-link-arg=/DEF:crates/driver-vulkan/driver.def")
         }
         tos => panic!("unknown target os {:?}", tos),
     }
 }
+
+/// Writes `vk_wie_icd.json` next to the built library, so `VK_DRIVER_FILES` can point at it directly. Library path is
+/// absolute, so the manifest can be copied to any directory searched by the loader.
+fn write_manifest(extension: &str) {
+    println!("cargo:rerun-if-changed=build.rs");
+
+    // OUT_DIR is `target/<profile>/build/<package>-<hash>/out`.
+    let out_dir = PathBuf::from(env::var("OUT_DIR").unwrap());
+    let target_dir = out_dir
+        .ancestors()
+        .nth(3)
+        .expect("unexpected OUT_DIR layout");
+    let library = target_dir.join(format!(
+        "lib{}.{}",
+        env::var("CARGO_PKG_NAME").unwrap().replace('-', "_"),
+        extension
+    ));
+
+    let manifest = format!(
+        r#"{{
+    "file_format_version": "1.0.0",
+    "ICD": {{
+        "library_path": "{}",
+        "api_version": "{}"
+    }}
+}}
+"#,
+        library.display(),
+        API_VERSION
+    );
+    fs::write(target_dir.join("vk_wie_icd.json"), manifest).expect("failed to write ICD manifest");
+}
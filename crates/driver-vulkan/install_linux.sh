@@ -0,0 +1,28 @@
+#!/bin/sh
+set -e
+
+# Installs the driver for the Vulkan loader, PREFIX defaults to /usr/local.
+prefix="${PREFIX:-/usr/local}"
+scriptdir="$(cd "$(dirname "$0")" && pwd)"
+profile="${PROFILE:-debug}"
+
+echo "Running code generators..."
+cd "$scriptdir/../.."
+cargo run --bin wie-driver-vulkan-generator
+echo "Building wie-driver-vulkan..."
+if [ "$profile" = "release" ]; then
+    cargo build -p wie-driver-vulkan --release
+else
+    cargo build -p wie-driver-vulkan
+fi
+
+echo "Copying driver file..."
+mkdir -p "$prefix/lib"
+cp "target/$profile/libwie_driver_vulkan.so" "$prefix/lib/"
+
+echo "Copying ICD file..."
+mkdir -p "$prefix/share/vulkan/icd.d"
+sed "s|\"library_path\": \".*\"|\"library_path\": \"$prefix/lib/libwie_driver_vulkan.so\"|" \
+    "target/$profile/vk_wie_icd.json" > "$prefix/share/vulkan/icd.d/vk_wie_icd.json"
+
+echo "Vulkan driver successfully installed."
@@ -0,0 +1,80 @@
+use std::{
+    collections::HashMap,
+    ffi::{c_char, c_void},
+    mem,
+};
+
+use ash::vk;
+use wie_driver_common_vulkan::{
+    generated::vulkan_types::VkDebugUtilsMessengerCallbackDataEXT, guest_handlers,
+};
+use wie_transport_guest::{Handler, Packet};
+
+/// Calls `PFN_vkDebugUtilsMessengerCallbackEXT` of the application, requested by host messenger.
+pub fn debug_utils_messenger_callback(mut packet: Packet) {
+    let pfn = packet.read_shallow::<u64>();
+    let user_data = packet.read_shallow::<u64>();
+    let message_severity = packet.read_shallow::<vk::DebugUtilsMessageSeverityFlagsEXT>();
+    let message_type = packet.read_shallow::<vk::DebugUtilsMessageTypeFlagsEXT>();
+    let p_callback_data = packet.read_deep::<VkDebugUtilsMessengerCallbackDataEXT>();
+
+    let result = unsafe {
+        let callback = mem::transmute::<u64, vk::PFN_vkDebugUtilsMessengerCallbackEXT>(pfn);
+        callback.map_or(vk::FALSE, |callback| {
+            callback(
+                message_severity,
+                message_type,
+                p_callback_data as *const vk::DebugUtilsMessengerCallbackDataEXT,
+                user_data as *mut c_void,
+            )
+        })
+    };
+
+    let mut response = packet.write_response(None);
+    response.write_shallow(result);
+    response.send();
+}
+
+/// Calls `PFN_vkDebugReportCallbackEXT` of the application, requested by host report callback.
+pub fn debug_report_callback(mut packet: Packet) {
+    let pfn = packet.read_shallow::<u64>();
+    let user_data = packet.read_shallow::<u64>();
+    let flags = packet.read_shallow::<vk::DebugReportFlagsEXT>();
+    let object_type = packet.read_shallow::<vk::DebugReportObjectTypeEXT>();
+    let object = packet.read_shallow::<u64>();
+    let location = packet.read_shallow::<usize>();
+    let message_code = packet.read_shallow::<i32>();
+    let p_layer_prefix: *const c_char = packet.read_null_str();
+    let p_message: *const c_char = packet.read_null_str();
+
+    let result = unsafe {
+        let callback = mem::transmute::<u64, vk::PFN_vkDebugReportCallbackEXT>(pfn);
+        callback.map_or(vk::FALSE, |callback| {
+            callback(
+                flags,
+                object_type,
+                object,
+                location,
+                message_code,
+                p_layer_prefix,
+                p_message,
+                user_data as *mut c_void,
+            )
+        })
+    };
+
+    let mut response = packet.write_response(None);
+    response.write_shallow(result);
+    response.send();
+}
+
+pub(crate) fn register_handlers_to(map: &mut HashMap<u64, Handler>) {
+    map.insert(
+        guest_handlers::DEBUG_UTILS_MESSENGER_CALLBACK,
+        Box::new(debug_utils_messenger_callback),
+    );
+    map.insert(
+        guest_handlers::DEBUG_REPORT_CALLBACK,
+        Box::new(debug_report_callback),
+    );
+}
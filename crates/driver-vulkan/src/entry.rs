@@ -13,7 +13,7 @@ const SUPPORTED_LOADER_ICD_INTERFACE_VERSION: u32 = 7;
 static mut CURRENT_LOADER_ICD_INTERFACE_VERSION: u32 = 0;
 
 #[no_mangle]
-extern "stdcall" fn vk_icdGetInstanceProcAddr(
+extern "system" fn vk_icdGetInstanceProcAddr(
     instance: vk::Instance,
     p_name: *const c_char,
 ) -> vk::PFN_vkVoidFunction {
@@ -29,6 +29,7 @@ extern "stdcall" fn vk_icdGetInstanceProcAddr(
     let name = name.to_str().expect("UTF-8 valid name");
     let address = match name {
         "vk_icdGetPhysicalDeviceProcAddr" => vk_icdGetPhysicalDeviceProcAddr as *const _,
+        #[cfg(target_os = "windows")]
         "vk_icdEnumerateAdapterPhysicalDevices" => {
             vk_icdEnumerateAdapterPhysicalDevices as *const _
         }
@@ -42,25 +43,32 @@ extern "stdcall" fn vk_icdGetInstanceProcAddr(
 
     trace!("requested address for function `{}`", name);
 
+    match is_supported_by_host(instance, p_name) {
+        true => unsafe { mem::transmute(address) },
+        false => None,
+    }
+}
+
+/// Asks the host whether its driver exposes the function.
+fn is_supported_by_host(instance: vk::Instance, p_name: *const c_char) -> bool {
     let mut packet = new_packet(1000000000);
     packet.write_shallow(instance);
     unsafe { packet.write_null_str(p_name) };
-    let mut response = packet.send_with_response();
-    if !response.read_shallow::<bool>() {
-        return None;
-    }
-
-    unsafe { mem::transmute(address) }
+    let Ok(mut response) = packet.try_send_with_response() else {
+        return false;
+    };
+    response.read_shallow::<bool>()
 }
 
 #[no_mangle]
-extern "stdcall" fn vk_icdNegotiateLoaderICDInterfaceVersion(
+extern "system" fn vk_icdNegotiateLoaderICDInterfaceVersion(
     p_supported_version: *mut u32,
 ) -> vk::Result {
     unsafe {
         if *p_supported_version > SUPPORTED_LOADER_ICD_INTERFACE_VERSION {
             *p_supported_version = SUPPORTED_LOADER_ICD_INTERFACE_VERSION;
         }
+        CURRENT_LOADER_ICD_INTERFACE_VERSION = *p_supported_version;
 
         trace!(
             "negotiated loader ICD interface version: {}",
@@ -71,22 +79,37 @@ extern "stdcall" fn vk_icdNegotiateLoaderICDInterfaceVersion(
     }
 }
 
+/// Returns physical device functions which are unknown to the loader, `None` tells the loader to ask other drivers.
 #[no_mangle]
-extern "stdcall" fn vk_icdGetPhysicalDeviceProcAddr(
-    _instance: vk::Instance,
-    _p_name: *const c_char,
+extern "system" fn vk_icdGetPhysicalDeviceProcAddr(
+    instance: vk::Instance,
+    p_name: *const c_char,
 ) -> vk::PFN_vkVoidFunction {
-    unimplemented!("vk_icdGetPhysicalDeviceProcAddr");
+    let name = unsafe { CStr::from_ptr(p_name) };
+    let address = definitions::get_function_address(name.to_str().expect("UTF-8 valid name"));
+    if address.is_null() || !is_supported_by_host(instance, p_name) {
+        return None;
+    }
+
+    trace!(
+        "requested physical device address for function `{:?}`",
+        name
+    );
+    unsafe { mem::transmute(address) }
 }
 
+#[cfg(target_os = "windows")]
 #[repr(C)]
 struct Luid {
     data: [u8; vk::LUID_SIZE],
 }
 
 /// https://github.com/KhronosGroup/Vulkan-Loader/blob/main/docs/LoaderDriverInterface.md#physical-device-sorting
+///
+/// Loader calls it only on Windows.
+#[cfg(target_os = "windows")]
 #[no_mangle]
-unsafe extern "stdcall" fn vk_icdEnumerateAdapterPhysicalDevices(
+unsafe extern "system" fn vk_icdEnumerateAdapterPhysicalDevices(
     _instance: vk::Instance,
     _adapter_luid: Luid,
     _p_physical_device_count: *mut u32,
@@ -1,8 +1,7 @@
-#![allow(unsupported_calling_conventions)]
-
 #[macro_use]
 extern crate log;
 
+mod callbacks;
 mod entry;
 pub(crate) mod generated;
 pub(crate) mod transport_handlers;
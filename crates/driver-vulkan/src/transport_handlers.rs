@@ -3,5 +3,7 @@ use std::collections::HashMap;
 use wie_transport_guest::Handler;
 
 pub fn get() -> HashMap<u64, Handler> {
-    HashMap::new()
+    let mut map = HashMap::new();
+    crate::callbacks::register_handlers_to(&mut map);
+    map
 }
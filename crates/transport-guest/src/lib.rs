@@ -3,21 +3,74 @@ extern crate log;
 
 use std::{
     collections::HashMap,
-    sync::{Arc, OnceLock},
+    io,
+    net::TcpStream,
+    ptr,
+    sync::{
+        atomic::{AtomicBool, AtomicPtr, AtomicU64, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use wie_transport::{packet::PacketWriter, Connection};
-use wie_transport_vsock::{errors::VsockConnectionError, VsockAddress, VsockCid, VsockStream};
+use wie_common::{stream::SplitStream, utils::env};
+use wie_transport::{
+    endpoint::{self, Endpoint},
+    handshake,
+    heartbeat::Heartbeat,
+    packet::{PacketWriter, Priority},
+    stream::Stream,
+    Connection,
+};
+use wie_transport_vsock::{
+    errors::{VsockConnectionError, VsockCreationError},
+    VsockAddress, VsockStream, VsockType,
+};
+
+pub type Handler = wie_transport::Handler<Stream>;
+pub type Packet<'c> = wie_transport::packet::Packet<'c, Stream>;
+
+const PORT: u32 = 13001;
+const INITIAL_BACKOFF: Duration = Duration::from_millis(50);
+const MAX_BACKOFF: Duration = Duration::from_secs(2);
+const DEFAULT_RECONNECT_TIMEOUT: Duration = Duration::from_secs(30);
 
-pub type Handler = wie_transport::Handler<VsockStream>;
+/// Current connection. Replaced connections are leaked, because packets can borrow them for `'static`.
+static CONNECTION: AtomicPtr<Arc<Connection<Stream>>> = AtomicPtr::new(ptr::null_mut());
+static CONFIG: OnceLock<Config> = OnceLock::new();
+/// Serializes connecting, threads which need a connection wait on it until the reconnect finishes.
+static CONNECT_LOCK: Mutex<()> = Mutex::new(());
+static SESSION_TOKEN: AtomicU64 = AtomicU64::new(0);
+static DEVICE_LOST: AtomicBool = AtomicBool::new(false);
+/// Cleared when the host or the vsock transport does not support seqpacket, so it is detected only once.
+static SEQPACKET_AVAILABLE: AtomicBool = AtomicBool::new(true);
 
-static CONNECTION: OnceLock<Arc<Connection<VsockStream>>> = OnceLock::new();
+struct Config {
+    handlers: fn() -> HashMap<u64, Handler>,
+    endpoints: Vec<Endpoint>,
+    stream_count: usize,
+    heartbeat: Option<Heartbeat>,
+    reconnect_timeout: Duration,
+    seqpacket: bool,
+}
+
+/// Connects to the host, if connection is not established yet.
+///
+/// `WIE_ENDPOINT` environment variable sets comma separated list of host endpoints, like `vsock://2:13001`,
+/// `tcp://10.0.0.5:13001` or `unix:///run/wie.sock`, which are tried in order. Defaults to vsock host on port 13001.
+/// `WIE_STREAM_COUNT` environment variable sets number of streams used by the connection, defaults to 1. Heartbeats
+/// are configured by [`Heartbeat::from_env`]. When the connection is closed, guest reconnects with backoff for
+/// `WIE_RECONNECT_TIMEOUT_MS`, defaults to 30 seconds. `WIE_VSOCK_SEQPACKET` set to `1` or `true` prefers seqpacket
+/// streams, which preserve packet boundaries, when both the host and the vsock transport support them. They connect to
+/// the next port of the vsock endpoint.
+pub fn start_connection(handlers: fn() -> HashMap<u64, Handler>) {
+    if CONFIG.get().is_some() {
+        return;
+    }
 
-pub fn start_connection<T>(handlers: T)
-where
-    T: FnOnce() -> HashMap<u64, Handler>,
-{
-    if CONNECTION.get().is_some() {
+    let _guard = CONNECT_LOCK.lock().unwrap();
+    if CONFIG.get().is_some() {
         return;
     }
 
@@ -28,33 +81,212 @@ where
         hook(panic_info);
     }));
 
-    let stream = match VsockStream::connect(VsockAddress {
-        cid: VsockCid::host(),
-        port: 13001,
-    }) {
-        Ok(stream) => stream,
-        Err(e) => match e {
-            VsockConnectionError::Creation(e) => panic!("Failed to create vsock connection: {}", e),
-            VsockConnectionError::Connection(e) => {
-                error!("FAILED TO CONNECT TO VSOCK HOST. MAKE SURE THE HOST LISTENER IS RUNNING.");
-                panic!("{}", e)
-            }
-        },
+    let endpoints = match std::env::var("WIE_ENDPOINT") {
+        Ok(value) => Endpoint::parse_list(&value)
+            .unwrap_or_else(|e| panic!("Invalid WIE_ENDPOINT `{}`: {}", value, e)),
+        Err(_) => vec![Endpoint::host(PORT)],
     };
 
-    info!("Connection established");
+    let config = CONFIG.get_or_init(|| Config {
+        handlers,
+        endpoints,
+        stream_count: env::parse("WIE_STREAM_COUNT").unwrap_or(1),
+        heartbeat: Heartbeat::from_env(),
+        reconnect_timeout: env::parse("WIE_RECONNECT_TIMEOUT_MS")
+            .map(Duration::from_millis)
+            .unwrap_or(DEFAULT_RECONNECT_TIMEOUT),
+        seqpacket: env::is_active("WIE_VSOCK_SEQPACKET"),
+    });
+
+    if let Err(e) = connect(config) {
+        error!("FAILED TO CONNECT TO VSOCK HOST. MAKE SURE THE HOST LISTENER IS RUNNING.");
+        panic!("Failed to connect to host: {}", e);
+    }
+}
+
+/// Returns the current connection, it waits for reconnect when the connection is closed.
+///
+/// Returned connection is closed when the reconnect failed.
+#[inline]
+pub fn get_connection() -> &'static Arc<Connection<Stream>> {
+    let connection = current_connection();
+    if !connection.is_closed() {
+        return connection;
+    }
 
-    CONNECTION
-        .set(Connection::new(stream, handlers(), None))
-        .unwrap();
+    reconnect();
+    current_connection()
 }
 
+/// Returns true when the connection was lost together with the host state of the application, every object created
+/// before is invalid.
+///
+/// It is reset by [`reset_device_lost`], when the application starts from scratch.
 #[inline]
-pub fn get_connection() -> &'static Arc<Connection<VsockStream>> {
-    CONNECTION.get().unwrap()
+pub fn is_device_lost() -> bool {
+    DEVICE_LOST.load(Ordering::Acquire)
 }
 
+/// Reports that the host state of the application was lost, called when a response was lost with the connection.
 #[inline]
-pub fn new_packet(destination: u64) -> PacketWriter<'static, VsockStream> {
+pub fn set_device_lost() {
+    if !DEVICE_LOST.swap(true, Ordering::AcqRel) {
+        warn!("Response of the host was lost, reporting device lost");
+    }
+}
+
+/// Marks the start of a new set of objects, called by instance creation.
+#[inline]
+pub fn reset_device_lost() {
+    if DEVICE_LOST.swap(false, Ordering::AcqRel) {
+        info!("Starting new session after device lost");
+    }
+}
+
+#[inline]
+pub fn new_packet(destination: u64) -> PacketWriter<'static, Stream> {
     get_connection().new_packet(destination)
 }
+
+#[inline]
+pub fn new_packet_with_priority(
+    destination: u64,
+    priority: Priority,
+) -> PacketWriter<'static, Stream> {
+    get_connection().new_packet_with_priority(destination, priority)
+}
+
+#[inline]
+fn current_connection() -> &'static Arc<Connection<Stream>> {
+    let connection = CONNECTION.load(Ordering::Acquire);
+    assert!(!connection.is_null(), "connection is not started");
+    unsafe { &*connection }
+}
+
+/// Reconnects with backoff, when the current connection is closed.
+fn reconnect() {
+    let _guard = CONNECT_LOCK.lock().unwrap();
+    if !current_connection().is_closed() {
+        return;
+    }
+
+    let config = CONFIG.get().unwrap();
+    let start = Instant::now();
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        match connect(config) {
+            Ok(()) => return,
+            Err(e) if start.elapsed() + backoff < config.reconnect_timeout => {
+                debug!("Reconnect failed, retrying in {:?}: {}", backoff, e);
+                thread::sleep(backoff);
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+            Err(e) => {
+                error!("Unable to reconnect to host: {}", e);
+                DEVICE_LOST.store(true, Ordering::Release);
+                return;
+            }
+        }
+    }
+}
+
+/// Must be called with `CONNECT_LOCK` acquired.
+fn connect(config: &Config) -> io::Result<()> {
+    let session_token = match SESSION_TOKEN.load(Ordering::Acquire) {
+        0 => None,
+        token => Some(token),
+    };
+
+    // Streams of a single connection must reach the same host, so the whole handshake falls back to the next endpoint.
+    let mut result = Err(io::Error::from(io::ErrorKind::NotConnected));
+    for endpoint in &config.endpoints {
+        result = handshake::connect(config.stream_count, session_token, || {
+            connect_stream(config, endpoint)
+        });
+        match &result {
+            Ok((streams, session)) => {
+                info!(
+                    "Connection established to {} with {} stream(s){}, session {:#x}",
+                    endpoint,
+                    streams.len(),
+                    match streams[0].is_message_based() {
+                        true => " preserving message boundaries",
+                        false => "",
+                    },
+                    session.token
+                );
+                break;
+            }
+            Err(e) => debug!("Unable to connect to {}: {}", endpoint, e),
+        }
+    }
+    let (streams, session) = result?;
+
+    if session_token.is_some() && !session.resumed {
+        warn!("Host did not resume the session, reporting device lost");
+        DEVICE_LOST.store(true, Ordering::Release);
+    } else if session.resumed {
+        // Packets queued or in flight when the previous connection was closed never reached the host, it has no way
+        // to replay them, so the application has to start from scratch.
+        let sent_packets = current_connection().sent_packets();
+        if session.received_packets != sent_packets {
+            warn!(
+                "Host received {} of {} packet(s) sent before reconnect, reporting device lost",
+                session.received_packets, sent_packets
+            );
+            DEVICE_LOST.store(true, Ordering::Release);
+        }
+    }
+    SESSION_TOKEN.store(session.token, Ordering::Release);
+
+    let connection = Connection::with_streams(streams, (config.handlers)(), None);
+    if let Some(heartbeat) = config.heartbeat {
+        connection.start_heartbeat(heartbeat);
+    }
+    connection.on_close(|| {
+        warn!("Connection with host is closed, reconnecting");
+        thread::spawn(reconnect);
+    });
+
+    CONNECTION.store(Box::into_raw(Box::new(connection)), Ordering::Release);
+    Ok(())
+}
+
+fn connect_stream(config: &Config, endpoint: &Endpoint) -> io::Result<Stream> {
+    match endpoint {
+        Endpoint::Vsock(address) => connect_vsock(config, *address).map(Stream::Vsock),
+        Endpoint::Tcp(address) => {
+            let stream = TcpStream::connect(address.as_str())?;
+            stream.set_nodelay(true)?;
+            Ok(Stream::Tcp(stream))
+        }
+        #[cfg(unix)]
+        Endpoint::Unix(path) => std::os::unix::net::UnixStream::connect(path).map(Stream::Unix),
+    }
+}
+
+fn connect_vsock(config: &Config, address: VsockAddress) -> io::Result<VsockStream> {
+    if config.seqpacket && SEQPACKET_AVAILABLE.load(Ordering::Relaxed) {
+        let seqpacket_address = match endpoint::seqpacket_address(address) {
+            Ok(seqpacket_address) => seqpacket_address,
+            Err(e) => {
+                info!("Seqpacket is not available, falling back to stream: {}", e);
+                SEQPACKET_AVAILABLE.store(false, Ordering::Relaxed);
+                return VsockStream::connect(address).map_err(io::Error::from);
+            }
+        };
+        match VsockStream::connect_with_type(seqpacket_address, VsockType::SeqPacket) {
+            Ok(stream) => return Ok(stream),
+            Err(
+                e @ (VsockConnectionError::Creation(VsockCreationError::UnsupportedType)
+                | VsockConnectionError::Refused(_)),
+            ) => {
+                info!("Seqpacket is not available, falling back to stream: {}", e);
+                SEQPACKET_AVAILABLE.store(false, Ordering::Relaxed);
+            }
+            Err(e) => debug!("Unable to connect with seqpacket: {}", e),
+        }
+    }
+
+    VsockStream::connect(address).map_err(io::Error::from)
+}
@@ -1,3 +1,5 @@
+use std::io;
+
 use thiserror::Error;
 use wie_common::errors::WindowsError;
 
@@ -7,6 +9,10 @@ use crate::VsockAddress;
 pub enum VsockCreationError {
     #[error("unable to create a new socket")]
     SocketCreationFail,
+    #[error("vsock is not supported by the system, make sure the vsock kernel module is loaded")]
+    Unsupported,
+    #[error("socket type is not supported by the vsock transport")]
+    UnsupportedType,
     #[error("{0}")]
     Windows(#[from] VsockCreationWindowsError),
 }
@@ -40,6 +46,53 @@ pub enum VsockListenerBindError {
 pub enum VsockConnectionError {
     #[error("{0}")]
     Creation(#[from] VsockCreationError),
-    #[error("unable connect to {0}")]
-    Connection(VsockAddress),
+    #[error("connection to {0} was refused")]
+    Refused(VsockAddress),
+    #[error("{0} is unreachable")]
+    Unreachable(VsockAddress),
+    #[error("connection to {0} timed out")]
+    TimedOut(VsockAddress),
+    #[error("unable connect to {0}: {1}")]
+    Connection(VsockAddress, #[source] io::Error),
+}
+
+impl VsockConnectionError {
+    pub(crate) fn from_os_error(address: VsockAddress, error: io::Error) -> Self {
+        match error.kind() {
+            io::ErrorKind::ConnectionRefused => Self::Refused(address),
+            io::ErrorKind::TimedOut => Self::TimedOut(address),
+            io::ErrorKind::HostUnreachable
+            | io::ErrorKind::NetworkUnreachable
+            | io::ErrorKind::AddrNotAvailable => Self::Unreachable(address),
+            _ => Self::Connection(address, error),
+        }
+    }
+}
+
+impl From<VsockConnectionError> for io::Error {
+    fn from(value: VsockConnectionError) -> Self {
+        let kind = match &value {
+            VsockConnectionError::Creation(
+                VsockCreationError::Unsupported | VsockCreationError::UnsupportedType,
+            ) => io::ErrorKind::Unsupported,
+            VsockConnectionError::Creation(_) => io::ErrorKind::Other,
+            VsockConnectionError::Refused(_) => io::ErrorKind::ConnectionRefused,
+            VsockConnectionError::Unreachable(_) => io::ErrorKind::HostUnreachable,
+            VsockConnectionError::TimedOut(_) => io::ErrorKind::TimedOut,
+            VsockConnectionError::Connection(_, e) => e.kind(),
+        };
+        io::Error::new(kind, value)
+    }
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum VsockAddressParseError {
+    #[error("address must start with `vsock://`")]
+    Scheme,
+    #[error("address must be in `vsock://cid:port` format")]
+    Format,
+    #[error("invalid CID `{0}`")]
+    Cid(String),
+    #[error("invalid port `{0}`")]
+    Port(String),
 }
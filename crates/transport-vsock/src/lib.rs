@@ -1,4 +1,8 @@
 pub mod errors;
+#[cfg(all(feature = "mio", not(target_os = "windows")))]
+mod event_source;
+#[cfg(all(feature = "tokio", not(target_os = "windows")))]
+pub mod tokio;
 
 #[cfg(not(target_os = "windows"))]
 mod unix;
@@ -8,14 +12,25 @@ mod windows;
 use std::{
     fmt,
     io::{Read, Write},
+    net::Shutdown,
     num::NonZeroU32,
+    str::FromStr,
+    sync::Arc,
+};
+#[cfg(not(target_os = "windows"))]
+use std::{
+    io::{self, IoSlice, IoSliceMut},
+    os::fd::{AsRawFd, RawFd},
+    time::Duration,
 };
 
-use errors::{VsockConnectionError, VsockCreationError, VsockListenerBindError};
+use errors::{
+    VsockAddressParseError, VsockConnectionError, VsockCreationError, VsockListenerBindError,
+};
 
 #[cfg(not(target_os = "windows"))]
 use unix as imp;
-use wie_common::stream::{UnsafeRead, UnsafeWrite};
+use wie_common::stream::{SplitStream, StreamShutdown};
 #[cfg(target_os = "windows")]
 use windows as imp;
 
@@ -25,9 +40,35 @@ pub struct VsockListener {
 }
 
 impl VsockListener {
+    /// Binds to the port of the host CID.
     pub fn bind(port: u32, max_connections: NonZeroU32) -> Result<Self, VsockListenerBindError> {
-        let mut socket = Vsock::new()?;
-        imp::bind(&mut socket, port)?;
+        Self::bind_address(
+            VsockAddress {
+                cid: VsockCid::host(),
+                port,
+            },
+            max_connections,
+        )
+    }
+
+    /// Binds to the address, [`VsockCid::any`] accepts connections to every CID of this machine and
+    /// [`VsockAddress::PORT_ANY`] picks a free port, which is returned by [`VsockListener::local_addr`].
+    pub fn bind_address(
+        address: VsockAddress,
+        max_connections: NonZeroU32,
+    ) -> Result<Self, VsockListenerBindError> {
+        Self::bind_with_type(address, max_connections, VsockType::Stream)
+    }
+
+    /// Same as [`VsockListener::bind_address`], but accepts only connections of the socket type. Stream and seqpacket
+    /// listeners share port numbers, so they cannot listen on the same port.
+    pub fn bind_with_type(
+        address: VsockAddress,
+        max_connections: NonZeroU32,
+        ty: VsockType,
+    ) -> Result<Self, VsockListenerBindError> {
+        let mut socket = Vsock::new(ty)?;
+        imp::bind(&mut socket, address)?;
         imp::listen(&mut socket, max_connections)?;
         Ok(Self { socket })
     }
@@ -46,6 +87,43 @@ impl VsockListener {
     }
 }
 
+#[cfg(not(target_os = "windows"))]
+impl VsockListener {
+    pub fn local_addr(&self) -> io::Result<VsockAddress> {
+        imp::local_addr(&self.socket)
+    }
+
+    /// Takes ownership of a listening socket, like the one passed by systemd socket activation.
+    ///
+    /// # Safety
+    /// `fd` must be an open vsock socket which listens for connections and is not owned by anything else. It is not
+    /// closed when this fails.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        Ok(Self {
+            socket: imp::from_fd(fd)?,
+        })
+    }
+
+    /// Makes [`VsockListener::accept`] return [`io::ErrorKind::WouldBlock`] instead of waiting.
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        imp::set_nonblocking(&self.socket, nonblocking)
+    }
+
+    /// Creates a new handle to the same socket, options are shared between them.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: imp::try_clone(&self.socket)?,
+        })
+    }
+}
+
+#[cfg(not(target_os = "windows"))]
+impl AsRawFd for VsockListener {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.inner
+    }
+}
+
 /// An iterator that infinitely accepts connections on a VsockListener.
 #[derive(Debug)]
 pub struct Incoming<'a> {
@@ -67,33 +145,133 @@ pub struct VsockStream {
 
 impl VsockStream {
     pub fn connect(address: VsockAddress) -> Result<Self, VsockConnectionError> {
-        let mut socket = Vsock::new()?;
+        Self::connect_with_type(address, VsockType::Stream)
+    }
+
+    /// Connects with the socket type, peer must listen with the same type.
+    ///
+    /// Creation fails with [`VsockCreationError::UnsupportedType`] when the vsock transport does not support the type,
+    /// and connecting fails with [`VsockConnectionError::Refused`] when the peer listens with the other one.
+    pub fn connect_with_type(
+        address: VsockAddress,
+        ty: VsockType,
+    ) -> Result<Self, VsockConnectionError> {
+        let mut socket = Vsock::new(ty)?;
         imp::connect(&mut socket, address)?;
         Ok(Self { socket })
     }
+
+    #[inline]
+    pub fn socket_type(&self) -> VsockType {
+        self.socket.ty
+    }
+
+    /// Shuts down the read, write, or both halves of the connection, pending and future calls return immediately.
+    pub fn shutdown(&self, how: Shutdown) -> std::io::Result<()> {
+        shutdown(&self.socket, how)
+    }
 }
 
-impl Read for VsockStream {
-    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
-        // SAFETY: Synchronized via &mut
-        unsafe { self.read_unsafe(buf) }
+#[cfg(not(target_os = "windows"))]
+impl VsockStream {
+    /// Takes ownership of a connected socket, like the one passed by the parent process.
+    ///
+    /// # Safety
+    /// `fd` must be an open vsock socket which is connected and is not owned by anything else. It is not closed when
+    /// this fails.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        Ok(Self {
+            socket: imp::from_fd(fd)?,
+        })
+    }
+
+    /// Same as [`VsockStream::connect`], but fails with [`VsockConnectionError::TimedOut`] after the timeout. Default
+    /// timeout of the kernel is 2 seconds.
+    pub fn connect_timeout(
+        address: VsockAddress,
+        timeout: Duration,
+    ) -> Result<Self, VsockConnectionError> {
+        let mut socket = Vsock::new(VsockType::Stream)?;
+        imp::set_connect_timeout(&socket, timeout)
+            .map_err(|e| VsockConnectionError::Connection(address, e))?;
+        imp::connect(&mut socket, address)?;
+        Ok(Self { socket })
+    }
+
+    pub fn local_addr(&self) -> io::Result<VsockAddress> {
+        imp::local_addr(&self.socket)
+    }
+
+    pub fn peer_addr(&self) -> io::Result<VsockAddress> {
+        imp::peer_addr(&self.socket)
+    }
+
+    /// Sets `SO_SNDBUF` of the socket. Vsock transports limit sending by the receive buffer of the peer, so it rarely
+    /// matters.
+    pub fn set_send_buffer_size(&self, size: usize) -> io::Result<()> {
+        imp::set_send_buffer_size(&self.socket, size)
+    }
+
+    pub fn send_buffer_size(&self) -> io::Result<usize> {
+        imp::send_buffer_size(&self.socket)
+    }
+
+    /// Sets size of the receive buffer, which the transport advertises to the peer. It is clamped by the kernel to
+    /// minimal and maximal buffer size of the socket, maximum defaults to 256 KiB.
+    pub fn set_recv_buffer_size(&self, size: u64) -> io::Result<()> {
+        imp::set_recv_buffer_size(&self.socket, size)
+    }
+
+    pub fn recv_buffer_size(&self) -> io::Result<u64> {
+        imp::recv_buffer_size(&self.socket)
+    }
+
+    /// Makes reads fail with [`io::ErrorKind::WouldBlock`] after the timeout, [`None`] waits infinitely.
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        imp::set_read_timeout(&self.socket, timeout)
+    }
+
+    pub fn read_timeout(&self) -> io::Result<Option<Duration>> {
+        imp::read_timeout(&self.socket)
+    }
+
+    /// Makes writes fail with [`io::ErrorKind::WouldBlock`] after the timeout, [`None`] waits infinitely.
+    pub fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        imp::set_write_timeout(&self.socket, timeout)
+    }
+
+    pub fn write_timeout(&self) -> io::Result<Option<Duration>> {
+        imp::write_timeout(&self.socket)
+    }
+
+    pub fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        imp::set_nonblocking(&self.socket, nonblocking)
+    }
+
+    /// Creates a new handle to the same socket, options are shared between them.
+    pub fn try_clone(&self) -> io::Result<Self> {
+        Ok(Self {
+            socket: imp::try_clone(&self.socket)?,
+        })
     }
 }
 
-impl UnsafeRead for VsockStream {
-    unsafe fn read_unsafe(&self, buf: &mut [u8]) -> std::io::Result<usize> {
-        let read = imp::recv(&self.socket, buf);
-        match read >= 0 {
-            true => Ok(read as usize),
-            false => Err(std::io::Error::last_os_error()),
-        }
+#[cfg(not(target_os = "windows"))]
+impl AsRawFd for VsockStream {
+    fn as_raw_fd(&self) -> RawFd {
+        self.socket.inner
+    }
+}
+
+impl Read for VsockStream {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        recv(&self.socket, buf)
     }
 }
 
 impl Write for VsockStream {
     fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
-        // SAFETY: Synchronized via &mut
-        unsafe { self.write_unsafe(buf) }
+        send(&self.socket, buf)
     }
 
     fn flush(&mut self) -> std::io::Result<()> {
@@ -101,53 +279,177 @@ impl Write for VsockStream {
     }
 }
 
-impl UnsafeWrite for VsockStream {
-    unsafe fn write_unsafe(&self, buf: &[u8]) -> std::io::Result<usize> {
-        let read = imp::send(&self.socket, buf);
-        match read >= 0 {
-            true => Ok(read as usize),
-            false => Err(std::io::Error::last_os_error()),
-        }
+impl SplitStream for VsockStream {
+    type ReadHalf = VsockReadHalf;
+    type WriteHalf = VsockWriteHalf;
+    type Shutdown = VsockShutdown;
+
+    fn split(self) -> (VsockReadHalf, VsockWriteHalf, VsockShutdown) {
+        let socket = Arc::new(self.socket);
+        (
+            VsockReadHalf {
+                socket: socket.clone(),
+            },
+            VsockWriteHalf {
+                socket: socket.clone(),
+            },
+            VsockShutdown { socket },
+        )
+    }
+
+    fn is_message_based(&self) -> bool {
+        self.socket.ty == VsockType::SeqPacket
     }
+}
 
-    unsafe fn flush_unsafe(&self) -> std::io::Result<()> {
+/// Read half of the [`VsockStream`], socket is closed when every half is dropped.
+#[derive(Debug)]
+pub struct VsockReadHalf {
+    socket: Arc<Vsock>,
+}
+
+impl Read for VsockReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        recv(&self.socket, buf)
+    }
+
+    /// Reads a single message into all buffers, when the socket is [`VsockType::SeqPacket`].
+    #[cfg(not(target_os = "windows"))]
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> std::io::Result<usize> {
+        imp::recv_vectored(&self.socket, bufs)
+    }
+}
+
+/// Write half of the [`VsockStream`], socket is closed when every half is dropped.
+#[derive(Debug)]
+pub struct VsockWriteHalf {
+    socket: Arc<Vsock>,
+}
+
+impl Write for VsockWriteHalf {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        send(&self.socket, buf)
+    }
+
+    /// Writes all buffers as a single message, when the socket is [`VsockType::SeqPacket`].
+    #[cfg(not(target_os = "windows"))]
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> std::io::Result<usize> {
+        imp::send_vectored(&self.socket, bufs)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
         Ok(())
     }
 }
 
+#[derive(Debug)]
+pub struct VsockShutdown {
+    socket: Arc<Vsock>,
+}
+
+impl StreamShutdown for VsockShutdown {
+    fn shutdown(&self) -> std::io::Result<()> {
+        shutdown(&self.socket, Shutdown::Both)
+    }
+}
+
+// Socket calls are thread safe, halves only limit which thread reads and which writes.
+fn recv(socket: &Vsock, buf: &mut [u8]) -> std::io::Result<usize> {
+    let read = imp::recv(socket, buf);
+    match read >= 0 {
+        true => Ok(read as usize),
+        false => Err(std::io::Error::last_os_error()),
+    }
+}
+
+fn shutdown(socket: &Vsock, how: Shutdown) -> std::io::Result<()> {
+    match imp::shutdown(socket, how) >= 0 {
+        true => Ok(()),
+        false => Err(std::io::Error::last_os_error()),
+    }
+}
+
+fn send(socket: &Vsock, buf: &[u8]) -> std::io::Result<usize> {
+    let written = imp::send(socket, buf);
+    match written >= 0 {
+        true => Ok(written as usize),
+        false => Err(std::io::Error::last_os_error()),
+    }
+}
+
 #[derive(Debug)]
 pub(crate) struct Vsock {
     #[cfg(not(target_os = "windows"))]
     pub(crate) inner: i32,
     #[cfg(target_os = "windows")]
     pub(crate) inner: ::windows::Win32::Networking::WinSock::SOCKET,
+    pub(crate) ty: VsockType,
 }
 
 impl Vsock {
-    pub(crate) fn new() -> Result<Self, VsockCreationError> {
-        imp::new_socket()
+    pub(crate) fn new(ty: VsockType) -> Result<Self, VsockCreationError> {
+        imp::new_socket(ty)
     }
 }
 
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum VsockType {
+    /// Byte stream, like TCP.
+    #[default]
+    Stream,
+    /// Connection which preserves message boundaries, every read returns a single message. Supported only on Linux,
+    /// with kernel 5.14 and newer.
+    SeqPacket,
+}
+
 impl Drop for Vsock {
     fn drop(&mut self) {
         imp::close(self);
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct VsockAddress {
     pub cid: VsockCid,
     pub port: u32,
 }
 
+impl VsockAddress {
+    /// Port used for binding to any free port.
+    pub const PORT_ANY: u32 = u32::MAX;
+
+    const SCHEME: &'static str = "vsock://";
+}
+
+/// Formats address as `vsock://cid:port`, which is parsed back by [`FromStr`].
 impl fmt::Display for VsockAddress {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{}:{}", self.cid.0, self.port)
+        write!(f, "{}{}:{}", Self::SCHEME, self.cid, self.port)
     }
 }
 
-#[derive(Debug)]
+/// Parses address in `vsock://cid:port` format. CID can be a number, `any` or `host`.
+impl FromStr for VsockAddress {
+    type Err = VsockAddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let address = s
+            .strip_prefix(Self::SCHEME)
+            .ok_or(VsockAddressParseError::Scheme)?;
+        let (cid, port) = address
+            .rsplit_once(':')
+            .ok_or(VsockAddressParseError::Format)?;
+
+        Ok(Self {
+            cid: cid.parse()?,
+            port: port
+                .parse()
+                .map_err(|_| VsockAddressParseError::Port(port.to_owned()))?,
+        })
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
 pub struct VsockCid(pub u32);
 
 impl VsockCid {
@@ -155,7 +457,84 @@ impl VsockCid {
         VsockCid(cid)
     }
 
+    /// CID used for binding to every CID of this machine.
+    pub fn any() -> Self {
+        VsockCid(u32::MAX)
+    }
+
     pub fn host() -> Self {
         VsockCid(2)
     }
+
+    /// Address of the local machine, it requires `vsock_loopback` transport on Linux.
+    pub fn loopback() -> Self {
+        VsockCid(1)
+    }
+
+    /// Returns CID of this machine, which is used by peers to connect to it. Guests usually get it from the
+    /// hypervisor, machines without any vsock transport return [`VsockCid::host`].
+    #[cfg(not(target_os = "windows"))]
+    pub fn local() -> std::io::Result<Self> {
+        imp::local_cid().map(VsockCid)
+    }
+}
+
+impl fmt::Display for VsockCid {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+impl FromStr for VsockCid {
+    type Err = VsockAddressParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "any" => Ok(Self::any()),
+            "host" => Ok(Self::host()),
+            _ => s
+                .parse()
+                .map(VsockCid)
+                .map_err(|_| VsockAddressParseError::Cid(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use rstest::rstest;
+
+    use crate::{errors::VsockAddressParseError, VsockAddress, VsockCid};
+
+    #[rstest]
+    #[case(VsockCid::host(), 13001)]
+    #[case(VsockCid::loopback(), 0)]
+    #[case(VsockCid(1234), 42)]
+    #[case(VsockCid::any(), VsockAddress::PORT_ANY)]
+    fn address_round_trip(#[case] cid: VsockCid, #[case] port: u32) {
+        let address = VsockAddress { cid, port };
+        let text = address.to_string();
+        assert!(text.starts_with("vsock://"));
+        assert_eq!(Ok(address), text.parse());
+    }
+
+    #[rstest]
+    #[case("vsock://host:13001", VsockCid::host(), 13001)]
+    #[case("vsock://any:5", VsockCid::any(), 5)]
+    #[case("vsock://3:1", VsockCid(3), 1)]
+    fn parse_address(#[case] text: &str, #[case] cid: VsockCid, #[case] port: u32) {
+        assert_eq!(Ok(VsockAddress { cid, port }), text.parse());
+    }
+
+    #[rstest]
+    #[case("2:13001", VsockAddressParseError::Scheme)]
+    #[case("tcp://2:13001", VsockAddressParseError::Scheme)]
+    #[case("vsock://2", VsockAddressParseError::Format)]
+    #[case("vsock://guest:13001", VsockAddressParseError::Cid("guest".to_owned()))]
+    #[case("vsock://-1:13001", VsockAddressParseError::Cid("-1".to_owned()))]
+    #[case("vsock://2:", VsockAddressParseError::Port("".to_owned()))]
+    #[case("vsock://2:70000000000", VsockAddressParseError::Port("70000000000".to_owned()))]
+    fn parse_invalid_address(#[case] text: &str, #[case] error: VsockAddressParseError) {
+        assert_eq!(Err(error), text.parse::<VsockAddress>());
+    }
 }
@@ -0,0 +1,240 @@
+//! Vsock sockets for [`tokio`] runtime, they are driven by the reactor of the runtime instead of a thread per stream.
+
+use std::{
+    io,
+    net::Shutdown,
+    num::NonZeroU32,
+    pin::Pin,
+    task::{ready, Context, Poll},
+};
+
+use ::tokio::{
+    io::{unix::AsyncFd, AsyncRead, AsyncWrite, ReadBuf},
+    task,
+};
+
+use crate::{
+    errors::{VsockConnectionError, VsockListenerBindError},
+    VsockAddress, VsockListener, VsockStream, VsockType,
+};
+
+/// Asynchronous version of [`VsockListener`].
+#[derive(Debug)]
+pub struct AsyncVsockListener {
+    inner: AsyncFd<VsockListener>,
+}
+
+impl AsyncVsockListener {
+    /// Same as [`VsockListener::bind_with_type`], must be called within the runtime.
+    pub fn bind(
+        address: VsockAddress,
+        max_connections: NonZeroU32,
+        ty: VsockType,
+    ) -> Result<Self, VsockListenerBindError> {
+        let listener = VsockListener::bind_with_type(address, max_connections, ty)?;
+        Self::new(listener).map_err(|_| VsockListenerBindError::Listen)
+    }
+
+    /// Registers the listener in the runtime, it is switched to non-blocking mode.
+    pub fn new(listener: VsockListener) -> io::Result<Self> {
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            inner: AsyncFd::new(listener)?,
+        })
+    }
+
+    pub async fn accept(&self) -> io::Result<(AsyncVsockStream, VsockAddress)> {
+        loop {
+            let mut guard = self.inner.readable().await?;
+            match guard.try_io(|inner| inner.get_ref().accept(None)) {
+                Ok(result) => {
+                    let (stream, address) = result?;
+                    return Ok((AsyncVsockStream::new(stream)?, address));
+                }
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    pub fn local_addr(&self) -> io::Result<VsockAddress> {
+        self.inner.get_ref().local_addr()
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &VsockListener {
+        self.inner.get_ref()
+    }
+}
+
+/// Asynchronous version of [`VsockStream`].
+#[derive(Debug)]
+pub struct AsyncVsockStream {
+    inner: AsyncFd<VsockStream>,
+}
+
+impl AsyncVsockStream {
+    /// Connects on the blocking thread pool of the runtime, kernel does not report progress of vsock connecting.
+    pub async fn connect(
+        address: VsockAddress,
+        ty: VsockType,
+    ) -> Result<Self, VsockConnectionError> {
+        let stream = task::spawn_blocking(move || VsockStream::connect_with_type(address, ty))
+            .await
+            .map_err(|e| VsockConnectionError::Connection(address, io::Error::other(e)))??;
+        Self::new(stream).map_err(|e| VsockConnectionError::Connection(address, e))
+    }
+
+    /// Registers the stream in the runtime, it is switched to non-blocking mode.
+    pub fn new(stream: VsockStream) -> io::Result<Self> {
+        stream.set_nonblocking(true)?;
+        Ok(Self {
+            inner: AsyncFd::new(stream)?,
+        })
+    }
+
+    #[inline]
+    pub fn get_ref(&self) -> &VsockStream {
+        self.inner.get_ref()
+    }
+
+    /// Deregisters the stream from the runtime, it stays in non-blocking mode.
+    pub fn into_inner(self) -> VsockStream {
+        self.inner.into_inner()
+    }
+}
+
+impl AsyncRead for AsyncVsockStream {
+    fn poll_read(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_read_ready(cx))?;
+
+            let unfilled = buf.initialize_unfilled();
+            match guard.try_io(|inner| crate::recv(&inner.get_ref().socket, unfilled)) {
+                Ok(Ok(read)) => {
+                    buf.advance(read);
+                    return Poll::Ready(Ok(()));
+                }
+                Ok(Err(e)) => return Poll::Ready(Err(e)),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+}
+
+impl AsyncWrite for AsyncVsockStream {
+    fn poll_write(
+        self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        loop {
+            let mut guard = ready!(self.inner.poll_write_ready(cx))?;
+
+            match guard.try_io(|inner| crate::send(&inner.get_ref().socket, buf)) {
+                Ok(result) => return Poll::Ready(result),
+                Err(_would_block) => continue,
+            }
+        }
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Poll::Ready(self.inner.get_ref().shutdown(Shutdown::Write))
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{num::NonZeroU32, os::fd::IntoRawFd, os::unix::net::UnixStream};
+
+    use ::tokio::io::{AsyncReadExt, AsyncWriteExt};
+
+    use super::{AsyncVsockListener, AsyncVsockStream};
+    use crate::{
+        errors::{VsockConnectionError, VsockCreationError, VsockListenerBindError},
+        Vsock, VsockAddress, VsockCid, VsockStream, VsockType,
+    };
+
+    /// Wraps Unix socket pair into vsock streams, they share the code path of vsock sockets, which works with every
+    /// socket file descriptor.
+    fn unix_pair() -> (AsyncVsockStream, AsyncVsockStream) {
+        let (a, b) = UnixStream::pair().unwrap();
+        let wrap = |stream: UnixStream| {
+            AsyncVsockStream::new(VsockStream {
+                socket: Vsock {
+                    inner: stream.into_raw_fd(),
+                    ty: VsockType::Stream,
+                },
+            })
+            .unwrap()
+        };
+        (wrap(a), wrap(b))
+    }
+
+    #[::tokio::test]
+    async fn read_write_over_unix_socket() {
+        let (mut a, mut b) = unix_pair();
+
+        // Bigger than the socket buffer, so both sides have to wait for readiness.
+        let data = (0..1024 * 1024).map(|x| x as u8).collect::<Vec<_>>();
+        let expected = data.clone();
+        let writer = ::tokio::spawn(async move {
+            a.write_all(&data).await.unwrap();
+            a.shutdown().await.unwrap();
+        });
+
+        let mut received = Vec::new();
+        b.read_to_end(&mut received).await.unwrap();
+        writer.await.unwrap();
+        assert_eq!(expected, received);
+    }
+
+    #[::tokio::test]
+    async fn accept_loopback() {
+        let address = VsockAddress {
+            cid: VsockCid::any(),
+            port: VsockAddress::PORT_ANY,
+        };
+        let listener =
+            match AsyncVsockListener::bind(address, NonZeroU32::new(1).unwrap(), VsockType::Stream)
+            {
+                Ok(listener) => listener,
+                Err(VsockListenerBindError::Creation(VsockCreationError::Unsupported)) => {
+                    eprintln!("skipped, vsock is not supported");
+                    return;
+                }
+                Err(e) => panic!("{e}"),
+            };
+        let port = listener.local_addr().unwrap().port;
+
+        let server = ::tokio::spawn(async move {
+            let (mut stream, _) = listener.accept().await.unwrap();
+            stream.write_all(b"wie").await.unwrap();
+        });
+
+        let address = VsockAddress {
+            cid: VsockCid::loopback(),
+            port,
+        };
+        let mut stream = match AsyncVsockStream::connect(address, VsockType::Stream).await {
+            Ok(stream) => stream,
+            Err(VsockConnectionError::Unreachable(_) | VsockConnectionError::TimedOut(_)) => {
+                eprintln!("skipped, vsock loopback is not available");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        };
+
+        let mut buffer = [0u8; 3];
+        stream.read_exact(&mut buffer).await.unwrap();
+        assert_eq!(b"wie", &buffer);
+        server.await.unwrap();
+    }
+}
@@ -1,40 +1,67 @@
-use std::{mem, num::NonZeroU32};
+use std::{
+    io::{self, IoSlice, IoSliceMut},
+    mem::{self, ManuallyDrop},
+    net::Shutdown,
+    num::NonZeroU32,
+    time::Duration,
+};
 
-use libc::{c_void, sa_family_t, sockaddr, sockaddr_vm};
+use libc::{c_int, c_void, sa_family_t, sockaddr, sockaddr_vm, socklen_t, timeval};
 
 use crate::{
     errors::{VsockConnectionError, VsockCreationError, VsockListenerBindError},
-    Vsock, VsockAddress, VsockCid,
+    Vsock, VsockAddress, VsockCid, VsockType,
 };
 
-pub(crate) fn new_socket() -> Result<Vsock, VsockCreationError> {
-    let result = unsafe { libc::socket(libc::AF_VSOCK, libc::SOCK_STREAM, 0) };
-    match result != -1 {
-        true => Ok(Vsock { inner: result }),
-        false => Err(VsockCreationError::SocketCreationFail),
+/// Options of `AF_VSOCK` level from `linux/vm_sockets.h`, they are not exported by libc.
+const SO_VM_SOCKETS_BUFFER_SIZE: c_int = 0;
+const SO_VM_SOCKETS_CONNECT_TIMEOUT_OLD: c_int = 6;
+/// `_IO(7, 0xb9)` from `linux/vm_sockets.h`.
+const IOCTL_VM_SOCKETS_GET_LOCAL_CID: libc::c_ulong = 0x7b9;
+
+pub(crate) fn new_socket(ty: VsockType) -> Result<Vsock, VsockCreationError> {
+    let socket_type = match ty {
+        VsockType::Stream => libc::SOCK_STREAM,
+        VsockType::SeqPacket => libc::SOCK_SEQPACKET,
+    };
+
+    let result = unsafe { libc::socket(libc::AF_VSOCK, socket_type | libc::SOCK_CLOEXEC, 0) };
+    if result != -1 {
+        return Ok(Vsock { inner: result, ty });
+    }
+
+    match io::Error::last_os_error().raw_os_error() {
+        Some(libc::EAFNOSUPPORT) => Err(VsockCreationError::Unsupported),
+        Some(libc::ESOCKTNOSUPPORT | libc::EPROTONOSUPPORT) => {
+            Err(VsockCreationError::UnsupportedType)
+        }
+        _ => Err(VsockCreationError::SocketCreationFail),
     }
 }
 
-pub(crate) fn bind(socket: &mut Vsock, port: u32) -> Result<(), VsockListenerBindError> {
-    let address = sockaddr_vm {
+pub(crate) fn bind(
+    socket: &mut Vsock,
+    address: VsockAddress,
+) -> Result<(), VsockListenerBindError> {
+    let address_vm = sockaddr_vm {
         svm_family: libc::AF_VSOCK as sa_family_t,
         svm_reserved1: 0,
-        svm_port: port,
-        svm_cid: libc::VMADDR_CID_HOST,
+        svm_port: address.port,
+        svm_cid: address.cid.0,
         svm_zero: [0u8; 4],
     };
 
     let result = unsafe {
         libc::bind(
             socket.inner,
-            &address as *const sockaddr_vm as *const sockaddr,
+            &address_vm as *const sockaddr_vm as *const sockaddr,
             mem::size_of::<sockaddr_vm>() as u32,
         )
     };
 
     match result >= 0 {
         true => Ok(()),
-        false => Err(VsockListenerBindError::Bind(port)),
+        false => Err(VsockListenerBindError::Bind(address.port)),
     }
 }
 
@@ -77,7 +104,10 @@ pub(crate) fn accept(
 
     match result >= 0 {
         true => Ok((
-            Vsock { inner: result },
+            Vsock {
+                inner: result,
+                ty: socket.ty,
+            },
             VsockAddress {
                 cid: VsockCid(address.svm_cid),
                 port: address.svm_port,
@@ -88,10 +118,41 @@ pub(crate) fn accept(
 }
 
 pub(crate) fn connect(
-    _socket: &mut Vsock,
-    _address: VsockAddress,
+    socket: &mut Vsock,
+    address: VsockAddress,
 ) -> Result<(), VsockConnectionError> {
-    unimplemented!()
+    let address_vm = sockaddr_vm {
+        svm_family: libc::AF_VSOCK as sa_family_t,
+        svm_reserved1: 0,
+        svm_port: address.port,
+        svm_cid: address.cid.0,
+        svm_zero: [0u8; 4],
+    };
+
+    let result = unsafe {
+        libc::connect(
+            socket.inner,
+            &address_vm as *const sockaddr_vm as *const sockaddr,
+            mem::size_of::<sockaddr_vm>() as u32,
+        )
+    };
+
+    if result >= 0 {
+        return Ok(());
+    }
+
+    let error = io::Error::last_os_error();
+    Err(match error.raw_os_error() {
+        // Vsock resets connections to ports without a listener.
+        Some(libc::ECONNRESET) => VsockConnectionError::Refused(address),
+        // No transport is able to reach the CID, e.g. connecting to the host from a machine which is not a guest.
+        Some(libc::ENODEV) => VsockConnectionError::Unreachable(address),
+        // Transport which reaches the CID is known only when connecting, and it may not support the socket type.
+        Some(libc::ESOCKTNOSUPPORT) => {
+            VsockConnectionError::Creation(VsockCreationError::UnsupportedType)
+        }
+        _ => VsockConnectionError::from_os_error(address, error),
+    })
 }
 
 pub(crate) fn recv(socket: &Vsock, buffer: &mut [u8]) -> isize {
@@ -116,6 +177,489 @@ pub(crate) fn send(socket: &Vsock, buffer: &[u8]) -> isize {
     }
 }
 
+pub(crate) fn recv_vectored(socket: &Vsock, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+    // IoSliceMut is guaranteed to be ABI compatible with iovec on Unix.
+    let result = unsafe {
+        libc::readv(
+            socket.inner,
+            bufs.as_mut_ptr() as *mut libc::iovec,
+            bufs.len().min(c_int::MAX as usize) as c_int,
+        )
+    };
+    match result >= 0 {
+        true => Ok(result as usize),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+pub(crate) fn send_vectored(socket: &Vsock, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+    let result = unsafe {
+        libc::writev(
+            socket.inner,
+            bufs.as_ptr() as *const libc::iovec,
+            bufs.len().min(c_int::MAX as usize) as c_int,
+        )
+    };
+    match result >= 0 {
+        true => Ok(result as usize),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+pub(crate) fn shutdown(socket: &Vsock, how: Shutdown) -> i32 {
+    let how = match how {
+        Shutdown::Read => libc::SHUT_RD,
+        Shutdown::Write => libc::SHUT_WR,
+        Shutdown::Both => libc::SHUT_RDWR,
+    };
+    unsafe { libc::shutdown(socket.inner, how) }
+}
+
+pub(crate) fn local_addr(socket: &Vsock) -> io::Result<VsockAddress> {
+    socket_address(socket, libc::getsockname)
+}
+
+pub(crate) fn peer_addr(socket: &Vsock) -> io::Result<VsockAddress> {
+    socket_address(socket, libc::getpeername)
+}
+
+pub(crate) fn set_connect_timeout(socket: &Vsock, timeout: Duration) -> io::Result<()> {
+    if timeout.is_zero() {
+        return Err(zero_timeout_error());
+    }
+    set_option(
+        socket,
+        libc::AF_VSOCK,
+        SO_VM_SOCKETS_CONNECT_TIMEOUT_OLD,
+        duration_to_timeval(timeout),
+    )
+}
+
+pub(crate) fn set_send_buffer_size(socket: &Vsock, size: usize) -> io::Result<()> {
+    let size = c_int::try_from(size).unwrap_or(c_int::MAX);
+    set_option(socket, libc::SOL_SOCKET, libc::SO_SNDBUF, size)
+}
+
+pub(crate) fn send_buffer_size(socket: &Vsock) -> io::Result<usize> {
+    get_option::<c_int>(socket, libc::SOL_SOCKET, libc::SO_SNDBUF).map(|x| x as usize)
+}
+
+pub(crate) fn set_recv_buffer_size(socket: &Vsock, size: u64) -> io::Result<()> {
+    set_option(socket, libc::AF_VSOCK, SO_VM_SOCKETS_BUFFER_SIZE, size)
+}
+
+pub(crate) fn recv_buffer_size(socket: &Vsock) -> io::Result<u64> {
+    get_option(socket, libc::AF_VSOCK, SO_VM_SOCKETS_BUFFER_SIZE)
+}
+
+pub(crate) fn set_read_timeout(socket: &Vsock, timeout: Option<Duration>) -> io::Result<()> {
+    set_timeout(socket, libc::SO_RCVTIMEO, timeout)
+}
+
+pub(crate) fn read_timeout(socket: &Vsock) -> io::Result<Option<Duration>> {
+    timeout(socket, libc::SO_RCVTIMEO)
+}
+
+pub(crate) fn set_write_timeout(socket: &Vsock, timeout: Option<Duration>) -> io::Result<()> {
+    set_timeout(socket, libc::SO_SNDTIMEO, timeout)
+}
+
+pub(crate) fn write_timeout(socket: &Vsock) -> io::Result<Option<Duration>> {
+    timeout(socket, libc::SO_SNDTIMEO)
+}
+
+/// Wraps the socket, its type is read from the socket.
+pub(crate) fn from_fd(fd: c_int) -> io::Result<Vsock> {
+    // Socket is not closed on error, it is still owned by the caller then.
+    let socket = ManuallyDrop::new(Vsock {
+        inner: fd,
+        ty: VsockType::Stream,
+    });
+    let ty = match get_option::<c_int>(&socket, libc::SOL_SOCKET, libc::SO_TYPE)? {
+        libc::SOCK_STREAM => VsockType::Stream,
+        libc::SOCK_SEQPACKET => VsockType::SeqPacket,
+        ty => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("unsupported vsock socket type {ty}"),
+            ))
+        }
+    };
+    Ok(Vsock { inner: fd, ty })
+}
+
+pub(crate) fn set_nonblocking(socket: &Vsock, nonblocking: bool) -> io::Result<()> {
+    let mut nonblocking = nonblocking as c_int;
+    match unsafe { libc::ioctl(socket.inner, libc::FIONBIO, &mut nonblocking) } >= 0 {
+        true => Ok(()),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+pub(crate) fn try_clone(socket: &Vsock) -> io::Result<Vsock> {
+    let result = unsafe { libc::fcntl(socket.inner, libc::F_DUPFD_CLOEXEC, 0) };
+    match result >= 0 {
+        true => Ok(Vsock {
+            inner: result,
+            ty: socket.ty,
+        }),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
 pub(crate) fn close(socket: &mut Vsock) {
     _ = unsafe { libc::close(socket.inner) };
 }
+
+pub(crate) fn local_cid() -> io::Result<u32> {
+    let fd = unsafe { libc::open(c"/dev/vsock".as_ptr(), libc::O_RDONLY | libc::O_CLOEXEC) };
+    if fd < 0 {
+        return Err(io::Error::last_os_error());
+    }
+
+    let mut cid: u32 = 0;
+    let result = unsafe { libc::ioctl(fd, IOCTL_VM_SOCKETS_GET_LOCAL_CID, &mut cid) };
+    let error = io::Error::last_os_error();
+    _ = unsafe { libc::close(fd) };
+
+    match result >= 0 {
+        true => Ok(cid),
+        false => Err(error),
+    }
+}
+
+fn socket_address(
+    socket: &Vsock,
+    f: unsafe extern "C" fn(c_int, *mut sockaddr, *mut socklen_t) -> c_int,
+) -> io::Result<VsockAddress> {
+    let mut address: sockaddr_vm = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<sockaddr_vm>() as socklen_t;
+    let result = unsafe {
+        f(
+            socket.inner,
+            &mut address as *mut sockaddr_vm as *mut sockaddr,
+            &mut length,
+        )
+    };
+
+    match result >= 0 {
+        true => Ok(VsockAddress {
+            cid: VsockCid(address.svm_cid),
+            port: address.svm_port,
+        }),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+fn set_option<T>(socket: &Vsock, level: c_int, name: c_int, value: T) -> io::Result<()> {
+    let result = unsafe {
+        libc::setsockopt(
+            socket.inner,
+            level,
+            name,
+            &value as *const T as *const c_void,
+            mem::size_of::<T>() as socklen_t,
+        )
+    };
+
+    match result >= 0 {
+        true => Ok(()),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+fn get_option<T>(socket: &Vsock, level: c_int, name: c_int) -> io::Result<T> {
+    let mut value: T = unsafe { mem::zeroed() };
+    let mut length = mem::size_of::<T>() as socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            socket.inner,
+            level,
+            name,
+            &mut value as *mut T as *mut c_void,
+            &mut length,
+        )
+    };
+
+    match result >= 0 {
+        true => Ok(value),
+        false => Err(io::Error::last_os_error()),
+    }
+}
+
+fn set_timeout(socket: &Vsock, name: c_int, timeout: Option<Duration>) -> io::Result<()> {
+    let value = match timeout {
+        Some(timeout) if timeout.is_zero() => return Err(zero_timeout_error()),
+        Some(timeout) => duration_to_timeval(timeout),
+        None => timeval {
+            tv_sec: 0,
+            tv_usec: 0,
+        },
+    };
+    set_option(socket, libc::SOL_SOCKET, name, value)
+}
+
+fn timeout(socket: &Vsock, name: c_int) -> io::Result<Option<Duration>> {
+    let value = get_option::<timeval>(socket, libc::SOL_SOCKET, name)?;
+    match value.tv_sec == 0 && value.tv_usec == 0 {
+        true => Ok(None),
+        false => Ok(Some(
+            Duration::from_secs(value.tv_sec as u64) + Duration::from_micros(value.tv_usec as u64),
+        )),
+    }
+}
+
+/// Zero timeval disables the timeout, so it is rejected like by [`std::net::TcpStream`].
+fn zero_timeout_error() -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        "cannot set a 0 duration timeout",
+    )
+}
+
+fn duration_to_timeval(duration: Duration) -> timeval {
+    let mut value = timeval {
+        tv_sec: duration.as_secs().try_into().unwrap_or(libc::time_t::MAX),
+        tv_usec: duration.subsec_micros() as libc::suseconds_t,
+    };
+    // Durations below a microsecond would disable the timeout.
+    if value.tv_sec == 0 && value.tv_usec == 0 {
+        value.tv_usec = 1;
+    }
+    value
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::Shutdown,
+        num::NonZeroU32,
+        thread,
+        time::Duration,
+    };
+
+    use crate::{
+        errors::{VsockConnectionError, VsockCreationError, VsockListenerBindError},
+        Vsock, VsockAddress, VsockCid, VsockListener, VsockStream, VsockType,
+    };
+    use wie_common::stream::SplitStream;
+
+    /// Binds listener to any CID and a free port, returns [`None`] when vsock or the socket type is not supported.
+    fn listen_on_any_port_with_type(ty: VsockType) -> Option<(VsockListener, u32)> {
+        let address = VsockAddress {
+            cid: VsockCid::any(),
+            port: VsockAddress::PORT_ANY,
+        };
+        let listener = match VsockListener::bind_with_type(address, NonZeroU32::new(1).unwrap(), ty)
+        {
+            Ok(listener) => listener,
+            Err(VsockListenerBindError::Creation(
+                VsockCreationError::Unsupported | VsockCreationError::UnsupportedType,
+            )) => return None,
+            Err(e) => panic!("{e}"),
+        };
+
+        let port = listener.local_addr().unwrap().port;
+        assert_ne!(VsockAddress::PORT_ANY, port);
+        Some((listener, port))
+    }
+
+    fn listen_on_any_port() -> Option<(VsockListener, u32)> {
+        listen_on_any_port_with_type(VsockType::Stream)
+    }
+
+    /// Returns true when the loopback transport is not available.
+    fn is_loopback_unavailable(result: &Result<VsockStream, VsockConnectionError>) -> bool {
+        matches!(
+            result,
+            Err(VsockConnectionError::Unreachable(_) | VsockConnectionError::TimedOut(_))
+        )
+    }
+
+    /// Requires vsock loopback transport, run with `cargo test -p wie-transport-vsock -- --ignored`.
+    #[test]
+    #[ignore]
+    fn connect_loopback() {
+        let (listener, port) = listen_on_any_port().expect("vsock is not supported");
+
+        let server = thread::spawn(move || {
+            let (mut stream, _) = listener.accept(None)?;
+            let mut buffer = [0u8; 4];
+            stream.read_exact(&mut buffer)?;
+            stream.write_all(&buffer.map(|x| x + 1))
+        });
+
+        let result = VsockStream::connect_timeout(
+            VsockAddress {
+                cid: VsockCid::loopback(),
+                port,
+            },
+            Duration::from_millis(500),
+        );
+        assert!(
+            !is_loopback_unavailable(&result),
+            "vsock loopback is not available"
+        );
+
+        let mut stream = result.unwrap();
+        assert_eq!(port, stream.peer_addr().unwrap().port);
+        stream.write_all(&[1, 2, 3, 4]).unwrap();
+        let mut buffer = [0u8; 4];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!([2, 3, 4, 5], buffer);
+        server.join().unwrap().unwrap();
+
+        stream.shutdown(Shutdown::Write).unwrap();
+        assert_eq!(0, stream.read(&mut buffer).unwrap());
+    }
+
+    /// Requires vsock loopback transport, run with `cargo test -p wie-transport-vsock -- --ignored`.
+    #[test]
+    #[ignore]
+    fn connect_loopback_refused() {
+        let (listener, port) = listen_on_any_port().expect("vsock is not supported");
+        // Port is released, so nothing listens on it.
+        drop(listener);
+
+        let result = VsockStream::connect_timeout(
+            VsockAddress {
+                cid: VsockCid::loopback(),
+                port,
+            },
+            Duration::from_millis(500),
+        );
+        assert!(
+            !is_loopback_unavailable(&result),
+            "vsock loopback is not available"
+        );
+
+        assert!(
+            matches!(result, Err(VsockConnectionError::Refused(address)) if address.port == port),
+            "{result:?}"
+        );
+    }
+
+    /// Requires vsock loopback transport with seqpacket support, run with
+    /// `cargo test -p wie-transport-vsock -- --ignored`.
+    #[test]
+    #[ignore]
+    fn seqpacket_loopback() {
+        let (listener, port) = listen_on_any_port_with_type(VsockType::SeqPacket)
+            .expect("vsock seqpacket is not supported");
+
+        let server = thread::spawn(move || {
+            let (stream, _) = listener.accept(None)?;
+            assert_eq!(VsockType::SeqPacket, stream.socket_type());
+            let (_, mut write, _) = stream.split();
+            write.write_all(&[1, 2, 3])?;
+            write.write_all(&[4, 5])
+        });
+
+        let address = VsockAddress {
+            cid: VsockCid::loopback(),
+            port,
+        };
+        let result = VsockStream::connect_with_type(address, VsockType::SeqPacket);
+        assert!(
+            !is_loopback_unavailable(&result),
+            "vsock loopback is not available"
+        );
+
+        let stream = result.unwrap();
+        assert!(stream.is_message_based());
+        server.join().unwrap().unwrap();
+
+        // Every read returns a single message, even when more data is available.
+        let (mut read, _, _) = stream.split();
+        let mut buffer = [0u8; 16];
+        assert_eq!(3, read.read(&mut buffer).unwrap());
+        assert_eq!(2, read.read(&mut buffer).unwrap());
+
+        // Listener accepts only connections of its own type.
+        assert!(matches!(
+            VsockStream::connect_with_type(address, VsockType::Stream),
+            Err(VsockConnectionError::Refused(_))
+        ));
+    }
+
+    #[test]
+    fn socket_options() {
+        let socket = match Vsock::new(VsockType::Stream) {
+            Ok(socket) => socket,
+            Err(VsockCreationError::Unsupported) => {
+                eprintln!("skipped, vsock is not supported");
+                return;
+            }
+            Err(e) => panic!("{e}"),
+        };
+        let stream = VsockStream { socket };
+
+        assert_eq!(None, stream.read_timeout().unwrap());
+        stream
+            .set_read_timeout(Some(Duration::from_millis(1500)))
+            .unwrap();
+        assert_eq!(
+            Some(Duration::from_millis(1500)),
+            stream.read_timeout().unwrap()
+        );
+        stream.set_read_timeout(None).unwrap();
+        assert_eq!(None, stream.read_timeout().unwrap());
+
+        stream
+            .set_write_timeout(Some(Duration::from_secs(3)))
+            .unwrap();
+        assert_eq!(
+            Some(Duration::from_secs(3)),
+            stream.write_timeout().unwrap()
+        );
+        assert_eq!(
+            io::ErrorKind::InvalidInput,
+            stream
+                .set_write_timeout(Some(Duration::ZERO))
+                .unwrap_err()
+                .kind()
+        );
+
+        stream.set_recv_buffer_size(64 * 1024).unwrap();
+        assert_eq!(64 * 1024, stream.recv_buffer_size().unwrap());
+        stream.set_send_buffer_size(64 * 1024).unwrap();
+        assert!(stream.send_buffer_size().unwrap() >= 64 * 1024);
+
+        // Clone shares options with the original socket.
+        let clone = stream.try_clone().unwrap();
+        assert_eq!(Some(Duration::from_secs(3)), clone.write_timeout().unwrap());
+
+        stream.set_nonblocking(true).unwrap();
+        stream.set_nonblocking(false).unwrap();
+    }
+
+    #[test]
+    fn local_cid() {
+        match VsockCid::local() {
+            Ok(cid) => assert_ne!(VsockCid::any(), cid),
+            Err(e) if e.kind() == io::ErrorKind::NotFound => eprintln!("skipped, {e}"),
+            Err(e) => panic!("{e}"),
+        }
+    }
+
+    #[test]
+    fn from_fd_reads_socket_type() {
+        let new_fd = |ty| {
+            let fd = unsafe { libc::socket(libc::AF_UNIX, ty | libc::SOCK_CLOEXEC, 0) };
+            assert!(fd >= 0, "{}", io::Error::last_os_error());
+            fd
+        };
+
+        let listener = unsafe { VsockListener::from_fd(new_fd(libc::SOCK_SEQPACKET)) }.unwrap();
+        assert_eq!(VsockType::SeqPacket, listener.socket.ty);
+        let listener = unsafe { VsockListener::from_fd(new_fd(libc::SOCK_STREAM)) }.unwrap();
+        assert_eq!(VsockType::Stream, listener.socket.ty);
+
+        let fd = new_fd(libc::SOCK_DGRAM);
+        let error = unsafe { VsockListener::from_fd(fd) }.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        // Socket is still owned by the caller after the error.
+        assert_eq!(0, unsafe { libc::close(fd) });
+    }
+}
@@ -1,6 +1,6 @@
 //! Implementation based on [github.com](https://gist.github.com/tuxxi/85c03d6593d1f121aa439c0a007f1475) - [archive](https://web.archive.org/web/20240518093847/https://gist.github.com/tuxxi/85c03d6593d1f121aa439c0a007f1475)
 
-use std::{ffi::c_void, mem, num::NonZeroU32};
+use std::{ffi::c_void, mem, net::Shutdown, num::NonZeroU32};
 
 use windows::{
     core::{w, PCWSTR},
@@ -18,13 +18,17 @@ use crate::{
     errors::{
         VsockConnectionError, VsockCreationError, VsockCreationWindowsError, VsockListenerBindError,
     },
-    Vsock, VsockAddress, VsockCid,
+    Vsock, VsockAddress, VsockType,
 };
 
 const VIOSOCK_NAME: PCWSTR = w!("\\??\\Viosock");
 const IOCTL_GET_AF: u32 = 0x0801300C;
 
-pub(crate) fn new_socket() -> Result<Vsock, VsockCreationError> {
+pub(crate) fn new_socket(ty: VsockType) -> Result<Vsock, VsockCreationError> {
+    if ty != VsockType::Stream {
+        return Err(VsockCreationError::UnsupportedType);
+    }
+
     let mut wsa_data = WSADATA::default();
     let i_res = unsafe { WinSock::WSAStartup((2 << 8) | 2, &mut wsa_data as *mut WSADATA) };
 
@@ -35,28 +39,31 @@ pub(crate) fn new_socket() -> Result<Vsock, VsockCreationError> {
     let af = viosock_get_af()?;
     let socket = unsafe { WinSock::socket(af.0 as i32, SOCK_STREAM, 0) };
 
-    Ok(Vsock { inner: socket })
+    Ok(Vsock { inner: socket, ty })
 }
 
-pub(crate) fn bind(socket: &mut Vsock, port: u32) -> Result<(), VsockListenerBindError> {
-    let address = sockaddr_vm {
+pub(crate) fn bind(
+    socket: &mut Vsock,
+    address: VsockAddress,
+) -> Result<(), VsockListenerBindError> {
+    let address_vm = sockaddr_vm {
         svm_family: ADDRESS_FAMILY(40), // AF_VSOCK
         svm_reserved1: 0,               // Unused
-        svm_port: port,
-        svm_cid: VsockCid::host().0,
+        svm_port: address.port,
+        svm_cid: address.cid.0,
     };
 
     let result = unsafe {
         WinSock::bind(
             socket.inner,
-            &address as *const sockaddr_vm as *const SOCKADDR,
+            &address_vm as *const sockaddr_vm as *const SOCKADDR,
             mem::size_of::<sockaddr_vm>() as i32,
         )
     };
 
     match result >= 0 {
         true => Ok(()),
-        false => Err(VsockListenerBindError::Bind(port)),
+        false => Err(VsockListenerBindError::Bind(address.port)),
     }
 }
 
@@ -95,7 +102,10 @@ pub(crate) fn connect(
 
     match result >= 0 {
         true => Ok(()),
-        false => Err(VsockConnectionError::Connection(address)),
+        false => Err(VsockConnectionError::from_os_error(
+            address,
+            std::io::Error::last_os_error(),
+        )),
     }
 }
 
@@ -107,6 +117,15 @@ pub(crate) fn send(socket: &Vsock, buffer: &[u8]) -> isize {
     (unsafe { WinSock::send(socket.inner, buffer, SEND_RECV_FLAGS(0)) }) as isize
 }
 
+pub(crate) fn shutdown(socket: &Vsock, how: Shutdown) -> i32 {
+    let how = match how {
+        Shutdown::Read => WinSock::SD_RECEIVE,
+        Shutdown::Write => WinSock::SD_SEND,
+        Shutdown::Both => WinSock::SD_BOTH,
+    };
+    unsafe { WinSock::shutdown(socket.inner, how) }
+}
+
 pub(crate) fn close(socket: &mut Vsock) {
     _ = unsafe { WinSock::closesocket(socket.inner) };
 }
@@ -0,0 +1,159 @@
+use std::{fmt, str::FromStr};
+
+#[cfg(unix)]
+use std::path::PathBuf;
+
+use wie_transport_vsock::{VsockAddress, VsockCid};
+
+use crate::errors::EndpointParseError;
+
+/// Address of the host, written as URL: `vsock://2:13001`, `tcp://10.0.0.5:13001` or `unix:///run/wie.sock`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Endpoint {
+    Vsock(VsockAddress),
+    /// Host name or IP address with port, resolved when connecting.
+    Tcp(String),
+    #[cfg(unix)]
+    Unix(PathBuf),
+}
+
+impl Endpoint {
+    /// Parses comma separated list of endpoints, which are tried in order.
+    pub fn parse_list(s: &str) -> Result<Vec<Self>, EndpointParseError> {
+        let endpoints = s
+            .split(',')
+            .map(str::trim)
+            .filter(|x| !x.is_empty())
+            .map(Self::from_str)
+            .collect::<Result<Vec<_>, _>>()?;
+        match endpoints.is_empty() {
+            true => Err(EndpointParseError::Empty),
+            false => Ok(endpoints),
+        }
+    }
+
+    /// Vsock endpoint of the host on `port`.
+    pub fn host(port: u32) -> Self {
+        Self::Vsock(VsockAddress {
+            cid: VsockCid::host(),
+            port,
+        })
+    }
+}
+
+/// Address of seqpacket streams of the vsock address. They cannot share the port with stream ones, so they use the next
+/// port.
+pub fn seqpacket_address(address: VsockAddress) -> Result<VsockAddress, EndpointParseError> {
+    let port = address
+        .port
+        .checked_add(1)
+        .ok_or(EndpointParseError::SeqpacketPort(address.port))?;
+    Ok(VsockAddress {
+        cid: address.cid,
+        port,
+    })
+}
+
+impl fmt::Display for Endpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Endpoint::Vsock(address) => write!(f, "{}", address),
+            Endpoint::Tcp(address) => write!(f, "tcp://{}", address),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => write!(f, "unix://{}", path.display()),
+        }
+    }
+}
+
+impl FromStr for Endpoint {
+    type Err = EndpointParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let Some((scheme, address)) = s.split_once("://") else {
+            return Err(EndpointParseError::Scheme(s.to_owned()));
+        };
+
+        match scheme {
+            "vsock" => Ok(Self::Vsock(s.parse()?)),
+            "tcp" => match address.rsplit_once(':') {
+                Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => {
+                    Ok(Self::Tcp(address.to_owned()))
+                }
+                _ => Err(EndpointParseError::Tcp(address.to_owned())),
+            },
+            #[cfg(unix)]
+            "unix" => match address.is_empty() {
+                true => Err(EndpointParseError::Unix),
+                false => Ok(Self::Unix(PathBuf::from(address))),
+            },
+            _ => Err(EndpointParseError::Scheme(s.to_owned())),
+        }
+    }
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use rstest::rstest;
+    use wie_transport_vsock::{errors::VsockAddressParseError, VsockAddress, VsockCid};
+
+    use super::Endpoint;
+    use crate::errors::EndpointParseError;
+
+    #[rstest]
+    #[case("vsock://2:13001", Endpoint::host(13001))]
+    #[case("vsock://host:13001", Endpoint::host(13001))]
+    #[case("vsock://3:1", Endpoint::Vsock(VsockAddress { cid: VsockCid(3), port: 1 }))]
+    #[case("tcp://10.0.0.5:13001", Endpoint::Tcp("10.0.0.5:13001".to_owned()))]
+    #[case("tcp://localhost:13001", Endpoint::Tcp("localhost:13001".to_owned()))]
+    #[case("tcp://[::1]:13001", Endpoint::Tcp("[::1]:13001".to_owned()))]
+    fn parse(#[case] input: &str, #[case] expected: Endpoint) {
+        let endpoint = input.parse::<Endpoint>().unwrap();
+        assert_eq!(expected, endpoint);
+        assert_eq!(expected, endpoint.to_string().parse().unwrap());
+    }
+
+    #[rstest]
+    #[case("2:13001", EndpointParseError::Scheme("2:13001".to_owned()))]
+    #[case("http://host:80", EndpointParseError::Scheme("http://host:80".to_owned()))]
+    #[case("vsock://2", VsockAddressParseError::Format.into())]
+    #[case("tcp://10.0.0.5", EndpointParseError::Tcp("10.0.0.5".to_owned()))]
+    #[case("tcp://:13001", EndpointParseError::Tcp(":13001".to_owned()))]
+    #[case("tcp://host:port", EndpointParseError::Tcp("host:port".to_owned()))]
+    fn parse_invalid(#[case] input: &str, #[case] expected: EndpointParseError) {
+        assert_eq!(Err(expected), input.parse::<Endpoint>());
+    }
+
+    #[test]
+    fn seqpacket_address() {
+        let address = |port| VsockAddress {
+            cid: VsockCid::host(),
+            port,
+        };
+        assert_eq!(Ok(address(13002)), super::seqpacket_address(address(13001)));
+        assert_eq!(
+            Err(EndpointParseError::SeqpacketPort(u32::MAX)),
+            super::seqpacket_address(address(u32::MAX))
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn parse_unix() {
+        let endpoint = "unix:///run/wie.sock".parse::<Endpoint>().unwrap();
+        assert_eq!(Endpoint::Unix("/run/wie.sock".into()), endpoint);
+        assert_eq!("unix:///run/wie.sock", endpoint.to_string());
+        assert_eq!(Err(EndpointParseError::Unix), "unix://".parse::<Endpoint>());
+    }
+
+    #[test]
+    fn parse_list() {
+        assert_eq!(
+            Ok(vec![
+                Endpoint::host(13001),
+                Endpoint::Tcp("10.0.0.5:13001".to_owned())
+            ]),
+            Endpoint::parse_list("vsock://2:13001, tcp://10.0.0.5:13001,")
+        );
+        assert_eq!(Err(EndpointParseError::Empty), Endpoint::parse_list(" , "));
+    }
+}
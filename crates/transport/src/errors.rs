@@ -0,0 +1,41 @@
+use thiserror::Error;
+use wie_transport_vsock::errors::VsockAddressParseError;
+
+#[derive(Error, Debug)]
+#[error("connection is closed")]
+pub struct ConnectionClosedError;
+
+/// Payload of the panic raised when a packet is read beyond its end, or it misses data which the reader expects. The
+/// connection which received it is closed, as its peer is broken or hostile.
+#[derive(Error, Debug)]
+#[error("packet is malformed")]
+pub struct MalformedPacket;
+
+/// Data received from peer which breaks the protocol, the connection which received it is closed.
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum ProtocolError {
+    #[error("unknown packet priority {0}")]
+    Priority(u8),
+    #[error("unknown packet destination {0}")]
+    Destination(u32),
+    #[error("response is addressed to thread 0")]
+    ThreadId,
+    #[error("packet length {0} is out of range")]
+    Length(usize),
+}
+
+#[derive(Error, Debug, PartialEq, Eq)]
+pub enum EndpointParseError {
+    #[error("endpoint list is empty")]
+    Empty,
+    #[error("unsupported endpoint scheme in `{0}`, expected `vsock://`, `tcp://` or `unix://`")]
+    Scheme(String),
+    #[error("invalid vsock endpoint: {0}")]
+    Vsock(#[from] VsockAddressParseError),
+    #[error("TCP endpoint must be in `tcp://host:port` format, got `{0}`")]
+    Tcp(String),
+    #[error("Unix endpoint must contain a socket path")]
+    Unix,
+    #[error("vsock port {0} is the last one, seqpacket streams need the next port")]
+    SeqpacketPort(u32),
+}
@@ -0,0 +1,45 @@
+use std::mem;
+
+use crate::{
+    errors::ProtocolError,
+    packet::{Priority, MAX_PACKET_LENGTH},
+};
+
+pub(crate) const FRAME_HEADER_SIZE: usize = 8;
+
+/// Header written before every fragment of a packet.
+///
+/// Fragments of packets with different priorities can be interleaved on the stream, but fragments within one lane are
+/// always written in order, so the receiver reassembles packets per lane.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct FrameHeader {
+    pub length: u32,
+    pub priority: Priority,
+}
+
+impl FrameHeader {
+    #[inline]
+    pub fn encode(&self) -> [u8; FRAME_HEADER_SIZE] {
+        let mut bytes = [0u8; FRAME_HEADER_SIZE];
+        bytes[..mem::size_of::<u32>()].copy_from_slice(&self.length.to_ne_bytes());
+        bytes[mem::size_of::<u32>()] = self.priority as u8;
+        bytes
+    }
+
+    /// Decodes header received from peer, which is not trusted.
+    #[inline]
+    pub fn decode(bytes: &[u8; FRAME_HEADER_SIZE]) -> Result<Self, ProtocolError> {
+        let mut length = [0u8; mem::size_of::<u32>()];
+        length.copy_from_slice(&bytes[..mem::size_of::<u32>()]);
+        let length = u32::from_ne_bytes(length);
+        if length as usize > MAX_PACKET_LENGTH {
+            return Err(ProtocolError::Length(length as usize));
+        }
+
+        let priority = bytes[mem::size_of::<u32>()];
+        Ok(Self {
+            length,
+            priority: Priority::from_lane(priority).ok_or(ProtocolError::Priority(priority))?,
+        })
+    }
+}
@@ -0,0 +1,457 @@
+//! Handshake which groups multiple streams into one [`Connection`](crate::Connection).
+//!
+//! Client opens all streams of the connection and writes a handshake to each of them. Server reads handshakes from
+//! accepted streams with [`read_handshake`] and collects them by connection id, until every stream of the connection
+//! arrives. Streams of different clients can be accepted in any order, connections which do not get all of their
+//! streams within [`HANDSHAKE_TIMEOUT`] are dropped.
+//!
+//! Handshake also carries session token of the previous connection, when client reconnects. Server answers on the first
+//! stream with the token of the session, whether the previous session was resumed and how many packets it received
+//! through the previous connection, so client finds out whether any of its packets were lost.
+
+use std::{
+    collections::{hash_map::RandomState, HashMap},
+    hash::{BuildHasher, Hasher},
+    io::{self, Read, Write},
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+const MAGIC: u32 = u32::from_le_bytes(*b"WIE\0");
+const VERSION: u32 = 4;
+const HANDSHAKE_SIZE: usize = 32;
+const REPLY_SIZE: usize = 24;
+const REPLY_RESUMED: u32 = 1;
+
+/// Maximum number of streams which can be used by a single connection.
+pub const MAX_STREAMS: usize = 64;
+/// Time in which server expects the handshake of a stream, and the remaining streams of its connection.
+pub const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+struct Handshake {
+    connection_id: u64,
+    stream_index: u32,
+    stream_count: u32,
+    /// Zero when client does not have a session yet.
+    session_token: u64,
+}
+
+/// Session assigned by the server to the connection.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Session {
+    /// Token which client passes on reconnect to resume the session.
+    pub token: u64,
+    /// True when the session requested by client was resumed, false when it is a new one.
+    pub resumed: bool,
+    /// Number of packets received through the previous connection of the resumed session, see
+    /// [`Connection::received_packets`](crate::Connection::received_packets).
+    pub received_packets: u64,
+}
+
+impl Session {
+    /// Creates a new session with a random token.
+    pub fn new() -> Self {
+        Self {
+            token: random_id(),
+            resumed: false,
+            received_packets: 0,
+        }
+    }
+
+    fn encode(&self) -> [u8; REPLY_SIZE] {
+        let mut bytes = [0u8; REPLY_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        let flags = match self.resumed {
+            true => REPLY_RESUMED,
+            false => 0,
+        };
+        bytes[4..8].copy_from_slice(&flags.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.token.to_le_bytes());
+        bytes[16..24].copy_from_slice(&self.received_packets.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; REPLY_SIZE]) -> io::Result<Self> {
+        if u32::from_le_bytes(bytes[0..4].try_into().unwrap()) != MAGIC {
+            return Err(invalid_data("handshake reply does not start with magic"));
+        }
+
+        let token = u64::from_le_bytes(bytes[8..16].try_into().unwrap());
+        if token == 0 {
+            return Err(invalid_data("handshake reply contains empty session token"));
+        }
+
+        Ok(Self {
+            token,
+            resumed: u32::from_le_bytes(bytes[4..8].try_into().unwrap()) & REPLY_RESUMED != 0,
+            received_packets: u64::from_le_bytes(bytes[16..24].try_into().unwrap()),
+        })
+    }
+}
+
+impl Default for Session {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Handshake {
+    fn encode(&self) -> [u8; HANDSHAKE_SIZE] {
+        let mut bytes = [0u8; HANDSHAKE_SIZE];
+        bytes[0..4].copy_from_slice(&MAGIC.to_le_bytes());
+        bytes[4..8].copy_from_slice(&VERSION.to_le_bytes());
+        bytes[8..16].copy_from_slice(&self.connection_id.to_le_bytes());
+        bytes[16..20].copy_from_slice(&self.stream_index.to_le_bytes());
+        bytes[20..24].copy_from_slice(&self.stream_count.to_le_bytes());
+        bytes[24..32].copy_from_slice(&self.session_token.to_le_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8; HANDSHAKE_SIZE]) -> io::Result<Self> {
+        let u32_at = |i: usize| u32::from_le_bytes(bytes[i..i + 4].try_into().unwrap());
+
+        if u32_at(0) != MAGIC {
+            return Err(invalid_data("stream does not start with wie handshake"));
+        }
+        if u32_at(4) != VERSION {
+            return Err(invalid_data("unsupported handshake version"));
+        }
+
+        let handshake = Self {
+            connection_id: u64::from_le_bytes(bytes[8..16].try_into().unwrap()),
+            stream_index: u32_at(16),
+            stream_count: u32_at(20),
+            session_token: u64::from_le_bytes(bytes[24..32].try_into().unwrap()),
+        };
+
+        if handshake.stream_count == 0
+            || handshake.stream_count as usize > MAX_STREAMS
+            || handshake.stream_index >= handshake.stream_count
+        {
+            return Err(invalid_data(
+                "handshake contains invalid stream index or count",
+            ));
+        }
+        Ok(handshake)
+    }
+}
+
+/// Opens `count` streams with `connect`, writes handshake to each of them and waits for the server reply.
+///
+/// `session_token` is the token of the session to resume, when client reconnects. Returned streams are ordered by their
+/// index and ready to be passed to [`Connection::with_streams`](crate::Connection::with_streams).
+pub fn connect<T, F>(
+    count: usize,
+    session_token: Option<u64>,
+    mut connect: F,
+) -> io::Result<(Vec<T>, Session)>
+where
+    T: Read + Write,
+    F: FnMut() -> io::Result<T>,
+{
+    if count == 0 || count > MAX_STREAMS {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("stream count must be in range 1..={MAX_STREAMS}"),
+        ));
+    }
+
+    let connection_id = random_id();
+    let mut streams = Vec::with_capacity(count);
+    for stream_index in 0..count {
+        let mut stream = connect()?;
+        stream.write_all(
+            &Handshake {
+                connection_id,
+                stream_index: stream_index as u32,
+                stream_count: count as u32,
+                session_token: session_token.unwrap_or(0),
+            }
+            .encode(),
+        )?;
+        stream.flush()?;
+        streams.push(stream);
+    }
+
+    let mut bytes = [0u8; REPLY_SIZE];
+    streams[0].read_exact(&mut bytes)?;
+    Ok((streams, Session::decode(&bytes)?))
+}
+
+/// Accepted stream with its handshake, which was not collected into its connection yet.
+pub struct HandshakeStream<T> {
+    stream: T,
+    handshake: Handshake,
+}
+
+impl<T> HandshakeStream<T> {
+    #[inline]
+    pub fn stream(&self) -> &T {
+        &self.stream
+    }
+}
+
+/// Reads handshake from the accepted stream.
+///
+/// It blocks until the whole handshake arrives, so server should read it with a read timeout and without holding the
+/// [`Acceptor`] shared by other streams.
+pub fn read_handshake<T>(mut stream: T) -> io::Result<HandshakeStream<T>>
+where
+    T: Read,
+{
+    let mut bytes = [0u8; HANDSHAKE_SIZE];
+    stream.read_exact(&mut bytes)?;
+    Ok(HandshakeStream {
+        stream,
+        handshake: Handshake::decode(&bytes)?,
+    })
+}
+
+/// Collects accepted streams into connections.
+pub struct Acceptor<T> {
+    pending: HashMap<u64, PendingStreams<T>>,
+    timeout: Duration,
+}
+
+struct PendingStreams<T> {
+    streams: Vec<Option<T>>,
+    session_token: u64,
+    created: Instant,
+}
+
+impl<T> Default for Acceptor<T> {
+    fn default() -> Self {
+        Self::with_timeout(HANDSHAKE_TIMEOUT)
+    }
+}
+
+impl<T> Acceptor<T> {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Creates acceptor which drops connections, when their remaining streams do not arrive within the timeout.
+    pub fn with_timeout(timeout: Duration) -> Self {
+        Self {
+            pending: HashMap::new(),
+            timeout,
+        }
+    }
+
+    /// Collects the stream into its connection.
+    ///
+    /// Returns the connection, when the stream was the last missing one.
+    pub fn accept(
+        &mut self,
+        stream: HandshakeStream<T>,
+    ) -> io::Result<Option<PendingConnection<T>>> {
+        self.expire();
+
+        let HandshakeStream { stream, handshake } = stream;
+        let pending = self
+            .pending
+            .entry(handshake.connection_id)
+            .or_insert_with(|| PendingStreams {
+                streams: (0..handshake.stream_count).map(|_| None).collect(),
+                session_token: handshake.session_token,
+                created: Instant::now(),
+            });
+
+        if pending.streams.len() != handshake.stream_count as usize
+            || pending.session_token != handshake.session_token
+        {
+            return Err(invalid_data(
+                "stream count or session token differs between streams of one connection",
+            ));
+        }
+
+        let slot = &mut pending.streams[handshake.stream_index as usize];
+        if slot.is_some() {
+            return Err(invalid_data("stream index is duplicated"));
+        }
+        *slot = Some(stream);
+
+        if pending.streams.iter().any(|x| x.is_none()) {
+            return Ok(None);
+        }
+
+        let pending = self.pending.remove(&handshake.connection_id).unwrap();
+        Ok(Some(PendingConnection {
+            streams: pending.streams.into_iter().map(|x| x.unwrap()).collect(),
+            session_token: match pending.session_token {
+                0 => None,
+                token => Some(token),
+            },
+        }))
+    }
+
+    /// Drops connections which wait for their remaining streams longer than the timeout.
+    fn expire(&mut self) {
+        let count = self.pending.len();
+        self.pending
+            .retain(|_, pending| pending.created.elapsed() < self.timeout);
+        if self.pending.len() < count {
+            log::warn!(
+                "dropped {} connection(s) which did not open all of their streams in time",
+                count - self.pending.len()
+            );
+        }
+    }
+
+    /// Number of connections which wait for their remaining streams.
+    pub fn pending_count(&self) -> usize {
+        self.pending.len()
+    }
+}
+
+/// Connection with every stream accepted, which waits for the server to assign a session.
+pub struct PendingConnection<T> {
+    streams: Vec<T>,
+    session_token: Option<u64>,
+}
+
+impl<T> PendingConnection<T>
+where
+    T: Write,
+{
+    /// Token of the session which client wants to resume.
+    #[inline]
+    pub fn session_token(&self) -> Option<u64> {
+        self.session_token
+    }
+
+    #[inline]
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Streams of the connection, ordered by their index.
+    #[inline]
+    pub fn streams(&self) -> &[T] {
+        &self.streams
+    }
+
+    /// Sends session to the client and returns streams ordered by their index.
+    pub fn finish(mut self, session: Session) -> io::Result<Vec<T>> {
+        self.streams[0].write_all(&session.encode())?;
+        self.streams[0].flush()?;
+        Ok(self.streams)
+    }
+}
+
+fn random_id() -> u64 {
+    let mut hasher = RandomState::new().build_hasher();
+    hasher.write_u128(
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_nanos(),
+    );
+    hasher.write_u32(std::process::id());
+    // Zero is reserved for the missing session token.
+    hasher.finish().max(1)
+}
+
+fn invalid_data(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message)
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{
+        io::{self, Write},
+        net::{TcpListener, TcpStream},
+        thread,
+        time::Duration,
+    };
+
+    use super::{connect, read_handshake, Acceptor, Handshake, Session};
+
+    fn handshake(
+        listener: &TcpListener,
+        stream_count: usize,
+        session_token: Option<u64>,
+        server: impl FnOnce(Option<u64>) -> Session,
+    ) -> Session {
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            connect(stream_count, session_token, || TcpStream::connect(address)).unwrap()
+        });
+
+        let mut acceptor = Acceptor::new();
+        let pending = loop {
+            let (stream, _) = listener.accept().unwrap();
+            if let Some(pending) = acceptor.accept(read_handshake(stream).unwrap()).unwrap() {
+                break pending;
+            }
+        };
+        assert_eq!(0, acceptor.pending_count());
+        assert_eq!(stream_count, pending.stream_count());
+
+        let session = server(pending.session_token());
+        pending.finish(session).unwrap();
+
+        let (streams, client_session) = client.join().unwrap();
+        assert_eq!(stream_count, streams.len());
+        assert_eq!(session, client_session);
+        client_session
+    }
+
+    #[test]
+    fn session_resumption() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+
+        let session = handshake(&listener, 3, None, |requested| {
+            assert_eq!(None, requested);
+            Session::new()
+        });
+        assert!(!session.resumed);
+
+        let resumed = handshake(&listener, 2, Some(session.token), |requested| {
+            assert_eq!(Some(session.token), requested);
+            Session {
+                token: session.token,
+                resumed: true,
+                received_packets: 42,
+            }
+        });
+        assert!(resumed.resumed);
+        assert_eq!(42, resumed.received_packets);
+    }
+
+    #[test]
+    fn reject_invalid_magic() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        client.write_all(&[0xffu8; super::HANDSHAKE_SIZE]).unwrap();
+
+        let (stream, _) = listener.accept().unwrap();
+        let error = read_handshake(stream).err().unwrap();
+        assert_eq!(io::ErrorKind::InvalidData, error.kind());
+    }
+
+    #[test]
+    fn expire_incomplete_connection() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut acceptor = Acceptor::with_timeout(Duration::from_millis(50));
+        let mut accept = |connection_id: u64| {
+            let mut client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+            let handshake = Handshake {
+                connection_id,
+                stream_index: 0,
+                stream_count: 2,
+                session_token: 0,
+            };
+            client.write_all(&handshake.encode()).unwrap();
+            let (stream, _) = listener.accept().unwrap();
+            acceptor.accept(read_handshake(stream).unwrap()).unwrap();
+            acceptor.pending_count()
+        };
+
+        assert_eq!(1, accept(1));
+        assert_eq!(2, accept(2));
+        thread::sleep(Duration::from_millis(100));
+        // Both connections miss their second stream, only the new one waits.
+        assert_eq!(1, accept(3));
+    }
+}
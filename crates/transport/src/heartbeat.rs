@@ -0,0 +1,84 @@
+//! Keepalive heartbeats of idle connections.
+//!
+//! Side which enables heartbeats sends a heartbeat to every stream, when nothing was received for the interval. Peer
+//! answers them automatically, without any configuration. Any received data counts as a sign of life, so busy
+//! connections do not send heartbeats at all.
+
+use std::{
+    sync::Weak,
+    thread,
+    time::{Duration, Instant},
+};
+
+use wie_common::{stream::SplitStream, utils::env};
+
+use crate::{packet::Destination, Connection};
+
+pub const DEFAULT_MISS_THRESHOLD: u32 = 3;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Heartbeat {
+    /// Time without received data after which heartbeat is sent.
+    pub interval: Duration,
+    /// Number of consecutive unanswered heartbeats after which connection is closed.
+    pub miss_threshold: u32,
+}
+
+impl Heartbeat {
+    /// Reads heartbeat configuration from environment variables.
+    ///
+    /// `WIE_HEARTBEAT_INTERVAL_MS` enables heartbeats when set to non zero value, and
+    /// `WIE_HEARTBEAT_MISS_THRESHOLD` overrides the miss threshold, which defaults to 3.
+    pub fn from_env() -> Option<Self> {
+        let interval = env::parse::<u64>("WIE_HEARTBEAT_INTERVAL_MS").filter(|x| *x != 0)?;
+        Some(Self {
+            interval: Duration::from_millis(interval),
+            miss_threshold: env::parse("WIE_HEARTBEAT_MISS_THRESHOLD")
+                .filter(|x| *x != 0)
+                .unwrap_or(DEFAULT_MISS_THRESHOLD),
+        })
+    }
+
+    /// Time without received data after which connection is closed.
+    #[inline]
+    pub fn timeout(&self) -> Duration {
+        self.interval * (self.miss_threshold + 1)
+    }
+}
+
+pub(crate) fn worker<T>(weak: Weak<Connection<T>>, heartbeat: Heartbeat)
+where
+    T: SplitStream,
+{
+    let mut next = Instant::now() + heartbeat.interval;
+    loop {
+        thread::sleep(next.saturating_duration_since(Instant::now()));
+        next += heartbeat.interval;
+
+        let Some(connection) = weak.upgrade() else {
+            return;
+        };
+        if connection.is_closed() {
+            return;
+        }
+
+        let idle = connection.idle_time();
+        if idle < heartbeat.interval {
+            continue;
+        }
+
+        if idle >= heartbeat.timeout() {
+            log::warn!(
+                "peer missed {} heartbeat(s), nothing received for {:.2?}",
+                heartbeat.miss_threshold,
+                idle
+            );
+            connection.close();
+            return;
+        }
+
+        for index in 0..connection.stream_count() {
+            connection.send_control(index, Destination::Heartbeat);
+        }
+    }
+}
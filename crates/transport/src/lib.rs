@@ -1,141 +1,327 @@
 use std::{
-    collections::HashMap,
-    fmt, mem,
+    collections::{hash_map::Entry, HashMap, VecDeque},
+    fmt,
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    mem,
+    panic::{self, AssertUnwindSafe},
     sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
         mpsc::{self, Sender},
         Arc, Mutex, Weak,
     },
     thread::{self, ThreadId},
+    time::{Duration, Instant},
 };
 
 use aligned_vec::AVec;
+use errors::{ConnectionClosedError, MalformedPacket, ProtocolError};
+use frame::{FrameHeader, FRAME_HEADER_SIZE};
+use heartbeat::Heartbeat;
 use lockfree::{map::Map, queue::Queue, stack::Stack};
-use packet::{Destination, Packet, PacketHeader, PacketWriter};
+use packet::{Destination, DetachedPacket, Packet, PacketHeader, PacketWriter, Priority};
 use rsevents::{AutoResetEvent, Awaitable};
 use unsafe_receiver::UnsafeReceiver;
-use wie_common::stream::{UnsafeRead, UnsafeWrite};
+use wie_common::stream::{SplitStream, StreamShutdown};
 
+pub mod endpoint;
+pub mod errors;
+mod frame;
+pub mod handshake;
+pub mod heartbeat;
 pub mod packet;
+pub mod stream;
 mod unsafe_receiver;
 
 const DEFAULT_MAX_ALIGNMENT: usize = 16;
 const DEFAULT_PART_SIZE: usize = 4096;
 
+static NEXT_ID: AtomicU64 = AtomicU64::new(1);
+
 pub struct Connection<T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
-    stream: T,
+    id: u64,
+    streams: Vec<StreamSlot<T>>,
+    part_size: usize,
     buffer_pool: Stack<AVec<u8>>,
-    write_queue: Queue<AVec<u8>>,
-    write_mutex: Mutex<()>,
     thread_channels: Map<u64, ThreadChannel>,
-    write_reset_event: AutoResetEvent,
     handlers: HashMap<u64, Handler<T>>,
+    /// Packets to handlers which wait for earlier packets of the same peer thread, keyed by the thread. Thread has an
+    /// entry while one of its packets is handled.
+    handler_queues: Mutex<HashMap<u64, VecDeque<AVec<u8>>>>,
+    closed: AtomicBool,
+    close_callbacks: Mutex<Vec<CloseCallback>>,
+    created: Instant,
+    /// Milliseconds since `created` of the last read from any stream.
+    last_received: AtomicU64,
+    sent_packets: AtomicU64,
+    received_packets: AtomicU64,
 }
 
 pub type Handler<T> = Box<dyn Fn(Packet<T>) + Send + Sync>;
+type CloseCallback = Box<dyn FnOnce() + Send>;
 
 impl<T> Connection<T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
+    /// Creates a new connection over the stream.
+    ///
+    /// Part size is the maximum size of a single fragment written to the stream, packets bigger than it are split,
+    /// and fragments of higher priority packets are interleaved between them.
     pub fn new(
         stream: T,
         handlers: HashMap<u64, Handler<T>>,
         part_size: Option<usize>,
     ) -> Arc<Self> {
+        Self::with_streams(vec![stream], handlers, part_size)
+    }
+
+    /// Creates a new connection striped over multiple streams, every stream is split and its halves are owned by its
+    /// own write and receive thread.
+    ///
+    /// Both sides must pass streams in the same order, see [`handshake`] module. Message based streams receive every
+    /// fragment with a single read, so both sides must use the same part size for them.
+    pub fn with_streams(
+        streams: Vec<T>,
+        handlers: HashMap<u64, Handler<T>>,
+        part_size: Option<usize>,
+    ) -> Arc<Self> {
+        assert!(
+            !streams.is_empty(),
+            "connection requires at least one stream"
+        );
         let part_size = part_size.unwrap_or(DEFAULT_PART_SIZE);
+        assert!(part_size > 0, "part size must be greater than zero");
+
+        let mut read_halves = Vec::with_capacity(streams.len());
+        let slots = streams
+            .into_iter()
+            .map(|stream| {
+                let message_based = stream.is_message_based();
+                let (read, write, shutdown) = stream.split();
+                read_halves.push((read, message_based));
+                StreamSlot::new(write, shutdown, message_based)
+            })
+            .collect();
 
         let connection = Arc::new(Self {
-            stream,
+            id: NEXT_ID.fetch_add(1, Ordering::Relaxed),
+            streams: slots,
+            part_size,
             buffer_pool: Stack::new(),
-            write_queue: Queue::new(),
-            write_mutex: Mutex::new(()),
             thread_channels: Map::new(),
-            write_reset_event: AutoResetEvent::new(rsevents::EventState::Unset),
             handlers,
+            handler_queues: Mutex::new(HashMap::new()),
+            closed: AtomicBool::new(false),
+            close_callbacks: Mutex::new(Vec::new()),
+            created: Instant::now(),
+            last_received: AtomicU64::new(0),
+            sent_packets: AtomicU64::new(0),
+            received_packets: AtomicU64::new(0),
         });
 
-        // Create write thread
-        let weak = Arc::downgrade(&connection);
-        thread::spawn(move || write_worker(weak));
+        for (index, (read, message_based)) in read_halves.into_iter().enumerate() {
+            // Create write thread
+            let weak = Arc::downgrade(&connection);
+            thread::spawn(move || write_worker(weak, index));
 
-        // Create receive thread
-        let weak = Arc::downgrade(&connection);
-        thread::spawn(move || receive_worker(weak, part_size));
+            // Create receive thread
+            let weak = Arc::downgrade(&connection);
+            thread::spawn(move || match message_based {
+                true => receive_message_worker(weak, index, read),
+                false => receive_worker(weak, index, read),
+            });
+        }
 
         connection
     }
 
+    /// Unique id of the connection within the process.
     #[inline]
-    pub fn new_packet(&self, destination: u64) -> PacketWriter<T> {
+    pub fn id(&self) -> u64 {
+        self.id
+    }
+
+    #[inline]
+    pub fn stream_count(&self) -> usize {
+        self.streams.len()
+    }
+
+    /// Starts sending heartbeats when the connection is idle, and closes it when peer stops answering them.
+    pub fn start_heartbeat(self: &Arc<Self>, heartbeat: Heartbeat) {
+        assert!(
+            !heartbeat.interval.is_zero() && heartbeat.miss_threshold > 0,
+            "heartbeat interval and miss threshold must be greater than zero"
+        );
+
+        let weak = Arc::downgrade(self);
+        thread::spawn(move || heartbeat::worker(weak, heartbeat));
+    }
+
+    #[inline]
+    pub fn is_closed(&self) -> bool {
+        self.closed.load(Ordering::SeqCst)
+    }
+
+    /// Closes the connection, shuts down its streams, wakes threads which wait for response and calls close
+    /// callbacks. Packets sent after it are dropped.
+    ///
+    /// It is called automatically when stream fails or peer misses heartbeats, calling it again does nothing.
+    pub fn close(&self) {
+        if self.closed.swap(true, Ordering::SeqCst) {
+            return;
+        }
+        log::info!("closing connection");
+
+        for slot in &self.streams {
+            if let Err(e) = slot.shutdown.shutdown() {
+                log::warn!("failed to shut down stream: {e}");
+            }
+            slot.notify_write_thread();
+        }
+
+        // Empty buffer tells the waiting thread, that response will never come.
+        for channel in self.thread_channels.iter() {
+            _ = channel.1.sender.send(AVec::new(DEFAULT_MAX_ALIGNMENT));
+        }
+
+        let callbacks = mem::take(&mut *self.close_callbacks.lock().unwrap());
+        for callback in callbacks {
+            callback();
+        }
+    }
+
+    /// Registers callback called once when the connection is closed, or immediately if it is already closed.
+    pub fn on_close<F>(&self, callback: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let mut callbacks = self.close_callbacks.lock().unwrap();
+        if !self.is_closed() {
+            callbacks.push(Box::new(callback));
+            return;
+        }
+
+        drop(callbacks);
+        callback();
+    }
+
+    /// Time since the last data was received from peer.
+    pub fn idle_time(&self) -> Duration {
+        let last_received = Duration::from_millis(self.last_received.load(Ordering::Relaxed));
+        self.created.elapsed().saturating_sub(last_received)
+    }
+
+    /// Number of packets sent to peer, including packets dropped because the connection was closed. Heartbeats are
+    /// not counted.
+    ///
+    /// Peer which received fewer packets than this lost some of them with the connection.
+    #[inline]
+    pub fn sent_packets(&self) -> u64 {
+        self.sent_packets.load(Ordering::SeqCst)
+    }
+
+    /// Number of packets received from peer, without heartbeats.
+    #[inline]
+    pub fn received_packets(&self) -> u64 {
+        self.received_packets.load(Ordering::SeqCst)
+    }
+
+    #[inline]
+    pub fn new_packet(&self, destination: u64) -> PacketWriter<'_, T> {
+        self.new_packet_with_priority(destination, Priority::default())
+    }
+
+    #[inline]
+    pub fn new_packet_with_priority(
+        &self,
+        destination: u64,
+        priority: Priority,
+    ) -> PacketWriter<'_, T> {
         let buffer = self.pop_buffer();
         PacketWriter::new(
             self,
             buffer,
             AVec::with_capacity(1, 0),
             Destination::Handler(destination),
+            priority,
         )
     }
 
     pub(crate) fn send(&self, mut buffer: AVec<u8>) {
         profiling::scope!("send packet");
+        self.sent_packets.fetch_add(1, Ordering::SeqCst);
+
+        if self.is_closed() {
+            log::trace!("dropping packet sent through closed connection");
+            self.push_buffer(buffer);
+            return;
+        }
 
         Self::update_header(&mut buffer, None);
-        self.write_queue.push(buffer);
-        self.notify_write_thread();
+        let slot = self.route(Self::header(&buffer));
+        slot.push_to_write_lane(buffer);
+        slot.notify_write_thread();
+    }
+
+    pub(crate) fn send_with_response(&self, buffer: AVec<u8>) -> Packet<'_, T> {
+        match self.try_send_with_response(buffer) {
+            Ok(packet) => packet,
+            Err(e) => panic!("unable to receive response: {e}"),
+        }
     }
 
-    pub(crate) fn send_with_response(&self, mut buffer: AVec<u8>) -> Packet<'_, T> {
+    pub(crate) fn try_send_with_response(
+        &self,
+        mut buffer: AVec<u8>,
+    ) -> Result<Packet<'_, T>, ConnectionClosedError> {
         profiling::scope!("send packet");
+        self.sent_packets.fetch_add(1, Ordering::SeqCst);
 
         let thread_id = thread::current().id();
         Self::update_header(&mut buffer, Some(thread_id));
 
-        if let Ok(_guard) = self.write_mutex.try_lock() {
-            profiling::scope!("self write");
-            self.write_impl(&buffer);
+        // Channel must exist before the request is written, otherwise response could be received before it.
+        let thread_id_raw: u64 = unsafe { mem::transmute(thread_id) };
+        let channel = self.thread_channel(thread_id_raw);
+
+        // Checked after the channel is created, so closing cannot be missed.
+        if self.is_closed() {
+            self.push_buffer(buffer);
+            return Err(ConnectionClosedError);
+        }
+
+        let slot = self.route(Self::header(&buffer));
+        if !self.try_write_directly(slot, &buffer) {
+            slot.push_to_write_lane(buffer);
+            slot.notify_write_thread();
         } else {
-            self.write_queue.push(buffer.clone());
+            self.push_buffer(buffer);
         }
-        self.notify_write_thread();
 
         profiling::scope!("wait for response");
 
-        // Get channel
-        let thread_id_raw: u64 = unsafe { mem::transmute(thread_id) };
-        let channel;
-        loop {
-            match self.thread_channels.get(&thread_id_raw) {
-                Some(a) => {
-                    channel = a;
-                    break;
-                }
-                None => {
-                    let (sender, receiver) = mpsc::channel();
-                    _ = self.thread_channels.insert(
-                        thread_id_raw,
-                        ThreadChannel {
-                            sender,
-                            receiver: UnsafeReceiver(receiver),
-                        },
-                    )
-                }
-            }
-        }
-
         // Wait for packet
         let buffer = channel
             .1
             .receiver
             .recv()
             .expect("expected data from channel");
-        Packet::new(self, buffer)
+        if buffer.is_empty() {
+            return Err(ConnectionClosedError);
+        }
+        Ok(Packet::new(self, buffer))
     }
 
     #[inline]
     pub(crate) fn push_buffer(&self, mut buffer: AVec<u8>) {
+        // Placeholder buffers without allocation cannot be reused.
+        if buffer.capacity() < mem::size_of::<PacketHeader>() {
+            return;
+        }
+
         unsafe { buffer.set_len(mem::size_of::<PacketHeader>()) }
         self.buffer_pool.push(buffer);
     }
@@ -152,150 +338,577 @@ where
         }
     }
 
+    fn thread_channel(
+        &self,
+        thread_id_raw: u64,
+    ) -> lockfree::map::ReadGuard<'_, u64, ThreadChannel> {
+        loop {
+            match self.thread_channels.get(&thread_id_raw) {
+                Some(channel) => return channel,
+                None => {
+                    let (sender, receiver) = mpsc::channel();
+                    _ = self.thread_channels.insert(
+                        thread_id_raw,
+                        ThreadChannel {
+                            sender,
+                            receiver: UnsafeReceiver(receiver),
+                        },
+                    )
+                }
+            }
+        }
+    }
+
+    /// Selects stream for the packet sent by the calling thread.
+    ///
+    /// Packets of one thread always go through the same stream, whatever their destination is, which keeps their order,
+    /// like commands recorded to a command buffer. Peer handles them in the same order, see `drain_handler_queue`. When the connection has more than one stream, the last one is
+    /// reserved for bulk packets.
+    fn route(&self, header: &PacketHeader) -> &StreamSlot<T> {
+        let count = self.streams.len();
+        if count == 1 {
+            return &self.streams[0];
+        }
+        if header.priority == Priority::Bulk {
+            return &self.streams[count - 1];
+        }
+
+        let thread_id: u64 = unsafe { mem::transmute(thread::current().id()) };
+        &self.streams[(thread_id % (count as u64 - 1)) as usize]
+    }
+
+    /// Writes packet from the calling thread, when it fits into a single fragment and its lane is empty.
+    fn try_write_directly(&self, slot: &StreamSlot<T>, buffer: &[u8]) -> bool {
+        if buffer.len() > self.part_size {
+            return false;
+        }
+
+        let Ok(mut write) = slot.write.try_lock() else {
+            return false;
+        };
+
+        // Lane is checked under the lock, so the write worker cannot be in the middle of a packet in this lane.
+        let priority = Self::header(buffer).priority;
+        if slot.write_lanes[priority.lane()]
+            .pending
+            .load(Ordering::Acquire)
+            != 0
+        {
+            return false;
+        }
+
+        profiling::scope!("self write");
+        if let Err(e) = write_frame(&mut *write, priority, buffer, slot.message_based) {
+            log::error!("failed to write to stream: {e}");
+            self.close();
+        }
+        true
+    }
+
+    /// Writes one fragment of the highest priority packet which awaits writing in the stream.
+    ///
+    /// Returns false when there is nothing to write.
+    fn write_next_fragment(
+        &self,
+        slot: &StreamSlot<T>,
+        in_progress: &mut [Option<(AVec<u8>, usize)>; Priority::COUNT],
+    ) -> bool {
+        for lane in (0..Priority::COUNT).rev() {
+            let pending = &mut in_progress[lane];
+            if pending.is_none() {
+                *pending = slot.write_lanes[lane].queue.pop().map(|buffer| (buffer, 0));
+            }
+
+            let Some((buffer, offset)) = pending else {
+                continue;
+            };
+
+            let end = (*offset + self.part_size).min(buffer.len());
+            let result = write_frame(
+                &mut *slot.write.lock().unwrap(),
+                Self::header(buffer).priority,
+                &buffer[*offset..end],
+                slot.message_based,
+            );
+            if let Err(e) = result {
+                log::error!("failed to write to stream: {e}");
+                self.close();
+                return false;
+            }
+            *offset = end;
+
+            if end == buffer.len() {
+                let (buffer, _) = pending.take().unwrap();
+                slot.write_lanes[lane]
+                    .pending
+                    .fetch_sub(1, Ordering::AcqRel);
+                self.push_buffer(buffer);
+            }
+            return true;
+        }
+        false
+    }
+
     #[inline]
-    fn notify_write_thread(&self) {
-        self.write_reset_event.set();
+    fn header(buffer: &[u8]) -> &PacketHeader {
+        unsafe { &*(buffer.as_ptr() as *const PacketHeader) }
     }
 
-    fn write_impl(&self, buffer: &[u8]) {
-        unsafe {
-            self.stream.write_unsafe(buffer).unwrap();
-            self.stream.flush_unsafe().unwrap();
+    /// Checks packet which is being received from peer, after data was appended to it. Returns true when the packet is
+    /// complete.
+    fn check_received(packet: &[u8]) -> Result<bool, ProtocolError> {
+        if packet.len() < mem::size_of::<PacketHeader>() {
+            return Ok(false);
         }
+
+        let length = PacketHeader::decode(packet)?.length;
+        if packet.len() > length {
+            return Err(ProtocolError::Length(packet.len()));
+        }
+        Ok(packet.len() == length)
+    }
+
+    /// Closes the connection, as data received from the stream breaks the protocol.
+    fn reject(&self, index: usize, error: ProtocolError) {
+        log::error!("received invalid data from stream {index}: {error}, closing connection");
+        self.close();
     }
 
     #[inline]
     fn update_header(buffer: &mut AVec<u8>, sender_thread_id: Option<ThreadId>) {
         let header = unsafe { &mut *(buffer.as_mut_ptr() as *mut PacketHeader) };
         header.length = buffer.len();
+        header.sender = unsafe { mem::transmute::<ThreadId, u64>(thread::current().id()) };
         header.sender_thread_id = sender_thread_id;
     }
+
+    /// Sends packet without payload to the given stream.
+    fn send_control(&self, index: usize, destination: Destination) {
+        if self.is_closed() {
+            return;
+        }
+
+        let mut buffer = self.pop_buffer();
+        {
+            let header = unsafe { &mut *(buffer.as_mut_ptr() as *mut PacketHeader) };
+            header.destination = destination;
+            header.priority = Priority::High;
+        }
+        Self::update_header(&mut buffer, None);
+
+        let slot = &self.streams[index];
+        slot.push_to_write_lane(buffer);
+        slot.notify_write_thread();
+    }
+
+    #[inline]
+    fn mark_received(&self) {
+        self.last_received
+            .store(self.created.elapsed().as_millis() as u64, Ordering::Relaxed);
+    }
+
+    fn dispatch(self: &Arc<Self>, packet: AVec<u8>, index: usize) {
+        let header = Self::header(&packet);
+        match header.destination {
+            Destination::Thread(thread_id) => {
+                self.received_packets.fetch_add(1, Ordering::SeqCst);
+                let thread_id_raw: u64 = unsafe { mem::transmute(thread_id) };
+                let channel = self.thread_channel(thread_id_raw);
+                channel.1.sender.send(packet).unwrap();
+            }
+            Destination::Handler(_) => {
+                self.received_packets.fetch_add(1, Ordering::SeqCst);
+                let sender = header.sender;
+                match self.handler_queues.lock().unwrap().entry(sender) {
+                    Entry::Occupied(mut queue) => {
+                        queue.get_mut().push_back(packet);
+                        return;
+                    }
+                    Entry::Vacant(queue) => _ = queue.insert(VecDeque::from([packet])),
+                }
+
+                let connection = self.clone();
+                rayon::spawn(move || connection.drain_handler_queue(sender));
+            }
+            Destination::Heartbeat => {
+                self.push_buffer(packet);
+                self.send_control(index, Destination::HeartbeatAck);
+            }
+            Destination::HeartbeatAck => self.push_buffer(packet),
+        }
+    }
+
+    /// Handles queued packets of the peer thread one by one, until its queue is empty.
+    fn drain_handler_queue(&self, sender: u64) {
+        loop {
+            let packet = {
+                let mut queues = self.handler_queues.lock().unwrap();
+                let queue = queues.get_mut(&sender).unwrap();
+                match queue.pop_front() {
+                    Some(packet) => packet,
+                    None => {
+                        queues.remove(&sender);
+                        return;
+                    }
+                }
+            };
+
+            profiling::scope!("handling packet");
+            let Destination::Handler(handler_id) = Self::header(&packet).destination else {
+                unreachable!()
+            };
+            let Some(handler) = self.handlers.get(&handler_id) else {
+                log::error!("Received packet for unknown handler {handler_id}, closing connection");
+                self.close();
+                self.push_buffer(packet);
+                continue;
+            };
+            self.handle(handler, Packet::new(self, packet));
+        }
+    }
+
+    /// Handles packet which was [`detached`](Packet::detach) from this connection on the calling thread, like packets
+    /// received by the connection are handled. Packets of other connections are dropped, as their peer does not wait
+    /// for them anymore.
+    pub fn handle_detached(&self, packet: DetachedPacket, handler: &Handler<T>) {
+        match packet.attach(self) {
+            Some(packet) => self.handle(handler, packet),
+            None => log::debug!("dropping packet detached from another connection"),
+        }
+    }
+
+    /// Peer is not trusted, a packet which does not match its handler closes the connection instead of aborting the
+    /// process.
+    fn handle(&self, handler: &Handler<T>, packet: Packet<'_, T>) {
+        let destination = packet.header().destination.clone();
+        if let Err(payload) = panic::catch_unwind(AssertUnwindSafe(|| handler(packet))) {
+            if !payload.is::<MalformedPacket>() {
+                panic::resume_unwind(payload);
+            }
+            log::error!("Received malformed packet for {destination:?}, closing connection");
+            self.close();
+        }
+    }
 }
 
 impl<T> fmt::Debug for Connection<T>
 where
-    T: fmt::Debug + UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         f.debug_struct("Connection")
-            .field("stream", &self.stream)
+            .field("stream_count", &self.streams.len())
+            .field("part_size", &self.part_size)
+            .field("closed", &self.is_closed())
             .finish()
     }
 }
 
+/// Single stream of the connection with its write state, read half is owned by the receive thread.
+struct StreamSlot<T>
+where
+    T: SplitStream,
+{
+    write_lanes: [WriteLane; Priority::COUNT],
+    /// Locked by the write thread for every fragment, or by the sending thread which writes directly.
+    write: Mutex<T::WriteHalf>,
+    shutdown: T::Shutdown,
+    write_reset_event: AutoResetEvent,
+    message_based: bool,
+}
+
+impl<T> StreamSlot<T>
+where
+    T: SplitStream,
+{
+    fn new(write: T::WriteHalf, shutdown: T::Shutdown, message_based: bool) -> Self {
+        Self {
+            write_lanes: Default::default(),
+            write: Mutex::new(write),
+            shutdown,
+            write_reset_event: AutoResetEvent::new(rsevents::EventState::Unset),
+            message_based,
+        }
+    }
+
+    #[inline]
+    fn notify_write_thread(&self) {
+        self.write_reset_event.set();
+    }
+
+    #[inline]
+    fn push_to_write_lane(&self, buffer: AVec<u8>) {
+        let lane = &self.write_lanes[Connection::<T>::header(&buffer).priority.lane()];
+        lane.pending.fetch_add(1, Ordering::AcqRel);
+        lane.queue.push(buffer);
+    }
+}
+
+/// Writes frame header and the fragment. Message based streams get them in a single message, which is never written
+/// partially.
+fn write_frame<W>(
+    write: &mut W,
+    priority: Priority,
+    fragment: &[u8],
+    message_based: bool,
+) -> io::Result<()>
+where
+    W: Write,
+{
+    let header = FrameHeader {
+        length: fragment.len() as u32,
+        priority,
+    }
+    .encode();
+
+    if message_based {
+        let written = write.write_vectored(&[IoSlice::new(&header), IoSlice::new(fragment)])?;
+        if written != header.len() + fragment.len() {
+            return Err(io::Error::new(
+                io::ErrorKind::WriteZero,
+                "message was written partially",
+            ));
+        }
+    } else {
+        write.write_all(&header)?;
+        write.write_all(fragment)?;
+    }
+    write.flush()
+}
+
+#[derive(Default)]
+struct WriteLane {
+    queue: Queue<AVec<u8>>,
+    /// Number of packets which are queued or partially written.
+    pending: AtomicUsize,
+}
+
 struct ThreadChannel {
     sender: Sender<AVec<u8>>,
     // Safety: Field access must be externally synchronized.
     receiver: UnsafeReceiver<AVec<u8>>,
 }
 
-fn write_worker<T>(weak: Weak<Connection<T>>)
+fn write_worker<T>(weak: Weak<Connection<T>>, index: usize)
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
+    let mut in_progress = Default::default();
+
     while let Some(connection) = weak.upgrade() {
+        let slot = &connection.streams[index];
         for _ in 0..64 {
-            {
-                let _guard = connection.write_mutex.lock().unwrap();
-                while let Some(buffer) = connection.write_queue.pop() {
-                    connection.write_impl(&buffer);
-                }
-            }
+            while connection.write_next_fragment(slot, &mut in_progress) {}
+            slot.write_reset_event.wait();
 
-            connection.write_reset_event.wait();
+            if connection.is_closed() {
+                return;
+            }
         }
     }
 }
 
-fn receive_worker<T>(weak: Weak<Connection<T>>, part_size: usize)
+fn receive_worker<T>(weak: Weak<Connection<T>>, index: usize, mut read: T::ReadHalf)
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
-    const MIN_PACKET_SIZE: usize = mem::size_of::<PacketHeader>();
+    let Some(part_size) = weak.upgrade().map(|c| c.part_size) else {
+        return;
+    };
+    let mut buffer = vec![0u8; part_size.max(FRAME_HEADER_SIZE)];
 
-    let mut buffer = vec![0u8; part_size];
-    let mut offset = 0;
+    let mut frame_header = [0u8; FRAME_HEADER_SIZE];
+    let mut frame_header_read = 0;
+    let mut frame_remaining = 0;
+    let mut frame_lane = 0;
 
-    let mut packet = AVec::new(DEFAULT_MAX_ALIGNMENT);
-    let mut packet_length = MIN_PACKET_SIZE;
-    let mut set_packet_length = false;
+    let mut lanes: [AVec<u8>; Priority::COUNT] =
+        std::array::from_fn(|_| AVec::new(DEFAULT_MAX_ALIGNMENT));
 
     while let Some(connection) = weak.upgrade() {
         for _ in 0..64 {
-            // Safety: Only one thread is reading from the stream.
-            let read = match unsafe { connection.stream.read_unsafe(&mut buffer) } {
-                Ok(read) => read,
-                Err(_) => panic!("failed to read from stream"),
+            let Some(read) = read_or_close(&connection, index, || read.read(&mut buffer)) else {
+                return;
             };
+            connection.mark_received();
 
-            // Read packet length
-            if !set_packet_length && read >= mem::size_of::<usize>() {
-                packet_length = unsafe { *(buffer.as_ptr() as *const usize) };
-                set_packet_length = true;
-            }
+            let mut data = &buffer[..read];
+            while !data.is_empty() {
+                // Read frame header, which can be split between reads
+                if frame_remaining == 0 {
+                    let r = (FRAME_HEADER_SIZE - frame_header_read).min(data.len());
+                    frame_header[frame_header_read..frame_header_read + r]
+                        .copy_from_slice(&data[..r]);
+                    frame_header_read += r;
+                    data = &data[r..];
+
+                    if frame_header_read == FRAME_HEADER_SIZE {
+                        let header = match FrameHeader::decode(&frame_header) {
+                            Ok(header) => header,
+                            Err(e) => return connection.reject(index, e),
+                        };
+                        frame_remaining = header.length as usize;
+                        frame_lane = header.priority.lane();
+                        frame_header_read = 0;
+                    }
+                    continue;
+                }
 
-            while offset != read {
+                let packet = &mut lanes[frame_lane];
                 if packet.is_empty() {
                     profiling::scope!("reading packet");
                 }
 
-                let r = (packet_length - packet.len()).min(read);
-                packet.extend_from_slice(&buffer[offset..r]);
-                offset += r;
+                let r = frame_remaining.min(data.len());
+                packet.extend_from_slice(&data[..r]);
+                frame_remaining -= r;
+                data = &data[r..];
+
+                let complete = match Connection::<T>::check_received(packet) {
+                    Ok(complete) => complete,
+                    Err(e) => return connection.reject(index, e),
+                };
+                // Packets are always fragmented on frame boundaries.
+                if frame_remaining == 0 && complete {
+                    let mut next = connection.pop_buffer();
+                    next.clear();
+                    connection.dispatch(mem::replace(packet, next), index);
 
-                if packet.len() < mem::size_of::<usize>() {
-                    break;
-                } else if !set_packet_length {
-                    packet_length = unsafe { *(packet.as_ptr() as *const usize) };
-                    set_packet_length = true;
+                    profiling::finish_frame!();
                 }
+            }
+        }
+    }
 
-                if packet.len() == packet_length {
-                    let header = unsafe { &*(packet.as_ptr() as *const PacketHeader) };
-                    match header.destination {
-                        Destination::Thread(thread_id) => {
-                            let thread_id_raw: u64 = unsafe { mem::transmute(thread_id) };
-                            let channel = connection.thread_channels.get(&thread_id_raw).unwrap();
-                            channel.1.sender.send(packet).unwrap();
-                        }
-                        Destination::Handler(handler_id) => {
-                            let connection = connection.clone();
-                            rayon::spawn(move || {
-                                profiling::scope!("handling packet");
-
-                                let handler = connection.handlers.get(&handler_id).unwrap();
-                                handler(Packet::new(&connection, packet));
-                            });
-                        }
-                    }
+    log::info!("receive worker {index} finished");
+}
 
-                    packet = connection.pop_buffer();
-                    packet.clear();
-                    packet_length = MIN_PACKET_SIZE;
-                    set_packet_length = false;
+/// Receive worker of message based streams, every read returns a whole fragment.
+///
+/// Fragment is read directly into a pooled buffer together with its frame header, which becomes the packet when it is
+/// its first fragment, so packets which fit into a single fragment are never copied.
+fn receive_message_worker<T>(weak: Weak<Connection<T>>, index: usize, mut read: T::ReadHalf)
+where
+    T: SplitStream,
+{
+    let Some(part_size) = weak.upgrade().map(|c| c.part_size) else {
+        return;
+    };
 
-                    profiling::finish_frame!();
-                }
+    let mut frame_header = [0u8; FRAME_HEADER_SIZE];
+    let mut lanes: [AVec<u8>; Priority::COUNT] =
+        std::array::from_fn(|_| AVec::new(DEFAULT_MAX_ALIGNMENT));
+
+    while let Some(connection) = weak.upgrade() {
+        for _ in 0..64 {
+            let mut fragment = connection.pop_buffer();
+            fragment.resize(part_size, 0);
+
+            let Some(read) = read_or_close(&connection, index, || {
+                read.read_vectored(&mut [
+                    IoSliceMut::new(&mut frame_header),
+                    IoSliceMut::new(&mut fragment),
+                ])
+            }) else {
+                return;
+            };
+            connection.mark_received();
+
+            if read < FRAME_HEADER_SIZE {
+                log::error!("received truncated message of {read} bytes from stream {index}");
+                connection.close();
+                return;
+            }
+            let header = match FrameHeader::decode(&frame_header) {
+                Ok(header) => header,
+                Err(e) => return connection.reject(index, e),
+            };
+            if header.length as usize != read - FRAME_HEADER_SIZE {
+                log::error!(
+                    "received truncated message of {read} bytes from stream {index}, peer must use part size {part_size}"
+                );
+                connection.close();
+                return;
             }
+            fragment.truncate(header.length as usize);
 
-            offset = 0;
+            let packet = &mut lanes[header.priority.lane()];
+            if packet.is_empty() {
+                profiling::scope!("reading packet");
+                *packet = fragment;
+            } else {
+                packet.extend_from_slice(&fragment);
+                connection.push_buffer(fragment);
+            }
+
+            let complete = match Connection::<T>::check_received(packet) {
+                Ok(complete) => complete,
+                Err(e) => return connection.reject(index, e),
+            };
+            if complete {
+                let packet = mem::replace(packet, AVec::new(DEFAULT_MAX_ALIGNMENT));
+                connection.dispatch(packet, index);
+
+                profiling::finish_frame!();
+            }
         }
     }
 
-    log::info!("receive worker finished");
+    log::info!("receive worker {index} finished");
+}
+
+/// Reads from the stream, retrying interrupted reads. Returns [`None`] and closes the connection when the stream is
+/// closed by peer or fails.
+fn read_or_close<T, F>(connection: &Connection<T>, index: usize, mut read: F) -> Option<usize>
+where
+    T: SplitStream,
+    F: FnMut() -> io::Result<usize>,
+{
+    loop {
+        match read() {
+            Ok(0) => {
+                if !connection.is_closed() {
+                    log::info!("stream {index} closed by peer");
+                    connection.close();
+                }
+                return None;
+            }
+            Ok(read) => return Some(read),
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => {
+                if !connection.is_closed() {
+                    log::error!("failed to read from stream {index}: {e}");
+                    connection.close();
+                }
+                return None;
+            }
+        }
+    }
 }
 
 #[cfg(all(test, debug_assertions))]
 mod tests {
-    use crate::{packet::Packet, Connection, Handler};
+    use crate::{
+        frame::{FrameHeader, FRAME_HEADER_SIZE},
+        handshake,
+        heartbeat::Heartbeat,
+        packet::{Packet, PacketHeader, Priority, MAX_PACKET_LENGTH},
+        Connection, Handler,
+    };
     use rsevents::{AutoResetEvent, Awaitable};
     use rstest::rstest;
     use std::{
         collections::HashMap,
+        io::{Read, Write},
+        mem,
         net::{TcpListener, TcpStream},
-        sync::Arc,
+        sync::{mpsc, Arc, Mutex, OnceLock, Weak},
+        thread,
+        time::{Duration, Instant},
     };
-    use wie_common::stream::mock::MockStream;
+    #[cfg(unix)]
+    use wie_common::stream::mock::MockSeqPacketStream;
+    use wie_common::stream::{mock::MockStream, SplitStream};
 
     fn new_mock_connection(
         part_size: Option<usize>,
@@ -312,6 +925,94 @@ mod tests {
         )
     }
 
+    fn new_mock_striped_connection(
+        stream_count: usize,
+        part_size: Option<usize>,
+        server_handlers: HashMap<u64, Handler<MockStream>>,
+        client_handlers: HashMap<u64, Handler<MockStream>>,
+    ) -> (Arc<Connection<MockStream>>, Arc<Connection<MockStream>>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            handshake::connect(stream_count, None, || TcpStream::connect(address)).unwrap()
+        });
+
+        let mut acceptor = handshake::Acceptor::new();
+        let server = loop {
+            let (stream, _) = listener.accept().unwrap();
+            if let Some(pending) = acceptor
+                .accept(handshake::read_handshake(stream).unwrap())
+                .unwrap()
+            {
+                break pending.finish(handshake::Session::new()).unwrap();
+            }
+        };
+        let (client, _) = client.join().unwrap();
+
+        (
+            Connection::with_streams(
+                server.into_iter().map(MockStream::from).collect(),
+                server_handlers,
+                part_size,
+            ),
+            Connection::with_streams(
+                client.into_iter().map(MockStream::from).collect(),
+                client_handlers,
+                part_size,
+            ),
+        )
+    }
+
+    fn echo_handle<T: SplitStream>(mut packet: Packet<T>) {
+        let length = packet.read_shallow::<u32>();
+        let mut sum = 0u64;
+        for _ in 0..length {
+            sum += packet.read_shallow::<u8>() as u64;
+        }
+
+        let mut response = packet.write_response(None);
+        response.write_shallow(sum);
+        response.send();
+    }
+
+    /// Sends packets from multiple threads and returns elapsed time.
+    fn send_from_threads<T: SplitStream>(
+        server: &Arc<Connection<T>>,
+        thread_count: usize,
+        packet_count: usize,
+        packet_length: u32,
+    ) -> std::time::Duration {
+        let start = Instant::now();
+        let threads = (0..thread_count)
+            .map(|t| {
+                let server = server.clone();
+                thread::spawn(move || {
+                    for i in 0..packet_count {
+                        let priority = match (t + i) % 3 {
+                            0 => Priority::Bulk,
+                            1 => Priority::Normal,
+                            _ => Priority::High,
+                        };
+                        let mut packet = server.new_packet_with_priority(9, priority);
+                        packet.write_shallow(packet_length);
+                        for j in 0..packet_length {
+                            packet.write_shallow(j as u8);
+                        }
+
+                        let mut response = packet.send_with_response();
+                        let expected = (0..packet_length).map(|j| j as u8 as u64).sum::<u64>();
+                        assert_eq!(expected, response.read_shallow::<u64>());
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+
+        for thread in threads {
+            thread.join().unwrap();
+        }
+        start.elapsed()
+    }
+
     #[rstest]
     #[case(None)]
     #[case(Some(3))]
@@ -382,4 +1083,533 @@ mod tests {
         response = packet.send_with_response();
         assert_eq!(4u128, response.read_shallow::<u128>());
     }
+
+    #[test]
+    fn sender_thread_id() {
+        let (sender, receiver) = mpsc::channel();
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(
+            6,
+            Box::new(move |packet: Packet<MockStream>| {
+                sender.send(packet.sender_thread_id()).unwrap();
+                if packet.sender_thread_id().is_some() {
+                    packet.write_response(None).send();
+                }
+            }),
+        );
+        let (server, _client) = new_mock_connection(None, HashMap::new(), client_handlers);
+
+        server.new_packet(6).send();
+        assert_eq!(None, receiver.recv().unwrap());
+
+        server.new_packet(6).send_with_response();
+        assert_eq!(Some(thread::current().id()), receiver.recv().unwrap());
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(3))]
+    #[case(Some(1000))]
+    fn priority_lanes(#[case] part_size: Option<usize>) {
+        const BULK_LENGTH: usize = 64 * 1024;
+
+        fn bulk_handle(mut packet: Packet<MockStream>) {
+            assert_eq!(Priority::Bulk, packet.priority());
+            for i in 0..BULK_LENGTH {
+                assert_eq!(i as u8, packet.read_shallow::<u8>());
+            }
+
+            let mut response = packet.write_response(None);
+            response.write_shallow(true);
+            response.send();
+        }
+
+        fn high_handle(mut packet: Packet<MockStream>) {
+            assert_eq!(Priority::High, packet.priority());
+            let value = packet.read_shallow::<u64>();
+
+            let mut response = packet.write_response(None);
+            assert_eq!(Priority::High, response.priority());
+            response.write_shallow(value + 1);
+            response.send();
+        }
+
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(7, Box::new(bulk_handle));
+        client_handlers.insert(8, Box::new(high_handle));
+        let (server, _client) = new_mock_connection(part_size, HashMap::new(), client_handlers);
+
+        let bulk_server = server.clone();
+        let bulk_thread = thread::spawn(move || {
+            let mut packet = bulk_server.new_packet_with_priority(7, Priority::Bulk);
+            for i in 0..BULK_LENGTH {
+                packet.write_shallow(i as u8);
+            }
+            let mut response = packet.send_with_response();
+            assert!(response.read_shallow::<bool>());
+        });
+
+        for i in 0..16u64 {
+            let mut packet = server.new_packet_with_priority(8, Priority::High);
+            packet.write_shallow(i);
+            let mut response = packet.send_with_response();
+            assert_eq!(i + 1, response.read_shallow::<u64>());
+        }
+
+        bulk_thread.join().unwrap();
+    }
+
+    #[rstest]
+    #[case(None)]
+    #[case(Some(1000))]
+    fn high_priority_overtakes_queued_bulk(#[case] part_size: Option<usize>) {
+        const CHUNK_LENGTH: usize = 4096;
+        const CHUNKS: usize = 4096;
+
+        let (sender, receiver) = mpsc::channel();
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        for destination in [7, 8] {
+            let sender = sender.clone();
+            client_handlers.insert(
+                destination,
+                Box::new(move |mut packet: Packet<MockStream>| {
+                    if packet.priority() == Priority::Bulk {
+                        for _ in 0..CHUNKS {
+                            packet.read_shallow::<[u8; CHUNK_LENGTH]>();
+                        }
+                    }
+                    sender.send(packet.priority()).unwrap();
+                }),
+            );
+        }
+        let (server, _client) = new_mock_connection(part_size, HashMap::new(), client_handlers);
+
+        // Bulk packet is split into thousands of fragments, the high priority one is written between them.
+        let mut bulk = server.new_packet_with_priority(7, Priority::Bulk);
+        for _ in 0..CHUNKS {
+            bulk.write_shallow([0u8; CHUNK_LENGTH]);
+        }
+        bulk.send();
+        server.new_packet_with_priority(8, Priority::High).send();
+
+        let timeout = Duration::from_secs(10);
+        assert_eq!(Priority::High, receiver.recv_timeout(timeout).unwrap());
+        assert_eq!(Priority::Bulk, receiver.recv_timeout(timeout).unwrap());
+    }
+
+    #[rstest]
+    #[case(1, None)]
+    #[case(2, Some(7))]
+    #[case(4, None)]
+    fn striped_streams(#[case] stream_count: usize, #[case] part_size: Option<usize>) {
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+        let (server, client) =
+            new_mock_striped_connection(stream_count, part_size, HashMap::new(), client_handlers);
+
+        assert_eq!(stream_count, server.stream_count());
+        assert_eq!(stream_count, client.stream_count());
+        send_from_threads(&server, 4, 32, 300);
+    }
+
+    #[test]
+    fn packets_of_thread_keep_order() {
+        const PACKETS: u32 = 64;
+
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let peer = thread::spawn(move || {
+            handshake::connect(4, None, || TcpStream::connect(address)).unwrap()
+        });
+        let mut acceptor = handshake::Acceptor::new();
+        let streams = loop {
+            let (stream, _) = listener.accept().unwrap();
+            if let Some(pending) = acceptor
+                .accept(handshake::read_handshake(stream).unwrap())
+                .unwrap()
+            {
+                break pending.finish(handshake::Session::new()).unwrap();
+            }
+        };
+        let connection = Connection::with_streams(
+            streams.into_iter().map(MockStream::from).collect(),
+            HashMap::new(),
+            None,
+        );
+
+        // Peer reads frames itself, so the order in which they arrive on its streams is seen.
+        let (sender, receiver) = mpsc::channel();
+        for (index, mut stream) in peer.join().unwrap().0.into_iter().enumerate() {
+            let sender = sender.clone();
+            thread::spawn(move || {
+                let mut frame = [0u8; FRAME_HEADER_SIZE + mem::size_of::<PacketHeader>() + 4];
+                while stream.read_exact(&mut frame).is_ok() {
+                    let value = u32::from_ne_bytes(frame[frame.len() - 4..].try_into().unwrap());
+                    _ = sender.send((index, value));
+                }
+            });
+        }
+
+        // Commands of one thread go to different handlers, like commands recorded to a command buffer.
+        for value in 0..PACKETS {
+            let mut packet = connection.new_packet(value as u64 % 6);
+            packet.write_shallow(value);
+            packet.send();
+        }
+
+        let received = (0..PACKETS)
+            .map(|_| receiver.recv_timeout(Duration::from_secs(5)).unwrap())
+            .collect::<Vec<_>>();
+        assert!(received.iter().all(|(index, _)| *index == received[0].0));
+        assert_eq!(
+            (0..PACKETS).collect::<Vec<_>>(),
+            received.iter().map(|(_, value)| *value).collect::<Vec<_>>()
+        );
+    }
+
+    #[rstest]
+    #[case(1)]
+    #[case(3)]
+    fn heartbeat_keeps_idle_connection_open(#[case] stream_count: usize) {
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+        let (server, client) =
+            new_mock_striped_connection(stream_count, None, HashMap::new(), client_handlers);
+
+        server.start_heartbeat(Heartbeat {
+            interval: Duration::from_millis(10),
+            miss_threshold: 3,
+        });
+        thread::sleep(Duration::from_millis(200));
+
+        assert!(!server.is_closed());
+        assert!(!client.is_closed());
+        send_from_threads(&server, 2, 4, 16);
+    }
+
+    #[rstest]
+    #[case::malformed(9)]
+    #[case::unknown_handler(42)]
+    fn invalid_packet_closes_connection(#[case] handler: u64) {
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+        let (server, client) = new_mock_connection(None, HashMap::new(), client_handlers);
+
+        let (sender, receiver) = mpsc::channel();
+        client.on_close(move || sender.send(()).unwrap());
+
+        // Echo handler expects the bytes after their count.
+        let mut packet = server.new_packet(handler);
+        packet.write_shallow(1000u32);
+        assert!(packet.try_send_with_response().is_err());
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(client.is_closed());
+    }
+
+    /// Returns packet header with the given fields, as peer could write it.
+    fn raw_packet_header(length: usize, destination: u32, value: u64, priority: u8) -> Vec<u8> {
+        let mut header = vec![0u8; mem::size_of::<PacketHeader>()];
+        let offset = mem::offset_of!(PacketHeader, length);
+        header[offset..offset + 8].copy_from_slice(&(length as u64).to_ne_bytes());
+        let offset = mem::offset_of!(PacketHeader, destination);
+        header[offset..offset + 4].copy_from_slice(&destination.to_ne_bytes());
+        header[offset + 8..offset + 16].copy_from_slice(&value.to_ne_bytes());
+        header[mem::offset_of!(PacketHeader, priority)] = priority;
+        header
+    }
+
+    fn raw_frame(packet: &[u8]) -> Vec<u8> {
+        let mut frame = FrameHeader {
+            length: packet.len() as u32,
+            priority: Priority::Normal,
+        }
+        .encode()
+        .to_vec();
+        frame.extend_from_slice(packet);
+        frame
+    }
+
+    #[rstest]
+    #[case::frame_priority({
+        let mut frame = raw_frame(&raw_packet_header(mem::size_of::<PacketHeader>(), 1, 9, 1));
+        frame[4] = 7;
+        frame
+    })]
+    #[case::frame_length({
+        let mut frame = vec![0u8; FRAME_HEADER_SIZE];
+        frame[..4].copy_from_slice(&u32::MAX.to_ne_bytes());
+        frame
+    })]
+    #[case::destination(raw_frame(&raw_packet_header(mem::size_of::<PacketHeader>(), 9, 9, 1)))]
+    #[case::thread_zero(raw_frame(&raw_packet_header(mem::size_of::<PacketHeader>(), 0, 0, 1)))]
+    #[case::packet_priority(raw_frame(&raw_packet_header(mem::size_of::<PacketHeader>(), 1, 9, 3)))]
+    #[case::packet_length(raw_frame(&raw_packet_header(MAX_PACKET_LENGTH + 1, 1, 9, 1)))]
+    #[case::packet_overrun({
+        let mut packet = raw_packet_header(mem::size_of::<PacketHeader>(), 1, 9, 1);
+        packet.extend_from_slice(&[0; 16]);
+        raw_frame(&packet)
+    })]
+    fn invalid_header_closes_connection(#[case] bytes: Vec<u8>) {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let mut peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+
+        let mut handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        handlers.insert(9, Box::new(|_| panic!("invalid packet was dispatched")));
+        let connection = Connection::new(MockStream::from(stream), handlers, None);
+        let (sender, receiver) = mpsc::channel();
+        connection.on_close(move || sender.send(()).unwrap());
+
+        peer.write_all(&bytes).unwrap();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(connection.is_closed());
+    }
+
+    #[test]
+    fn heartbeat_closes_dead_peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        // Peer accepts the connection, but never answers.
+        let _peer = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let (stream, _) = listener.accept().unwrap();
+        let connection = Connection::new(MockStream::from(stream), HashMap::new(), None);
+
+        let (sender, receiver) = mpsc::channel();
+        connection.on_close(move || sender.send(()).unwrap());
+        connection.start_heartbeat(Heartbeat {
+            interval: Duration::from_millis(10),
+            miss_threshold: 2,
+        });
+
+        let waiting = {
+            let connection = connection.clone();
+            thread::spawn(move || connection.new_packet(9).try_send_with_response().is_err())
+        };
+
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(connection.is_closed());
+        assert!(waiting.join().unwrap());
+
+        // Callbacks registered after closing are called immediately.
+        let (sender, receiver) = mpsc::channel();
+        connection.on_close(move || sender.send(()).unwrap());
+        receiver.try_recv().unwrap();
+    }
+
+    #[test]
+    fn close_is_propagated_to_peer() {
+        let (server, client) = new_mock_connection(None, HashMap::new(), HashMap::new());
+
+        let (sender, receiver) = mpsc::channel();
+        server.on_close(move || sender.send(()).unwrap());
+
+        client.close();
+        receiver.recv_timeout(Duration::from_secs(5)).unwrap();
+        assert!(server.is_closed());
+
+        // Packets sent through closed connection are dropped.
+        server.new_packet(9).send();
+        assert!(server.new_packet(9).try_send_with_response().is_err());
+    }
+
+    #[test]
+    fn count_packets() {
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+        client_handlers.insert(10, Box::new(|_| {}));
+        let (server, client) = new_mock_connection(None, HashMap::new(), client_handlers);
+
+        let mut packet = server.new_packet(9);
+        packet.write_shallow(0u32);
+        assert_eq!(0, packet.send_with_response().read_shallow::<u64>());
+        server.new_packet(10).send();
+
+        assert_eq!(2, server.sent_packets());
+        assert_eq!(1, server.received_packets());
+        while client.received_packets() < 2 {
+            thread::yield_now();
+        }
+        assert_eq!(1, client.sent_packets());
+
+        // Packets dropped by closed connection are sent, but never received by peer.
+        server.close();
+        server.new_packet(10).send();
+        assert_eq!(3, server.sent_packets());
+        assert_eq!(2, client.received_packets());
+    }
+
+    #[test]
+    fn handlers_run_in_order_of_sender_thread() {
+        const PACKETS: u32 = 64;
+
+        let handled = Arc::new(Mutex::new(Vec::new()));
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        for id in 0..2 {
+            let handled = handled.clone();
+            client_handlers.insert(
+                id,
+                Box::new(move |mut packet| {
+                    let value = packet.read_shallow::<u32>();
+                    // Slower handler would be overtaken by the faster one, if they ran in parallel.
+                    if id == 0 {
+                        thread::sleep(Duration::from_micros(200));
+                    }
+                    handled.lock().unwrap().push(value);
+                }),
+            );
+        }
+        client_handlers.insert(9, Box::new(echo_handle));
+        let (server, _client) = new_mock_connection(None, HashMap::new(), client_handlers);
+
+        for value in 0..PACKETS {
+            let mut packet = server.new_packet(value as u64 % 2);
+            packet.write_shallow(value);
+            packet.send();
+        }
+        // Response is sent once every previous packet of this thread was handled.
+        let mut packet = server.new_packet(9);
+        packet.write_shallow(0u32);
+        assert_eq!(0, packet.send_with_response().read_shallow::<u64>());
+
+        assert_eq!((0..PACKETS).collect::<Vec<_>>(), *handled.lock().unwrap());
+    }
+
+    #[test]
+    fn handle_detached_packet() {
+        let (sender, receiver) = mpsc::channel();
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(
+            9,
+            Box::new(move |packet| sender.send(packet.detach()).unwrap()),
+        );
+        let (server, client) = new_mock_connection(None, HashMap::new(), client_handlers);
+        let (_, other) = new_mock_connection(None, HashMap::new(), HashMap::new());
+
+        let worker = thread::spawn(move || {
+            let handler: Handler<MockStream> = Box::new(echo_handle);
+            // Packet of another connection is dropped without response.
+            other.handle_detached(receiver.recv().unwrap(), &handler);
+            client.handle_detached(receiver.recv().unwrap(), &handler);
+        });
+
+        let mut packet = server.new_packet(9);
+        packet.write_shallow(1u32);
+        packet.write_shallow(3u8);
+        packet.send();
+
+        let mut packet = server.new_packet(9);
+        packet.write_shallow(2u32);
+        packet.write_shallow(3u8);
+        packet.write_shallow(4u8);
+        assert_eq!(7, packet.send_with_response().read_shallow::<u64>());
+        worker.join().unwrap();
+    }
+
+    #[cfg(unix)]
+    #[rstest]
+    #[case(None)]
+    #[case(Some(64))]
+    fn message_based_stream(#[case] part_size: Option<usize>) {
+        let (server_stream, client_stream) = MockSeqPacketStream::pair();
+        let mut client_handlers: HashMap<u64, Handler<MockSeqPacketStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+
+        let server = Connection::new(server_stream, HashMap::new(), part_size);
+        let _client = Connection::new(client_stream, client_handlers, part_size);
+
+        // Packets bigger than the part size are fragmented and interleaved with other priorities.
+        send_from_threads(&server, 4, 32, 1000);
+        send_from_threads(&server, 2, 8, 20_000);
+        assert!(!server.is_closed());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn message_based_stream_rejects_bigger_part_size() {
+        let (server_stream, client_stream) = MockSeqPacketStream::pair();
+        let mut client_handlers: HashMap<u64, Handler<MockSeqPacketStream>> = HashMap::new();
+        client_handlers.insert(9, Box::new(echo_handle));
+
+        let server = Connection::new(server_stream, HashMap::new(), Some(256));
+        let client = Connection::new(client_stream, client_handlers, Some(64));
+
+        let mut packet = server.new_packet(9);
+        packet.write_shallow(200u32);
+        for i in 0..200u32 {
+            packet.write_shallow(i as u8);
+        }
+        assert!(packet.try_send_with_response().is_err());
+        assert!(client.is_closed());
+    }
+
+    #[test]
+    fn reverse_call_while_waiting_for_response() {
+        // Handlers of both sides share the thread pool of the test process, the blocked server handler would starve
+        // the client one.
+        if rayon::current_num_threads() < 2 {
+            eprintln!("skipped, thread pool has a single thread");
+            return;
+        }
+
+        static SERVER: OnceLock<Weak<Connection<MockStream>>> = OnceLock::new();
+
+        // Server calls back the client before it responds, like host calls guest callbacks during commands.
+        fn server_handle(mut packet: Packet<MockStream>) {
+            let value = packet.read_shallow::<u32>();
+
+            let server = SERVER.get().unwrap().upgrade().unwrap();
+            let mut call = server.new_packet(2);
+            call.write_shallow(value);
+            let callback_result = call.send_with_response().read_shallow::<u32>();
+
+            let mut response = packet.write_response(None);
+            response.write_shallow(callback_result + 1);
+            response.send();
+        }
+
+        fn client_handle(mut packet: Packet<MockStream>) {
+            let value = packet.read_shallow::<u32>();
+            let mut response = packet.write_response(None);
+            response.write_shallow(value * 2);
+            response.send();
+        }
+
+        let mut server_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        server_handlers.insert(1, Box::new(server_handle));
+        let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+        client_handlers.insert(2, Box::new(client_handle));
+
+        let (server, client) = new_mock_connection(None, server_handlers, client_handlers);
+        SERVER.set(Arc::downgrade(&server)).unwrap();
+
+        let mut packet = client.new_packet(1);
+        packet.write_shallow(20u32);
+        assert_eq!(41, packet.send_with_response().read_shallow::<u32>());
+    }
+
+    /// Compares throughput of a single stream connection with a striped one.
+    ///
+    /// Run with `cargo test -p wie-transport -- --ignored --nocapture striping_throughput`.
+    #[test]
+    #[ignore]
+    fn striping_throughput() {
+        const THREADS: usize = 8;
+        const PACKETS: usize = 256;
+        const PACKET_LENGTH: u32 = 64 * 1024;
+
+        for stream_count in [1, 2, 4, 8] {
+            let mut client_handlers: HashMap<u64, Handler<MockStream>> = HashMap::new();
+            client_handlers.insert(9, Box::new(echo_handle));
+            let (server, _client) =
+                new_mock_striped_connection(stream_count, None, HashMap::new(), client_handlers);
+
+            let elapsed = send_from_threads(&server, THREADS, PACKETS, PACKET_LENGTH);
+            let bytes = (THREADS * PACKETS) as f64 * PACKET_LENGTH as f64;
+            println!(
+                "{stream_count} stream(s): {:.2?}, {:.1} MiB/s",
+                elapsed,
+                bytes / elapsed.as_secs_f64() / (1024.0 * 1024.0)
+            );
+        }
+    }
 }
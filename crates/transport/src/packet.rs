@@ -2,33 +2,135 @@ use std::{
     cell::UnsafeCell,
     ffi::c_char,
     mem::{self, MaybeUninit},
-    ptr, slice,
-    thread::ThreadId,
+    panic, ptr, slice,
+    thread::{self, ThreadId},
 };
 
 use aligned_vec::AVec;
 use cdump::{CDeserialize, CDumpReader, CDumpWriter, CSerialize};
-use wie_common::stream::{UnsafeRead, UnsafeWrite};
+use wie_common::stream::SplitStream;
 
-use crate::Connection;
+use crate::{
+    errors::{ConnectionClosedError, MalformedPacket, ProtocolError},
+    Connection,
+};
+
+/// Longest packet which is accepted from peer, longer ones close the connection before their data is buffered.
+pub const MAX_PACKET_LENGTH: usize = 1 << 30;
 
 #[derive(Clone, Debug)]
 #[repr(C)]
 pub(crate) struct PacketHeader {
     pub length: usize,
+    /// Thread of the peer which sent the packet, its packets to handlers are handled in the order it sent them.
+    pub sender: u64,
+    /// Thread of the peer which waits for the response, it is missing for packets sent without response.
     pub sender_thread_id: Option<ThreadId>,
     pub destination: Destination,
+    pub priority: Priority,
+}
+
+impl PacketHeader {
+    /// Checks header of a packet received from peer, field by field, and returns it once it is valid.
+    ///
+    /// Peer is not trusted, its bytes cannot be simply cast to the header, as invalid discriminants of its enums are
+    /// undefined behaviour.
+    pub(crate) fn decode(bytes: &[u8]) -> Result<&Self, ProtocolError> {
+        assert!(bytes.len() >= mem::size_of::<Self>());
+        let read_u64 = |offset: usize| {
+            let mut value = [0u8; mem::size_of::<u64>()];
+            value.copy_from_slice(&bytes[offset..offset + mem::size_of::<u64>()]);
+            u64::from_ne_bytes(value)
+        };
+
+        let length = read_u64(mem::offset_of!(Self, length)) as usize;
+        if !(mem::size_of::<Self>()..=MAX_PACKET_LENGTH).contains(&length) {
+            return Err(ProtocolError::Length(length));
+        }
+
+        let offset = mem::offset_of!(Self, destination);
+        let mut tag = [0u8; mem::size_of::<u32>()];
+        tag.copy_from_slice(&bytes[offset..offset + mem::size_of::<u32>()]);
+        match u32::from_ne_bytes(tag) {
+            DESTINATION_THREAD => {
+                // Thread IDs are never zero.
+                if read_u64(offset + mem::offset_of!(RawDestination, value)) == 0 {
+                    return Err(ProtocolError::ThreadId);
+                }
+            }
+            DESTINATION_HANDLER | DESTINATION_HEARTBEAT | DESTINATION_HEARTBEAT_ACK => {}
+            tag => return Err(ProtocolError::Destination(tag)),
+        }
+
+        let priority = bytes[mem::offset_of!(Self, priority)];
+        if Priority::from_lane(priority).is_none() {
+            return Err(ProtocolError::Priority(priority));
+        }
+
+        Ok(unsafe { &*(bytes.as_ptr() as *const Self) })
+    }
 }
 
+/// Layout of [`Destination`] is defined by its `repr`, as a tag followed by the value of the variant.
 #[derive(Clone, Debug)]
+#[repr(u32)]
 pub(crate) enum Destination {
-    Thread(ThreadId),
-    Handler(u64),
+    Thread(ThreadId) = DESTINATION_THREAD,
+    Handler(u64) = DESTINATION_HANDLER,
+    /// Control packet without payload, which peer answers with [`Destination::HeartbeatAck`].
+    Heartbeat = DESTINATION_HEARTBEAT,
+    HeartbeatAck = DESTINATION_HEARTBEAT_ACK,
+}
+
+const DESTINATION_THREAD: u32 = 0;
+const DESTINATION_HANDLER: u32 = 1;
+const DESTINATION_HEARTBEAT: u32 = 2;
+const DESTINATION_HEARTBEAT_ACK: u32 = 3;
+
+#[repr(C)]
+struct RawDestination {
+    tag: u32,
+    value: u64,
+}
+
+const _: () = assert!(mem::size_of::<Destination>() == mem::size_of::<RawDestination>());
+const _: () = assert!(mem::size_of::<ThreadId>() == mem::size_of::<u64>());
+
+/// Priority class of a packet.
+///
+/// Every class is written through its own lane. Packets bigger than the part size are split into fragments, and the
+/// writer checks higher lanes before each fragment, so small latency-critical packets do not wait behind bulk data.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[repr(u8)]
+pub enum Priority {
+    Bulk = 0,
+    #[default]
+    Normal = 1,
+    High = 2,
+}
+
+impl Priority {
+    pub(crate) const COUNT: usize = 3;
+
+    #[inline]
+    pub(crate) fn lane(self) -> usize {
+        self as usize
+    }
+
+    #[inline]
+    pub(crate) fn from_lane(lane: u8) -> Option<Self> {
+        match lane {
+            0 => Some(Self::Bulk),
+            1 => Some(Self::Normal),
+            2 => Some(Self::High),
+            _ => None,
+        }
+    }
 }
 
 pub struct PacketWriter<'c, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     connection: &'c Connection<T>,
     buffer: AVec<u8>,
@@ -38,7 +140,7 @@ where
 
 impl<'c, T> PacketWriter<'c, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     #[inline]
     pub(crate) fn new(
@@ -46,9 +148,11 @@ where
         mut buffer: AVec<u8>,
         read_buffer: AVec<u8>,
         destination: Destination,
+        priority: Priority,
     ) -> Self {
         let header = unsafe { &mut *(buffer.as_mut_ptr() as *mut PacketHeader) };
         header.destination = destination;
+        header.priority = priority;
         Self {
             connection,
             buffer,
@@ -56,6 +160,19 @@ where
         }
     }
 
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        let header = unsafe { &*(self.buffer.as_ptr() as *const PacketHeader) };
+        header.priority
+    }
+
+    /// Changes priority class of this packet, responses inherit it by default.
+    #[inline]
+    pub fn set_priority(&mut self, priority: Priority) {
+        let header = unsafe { &mut *(self.buffer.as_mut_ptr() as *mut PacketHeader) };
+        header.priority = priority;
+    }
+
     #[inline]
     pub fn write_shallow<TO>(&mut self, object: TO) {
         self.align::<TO>();
@@ -170,13 +287,28 @@ where
     #[inline]
     pub fn send_with_response(mut self) -> Packet<'c, T> {
         let buffer = mem::replace(&mut self.buffer, AVec::with_capacity(0, 0));
-        let packet = self.connection.send_with_response(buffer);
-        self.connection.push_buffer(mem::replace(
+        let connection = self.connection;
+        connection.push_buffer(mem::replace(
+            &mut self.read_buffer,
+            AVec::with_capacity(0, 0),
+        ));
+        // Writer is forgotten before waiting, which panics when the connection is closed.
+        mem::forget(self);
+        connection.send_with_response(buffer)
+    }
+
+    /// Same as [`PacketWriter::send_with_response`], but returns error instead of panicking, when the connection is
+    /// closed before the response arrives.
+    #[inline]
+    pub fn try_send_with_response(mut self) -> Result<Packet<'c, T>, ConnectionClosedError> {
+        let buffer = mem::replace(&mut self.buffer, AVec::with_capacity(0, 0));
+        let connection = self.connection;
+        connection.push_buffer(mem::replace(
             &mut self.read_buffer,
             AVec::with_capacity(0, 0),
         ));
         mem::forget(self);
-        packet
+        connection.try_send_with_response(buffer)
     }
 
     #[inline]
@@ -201,16 +333,18 @@ where
 
 impl<T> Drop for PacketWriter<'_, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     fn drop(&mut self) {
-        panic!("PacketWriter dropped without sending packet.")
+        if !thread::panicking() {
+            panic!("PacketWriter dropped without sending packet.")
+        }
     }
 }
 
 unsafe impl<T> CDumpWriter for PacketWriter<'_, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     #[inline]
     fn align<TO>(&mut self) {
@@ -235,7 +369,7 @@ where
 
 pub struct Packet<'c, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     connection: &'c Connection<T>,
     buffer: UnsafeCell<AVec<u8>>,
@@ -244,7 +378,7 @@ where
 
 impl<'c, T> Packet<'c, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     pub(crate) fn new(connection: &'c Connection<T>, buffer: AVec<u8>) -> Self {
         Self {
@@ -261,36 +395,44 @@ where
         }
     }
 
+    #[inline]
+    pub fn priority(&self) -> Priority {
+        self.header().priority
+    }
+
+    /// Thread of the peer which waits for the response. Packets sent without response do not have it.
+    #[inline]
+    pub fn sender_thread_id(&self) -> Option<ThreadId> {
+        self.header().sender_thread_id
+    }
+
     #[inline]
     pub fn read_shallow<TO>(&mut self) -> TO {
         self.align::<TO>();
-        let size = mem::size_of::<TO>();
+        let start = self.claim(mem::size_of::<TO>());
 
         let mut object = MaybeUninit::<TO>::uninit();
         unsafe {
             ptr::copy_nonoverlapping(
-                self.buffer.get_mut()[self.read..].as_ptr(),
+                self.buffer.get_mut()[start..].as_ptr(),
                 &mut object as *mut _ as *mut u8,
-                size,
+                mem::size_of::<TO>(),
             );
         }
-
-        self.read += size;
         unsafe { object.assume_init() }
     }
 
     #[inline]
     pub fn read_to_raw_ptr<TO>(&mut self, ptr: *mut TO) {
         self.align::<TO>();
-        let size = mem::size_of::<TO>();
+        let start = self.claim(mem::size_of::<TO>());
         unsafe {
             ptr::copy_nonoverlapping(
-                self.buffer.get_mut()[self.read..].as_ptr(),
+                self.buffer.get_mut()[start..].as_ptr(),
                 ptr as *mut u8,
-                size,
+                mem::size_of::<TO>(),
             );
         }
-        self.read += size;
     }
 
     #[inline]
@@ -299,9 +441,8 @@ where
             ptr::null()
         } else {
             self.align::<TO>();
-            let ptr = unsafe { self.buffer.get_mut().as_ptr().add(self.read) } as *const TO;
-            self.read += mem::size_of::<TO>();
-            ptr
+            let start = self.claim(mem::size_of::<TO>());
+            unsafe { self.buffer.get_mut().as_ptr().add(start) as *const TO }
         }
     }
 
@@ -320,25 +461,27 @@ where
 
     #[inline]
     pub fn read_null_str(&mut self) -> *const c_char {
-        if self.buffer.get_mut()[self.read] == 0 {
-            self.read += 1;
-            return ptr::null();
-        }
-
         let start = self.read;
-        while self.buffer.get_mut()[self.read] != 0 {
-            self.read += 1;
-        }
+        let Some(len) = self
+            .buffer
+            .get_mut()
+            .get(start..)
+            .and_then(|rest| rest.iter().position(|x| *x == 0))
+        else {
+            malformed()
+        };
 
-        self.read += 1;
-        self.buffer.get_mut()[start..self.read].as_ptr() as *const c_char
+        self.read += len + 1;
+        match len {
+            0 => ptr::null(),
+            _ => self.buffer.get_mut()[start..].as_ptr() as *const c_char,
+        }
     }
 
     #[inline]
     pub fn read_is_null_ptr(&mut self) -> bool {
-        let is_null = self.buffer.get_mut()[self.read] == 1;
-        self.read += 1;
-        is_null
+        let start = self.claim(1);
+        self.buffer.get_mut()[start] == 1
     }
 
     /// # Safety
@@ -352,7 +495,7 @@ where
         if !destination.is_null() && c != 0 {
             self.align::<TO>();
 
-            let size = mem::size_of::<TO>() * c as usize;
+            let size = array_size::<TO>(c);
             ptr::copy_nonoverlapping(self.read_raw_slice(size), destination as *mut u8, size);
 
             for i in 0..c as usize {
@@ -373,9 +516,8 @@ where
         let count = self.read_shallow::<u32>();
         if count != 0 {
             self.align::<TO>();
-            let read = self.get_read();
+            let read = self.claim(array_size::<TO>(count));
             let size = mem::size_of::<TO>();
-            self.add_read(size * count as usize);
 
             for i in 0..count as usize {
                 TO::deserialize_to_without_shallow_copy(self, self.as_mut_ptr_at(read + size * i));
@@ -426,6 +568,17 @@ where
         unimplemented!()
     }
 
+    /// Detaches the packet from the connection, so it can be handled by another thread, see
+    /// [`Connection::handle_detached`].
+    pub fn detach(mut self) -> DetachedPacket {
+        DetachedPacket {
+            connection: self.connection.id(),
+            buffer: mem::replace(&mut self.buffer, UnsafeCell::new(AVec::with_capacity(0, 0)))
+                .into_inner(),
+            read: self.read,
+        }
+    }
+
     pub fn write_response(mut self, destination: Option<u64>) -> PacketWriter<'c, T> {
         if self.buffer.get_mut().len() != self.read {
             panic!("Packet buffer is not fully read.");
@@ -439,6 +592,7 @@ where
             },
         };
 
+        let priority = self.header().priority;
         let read_buffer =
             mem::replace(&mut self.buffer, UnsafeCell::new(AVec::with_capacity(0, 0))).into_inner();
         PacketWriter::new(
@@ -446,6 +600,7 @@ where
             self.connection.pop_buffer(),
             read_buffer,
             destination,
+            priority,
         )
     }
 
@@ -457,15 +612,69 @@ where
         }
         debug_assert_eq!(0, self.read % mem::align_of::<TO>());
     }
+
+    /// Marks `len` bytes as read and returns index of the first one.
+    ///
+    /// # Panics
+    /// With [`MalformedPacket`], when the packet is shorter.
+    #[inline]
+    fn claim(&mut self, len: usize) -> usize {
+        let start = self.read;
+        match start.checked_add(len) {
+            Some(end) if end <= self.buffer.get_mut().len() => {
+                self.read = end;
+                start
+            }
+            _ => malformed(),
+        }
+    }
+}
+
+/// Size of the array with `count` elements, sizes which do not fit in memory are reported as malformed packet.
+#[inline]
+fn array_size<TO>(count: u32) -> usize {
+    mem::size_of::<TO>()
+        .checked_mul(count as usize)
+        .unwrap_or_else(|| malformed())
+}
+
+#[cold]
+fn malformed() -> ! {
+    panic::panic_any(MalformedPacket)
+}
+
+/// Packet which does not borrow its connection, it is handled by the connection which received it.
+pub struct DetachedPacket {
+    /// Id of the connection, see [`Connection::id`].
+    connection: u64,
+    buffer: AVec<u8>,
+    read: usize,
+}
+
+impl DetachedPacket {
+    /// Attaches the packet back to the connection which received it, other connections return `None`.
+    pub(crate) fn attach<T>(self, connection: &Connection<T>) -> Option<Packet<'_, T>>
+    where
+        T: SplitStream,
+    {
+        if self.connection != connection.id() {
+            return None;
+        }
+        Some(Packet {
+            connection,
+            buffer: UnsafeCell::new(self.buffer),
+            read: self.read,
+        })
+    }
 }
 
 impl<T> Drop for Packet<'_, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     fn drop(&mut self) {
-        // Ignore if buffer is cleared.
-        if self.buffer.get_mut().capacity() != 0 {
+        // Ignore if buffer is cleared, or the handler panicked while reading it.
+        if self.buffer.get_mut().capacity() != 0 && !thread::panicking() {
             if self.buffer.get_mut().len() != self.read {
                 panic!("Packet buffer is not fully read.");
             }
@@ -479,21 +688,20 @@ where
 
 unsafe impl<T> CDumpReader for Packet<'_, T>
 where
-    T: UnsafeWrite + UnsafeRead + Send + Sync + 'static,
+    T: SplitStream,
 {
     fn align<TO>(&mut self) {
         self.align::<TO>();
     }
 
     fn add_read(&mut self, len: usize) {
-        self.read += len;
+        self.claim(len);
     }
 
     unsafe fn read_raw_slice(&mut self, len: usize) -> *const u8 {
+        let start = self.claim(len);
         let s = unsafe { &*self.buffer.get() };
-        let ptr = s.as_ptr().add(self.read);
-        self.read += len;
-        ptr
+        s.as_ptr().add(start)
     }
 
     unsafe fn as_mut_ptr_at<TO>(&self, index: usize) -> *mut TO {
@@ -511,7 +719,7 @@ mod tests {
     use std::{
         cell::UnsafeCell,
         ffi::CStr,
-        mem,
+        mem, panic,
         ptr::{self, NonNull},
         slice,
     };
@@ -520,9 +728,9 @@ mod tests {
     use cdump::{CDeserialize, CSerialize};
     use wie_common::stream::mock::MockStream;
 
-    use crate::packet::PacketHeader;
+    use crate::{errors::MalformedPacket, packet::PacketHeader};
 
-    use super::{Destination, Packet, PacketWriter};
+    use super::{Destination, Packet, PacketWriter, Priority};
 
     fn helper<F1, F2>(write: F1, read: F2)
     where
@@ -536,6 +744,7 @@ mod tests {
             avec![0; mem::size_of::<PacketHeader>()],
             AVec::with_capacity(1, 0),
             Destination::Handler(0),
+            Priority::Normal,
         );
         write(&mut writer);
 
@@ -604,6 +813,28 @@ mod tests {
         )
     }
 
+    #[test]
+    fn vk_array_longer_than_packet_is_malformed() {
+        let result = panic::catch_unwind(|| {
+            helper(
+                |packet| packet.write_shallow(u32::MAX),
+                |packet| _ = unsafe { packet.read_vk_array_ref_mut::<u64>() },
+            )
+        });
+        assert!(result.unwrap_err().is::<MalformedPacket>());
+    }
+
+    #[test]
+    fn null_str_without_terminator_is_malformed() {
+        let result = panic::catch_unwind(|| {
+            helper(
+                |packet| packet.write_shallow(0x61626364u32),
+                |packet| _ = packet.read_null_str(),
+            )
+        });
+        assert!(result.unwrap_err().is::<MalformedPacket>());
+    }
+
     #[test]
     fn vk_array_check_if_inline() {
         #[derive(CSerialize, CDeserialize, Copy, Clone)]
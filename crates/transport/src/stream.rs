@@ -0,0 +1,414 @@
+use std::{
+    io::{self, IoSlice, IoSliceMut, Read, Write},
+    net::{IpAddr, TcpListener, TcpStream},
+    num::NonZeroU32,
+    time::Duration,
+};
+
+#[cfg(target_os = "linux")]
+use std::os::fd::{AsRawFd, FromRawFd, RawFd};
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+use wie_common::stream::{SplitStream, StreamShutdown};
+use wie_transport_vsock::{
+    VsockListener, VsockReadHalf, VsockShutdown, VsockStream, VsockType, VsockWriteHalf,
+};
+
+use crate::endpoint::Endpoint;
+
+/// Stream of any transport, so a single build works with every [`Endpoint`].
+#[derive(Debug)]
+pub enum Stream {
+    Vsock(VsockStream),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+macro_rules! dispatch {
+    ($value:expr, $enum:ident, $inner:ident => $body:expr) => {
+        match $value {
+            $enum::Vsock($inner) => $body,
+            $enum::Tcp($inner) => $body,
+            #[cfg(unix)]
+            $enum::Unix($inner) => $body,
+        }
+    };
+}
+
+#[cfg(target_os = "linux")]
+impl Stream {
+    /// Takes ownership of a connected socket inherited from the parent process, like streams of a session which is
+    /// handed to a worker process. Transport is detected from the socket domain.
+    ///
+    /// # Safety
+    /// `fd` must be an open socket which is connected and is not owned by anything else. It is not closed when this
+    /// fails.
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        match socket_domain(fd)? {
+            libc::AF_VSOCK => VsockStream::from_fd(fd).map(Self::Vsock),
+            libc::AF_INET | libc::AF_INET6 => Ok(Self::Tcp(TcpStream::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Self::Unix(UnixStream::from_raw_fd(fd))),
+            domain => Err(unsupported_domain(domain)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+impl AsRawFd for Stream {
+    fn as_raw_fd(&self) -> RawFd {
+        dispatch!(self, Stream, inner => inner.as_raw_fd())
+    }
+}
+
+impl Stream {
+    pub fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        dispatch!(self, Stream, inner => inner.set_read_timeout(timeout))
+    }
+
+    /// Returns identity of the peer, which stays the same when it reconnects.
+    pub fn peer(&self) -> io::Result<Peer> {
+        match self {
+            #[cfg(not(target_os = "windows"))]
+            Stream::Vsock(inner) => inner.peer_addr().map(|address| Peer::Vsock(address.cid.0)),
+            #[cfg(target_os = "windows")]
+            Stream::Vsock(_) => Err(io::ErrorKind::Unsupported.into()),
+            Stream::Tcp(inner) => inner.peer_addr().map(|address| Peer::Tcp(address.ip())),
+            #[cfg(unix)]
+            Stream::Unix(inner) => peer_user(inner).map(Peer::Unix),
+        }
+    }
+}
+
+/// Identity of the peer of a stream, sessions are resumed only by the peer which started them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Peer {
+    /// CID of the virtual machine.
+    Vsock(u32),
+    /// IP address, without the port which changes with every connection.
+    Tcp(IpAddr),
+    /// User ID of the local process.
+    Unix(u32),
+}
+
+#[cfg(target_os = "linux")]
+fn peer_user(stream: &UnixStream) -> io::Result<u32> {
+    let mut credentials = libc::ucred {
+        pid: 0,
+        uid: 0,
+        gid: 0,
+    };
+    let mut length = std::mem::size_of::<libc::ucred>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            stream.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_PEERCRED,
+            &mut credentials as *mut libc::ucred as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    match result < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(credentials.uid),
+    }
+}
+
+#[cfg(all(unix, not(target_os = "linux")))]
+fn peer_user(_stream: &UnixStream) -> io::Result<u32> {
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+impl Read for Stream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, Stream, inner => inner.read(buf))
+    }
+}
+
+impl Write for Stream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, Stream, inner => inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(self, Stream, inner => inner.flush())
+    }
+}
+
+impl SplitStream for Stream {
+    type ReadHalf = ReadHalf;
+    type WriteHalf = WriteHalf;
+    type Shutdown = Shutdown;
+
+    fn split(self) -> (ReadHalf, WriteHalf, Shutdown) {
+        match self {
+            Stream::Vsock(inner) => {
+                let (read, write, shutdown) = inner.split();
+                (
+                    ReadHalf::Vsock(read),
+                    WriteHalf::Vsock(write),
+                    Shutdown::Vsock(shutdown),
+                )
+            }
+            Stream::Tcp(inner) => {
+                let (read, write, shutdown) = inner.split();
+                (
+                    ReadHalf::Tcp(read),
+                    WriteHalf::Tcp(write),
+                    Shutdown::Tcp(shutdown),
+                )
+            }
+            #[cfg(unix)]
+            Stream::Unix(inner) => {
+                let (read, write, shutdown) = inner.split();
+                (
+                    ReadHalf::Unix(read),
+                    WriteHalf::Unix(write),
+                    Shutdown::Unix(shutdown),
+                )
+            }
+        }
+    }
+
+    fn is_message_based(&self) -> bool {
+        dispatch!(self, Stream, inner => inner.is_message_based())
+    }
+}
+
+#[derive(Debug)]
+pub enum ReadHalf {
+    Vsock(VsockReadHalf),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Read for ReadHalf {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        dispatch!(self, ReadHalf, inner => inner.read(buf))
+    }
+
+    fn read_vectored(&mut self, bufs: &mut [IoSliceMut<'_>]) -> io::Result<usize> {
+        dispatch!(self, ReadHalf, inner => inner.read_vectored(bufs))
+    }
+}
+
+#[derive(Debug)]
+pub enum WriteHalf {
+    Vsock(VsockWriteHalf),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl Write for WriteHalf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        dispatch!(self, WriteHalf, inner => inner.write(buf))
+    }
+
+    fn write_vectored(&mut self, bufs: &[IoSlice<'_>]) -> io::Result<usize> {
+        dispatch!(self, WriteHalf, inner => inner.write_vectored(bufs))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        dispatch!(self, WriteHalf, inner => inner.flush())
+    }
+}
+
+#[derive(Debug)]
+pub enum Shutdown {
+    Vsock(VsockShutdown),
+    Tcp(TcpStream),
+    #[cfg(unix)]
+    Unix(UnixStream),
+}
+
+impl StreamShutdown for Shutdown {
+    fn shutdown(&self) -> io::Result<()> {
+        dispatch!(self, Shutdown, inner => StreamShutdown::shutdown(inner))
+    }
+}
+
+/// Listener of any transport, which accepts [`Stream`]s.
+#[derive(Debug)]
+pub enum Listener {
+    Vsock(VsockListener),
+    Tcp(TcpListener),
+    #[cfg(unix)]
+    Unix(UnixListener),
+}
+
+impl Listener {
+    /// Listens on the endpoint, vsock endpoints accept streams of `ty`.
+    ///
+    /// Unix socket file left by a process which is not running anymore is replaced.
+    pub fn bind(
+        endpoint: &Endpoint,
+        max_connections: NonZeroU32,
+        ty: VsockType,
+    ) -> io::Result<Self> {
+        match endpoint {
+            Endpoint::Vsock(address) => {
+                VsockListener::bind_with_type(*address, max_connections, ty)
+                    .map(Self::Vsock)
+                    .map_err(io::Error::other)
+            }
+            Endpoint::Tcp(address) => TcpListener::bind(address.as_str()).map(Self::Tcp),
+            #[cfg(unix)]
+            Endpoint::Unix(path) => match UnixListener::bind(path) {
+                Err(e)
+                    if e.kind() == io::ErrorKind::AddrInUse
+                        && UnixStream::connect(path).is_err() =>
+                {
+                    std::fs::remove_file(path)?;
+                    UnixListener::bind(path).map(Self::Unix)
+                }
+                result => result.map(Self::Unix),
+            },
+        }
+    }
+
+    /// Takes ownership of a listening socket inherited from the parent process, like the ones passed by systemd socket
+    /// activation. Transport is detected from the socket domain.
+    ///
+    /// # Safety
+    /// `fd` must be an open socket which listens for connections and is not owned by anything else. It is not closed
+    /// when this fails.
+    #[cfg(target_os = "linux")]
+    pub unsafe fn from_fd(fd: RawFd) -> io::Result<Self> {
+        match socket_domain(fd)? {
+            libc::AF_VSOCK => VsockListener::from_fd(fd).map(Self::Vsock),
+            libc::AF_INET | libc::AF_INET6 => Ok(Self::Tcp(TcpListener::from_raw_fd(fd))),
+            libc::AF_UNIX => Ok(Self::Unix(UnixListener::from_raw_fd(fd))),
+            domain => Err(unsupported_domain(domain)),
+        }
+    }
+
+    pub fn accept(&self) -> io::Result<Stream> {
+        match self {
+            Listener::Vsock(listener) => listener
+                .accept(None)
+                .map(|(stream, _)| Stream::Vsock(stream)),
+            Listener::Tcp(listener) => {
+                let (stream, _) = listener.accept()?;
+                stream.set_nodelay(true)?;
+                Ok(Stream::Tcp(stream))
+            }
+            #[cfg(unix)]
+            Listener::Unix(listener) => listener.accept().map(|(stream, _)| Stream::Unix(stream)),
+        }
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn socket_domain(fd: RawFd) -> io::Result<libc::c_int> {
+    let mut domain: libc::c_int = 0;
+    let mut length = std::mem::size_of::<libc::c_int>() as libc::socklen_t;
+    let result = unsafe {
+        libc::getsockopt(
+            fd,
+            libc::SOL_SOCKET,
+            libc::SO_DOMAIN,
+            &mut domain as *mut libc::c_int as *mut libc::c_void,
+            &mut length,
+        )
+    };
+    match result < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(domain),
+    }
+}
+
+#[cfg(target_os = "linux")]
+fn unsupported_domain(domain: libc::c_int) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidInput,
+        format!("unsupported socket domain {domain}"),
+    )
+}
+
+#[cfg(all(test, debug_assertions, target_os = "linux"))]
+mod tests {
+    use std::{
+        io::{self, Read, Write},
+        net::{Ipv4Addr, TcpListener, TcpStream},
+        os::{
+            fd::IntoRawFd,
+            unix::net::{UnixListener, UnixStream},
+        },
+    };
+
+    use super::{Listener, Peer, Stream};
+
+    #[test]
+    fn from_fd_tcp() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let listener = unsafe { Listener::from_fd(listener.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Tcp(_)));
+
+        let mut client = TcpStream::connect(address).unwrap();
+        let mut stream = listener.accept().unwrap();
+        assert!(matches!(stream, Stream::Tcp(_)));
+        client.write_all(&[1, 2, 3]).unwrap();
+        let mut buffer = [0; 3];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!([1, 2, 3], buffer);
+    }
+
+    #[test]
+    fn from_fd_unix() {
+        let path = std::env::temp_dir().join(format!("wie-from-fd-{}.sock", std::process::id()));
+        _ = std::fs::remove_file(&path);
+        let listener = UnixListener::bind(&path).unwrap();
+        let listener = unsafe { Listener::from_fd(listener.into_raw_fd()) }.unwrap();
+        assert!(matches!(listener, Listener::Unix(_)));
+
+        let _client = UnixStream::connect(&path).unwrap();
+        assert!(matches!(listener.accept().unwrap(), Stream::Unix(_)));
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn stream_from_fd_unix() {
+        let (client, stream) = UnixStream::pair().unwrap();
+        let mut stream = unsafe { Stream::from_fd(stream.into_raw_fd()) }.unwrap();
+        assert!(matches!(stream, Stream::Unix(_)));
+
+        (&client).write_all(&[4, 5, 6]).unwrap();
+        let mut buffer = [0; 3];
+        stream.read_exact(&mut buffer).unwrap();
+        assert_eq!([4, 5, 6], buffer);
+    }
+
+    #[test]
+    fn peer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let _client = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        let stream = Stream::Tcp(listener.accept().unwrap().0);
+        assert_eq!(
+            Peer::Tcp(Ipv4Addr::LOCALHOST.into()),
+            stream.peer().unwrap()
+        );
+
+        let (_client, stream) = UnixStream::pair().unwrap();
+        let user = unsafe { libc::getuid() };
+        assert_eq!(Peer::Unix(user), Stream::Unix(stream).peer().unwrap());
+    }
+
+    #[test]
+    fn from_fd_unsupported_domain() {
+        let fd = unsafe { libc::socket(libc::AF_NETLINK, libc::SOCK_RAW, libc::NETLINK_ROUTE) };
+        if fd < 0 {
+            eprintln!("skipped, {}", io::Error::last_os_error());
+            return;
+        }
+
+        let error = unsafe { Listener::from_fd(fd) }.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        let error = unsafe { Stream::from_fd(fd) }.unwrap_err();
+        assert_eq!(io::ErrorKind::InvalidInput, error.kind());
+        assert_eq!(0, unsafe { libc::close(fd) });
+    }
+}
@@ -0,0 +1,1014 @@
+//! Configuration of the host, read from command line arguments, environment variables and TOML file, in this order
+//! of precedence.
+
+use std::{
+    collections::BTreeMap,
+    env,
+    fmt::Display,
+    fs, io, iter,
+    num::{NonZeroU32, NonZeroUsize},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use log::LevelFilter;
+use serde::{de, Deserialize, Deserializer};
+use thiserror::Error;
+use wie_driver_listener_vulkan::{
+    devices::{DevicePolicy, DeviceSelector},
+    driver::Driver,
+    quotas::Quotas,
+    settings::{Capture, ValidationLayers},
+};
+use wie_transport::{
+    endpoint::Endpoint,
+    handshake,
+    heartbeat::{self, Heartbeat},
+};
+
+const PORT: u32 = 13001;
+const DEFAULT_SESSION_RESUME_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(5);
+const MIB: u64 = 1024 * 1024;
+
+/// Host of wie, which executes Vulkan commands of guests.
+#[derive(Parser, Debug)]
+#[command(version, about)]
+struct Args {
+    /// Path to TOML config file.
+    #[arg(short, long, env = "WIE_CONFIG")]
+    config: Option<PathBuf>,
+    /// Endpoint to listen on, like `vsock://any:13001`, `tcp://0.0.0.0:13001` or `unix:///run/wie.sock`. Vsock
+    /// endpoints also listen for seqpacket streams on the next port.
+    #[arg(short, long, env = "WIE_LISTEN", value_delimiter = ',')]
+    listen: Vec<Endpoint>,
+    /// Maximum number of pending connections of every listener.
+    #[arg(long, env = "WIE_MAX_CONNECTIONS")]
+    max_connections: Option<NonZeroU32>,
+    #[arg(long, env = "WIE_LOG_LEVEL", value_parser = parse_level)]
+    log_level: Option<LevelFilter>,
+    /// Log level of a target, like `wie_transport=debug`.
+    #[arg(long = "log-target", env = "WIE_LOG_TARGETS", value_delimiter = ',')]
+    log_targets: Vec<LogTarget>,
+    /// Number of threads which execute guest commands, defaults to number of CPUs.
+    #[arg(long, env = "WIE_HANDLER_THREADS")]
+    handler_threads: Option<NonZeroUsize>,
+    /// Size of parts which packets are split into, message based streams of guests must use the same one.
+    #[arg(long, env = "WIE_PART_SIZE")]
+    part_size: Option<NonZeroUsize>,
+    /// Khronos validation layers policy: `auto`, `enabled` or `disabled`.
+    #[arg(long, env = "WIE_VALIDATION_LAYERS")]
+    validation_layers: Option<ValidationLayers>,
+    /// Checks commands of guests against the Vulkan registry before they are executed, like `sType` values, `pNext`
+    /// chains and required pointers.
+    #[arg(long, env = "WIE_HARDENED_VALIDATION")]
+    hardened_validation: Option<bool>,
+    /// Physical devices exposed to guests, in the order in which they see them, like `vendor:10de`, `device:10de:2684`,
+    /// `name:*RTX*`, `uuid:<device UUID>` or `type:cpu`. Every device is exposed when not set.
+    #[arg(long, env = "WIE_EXPOSE_DEVICES", value_delimiter = ',')]
+    expose_devices: Vec<DeviceSelector>,
+    /// Physical devices hidden from guests, with the same selectors as `--expose-devices`.
+    #[arg(long, env = "WIE_HIDE_DEVICES", value_delimiter = ',')]
+    hide_devices: Vec<DeviceSelector>,
+    /// Vulkan library which executes commands of guests instead of the system loader, like a loader built with other
+    /// drivers or a driver which is loaded directly, such as SwiftShader.
+    #[arg(long, env = "WIE_VULKAN_LIBRARY")]
+    vulkan_library: Option<PathBuf>,
+    /// ICD manifests which the Vulkan loader uses instead of the installed drivers, like the one of lavapipe.
+    #[arg(long, env = "WIE_ICD_FILES", value_delimiter = ',')]
+    icd_files: Vec<PathBuf>,
+    /// Device memory which every guest can allocate from each memory heap, allocations over it fail.
+    #[arg(long, env = "WIE_MEMORY_PER_HEAP_MIB")]
+    memory_per_heap_mib: Option<u64>,
+    /// Host objects which every guest can have alive, creating more fails.
+    #[arg(long, env = "WIE_MAX_OBJECTS")]
+    max_objects: Option<usize>,
+    /// Queue submissions per second of every guest, submissions over it are delayed.
+    #[arg(long, env = "WIE_SUBMISSIONS_PER_SECOND")]
+    submissions_per_second: Option<NonZeroU32>,
+    /// Share of GPU queue time of every guest, relative to weights of other guests which use the GPU at the same time.
+    #[arg(long, env = "WIE_SCHEDULING_WEIGHT")]
+    scheduling_weight: Option<NonZeroU32>,
+    /// Captures Vulkan calls to the file with GFXReconstruct layer.
+    #[arg(long, env = "WIE_CAPTURE_FILE")]
+    capture_file: Option<PathBuf>,
+    /// Frame ranges to capture, like `1-10,20`.
+    #[arg(long, env = "WIE_CAPTURE_FRAMES")]
+    capture_frames: Option<String>,
+    /// Time without data from the guest after which a heartbeat is sent, heartbeats are disabled when it is not set or
+    /// zero.
+    #[arg(long, env = "WIE_HEARTBEAT_INTERVAL_MS")]
+    heartbeat_interval_ms: Option<u64>,
+    /// Number of unanswered heartbeats after which the guest is treated as dead and its connection is closed.
+    #[arg(long, env = "WIE_HEARTBEAT_MISS_THRESHOLD")]
+    heartbeat_miss_threshold: Option<NonZeroU32>,
+    /// Time for which session is kept after the guest disconnected.
+    #[arg(long, env = "WIE_SESSION_RESUME_TIMEOUT_MS")]
+    session_resume_timeout_ms: Option<u64>,
+    /// Time for which shutdown waits for running commands of guests, before their objects are destroyed.
+    #[arg(long, env = "WIE_SHUTDOWN_TIMEOUT_MS")]
+    shutdown_timeout_ms: Option<u64>,
+    /// Serves every session in its own worker process, so a crash of the host driver takes down a single guest. Linux
+    /// only.
+    #[arg(long, env = "WIE_ISOLATE_SESSIONS")]
+    isolate_sessions: Option<bool>,
+    /// Restricts worker processes of isolated sessions to GPU devices, driver files and streams of their session.
+    #[arg(long, env = "WIE_SANDBOX")]
+    sandbox: Option<bool>,
+    /// Directory to which statistics of sessions are written, for the textfile collector of Prometheus node_exporter.
+    #[arg(long, env = "WIE_STATS_DIRECTORY")]
+    stats_directory: Option<PathBuf>,
+    /// Runs as a worker of the session, which is started by the host itself.
+    #[arg(long, hide = true)]
+    session_worker: Option<SessionWorker>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct File {
+    #[serde(deserialize_with = "parse_vec")]
+    listen: Vec<Endpoint>,
+    max_connections: Option<NonZeroU32>,
+    log: LogFile,
+    handler_threads: Option<NonZeroUsize>,
+    part_size: Option<NonZeroUsize>,
+    #[serde(deserialize_with = "parse_option")]
+    validation_layers: Option<ValidationLayers>,
+    hardened_validation: Option<bool>,
+    devices: DevicesFile,
+    driver: DriverFile,
+    quotas: QuotasFile,
+    scheduling: SchedulingFile,
+    capture: Option<CaptureFile>,
+    heartbeat_interval_ms: Option<u64>,
+    heartbeat_miss_threshold: Option<NonZeroU32>,
+    session_resume_timeout_ms: Option<u64>,
+    shutdown_timeout_ms: Option<u64>,
+    isolate_sessions: Option<bool>,
+    sandbox: Option<bool>,
+    stats_directory: Option<PathBuf>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct LogFile {
+    #[serde(deserialize_with = "parse_option")]
+    level: Option<LevelFilter>,
+    /// Levels of log targets, keyed by target.
+    #[serde(deserialize_with = "parse_map")]
+    targets: BTreeMap<String, LevelFilter>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DevicesFile {
+    #[serde(deserialize_with = "parse_vec")]
+    expose: Vec<DeviceSelector>,
+    #[serde(deserialize_with = "parse_vec")]
+    hide: Vec<DeviceSelector>,
+    /// Policies of guests, keyed by their vsock CID.
+    #[serde(deserialize_with = "parse_keys")]
+    guests: BTreeMap<u32, DevicePolicyFile>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct DevicePolicyFile {
+    #[serde(deserialize_with = "parse_vec")]
+    expose: Vec<DeviceSelector>,
+    #[serde(deserialize_with = "parse_vec")]
+    hide: Vec<DeviceSelector>,
+}
+
+impl From<DevicePolicyFile> for DevicePolicy {
+    fn from(file: DevicePolicyFile) -> Self {
+        Self {
+            expose: file.expose,
+            hide: file.hide,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct DriverFile {
+    library: Option<PathBuf>,
+    icd_files: Vec<PathBuf>,
+    /// Drivers of guests, keyed by their vsock CID.
+    #[serde(deserialize_with = "parse_keys")]
+    guests: BTreeMap<u32, GuestDriverFile>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct GuestDriverFile {
+    library: Option<PathBuf>,
+    icd_files: Vec<PathBuf>,
+}
+
+impl GuestDriverFile {
+    /// Returns driver of the guest, fields missing from the file are taken from the default driver.
+    fn or(self, default: &Driver) -> Driver {
+        Driver {
+            library: self.library.or_else(|| default.library.clone()),
+            icd_files: match self.icd_files.is_empty() {
+                true => default.icd_files.clone(),
+                false => self.icd_files,
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct QuotasFile {
+    memory_per_heap_mib: Option<u64>,
+    max_objects: Option<usize>,
+    submissions_per_second: Option<NonZeroU32>,
+    /// Quotas of guests, keyed by their vsock CID.
+    #[serde(deserialize_with = "parse_keys")]
+    guests: BTreeMap<u32, GuestQuotasFile>,
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields, rename_all = "kebab-case")]
+struct GuestQuotasFile {
+    memory_per_heap_mib: Option<u64>,
+    max_objects: Option<usize>,
+    submissions_per_second: Option<NonZeroU32>,
+}
+
+impl From<GuestQuotasFile> for Quotas {
+    fn from(file: GuestQuotasFile) -> Self {
+        Self {
+            memory_per_heap: file.memory_per_heap_mib.map(|mib| mib * MIB),
+            objects: file.max_objects,
+            submissions_per_second: file.submissions_per_second,
+        }
+    }
+}
+
+#[derive(Deserialize, Debug, Default)]
+#[serde(default, deny_unknown_fields)]
+struct SchedulingFile {
+    weight: Option<NonZeroU32>,
+    /// Scheduling of guests, keyed by their vsock CID.
+    #[serde(deserialize_with = "parse_keys")]
+    guests: BTreeMap<u32, GuestSchedulingFile>,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct GuestSchedulingFile {
+    weight: NonZeroU32,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(deny_unknown_fields)]
+struct CaptureFile {
+    file: PathBuf,
+    frames: Option<String>,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+struct LogTarget {
+    target: String,
+    level: LevelFilter,
+}
+
+impl FromStr for LogTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (target, level) = s
+            .split_once('=')
+            .ok_or_else(|| format!("log target must be in `target=level` format, got `{s}`"))?;
+        Ok(Self {
+            target: target.to_owned(),
+            level: parse_level(level)?,
+        })
+    }
+}
+
+/// Session which is served by the worker process, with number of its streams. Streams are inherited as file
+/// descriptors starting from [`SessionWorker::FIRST_FD`].
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct SessionWorker {
+    pub token: u64,
+    pub streams: usize,
+}
+
+impl SessionWorker {
+    pub const FIRST_FD: i32 = 3;
+}
+
+impl Display for SessionWorker {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:x}:{}", self.token, self.streams)
+    }
+}
+
+impl FromStr for SessionWorker {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let invalid = || format!("session worker must be in `token:streams` format, got `{s}`");
+        let (token, streams) = s.split_once(':').ok_or_else(invalid)?;
+        Ok(Self {
+            token: u64::from_str_radix(token, 16).map_err(|_| invalid())?,
+            streams: streams.parse().map_err(|_| invalid())?,
+        })
+    }
+}
+
+#[derive(Error, Debug)]
+pub enum ConfigError {
+    #[error("unable to read config file {0}: {1}")]
+    Read(PathBuf, #[source] io::Error),
+    #[error("invalid config file {0}: {1}")]
+    Parse(PathBuf, #[source] toml::de::Error),
+    #[error("guest {0} has its own ICD files, which needs isolated sessions on Linux")]
+    GuestIcdFiles(u32),
+    #[error("guest {0} has its own scheduling weight, which isolated sessions do not use")]
+    GuestWeight(u32),
+    #[error("ICD file {0} cannot be passed to the Vulkan loader, its path contains the separator of path lists")]
+    IcdFile(PathBuf),
+}
+
+#[derive(Debug)]
+pub struct Config {
+    pub listen: Vec<Endpoint>,
+    pub max_connections: NonZeroU32,
+    pub log_level: LevelFilter,
+    pub log_targets: Vec<(String, LevelFilter)>,
+    pub handler_threads: Option<usize>,
+    pub part_size: Option<usize>,
+    pub validation_layers: ValidationLayers,
+    pub hardened_validation: bool,
+    /// Physical devices which guests see, unless they have their own policy.
+    pub devices: DevicePolicy,
+    /// Physical devices which guests see, keyed by their vsock CID.
+    pub guest_devices: BTreeMap<u32, DevicePolicy>,
+    /// Vulkan implementation which serves guests, unless they have their own one.
+    pub driver: Driver,
+    /// Vulkan implementations which serve guests, keyed by their vsock CID.
+    pub guest_drivers: BTreeMap<u32, Driver>,
+    /// Limits of resources which guests can use, unless they have their own ones.
+    pub quotas: Quotas,
+    /// Limits of resources which guests can use, keyed by their vsock CID.
+    pub guest_quotas: BTreeMap<u32, Quotas>,
+    /// Share of GPU queue time of guests, unless they have their own one.
+    pub weight: NonZeroU32,
+    /// Shares of GPU queue time, keyed by vsock CID of guests.
+    pub guest_weights: BTreeMap<u32, NonZeroU32>,
+    pub capture: Option<Capture>,
+    /// Heartbeats of idle connections, which close connections of dead guests.
+    pub heartbeat: Option<Heartbeat>,
+    pub session_resume_timeout: Duration,
+    pub shutdown_timeout: Duration,
+    pub isolate_sessions: bool,
+    pub sandbox: bool,
+    /// Directory to which statistics of sessions are written, see [`stats`](crate::stats).
+    pub stats_directory: Option<PathBuf>,
+    pub session_worker: Option<SessionWorker>,
+}
+
+impl Config {
+    /// Reads command line arguments and the config file which they point to.
+    pub fn load() -> Result<Self, ConfigError> {
+        let args = Args::parse();
+        let file = match &args.config {
+            Some(path) => {
+                let content =
+                    fs::read_to_string(path).map_err(|e| ConfigError::Read(path.clone(), e))?;
+                toml::from_str(&content).map_err(|e| ConfigError::Parse(path.clone(), e))?
+            }
+            None => File::default(),
+        };
+        let config = Self::merge(args, file);
+        config.validate()?;
+        Ok(config)
+    }
+
+    /// Returns physical devices which the guest sees. Guests which are not connected through vsock, or which do not
+    /// have their own policy, get the default one.
+    pub fn device_policy(&self, cid: Option<u32>) -> DevicePolicy {
+        cid.and_then(|cid| self.guest_devices.get(&cid))
+            .unwrap_or(&self.devices)
+            .clone()
+    }
+
+    /// Returns Vulkan implementation which serves the guest, like [`Config::device_policy`] does.
+    pub fn driver(&self, cid: Option<u32>) -> &Driver {
+        cid.and_then(|cid| self.guest_drivers.get(&cid))
+            .unwrap_or(&self.driver)
+    }
+
+    /// Returns limits of resources which the guest can use, like [`Config::device_policy`] does.
+    pub fn quotas(&self, cid: Option<u32>) -> Quotas {
+        cid.and_then(|cid| self.guest_quotas.get(&cid))
+            .unwrap_or(&self.quotas)
+            .clone()
+    }
+
+    /// Returns share of GPU queue time of the guest, like [`Config::device_policy`] does.
+    pub fn weight(&self, cid: Option<u32>) -> NonZeroU32 {
+        cid.and_then(|cid| self.guest_weights.get(&cid).copied())
+            .unwrap_or(self.weight)
+    }
+
+    /// Checks that ICD files can be passed to the Vulkan loader, and that every guest which has its own ICD files gets
+    /// its own process, as the loader reads them from environment of the process. Guests cannot have their own weights
+    /// then, as the scheduler shares GPU time only between sessions of the same process.
+    fn validate(&self) -> Result<(), ConfigError> {
+        if let Some(file) = iter::once(&self.driver)
+            .chain(self.guest_drivers.values())
+            .flat_map(|driver| &driver.icd_files)
+            .find(|file| env::join_paths([file]).is_err())
+        {
+            return Err(ConfigError::IcdFile(file.clone()));
+        }
+
+        if self.isolate_sessions && cfg!(target_os = "linux") {
+            return match self
+                .guest_weights
+                .iter()
+                .find(|(_, weight)| **weight != self.weight)
+            {
+                Some((cid, _)) => Err(ConfigError::GuestWeight(*cid)),
+                None => Ok(()),
+            };
+        }
+        match self
+            .guest_drivers
+            .iter()
+            .find(|(_, driver)| driver.icd_files != self.driver.icd_files)
+        {
+            Some((cid, _)) => Err(ConfigError::GuestIcdFiles(*cid)),
+            None => Ok(()),
+        }
+    }
+
+    fn merge(args: Args, file: File) -> Self {
+        let listen = match (args.listen.is_empty(), file.listen.is_empty()) {
+            (false, _) => args.listen,
+            (true, false) => file.listen,
+            (true, true) => vec![Endpoint::host(PORT)],
+        };
+
+        // Targets of arguments override the same targets of the file.
+        let mut log_targets = file.log.targets;
+        log_targets.extend(args.log_targets.into_iter().map(|x| (x.target, x.level)));
+
+        // Arguments override lists of the default policy, policies of guests are only in the file.
+        let devices = DevicePolicy {
+            expose: match args.expose_devices.is_empty() {
+                true => file.devices.expose,
+                false => args.expose_devices,
+            },
+            hide: match args.hide_devices.is_empty() {
+                true => file.devices.hide,
+                false => args.hide_devices,
+            },
+        };
+        let guest_devices = file
+            .devices
+            .guests
+            .into_iter()
+            .map(|(cid, policy)| (cid, policy.into()))
+            .collect();
+
+        // Like the device policy, arguments override only the default driver. Guests take fields they do not set from it.
+        let driver = Driver {
+            library: args.vulkan_library.or(file.driver.library),
+            icd_files: match args.icd_files.is_empty() {
+                true => file.driver.icd_files,
+                false => args.icd_files,
+            },
+        };
+        let guest_drivers = file
+            .driver
+            .guests
+            .into_iter()
+            .map(|(cid, guest)| (cid, guest.or(&driver)))
+            .collect();
+
+        let quotas = Quotas {
+            memory_per_heap: args
+                .memory_per_heap_mib
+                .or(file.quotas.memory_per_heap_mib)
+                .map(|mib| mib * MIB),
+            objects: args.max_objects.or(file.quotas.max_objects),
+            submissions_per_second: args
+                .submissions_per_second
+                .or(file.quotas.submissions_per_second),
+        };
+        let guest_quotas = file
+            .quotas
+            .guests
+            .into_iter()
+            .map(|(cid, quotas)| (cid, quotas.into()))
+            .collect();
+        let guest_weights = file
+            .scheduling
+            .guests
+            .into_iter()
+            .map(|(cid, scheduling)| (cid, scheduling.weight))
+            .collect();
+
+        let heartbeat = args
+            .heartbeat_interval_ms
+            .or(file.heartbeat_interval_ms)
+            .filter(|interval| *interval != 0)
+            .map(|interval| Heartbeat {
+                interval: Duration::from_millis(interval),
+                miss_threshold: args
+                    .heartbeat_miss_threshold
+                    .or(file.heartbeat_miss_threshold)
+                    .map_or(heartbeat::DEFAULT_MISS_THRESHOLD, NonZeroU32::get),
+            });
+
+        let capture = match (args.capture_file, file.capture) {
+            (Some(file), capture) => Some(Capture {
+                file,
+                frames: args
+                    .capture_frames
+                    .or(capture.and_then(|capture| capture.frames)),
+            }),
+            (None, Some(capture)) => Some(Capture {
+                file: capture.file,
+                frames: args.capture_frames.or(capture.frames),
+            }),
+            (None, None) => None,
+        };
+
+        Self {
+            listen,
+            max_connections: args
+                .max_connections
+                .or(file.max_connections)
+                .unwrap_or(NonZeroU32::new(handshake::MAX_STREAMS as u32).unwrap()),
+            log_level: args
+                .log_level
+                .or(file.log.level)
+                .unwrap_or(LevelFilter::Info),
+            log_targets: log_targets.into_iter().collect(),
+            handler_threads: args
+                .handler_threads
+                .or(file.handler_threads)
+                .map(Into::into),
+            part_size: args.part_size.or(file.part_size).map(Into::into),
+            validation_layers: args
+                .validation_layers
+                .or(file.validation_layers)
+                .unwrap_or_default(),
+            hardened_validation: args
+                .hardened_validation
+                .or(file.hardened_validation)
+                .unwrap_or_default(),
+            devices,
+            guest_devices,
+            driver,
+            guest_drivers,
+            quotas,
+            guest_quotas,
+            weight: args
+                .scheduling_weight
+                .or(file.scheduling.weight)
+                .unwrap_or(NonZeroU32::MIN),
+            guest_weights,
+            capture,
+            heartbeat,
+            session_resume_timeout: args
+                .session_resume_timeout_ms
+                .or(file.session_resume_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_SESSION_RESUME_TIMEOUT),
+            shutdown_timeout: args
+                .shutdown_timeout_ms
+                .or(file.shutdown_timeout_ms)
+                .map(Duration::from_millis)
+                .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT),
+            isolate_sessions: args
+                .isolate_sessions
+                .or(file.isolate_sessions)
+                .unwrap_or_default(),
+            sandbox: args.sandbox.or(file.sandbox).unwrap_or(true),
+            stats_directory: args.stats_directory.or(file.stats_directory),
+            session_worker: args.session_worker,
+        }
+    }
+}
+
+fn parse_level(s: &str) -> Result<LevelFilter, String> {
+    s.parse().map_err(|_| format!("invalid log level `{s}`"))
+}
+
+fn parse<T, E>(value: &str) -> Result<T, E>
+where
+    T: FromStr,
+    T::Err: Display,
+    E: de::Error,
+{
+    value
+        .parse()
+        .map_err(|e| E::custom(format!("`{value}`: {e}")))
+}
+
+fn parse_option<'de, D, T>(deserializer: D) -> Result<Option<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Option::<String>::deserialize(deserializer)?
+        .map(|x| parse(&x))
+        .transpose()
+}
+
+fn parse_vec<'de, D, T>(deserializer: D) -> Result<Vec<T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    Vec::<String>::deserialize(deserializer)?
+        .iter()
+        .map(|x| parse(x))
+        .collect()
+}
+
+fn parse_keys<'de, D, K, V>(deserializer: D) -> Result<BTreeMap<K, V>, D::Error>
+where
+    D: Deserializer<'de>,
+    K: FromStr + Ord,
+    K::Err: Display,
+    V: Deserialize<'de>,
+{
+    BTreeMap::<String, V>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| Ok((parse(&key)?, value)))
+        .collect()
+}
+
+fn parse_map<'de, D, T>(deserializer: D) -> Result<BTreeMap<String, T>, D::Error>
+where
+    D: Deserializer<'de>,
+    T: FromStr,
+    T::Err: Display,
+{
+    BTreeMap::<String, String>::deserialize(deserializer)?
+        .into_iter()
+        .map(|(key, value)| Ok((key, parse(&value)?)))
+        .collect()
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{num::NonZeroU32, path::PathBuf, time::Duration};
+
+    use clap::Parser;
+    use log::LevelFilter;
+    use wie_driver_listener_vulkan::{
+        devices::{DevicePolicy, DeviceSelector},
+        driver::Driver,
+        quotas::Quotas,
+        settings::ValidationLayers,
+    };
+    use wie_transport::{endpoint::Endpoint, heartbeat::Heartbeat};
+
+    use super::{Args, Config, ConfigError, File, SessionWorker};
+
+    const FILE: &str = r#"
+listen = ["vsock://any:13001", "tcp://127.0.0.1:13001"]
+max-connections = 4
+handler-threads = 2
+validation-layers = "disabled"
+hardened-validation = true
+isolate-sessions = true
+sandbox = false
+heartbeat-interval-ms = 2000
+stats-directory = "/var/lib/node_exporter"
+
+[log]
+level = "warn"
+targets = { wie_transport = "debug", wie = "info" }
+
+[devices]
+expose = ["type:discrete", "type:cpu"]
+hide = ["vendor:8086"]
+
+[devices.guests.3]
+expose = ["name:llvmpipe*"]
+
+[driver]
+icd-files = ["/usr/share/vulkan/icd.d/nvidia_icd.json"]
+
+[driver.guests.3]
+library = "/opt/swiftshader/libvk_swiftshader.so"
+
+[quotas]
+memory-per-heap-mib = 2048
+max-objects = 10000
+
+[quotas.guests.3]
+submissions-per-second = 60
+
+[scheduling]
+weight = 2
+
+[scheduling.guests.3]
+weight = 5
+
+[capture]
+file = "/tmp/wie.gfxr"
+frames = "1-10"
+"#;
+
+    #[test]
+    fn file_values() {
+        let file = toml::from_str::<File>(FILE).unwrap();
+        let config = Config::merge(Args::parse_from(["wie"]), file);
+
+        assert_eq!(
+            vec![
+                "vsock://any:13001".parse::<Endpoint>().unwrap(),
+                Endpoint::Tcp("127.0.0.1:13001".to_owned())
+            ],
+            config.listen
+        );
+        assert_eq!(NonZeroU32::new(4).unwrap(), config.max_connections);
+        assert_eq!(Some(2), config.handler_threads);
+        assert_eq!(None, config.part_size);
+        assert_eq!(ValidationLayers::Disabled, config.validation_layers);
+        assert!(config.hardened_validation);
+        assert!(config.isolate_sessions);
+        assert!(!config.sandbox);
+        assert_eq!(LevelFilter::Warn, config.log_level);
+        assert_eq!(
+            vec![
+                ("wie".to_owned(), LevelFilter::Info),
+                ("wie_transport".to_owned(), LevelFilter::Debug)
+            ],
+            config.log_targets
+        );
+        assert_eq!(
+            DevicePolicy {
+                expose: vec![
+                    "type:discrete".parse().unwrap(),
+                    "type:cpu".parse().unwrap()
+                ],
+                hide: vec!["vendor:8086".parse().unwrap()],
+            },
+            config.device_policy(Some(4))
+        );
+        assert_eq!(
+            DevicePolicy {
+                expose: vec!["name:llvmpipe*".parse().unwrap()],
+                hide: Vec::new(),
+            },
+            config.device_policy(Some(3))
+        );
+        assert_eq!(config.devices, config.device_policy(None));
+        assert_eq!(
+            &Driver {
+                library: None,
+                icd_files: vec!["/usr/share/vulkan/icd.d/nvidia_icd.json".into()],
+            },
+            config.driver(Some(4))
+        );
+        // Guest which overrides only the library keeps the default ICD files.
+        assert_eq!(
+            &Driver {
+                library: Some("/opt/swiftshader/libvk_swiftshader.so".into()),
+                icd_files: vec!["/usr/share/vulkan/icd.d/nvidia_icd.json".into()],
+            },
+            config.driver(Some(3))
+        );
+        // Own ICD files are served by isolated sessions, which do not use own weights.
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::GuestWeight(3))
+        ));
+        assert_eq!(
+            Quotas {
+                memory_per_heap: Some(2048 * 1024 * 1024),
+                objects: Some(10000),
+                submissions_per_second: None,
+            },
+            config.quotas(Some(4))
+        );
+        assert_eq!(
+            Quotas {
+                memory_per_heap: None,
+                objects: None,
+                submissions_per_second: NonZeroU32::new(60),
+            },
+            config.quotas(Some(3))
+        );
+        assert_eq!(NonZeroU32::new(2).unwrap(), config.weight(Some(4)));
+        assert_eq!(NonZeroU32::new(5).unwrap(), config.weight(Some(3)));
+        assert_eq!(
+            Some(Heartbeat {
+                interval: Duration::from_millis(2000),
+                miss_threshold: 3,
+            }),
+            config.heartbeat
+        );
+        assert_eq!(
+            Some(PathBuf::from("/var/lib/node_exporter")),
+            config.stats_directory
+        );
+        let capture = config.capture.unwrap();
+        assert_eq!("/tmp/wie.gfxr", capture.file.to_str().unwrap());
+        assert_eq!(Some("1-10"), capture.frames.as_deref());
+    }
+
+    #[test]
+    fn arguments_override_file() {
+        let file = toml::from_str::<File>(FILE).unwrap();
+        let args = Args::parse_from([
+            "wie",
+            "--listen",
+            "tcp://0.0.0.0:13001",
+            "--log-level",
+            "trace",
+            "--log-target",
+            "wie_transport=error",
+            "--part-size",
+            "65536",
+            "--capture-frames",
+            "5",
+            "--hardened-validation",
+            "false",
+            "--sandbox",
+            "true",
+            "--expose-devices",
+            "vendor:10de,type:cpu",
+            "--icd-files",
+            "/a.json,/b.json",
+            "--max-objects",
+            "500",
+            "--scheduling-weight",
+            "3",
+            "--heartbeat-miss-threshold",
+            "5",
+            "--stats-directory",
+            "/run/wie",
+        ]);
+        let config = Config::merge(args, file);
+
+        assert_eq!(
+            vec![Endpoint::Tcp("0.0.0.0:13001".to_owned())],
+            config.listen
+        );
+        assert_eq!(LevelFilter::Trace, config.log_level);
+        assert_eq!(
+            vec![
+                ("wie".to_owned(), LevelFilter::Info),
+                ("wie_transport".to_owned(), LevelFilter::Error)
+            ],
+            config.log_targets
+        );
+        assert_eq!(Some(65536), config.part_size);
+        assert_eq!(NonZeroU32::new(3).unwrap(), config.weight(Some(4)));
+        assert_eq!(NonZeroU32::new(5).unwrap(), config.weight(Some(3)));
+        assert_eq!(5, config.heartbeat.unwrap().miss_threshold);
+        assert_eq!(Some("5"), config.capture.unwrap().frames.as_deref());
+        assert!(!config.hardened_validation);
+        assert!(config.sandbox);
+        let selector = |selector: &str| selector.parse::<DeviceSelector>().unwrap();
+        assert_eq!(
+            vec![selector("vendor:10de"), selector("type:cpu")],
+            config.devices.expose
+        );
+        assert_eq!(vec![selector("vendor:8086")], config.devices.hide);
+        assert_eq!(
+            vec![PathBuf::from("/a.json"), PathBuf::from("/b.json")],
+            config.driver.icd_files
+        );
+        assert_eq!(Some(PathBuf::from("/run/wie")), config.stats_directory);
+        assert_eq!(Some(500), config.quotas.objects);
+        assert_eq!(Some(2048 * 1024 * 1024), config.quotas.memory_per_heap);
+    }
+
+    #[test]
+    fn defaults() {
+        let config = Config::merge(Args::parse_from(["wie"]), File::default());
+        assert_eq!(vec![Endpoint::host(13001)], config.listen);
+        assert_eq!(LevelFilter::Info, config.log_level);
+        assert_eq!(ValidationLayers::Auto, config.validation_layers);
+        assert!(!config.hardened_validation);
+        assert!(!config.isolate_sessions);
+        assert!(config.sandbox);
+        assert!(config.session_worker.is_none());
+        assert!(config.device_policy(Some(3)).is_unrestricted());
+        assert!(config.driver(Some(3)).is_default());
+        assert!(config.quotas(Some(3)).is_unlimited());
+        assert_eq!(NonZeroU32::MIN, config.weight(Some(3)));
+        assert!(config.heartbeat.is_none());
+        assert!(config.capture.is_none());
+        assert!(config.stats_directory.is_none());
+    }
+
+    #[test]
+    fn session_worker() {
+        let worker = SessionWorker {
+            token: 0xdead_beef_0123,
+            streams: 4,
+        };
+        let args = Args::parse_from(["wie", "--session-worker", &worker.to_string()]);
+        let config = Config::merge(args, File::default());
+        assert_eq!(Some(worker), config.session_worker);
+
+        assert!("1234".parse::<SessionWorker>().is_err());
+        assert!("xyz:1".parse::<SessionWorker>().is_err());
+    }
+
+    #[test]
+    fn guest_icd_files_need_isolation() {
+        let file = r#"
+[driver.guests.3]
+icd-files = ["/usr/share/vulkan/icd.d/lvp_icd.x86_64.json"]
+"#;
+        let config = Config::merge(Args::parse_from(["wie"]), toml::from_str(file).unwrap());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::GuestIcdFiles(3))
+        ));
+
+        let args = Args::parse_from(["wie", "--isolate-sessions", "true"]);
+        let config = Config::merge(args, toml::from_str(file).unwrap());
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn guest_weights_need_shared_process() {
+        let file = r#"
+[scheduling]
+weight = 2
+
+[scheduling.guests.3]
+weight = 2
+
+[scheduling.guests.4]
+weight = 5
+"#;
+        let config = Config::merge(Args::parse_from(["wie"]), toml::from_str(file).unwrap());
+        assert!(config.validate().is_ok());
+
+        let args = Args::parse_from(["wie", "--isolate-sessions", "true"]);
+        let config = Config::merge(args, toml::from_str(file).unwrap());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::GuestWeight(4))
+        ));
+    }
+
+    #[test]
+    fn icd_file_with_path_separator_is_rejected() {
+        let file = r#"
+[driver.guests.3]
+icd-files = ["/opt/icd:v2/icd.json"]
+"#;
+        let args = Args::parse_from(["wie", "--isolate-sessions", "true"]);
+        let config = Config::merge(args, toml::from_str(file).unwrap());
+        assert!(matches!(
+            config.validate(),
+            Err(ConfigError::IcdFile(file)) if file.to_str() == Some("/opt/icd:v2/icd.json")
+        ));
+    }
+
+    #[test]
+    fn guest_library_keeps_default_icd_files() {
+        let file = r#"
+[driver]
+icd-files = ["/usr/share/vulkan/icd.d/lvp_icd.x86_64.json"]
+
+[driver.guests.3]
+library = "/opt/swiftshader/libvk_swiftshader.so"
+"#;
+        let config = Config::merge(Args::parse_from(["wie"]), toml::from_str(file).unwrap());
+        assert_eq!(
+            config.driver(None).icd_files,
+            config.driver(Some(3)).icd_files
+        );
+        assert_eq!(
+            Some(PathBuf::from("/opt/swiftshader/libvk_swiftshader.so")),
+            config.driver(Some(3)).library
+        );
+        assert!(config.validate().is_ok());
+    }
+
+    #[test]
+    fn invalid_file() {
+        assert!(toml::from_str::<File>(r#"listen = ["http://host:80"]"#).is_err());
+        assert!(toml::from_str::<File>(r#"unknown = 1"#).is_err());
+        assert!(toml::from_str::<File>("[devices]\nexpose = [\"pci:10de\"]").is_err());
+        assert!(toml::from_str::<File>("[devices.guests.guest]\nexpose = [\"type:cpu\"]").is_err());
+        assert!(toml::from_str::<File>("[quotas]\nsubmissions-per-second = 0").is_err());
+        assert!(toml::from_str::<File>("[scheduling.guests.3]\nweight = 0").is_err());
+    }
+}
@@ -0,0 +1,116 @@
+//! Integration with the service manager: shutdown and dump signals, systemd socket activation and readiness
+//! notifications. Everything here does nothing when the host is not started by systemd, or on platforms which do not
+//! support it.
+
+use std::io;
+
+use wie_transport::stream::Listener;
+
+/// Calls `shutdown` once SIGTERM or SIGINT is received. The second signal exits immediately, when the graceful
+/// shutdown hangs.
+#[cfg(unix)]
+pub fn on_shutdown_signal<F>(shutdown: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    use signal_hook::{
+        consts::{SIGINT, SIGTERM},
+        iterator::Signals,
+    };
+
+    let mut signals = Signals::new([SIGTERM, SIGINT])?;
+    std::thread::spawn(move || {
+        let mut signals = signals.forever();
+        if let Some(signal) = signals.next() {
+            info!("Received signal {}, shutting down", signal);
+            shutdown();
+        }
+        if let Some(signal) = signals.next() {
+            warn!(
+                "Received signal {} during shutdown, exiting immediately",
+                signal
+            );
+            std::process::exit(128 + signal);
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_shutdown_signal<F>(_shutdown: F) -> io::Result<()>
+where
+    F: FnOnce() + Send + 'static,
+{
+    Ok(())
+}
+
+/// Calls `dump` every time SIGUSR1 is received, like `systemctl kill -s USR1 wie`.
+#[cfg(unix)]
+pub fn on_dump_signal<F>(dump: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    use signal_hook::{consts::SIGUSR1, iterator::Signals};
+
+    let mut signals = Signals::new([SIGUSR1])?;
+    std::thread::spawn(move || {
+        for _ in signals.forever() {
+            dump();
+        }
+    });
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn on_dump_signal<F>(_dump: F) -> io::Result<()>
+where
+    F: Fn() + Send + 'static,
+{
+    Ok(())
+}
+
+/// Listening sockets passed by systemd socket activation, in order of `ListenStream=` entries of the socket unit.
+#[cfg(target_os = "linux")]
+pub fn inherited_listeners() -> io::Result<Vec<Listener>> {
+    sd_notify::listen_fds()?
+        .map(|fd| unsafe { Listener::from_fd(fd) })
+        .collect()
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn inherited_listeners() -> io::Result<Vec<Listener>> {
+    Ok(Vec::new())
+}
+
+/// Tells systemd that the host accepts connections, it is needed by `Type=notify` services.
+pub fn notify_ready() {
+    #[cfg(target_os = "linux")]
+    notify(&[
+        sd_notify::NotifyState::Ready,
+        sd_notify::NotifyState::Status("Waiting for incoming connections"),
+    ]);
+}
+
+/// Tells systemd that the host is shutting down.
+pub fn notify_stopping() {
+    #[cfg(target_os = "linux")]
+    notify(&[
+        sd_notify::NotifyState::Stopping,
+        sd_notify::NotifyState::Status("Shutting down"),
+    ]);
+}
+
+/// Sets status shown by `systemctl status`.
+pub fn notify_status(status: &str) {
+    #[cfg(target_os = "linux")]
+    notify(&[sd_notify::NotifyState::Status(status)]);
+    #[cfg(not(target_os = "linux"))]
+    let _ = status;
+}
+
+#[cfg(target_os = "linux")]
+fn notify(state: &[sd_notify::NotifyState]) {
+    if let Err(e) = sd_notify::notify(false, state) {
+        warn!("Failed to notify systemd: {}", e);
+    }
+}
@@ -1,34 +1,594 @@
 #[macro_use]
 extern crate log;
 
-use std::{collections::HashMap, num::NonZeroU32, thread, time::Duration};
+mod config;
+mod daemon;
+#[cfg(target_os = "linux")]
+mod sandbox;
+mod stats;
+mod worker;
 
-use wie_transport::Connection;
-use wie_transport_vsock::VsockListener;
+use std::{
+    collections::{HashMap, HashSet},
+    fs, io,
+    path::Path,
+    process,
+    sync::{
+        mpsc::{self, Receiver, RecvTimeoutError, Sender},
+        Arc, Mutex,
+    },
+    thread,
+    time::{Duration, Instant},
+};
 
-const PORT: u32 = 13001;
+use config::{Config, SessionWorker};
+use simple_logger::SimpleLogger;
+use wie_driver_listener_vulkan::{
+    session as vulkan,
+    settings::{self, Settings},
+};
+use wie_transport::{
+    endpoint::{self, Endpoint},
+    handshake::{self, PendingConnection, Session},
+    stream::{Listener, Peer, Stream},
+    Connection,
+};
+use wie_transport_vsock::VsockType;
+use worker::Worker;
+
+/// Pause after a failed accept, so errors like exhausted file descriptors do not spin the accept thread.
+const ACCEPT_BACKOFF: Duration = Duration::from_millis(100);
+
+enum Event {
+    Accepted(PendingConnection<Stream>),
+    /// Connection of the session with the token was closed.
+    Closed(u64),
+    /// SIGTERM or SIGINT was received.
+    Shutdown,
+    /// SIGUSR1 was received.
+    DumpObjects,
+    /// Statistics of sessions should be written to the stats directory.
+    WriteStats,
+    /// Worker process of the session with the token exited.
+    WorkerExited(u64),
+    /// Session with the token, which was not resumed, was closed.
+    SessionClosed(u64),
+}
+
+/// Guest process which is served by the host, keyed by its session token.
+struct Guest {
+    session: Arc<vulkan::Session>,
+    connection: Option<Arc<Connection<Stream>>>,
+    disconnected_at: Option<Instant>,
+    /// Guest which started the session, only it can resume the session. Sessions of unknown guests are not resumed.
+    peer: Option<Peer>,
+    /// Packets received through the closed connection, guest compares them with the packets it sent when it resumes.
+    received_packets: u64,
+}
 
 fn main() {
-    simple_logger::init().unwrap();
+    let config = match Config::load() {
+        Ok(config) => config,
+        Err(e) => {
+            eprintln!("{}", e);
+            process::exit(2);
+        }
+    };
+
+    let mut logger = SimpleLogger::new().with_level(config.log_level);
+    for (target, level) in &config.log_targets {
+        logger = logger.with_module_level(target, *level);
+    }
+    logger.init().unwrap();
     let hook = std::panic::take_hook();
     std::panic::set_hook(Box::new(move |panic_info| {
         error!("{}", panic_info);
         hook(panic_info);
     }));
 
-    info!("Setting up listening socket on port {}", PORT);
-    let listener = VsockListener::bind(PORT, NonZeroU32::new(1).unwrap())
-        .expect("Failed to set up listening port");
+    #[cfg(target_os = "linux")]
+    if let Some(session) = config.session_worker {
+        worker::run(&config, session);
+        return;
+    }
+    config.driver.use_icd_files();
+    configure_handlers(&config);
+
+    let isolate_sessions = config.isolate_sessions && cfg!(target_os = "linux");
+    if config.isolate_sessions && !isolate_sessions {
+        warn!("Isolated sessions are supported only on Linux, sessions are served by the host process");
+    }
+
+    let inherited_listeners = match daemon::inherited_listeners() {
+        Ok(listeners) => listeners,
+        Err(e) => panic!("Failed to take sockets passed by systemd: {}", e),
+    };
+    // Sockets passed by systemd are owned by the socket unit, so their files are not removed at exit.
+    let bound_by_host = inherited_listeners.is_empty();
+    let listeners = match bound_by_host {
+        true => bind_listeners(&config),
+        false => {
+            info!(
+                "Using {} listening socket(s) passed by systemd",
+                inherited_listeners.len()
+            );
+            inherited_listeners
+        }
+    };
+
+    let resume_timeout = config.session_resume_timeout;
+    let (sender, receiver) = mpsc::channel();
+    let acceptor = Arc::new(Mutex::new(handshake::Acceptor::new()));
+    for listener in listeners {
+        let sender = sender.clone();
+        let acceptor = acceptor.clone();
+        thread::spawn(move || accept_worker(listener, acceptor, sender));
+    }
+
+    let shutdown_sender = sender.clone();
+    if let Err(e) = daemon::on_shutdown_signal(move || _ = shutdown_sender.send(Event::Shutdown)) {
+        warn!("Unable to handle shutdown signals: {}", e);
+    }
+    let dump_sender = sender.clone();
+    if let Err(e) = daemon::on_dump_signal(move || _ = dump_sender.send(Event::DumpObjects)) {
+        warn!("Unable to handle dump signal: {}", e);
+    }
+    if let Some(directory) = &config.stats_directory {
+        if let Err(e) = fs::create_dir_all(directory) {
+            warn!(
+                "Unable to create stats directory {}: {}",
+                directory.display(),
+                e
+            );
+        }
+        let stats_sender = sender.clone();
+        stats::on_interval(move || _ = stats_sender.send(Event::WriteStats));
+    }
 
     info!("Waiting for incoming connections...");
-    let (stream, _) = listener
-        .accept(None)
-        .expect("Failed to accept incoming connection");
+    daemon::notify_ready();
+
+    // Sessions are kept after their connection is closed, until the guest resumes them or the resume timeout passes.
+    let mut guests: HashMap<u64, Guest> = HashMap::new();
+    // Isolated sessions, keyed by their session token.
+    let mut workers: HashMap<u64, Worker> = HashMap::new();
+    // Sessions which were not resumed and are being closed by their own thread, so the loop keeps serving others.
+    let mut closing: HashSet<u64> = HashSet::new();
+    loop {
+        let deadline = guests
+            .values()
+            .filter_map(|guest| guest.disconnected_at)
+            .min()
+            .map(|disconnected_at| disconnected_at + resume_timeout);
+        let event = match deadline {
+            Some(deadline) => {
+                match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+                    Ok(event) => Some(event),
+                    Err(RecvTimeoutError::Timeout) => None,
+                    Err(RecvTimeoutError::Disconnected) => unreachable!(),
+                }
+            }
+            None => Some(receiver.recv().unwrap()),
+        };
 
-    info!("Connection established");
+        match event {
+            Some(Event::Accepted(pending)) => {
+                let peer = match guest_peer(pending.streams()) {
+                    Ok(peer) => peer,
+                    Err(e) => {
+                        warn!("Rejected connection: {}", e);
+                        continue;
+                    }
+                };
+                let (session, resumed_session) = match pending.session_token() {
+                    Some(token) => match guests.get(&token) {
+                        Some(guest) if guest.peer.is_none() || guest.peer != peer => {
+                            warn!(
+                                "Rejected connection, session {:#x} was started by another peer",
+                                token
+                            );
+                            continue;
+                        }
+                        Some(guest) if guest.connection.is_none() => (
+                            Session {
+                                token,
+                                resumed: true,
+                                received_packets: guest.received_packets,
+                            },
+                            Some(guest.session.clone()),
+                        ),
+                        Some(_) => {
+                            warn!(
+                                "Rejected connection, session {:#x} is already connected",
+                                token
+                            );
+                            continue;
+                        }
+                        None => (Session::new(), None),
+                    },
+                    None => (Session::new(), None),
+                };
 
+                let streams = match pending.finish(session) {
+                    Ok(streams) => streams,
+                    Err(e) => {
+                        error!("Failed to finish handshake: {}", e);
+                        continue;
+                    }
+                };
+
+                match session.resumed {
+                    true => info!(
+                        "Session {:#x} resumed with {} stream(s)",
+                        session.token,
+                        streams.len()
+                    ),
+                    false => info!(
+                        "Session {:#x} started with {} stream(s)",
+                        session.token,
+                        streams.len()
+                    ),
+                }
+
+                if isolate_sessions {
+                    if let Some(worker) = spawn_worker(session.token, streams, sender.clone()) {
+                        workers.insert(session.token, worker);
+                    }
+                } else {
+                    let host_session = match resumed_session {
+                        Some(host_session) => host_session,
+                        None => match new_host_session(&config, &streams) {
+                            Some(host_session) => host_session,
+                            None => continue,
+                        },
+                    };
+                    let connection = start_connection(
+                        &host_session,
+                        session.token,
+                        streams,
+                        sender.clone(),
+                        &config,
+                    );
+                    guests.insert(
+                        session.token,
+                        Guest {
+                            session: host_session,
+                            connection: Some(connection),
+                            disconnected_at: None,
+                            peer,
+                            received_packets: 0,
+                        },
+                    );
+                }
+            }
+            Some(Event::Closed(token)) => {
+                if let Some(guest) = guests.get_mut(&token) {
+                    if let Some(connection) = guest.connection.take() {
+                        guest.received_packets = connection.received_packets();
+                    }
+                    guest.disconnected_at = Some(Instant::now());
+                    info!(
+                        "Connection of session {:#x} closed, waiting {:?} for guest to resume it",
+                        token, resume_timeout
+                    );
+                }
+            }
+            Some(Event::WorkerExited(token)) => {
+                workers.remove(&token);
+                // Worker which crashed did not remove its statistics.
+                if let Some(directory) = &config.stats_directory {
+                    stats::remove(directory, token);
+                }
+            }
+            Some(Event::Shutdown) => break,
+            Some(Event::DumpObjects) => {
+                for (token, guest) in &guests {
+                    info!("Live objects of session {:#x}:", token);
+                    guest.session.dump_objects();
+                    info!("Session {:#x}: {}", token, guest.session.metrics());
+                }
+                for worker in workers.values() {
+                    worker.dump_objects();
+                }
+            }
+            Some(Event::WriteStats) => {
+                if let Some(directory) = &config.stats_directory {
+                    for (token, guest) in &guests {
+                        stats::write(directory, *token, &guest.session.metrics());
+                    }
+                }
+            }
+            Some(Event::SessionClosed(token)) => _ = closing.remove(&token),
+            None => {
+                let expired: Vec<u64> = guests
+                    .iter()
+                    .filter(|(_, guest)| {
+                        guest.disconnected_at.is_some_and(|disconnected_at| {
+                            disconnected_at.elapsed() >= resume_timeout
+                        })
+                    })
+                    .map(|(token, _)| *token)
+                    .collect();
+                for token in expired {
+                    info!("Session {:#x} was not resumed, cleaning up", token);
+                    let guest = guests.remove(&token).unwrap();
+                    closing.insert(token);
+                    let sender = sender.clone();
+                    let timeout = config.shutdown_timeout;
+                    let stats_directory = config.stats_directory.clone();
+                    thread::spawn(move || {
+                        guest.session.close(timeout);
+                        if let Some(directory) = &stats_directory {
+                            stats::remove(directory, token);
+                        }
+                        _ = sender.send(Event::SessionClosed(token));
+                    });
+                }
+            }
+        }
+        daemon::notify_status(&format!(
+            "Serving {} session(s)",
+            guests.len() + workers.len()
+        ));
+    }
+
+    shutdown(
+        guests,
+        workers,
+        closing,
+        &receiver,
+        config.shutdown_timeout,
+        config.stats_directory.as_deref(),
+    );
+    if bound_by_host {
+        for endpoint in &config.listen {
+            if let Endpoint::Unix(path) = endpoint {
+                _ = std::fs::remove_file(path);
+            }
+        }
+    }
+}
+
+/// Closes connections, so guests know that the host is gone, and destroys objects of every session. Listeners are not
+/// closed, but streams which they accept are not served anymore.
+///
+/// Workers shut down their sessions themselves, the ones which do not exit in time are killed. Sessions which are
+/// already being closed are waited for as long as workers.
+fn shutdown(
+    guests: HashMap<u64, Guest>,
+    mut workers: HashMap<u64, Worker>,
+    mut closing: HashSet<u64>,
+    receiver: &Receiver<Event>,
+    timeout: Duration,
+    stats_directory: Option<&Path>,
+) {
+    daemon::notify_stopping();
+    info!("Shutting down {} session(s)", guests.len() + workers.len());
+
+    for worker in workers.values() {
+        worker.terminate();
+    }
+    for guest in guests.values() {
+        if let Some(connection) = &guest.connection {
+            connection.close();
+        }
+    }
+
+    let deadline = Instant::now() + timeout;
+    for (token, guest) in guests {
+        info!("Closing session {:#x}", token);
+        guest
+            .session
+            .close(deadline.saturating_duration_since(Instant::now()));
+        if let Some(directory) = stats_directory {
+            stats::remove(directory, token);
+        }
+    }
+
+    let deadline = deadline + worker::EXIT_TIMEOUT;
+    while !workers.is_empty() || !closing.is_empty() {
+        match receiver.recv_timeout(deadline.saturating_duration_since(Instant::now())) {
+            Ok(Event::WorkerExited(token)) => _ = workers.remove(&token),
+            Ok(Event::SessionClosed(token)) => _ = closing.remove(&token),
+            Ok(_) => {}
+            Err(_) => break,
+        }
+    }
+    for token in closing {
+        warn!("Session {:#x} was not closed in time", token);
+    }
+    for (token, worker) in workers {
+        warn!(
+            "Worker {} of session {:#x} did not exit in time, killing it",
+            worker.pid(),
+            token
+        );
+        worker.kill();
+        if let Some(directory) = stats_directory {
+            stats::remove(directory, token);
+        }
+    }
+    info!("Shutdown finished");
+}
+
+/// Sets up threads which execute guest commands and settings of the Vulkan listener.
+fn configure_handlers(config: &Config) {
+    if let Some(threads) = config.handler_threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("Failed to set up handler threads");
+    }
+    settings::configure(Settings {
+        validation_layers: config.validation_layers,
+        hardened_validation: config.hardened_validation,
+        capture: config.capture.clone(),
+    });
+}
+
+fn bind_listeners(config: &Config) -> Vec<Listener> {
+    let mut listeners = Vec::new();
+    for endpoint in &config.listen {
+        info!("Setting up listening socket on {}", endpoint);
+        let listener = Listener::bind(endpoint, config.max_connections, VsockType::Stream)
+            .unwrap_or_else(|e| panic!("Failed to listen on {}: {}", endpoint, e));
+        listeners.push(listener);
+
+        // Seqpacket sockets cannot share the port with stream ones, guests which prefer them connect to the next port.
+        if let Endpoint::Vsock(address) = endpoint {
+            let endpoint = match endpoint::seqpacket_address(*address) {
+                Ok(address) => Endpoint::Vsock(address),
+                Err(e) => {
+                    info!("Seqpacket is not available: {}", e);
+                    continue;
+                }
+            };
+            match Listener::bind(&endpoint, config.max_connections, VsockType::SeqPacket) {
+                Ok(listener) => {
+                    info!("Listening for seqpacket streams on {}", endpoint);
+                    listeners.push(listener);
+                }
+                Err(e) => info!("Seqpacket is not available: {}", e),
+            }
+        }
+    }
+
+    listeners
+}
+
+/// Accepts streams of the listener, listeners share the acceptor so a connection can mix stream types.
+fn accept_worker(
+    listener: Listener,
+    acceptor: Arc<Mutex<handshake::Acceptor<Stream>>>,
+    sender: Sender<Event>,
+) {
+    loop {
+        let stream = match listener.accept() {
+            Ok(stream) => stream,
+            Err(e) => {
+                // Like running out of file descriptors, or peer which aborted the connection before it was accepted.
+                error!("Failed to accept incoming connection: {}", e);
+                thread::sleep(ACCEPT_BACKOFF);
+                continue;
+            }
+        };
+
+        // Handshake is read by its own thread, so a slow or silent peer does not block other streams.
+        let acceptor = acceptor.clone();
+        let sender = sender.clone();
+        thread::spawn(move || {
+            let result =
+                read_handshake(stream).and_then(|stream| acceptor.lock().unwrap().accept(stream));
+            match result {
+                Ok(Some(pending)) => _ = sender.send(Event::Accepted(pending)),
+                Ok(None) => {}
+                Err(e) => error!("Rejected incoming stream: {}", e),
+            }
+        });
+    }
+}
+
+/// Reads handshake of the stream with a timeout, so a peer which never sends it does not keep its thread forever.
+fn read_handshake(stream: Stream) -> io::Result<handshake::HandshakeStream<Stream>> {
+    stream.set_read_timeout(Some(handshake::HANDSHAKE_TIMEOUT))?;
+    let stream = handshake::read_handshake(stream)?;
+    stream.stream().set_read_timeout(None)?;
+    Ok(stream)
+}
+
+/// Returns vsock CID of the guest, other transports do not identify guests.
+/// Returns identity of the guest which opened the streams, they must all come from the same one. It is `None` when
+/// the transport cannot tell it.
+fn guest_peer(streams: &[Stream]) -> io::Result<Option<Peer>> {
+    let peers = streams
+        .iter()
+        .map(|stream| stream.peer().ok())
+        .collect::<Vec<_>>();
+    match peers.iter().all(|peer| *peer == peers[0]) {
+        true => Ok(peers[0]),
+        false => Err(io::Error::new(
+            io::ErrorKind::PermissionDenied,
+            "streams of the connection come from different peers",
+        )),
+    }
+}
+
+fn guest_cid(streams: &[Stream]) -> Option<u32> {
+    #[cfg(not(target_os = "windows"))]
+    if let Some(Stream::Vsock(stream)) = streams.first() {
+        return stream.peer_addr().ok().map(|address| address.cid.0);
+    }
+    #[cfg(target_os = "windows")]
+    let _ = streams;
+    None
+}
+
+/// Creates host session with the driver, device policy, quotas and scheduling weight of the guest, failures are
+/// logged.
+fn new_host_session(config: &Config, streams: &[Stream]) -> Option<Arc<vulkan::Session>> {
+    let cid = guest_cid(streams);
+    let driver = config.driver(cid);
+    match unsafe { driver.load() } {
+        Ok(entry) => Some(vulkan::Session::new(
+            config.device_policy(cid),
+            config.quotas(cid),
+            config.weight(cid),
+            entry,
+        )),
+        Err(e) => {
+            error!("Failed to load Vulkan {}: {}", driver, e);
+            None
+        }
+    }
+}
+
+/// Hands streams of the session to a new worker process. Workers are not resumed, so their guests always start a new
+/// session.
+fn spawn_worker(token: u64, streams: Vec<Stream>, sender: Sender<Event>) -> Option<Worker> {
+    let session = SessionWorker {
+        token,
+        streams: streams.len(),
+    };
+    let result = worker::spawn(session, streams, move |status| {
+        match status.success() {
+            true => info!("Worker of session {:#x} exited", token),
+            false => warn!("Worker of session {:#x} exited with {}", token, status),
+        }
+        _ = sender.send(Event::WorkerExited(token));
+    });
+
+    match result {
+        Ok(worker) => {
+            info!("Session {:#x} handed to worker {}", token, worker.pid());
+            Some(worker)
+        }
+        Err(e) => {
+            error!("Failed to start worker of session {:#x}: {}", token, e);
+            None
+        }
+    }
+}
+
+fn start_connection(
+    session: &Arc<vulkan::Session>,
+    token: u64,
+    streams: Vec<Stream>,
+    sender: Sender<Event>,
+    config: &Config,
+) -> Arc<Connection<Stream>> {
     let mut map = HashMap::new();
-    wie_driver_listener_vulkan::register_handlers_to(&mut map);
-    let _what = Connection::new(stream, map, None);
-    thread::sleep(Duration::from_secs(600));
+    session.register_handlers_to(&mut map);
+    let connection = Connection::with_streams(streams, map, config.part_size);
+    session.set_connection(&connection);
+
+    if let Some(heartbeat) = config.heartbeat {
+        info!(
+            "Heartbeat enabled, connection will be closed after {:?} without data",
+            heartbeat.timeout()
+        );
+        connection.start_heartbeat(heartbeat);
+    }
+
+    connection.on_close(move || _ = sender.send(Event::Closed(token)));
+    connection
 }
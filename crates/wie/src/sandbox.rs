@@ -0,0 +1,242 @@
+//! Sandbox of worker processes, which limits them to GPU devices, files of drivers and streams of their session.
+//!
+//! Landlock restricts the filesystem, and seccomp denies syscalls which leave the sandbox, like starting programs or
+//! opening new sockets. Both restrict only the calling thread and threads which it creates later, so the sandbox is
+//! applied before the worker starts any thread.
+
+use std::{
+    collections::BTreeMap,
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use landlock::{
+    path_beneath_rules, Access, AccessFs, AccessNet, BitFlags, Ruleset, RulesetAttr,
+    RulesetCreatedAttr, RulesetError, RulesetStatus, Scope, ABI,
+};
+use seccompiler::{
+    BackendError, BpfProgram, SeccompAction, SeccompCmpArgLen, SeccompCmpOp, SeccompCondition,
+    SeccompFilter, SeccompRule, TargetArch,
+};
+use thiserror::Error;
+
+/// Newest Landlock ABI which is used, older kernels enforce what they support.
+const ABI: ABI = ABI::V6;
+
+/// Directories of libraries, drivers and their configs, which are only read.
+const READ_ONLY: &[&str] = &[
+    "/usr",
+    "/lib",
+    "/lib32",
+    "/lib64",
+    "/opt",
+    "/etc",
+    "/sys",
+    "/proc/self",
+    "/dev/urandom",
+];
+
+/// Device nodes of GPUs and files which drivers write to.
+const READ_WRITE: &[&str] = &["/dev/dri", "/dev/null", "/dev/zero", "/dev/shm"];
+
+/// Environment variables of the Vulkan loader with paths to drivers and layers, separated by `:`.
+const LOADER_PATHS: &[&str] = &[
+    "VK_DRIVER_FILES",
+    "VK_ICD_FILENAMES",
+    "VK_ADD_DRIVER_FILES",
+    "VK_LAYER_PATH",
+    "VK_ADD_LAYER_PATH",
+];
+
+#[derive(Error, Debug)]
+pub enum SandboxError {
+    #[error("unable to restrict filesystem: {0}")]
+    Landlock(#[from] RulesetError),
+    #[error("unable to compile seccomp filter: {0}")]
+    Filter(#[from] BackendError),
+    #[error("unable to apply seccomp filter: {0}")]
+    Seccomp(#[from] seccompiler::Error),
+}
+
+/// Sandboxes the calling thread, writable directories stay writable, like the capture directory of GFXReconstruct or
+/// the stats directory, and the directory of the Vulkan library readable. Kernels without Landlock leave the filesystem
+/// unrestricted, which is only logged.
+pub fn apply(
+    writable_directories: &[&Path],
+    library_directory: Option<&Path>,
+) -> Result<(), SandboxError> {
+    match restrict_filesystem(writable_directories, library_directory)? {
+        RulesetStatus::FullyEnforced => {}
+        RulesetStatus::PartiallyEnforced => {
+            info!("Kernel supports older Landlock ABI, filesystem is partially restricted")
+        }
+        RulesetStatus::NotEnforced => {
+            warn!("Kernel does not support Landlock, filesystem of the worker is not restricted")
+        }
+    }
+    restrict_syscalls()
+}
+
+fn restrict_filesystem(
+    writable_directories: &[&Path],
+    library_directory: Option<&Path>,
+) -> Result<RulesetStatus, RulesetError> {
+    let mut paths = BTreeMap::new();
+    for path in READ_ONLY {
+        paths.insert(PathBuf::from(path), AccessFs::from_read(ABI));
+    }
+    for path in loader_paths()
+        .into_iter()
+        .chain(library_directory.map(Path::to_owned))
+    {
+        paths.insert(path, AccessFs::from_read(ABI));
+    }
+    if let Some(data) = user_directory("XDG_DATA_HOME", ".local/share") {
+        paths.insert(data.join("vulkan"), AccessFs::from_read(ABI));
+    }
+
+    for path in READ_WRITE {
+        paths.insert(PathBuf::from(path), AccessFs::from_all(ABI));
+    }
+    // Devices of the proprietary NVIDIA driver, like `/dev/nvidiactl` and `/dev/nvidia0`.
+    if let Ok(entries) = fs::read_dir("/dev") {
+        for entry in entries.flatten() {
+            if entry.file_name().to_string_lossy().starts_with("nvidia") {
+                paths.insert(entry.path(), AccessFs::from_all(ABI));
+            }
+        }
+    }
+    // Shader caches of drivers.
+    if let Some(cache) = user_directory("XDG_CACHE_HOME", ".cache") {
+        paths.insert(cache, AccessFs::from_all(ABI));
+    }
+    for directory in writable_directories {
+        paths.insert(directory.to_path_buf(), AccessFs::from_all(ABI));
+    }
+
+    // Missing paths are skipped, like devices of vendors which are not installed.
+    let mut ruleset = Ruleset::default()
+        .handle_access(AccessFs::from_all(ABI))?
+        .handle_access(AccessNet::from_all(ABI))?
+        .scope(BitFlags::<Scope>::all())?
+        .create()?;
+    for (path, access) in paths.iter().filter(|(path, _)| path.exists()) {
+        ruleset = ruleset.add_rules(path_beneath_rules([path], *access))?;
+    }
+    Ok(ruleset.restrict_self()?.ruleset)
+}
+
+/// Paths of drivers and layers passed to the Vulkan loader, files are read with their whole directory.
+fn loader_paths() -> Vec<PathBuf> {
+    LOADER_PATHS
+        .iter()
+        .filter_map(env::var_os)
+        .flat_map(|value| env::split_paths(&value).collect::<Vec<_>>())
+        .map(|path| match path.is_file() {
+            true => path.parent().map(Path::to_owned).unwrap_or(path),
+            false => path,
+        })
+        .collect()
+}
+
+/// Returns directory from the XDG variable, or its default under the home directory.
+fn user_directory(variable: &str, default: &str) -> Option<PathBuf> {
+    env::var_os(variable)
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(default)))
+}
+
+fn restrict_syscalls() -> Result<(), SandboxError> {
+    let arch = TargetArch::try_from(env::consts::ARCH)?;
+
+    let mut denied: BTreeMap<i64, Vec<SeccompRule>> = [
+        libc::SYS_execve,
+        libc::SYS_execveat,
+        libc::SYS_ptrace,
+        libc::SYS_process_vm_readv,
+        libc::SYS_process_vm_writev,
+        libc::SYS_mount,
+        libc::SYS_umount2,
+        libc::SYS_pivot_root,
+        libc::SYS_chroot,
+        libc::SYS_setns,
+        libc::SYS_unshare,
+        libc::SYS_bpf,
+        libc::SYS_kexec_load,
+        libc::SYS_init_module,
+        libc::SYS_finit_module,
+        libc::SYS_delete_module,
+        libc::SYS_keyctl,
+        libc::SYS_add_key,
+        libc::SYS_request_key,
+        libc::SYS_reboot,
+        // Guest is reached only through streams of its session, `socketpair` stays allowed.
+        libc::SYS_socket,
+        libc::SYS_connect,
+        libc::SYS_bind,
+        libc::SYS_listen,
+        libc::SYS_accept,
+        libc::SYS_accept4,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_fork,
+        #[cfg(target_arch = "x86_64")]
+        libc::SYS_vfork,
+    ]
+    .into_iter()
+    .map(|syscall| (syscall, Vec::new()))
+    .collect();
+    // Threads are created with `CLONE_THREAD`, processes are not.
+    denied.insert(
+        libc::SYS_clone,
+        vec![SeccompRule::new(vec![SeccompCondition::new(
+            0,
+            SeccompCmpArgLen::Qword,
+            SeccompCmpOp::MaskedEq(libc::CLONE_THREAD as u64),
+            0,
+        )?])?],
+    );
+    let denied = SeccompFilter::new(
+        denied,
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::EPERM as u32),
+        arch,
+    )?;
+
+    // Flags of `clone3` are passed in memory which seccomp cannot read. Libc falls back to `clone` when it is not
+    // supported, which is filtered above.
+    let clone3 = SeccompFilter::new(
+        [(libc::SYS_clone3, Vec::new())].into_iter().collect(),
+        SeccompAction::Allow,
+        SeccompAction::Errno(libc::ENOSYS as u32),
+        arch,
+    )?;
+
+    for filter in [denied, clone3] {
+        seccompiler::apply_filter(&BpfProgram::try_from(filter)?)?;
+    }
+    Ok(())
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{io, net::TcpListener, os::unix::net::UnixStream, process::Command, thread};
+
+    #[test]
+    fn denies_processes_and_sockets() {
+        // Sandbox restricts only the thread which applies it, so other tests are not affected.
+        thread::spawn(|| {
+            super::apply(&[], None).unwrap();
+
+            let error = Command::new("/bin/true").spawn().unwrap_err();
+            assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+            let error = TcpListener::bind("127.0.0.1:0").unwrap_err();
+            assert_eq!(io::ErrorKind::PermissionDenied, error.kind());
+
+            // Threads and socket pairs are still available.
+            thread::spawn(|| {}).join().unwrap();
+            UnixStream::pair().unwrap();
+        })
+        .join()
+        .unwrap();
+    }
+}
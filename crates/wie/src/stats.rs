@@ -0,0 +1,152 @@
+//! Statistics of sessions for monitoring tools. Every process which serves sessions writes a file for each of them to
+//! the stats directory, in the text format of Prometheus, so the textfile collector of node_exporter can export them.
+//! Files are named by session tokens, they are refreshed every [`INTERVAL`] and removed when their session is closed.
+
+use std::{
+    fmt::Write,
+    fs, io,
+    path::{Path, PathBuf},
+    thread,
+    time::Duration,
+};
+
+use wie_driver_listener_vulkan::scheduler::SchedulerMetrics;
+
+/// Time between updates of the statistics.
+pub const INTERVAL: Duration = Duration::from_secs(5);
+
+/// Calls `update` every [`INTERVAL`].
+pub fn on_interval<F>(update: F)
+where
+    F: Fn() + Send + 'static,
+{
+    thread::spawn(move || loop {
+        thread::sleep(INTERVAL);
+        update();
+    });
+}
+
+/// Writes statistics of the session. The file is replaced at once, so readers never see it partially written.
+pub fn write(directory: &Path, token: u64, metrics: &SchedulerMetrics) {
+    let path = path(directory, token);
+    let temporary = path.with_extension("tmp");
+    let result =
+        fs::write(&temporary, format(token, metrics)).and_then(|()| fs::rename(&temporary, &path));
+    if let Err(e) = result {
+        warn!(
+            "Failed to write statistics of session {:#x} to {}: {}",
+            token,
+            path.display(),
+            e
+        );
+    }
+}
+
+/// Removes statistics of the closed session.
+pub fn remove(directory: &Path, token: u64) {
+    let path = path(directory, token);
+    match fs::remove_file(&path) {
+        Ok(()) => {}
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+        Err(e) => warn!("Failed to remove statistics {}: {}", path.display(), e),
+    }
+}
+
+fn path(directory: &Path, token: u64) -> PathBuf {
+    directory.join(format!("wie-session-{token:016x}.prom"))
+}
+
+fn format(token: u64, metrics: &SchedulerMetrics) -> String {
+    let values = [
+        (
+            "wie_session_weight",
+            "gauge",
+            "Share of GPU queue time of the session.",
+            metrics.weight.to_string(),
+        ),
+        (
+            "wie_session_submissions_total",
+            "counter",
+            "Queue submissions of the session.",
+            metrics.submissions.to_string(),
+        ),
+        (
+            "wie_session_presents_total",
+            "counter",
+            "Presents of the session.",
+            metrics.presents.to_string(),
+        ),
+        (
+            "wie_session_gpu_occupancy_seconds_total",
+            "counter",
+            "Time of GPU queues which submissions of the session occupied.",
+            metrics.occupancy.as_secs_f64().to_string(),
+        ),
+        (
+            "wie_session_delay_seconds_total",
+            "counter",
+            "Time which submissions and presents of the session waited for other sessions.",
+            metrics.delay.as_secs_f64().to_string(),
+        ),
+    ];
+
+    let mut output = String::new();
+    for (name, kind, help, value) in values {
+        _ = writeln!(output, "# HELP {name} {help}");
+        _ = writeln!(output, "# TYPE {name} {kind}");
+        _ = writeln!(output, "{name}{{session=\"{token:016x}\"}} {value}");
+    }
+    output
+}
+
+#[cfg(all(test, debug_assertions))]
+mod tests {
+    use std::{env, fs, process, time::Duration};
+
+    use wie_driver_listener_vulkan::scheduler::SchedulerMetrics;
+
+    use super::{format, remove, write};
+
+    const METRICS: SchedulerMetrics = SchedulerMetrics {
+        weight: 2,
+        submissions: 120,
+        presents: 60,
+        occupancy: Duration::from_millis(1500),
+        delay: Duration::from_millis(250),
+    };
+
+    #[test]
+    fn format_metrics() {
+        let output = format(0xbeef, &METRICS);
+        let samples: Vec<_> = output.lines().filter(|x| !x.starts_with('#')).collect();
+        assert_eq!(
+            vec![
+                "wie_session_weight{session=\"000000000000beef\"} 2",
+                "wie_session_submissions_total{session=\"000000000000beef\"} 120",
+                "wie_session_presents_total{session=\"000000000000beef\"} 60",
+                "wie_session_gpu_occupancy_seconds_total{session=\"000000000000beef\"} 1.5",
+                "wie_session_delay_seconds_total{session=\"000000000000beef\"} 0.25",
+            ],
+            samples
+        );
+        assert!(output.contains("# TYPE wie_session_weight gauge\n"));
+        assert!(output.contains("# TYPE wie_session_presents_total counter\n"));
+    }
+
+    #[test]
+    fn write_and_remove() {
+        let directory = env::temp_dir().join(format!("wie-stats-{}", process::id()));
+        fs::create_dir_all(&directory).unwrap();
+
+        write(&directory, 0xbeef, &METRICS);
+        let path = directory.join("wie-session-000000000000beef.prom");
+        assert_eq!(format(0xbeef, &METRICS), fs::read_to_string(&path).unwrap());
+        assert_eq!(1, fs::read_dir(&directory).unwrap().count());
+
+        remove(&directory, 0xbeef);
+        assert!(!path.exists());
+        // Statistics which were never written are skipped.
+        remove(&directory, 0xbeef);
+        fs::remove_dir(&directory).unwrap();
+    }
+}
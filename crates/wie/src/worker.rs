@@ -0,0 +1,216 @@
+//! Worker processes of isolated sessions. The host accepts connections and hands streams of every session to a new
+//! process of itself, which loads Vulkan and serves only that session. Crash of a host driver takes down the worker,
+//! while the host and other guests keep running.
+//!
+//! Sessions of workers are not resumed, the worker exits with its connection.
+
+use std::{io, process::ExitStatus, time::Duration};
+
+use wie_transport::stream::Stream;
+
+use crate::config::SessionWorker;
+
+/// Time for which workers may destroy objects of their guests after handlers finished, before they are killed.
+pub const EXIT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Worker process of a session, it is not waited for when dropped.
+#[derive(Debug)]
+pub struct Worker {
+    pid: u32,
+}
+
+impl Worker {
+    #[inline]
+    pub fn pid(&self) -> u32 {
+        self.pid
+    }
+}
+
+/// Starts worker of the session, which inherits its streams. `exited` is called from another thread once the worker
+/// exits.
+#[cfg(target_os = "linux")]
+pub fn spawn<F>(session: SessionWorker, streams: Vec<Stream>, exited: F) -> io::Result<Worker>
+where
+    F: FnOnce(ExitStatus) + Send + 'static,
+{
+    use std::{
+        env,
+        os::{
+            fd::{AsRawFd, RawFd},
+            unix::process::CommandExt,
+        },
+        process::Command,
+    };
+
+    let mut command = Command::new("/proc/self/exe");
+    command
+        .args(env::args_os().skip(1))
+        .arg("--session-worker")
+        .arg(session.to_string())
+        .env_remove("LISTEN_PID")
+        .env_remove("LISTEN_FDS")
+        .env_remove("LISTEN_FDNAMES")
+        .env_remove("NOTIFY_SOCKET");
+
+    let mut fds: Vec<RawFd> = streams.iter().map(AsRawFd::as_raw_fd).collect();
+    let above = SessionWorker::FIRST_FD + fds.len() as RawFd;
+    unsafe {
+        command.pre_exec(move || {
+            // Streams are moved above the inherited range first, so none of them is overwritten before it is moved.
+            for fd in fds.iter_mut() {
+                *fd = cvt(libc::fcntl(*fd, libc::F_DUPFD_CLOEXEC, above))?;
+            }
+            for (index, fd) in fds.iter().enumerate() {
+                cvt(libc::dup2(*fd, SessionWorker::FIRST_FD + index as RawFd))?;
+            }
+            // Orphaned worker would keep objects of its guest on the GPU.
+            cvt(libc::prctl(libc::PR_SET_PDEATHSIG, libc::SIGTERM))?;
+            Ok(())
+        })
+    };
+
+    let mut child = command.spawn()?;
+    let worker = Worker { pid: child.id() };
+    std::thread::spawn(move || match child.wait() {
+        Ok(status) => exited(status),
+        Err(e) => error!("Failed to wait for worker {}: {}", child.id(), e),
+    });
+    Ok(worker)
+}
+
+#[cfg(not(target_os = "linux"))]
+pub fn spawn<F>(_session: SessionWorker, _streams: Vec<Stream>, _exited: F) -> io::Result<Worker>
+where
+    F: FnOnce(ExitStatus) + Send + 'static,
+{
+    Err(io::ErrorKind::Unsupported.into())
+}
+
+#[cfg(target_os = "linux")]
+impl Worker {
+    /// Asks the worker to close its connection and destroy objects of the guest, like SIGTERM of the host does.
+    pub fn terminate(&self) {
+        self.signal(libc::SIGTERM);
+    }
+
+    pub fn kill(&self) {
+        self.signal(libc::SIGKILL);
+    }
+
+    /// Makes the worker log live objects of its guest.
+    pub fn dump_objects(&self) {
+        self.signal(libc::SIGUSR1);
+    }
+
+    fn signal(&self, signal: libc::c_int) {
+        if unsafe { libc::kill(self.pid as libc::pid_t, signal) } < 0 {
+            warn!(
+                "Failed to send signal {} to worker {}: {}",
+                signal,
+                self.pid,
+                io::Error::last_os_error()
+            );
+        }
+    }
+}
+
+#[cfg(not(target_os = "linux"))]
+impl Worker {
+    pub fn terminate(&self) {}
+
+    pub fn kill(&self) {}
+
+    pub fn dump_objects(&self) {}
+}
+
+#[cfg(target_os = "linux")]
+fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
+    match result < 0 {
+        true => Err(io::Error::last_os_error()),
+        false => Ok(result),
+    }
+}
+
+/// Serves the session handed over by the host, until its connection is closed or the host shuts down.
+#[cfg(target_os = "linux")]
+pub fn run(config: &crate::config::Config, session: SessionWorker) {
+    use std::{os::fd::RawFd, path::Path, process, sync::mpsc};
+
+    use crate::{daemon, stats, Event};
+
+    let streams = (0..session.streams)
+        .map(|index| unsafe { Stream::from_fd(SessionWorker::FIRST_FD + index as RawFd) })
+        .collect::<io::Result<Vec<_>>>()
+        .unwrap_or_else(|e| panic!("Failed to take streams of the session: {}", e));
+    info!(
+        "Worker {} serves session {:#x} with {} stream(s)",
+        process::id(),
+        session.token,
+        streams.len()
+    );
+
+    // Vulkan library is loaded before the sandbox, drivers are loaded later by instances of the guest.
+    let driver = config.driver(crate::guest_cid(&streams));
+    driver.use_icd_files();
+    let Some(host_session) = crate::new_host_session(config, &streams) else {
+        process::exit(1);
+    };
+    if config.sandbox {
+        let writable_directories: Vec<&Path> = config
+            .capture
+            .as_ref()
+            .and_then(|capture| capture.file.parent())
+            .into_iter()
+            .chain(config.stats_directory.as_deref())
+            .collect();
+        let library_directory = driver.library.as_deref().and_then(Path::parent);
+        if let Err(e) = crate::sandbox::apply(&writable_directories, library_directory) {
+            error!("Failed to sandbox worker: {}", e);
+            process::exit(1);
+        }
+    }
+    crate::configure_handlers(config);
+
+    let (sender, receiver) = mpsc::channel();
+    let shutdown_sender = sender.clone();
+    if let Err(e) = daemon::on_shutdown_signal(move || _ = shutdown_sender.send(Event::Shutdown)) {
+        warn!("Unable to handle shutdown signals: {}", e);
+    }
+    let dump_sender = sender.clone();
+    if let Err(e) = daemon::on_dump_signal(move || _ = dump_sender.send(Event::DumpObjects)) {
+        warn!("Unable to handle dump signal: {}", e);
+    }
+    if config.stats_directory.is_some() {
+        let stats_sender = sender.clone();
+        stats::on_interval(move || _ = stats_sender.send(Event::WriteStats));
+    }
+
+    let connection = crate::start_connection(&host_session, session.token, streams, sender, config);
+    loop {
+        match receiver.recv().unwrap() {
+            Event::Closed(_) => {
+                info!("Connection of session {:#x} closed", session.token);
+                break;
+            }
+            Event::Shutdown => {
+                connection.close();
+                break;
+            }
+            Event::DumpObjects => {
+                info!("Live objects of session {:#x}:", session.token);
+                host_session.dump_objects();
+                info!("Session {:#x}: {}", session.token, host_session.metrics());
+            }
+            Event::WriteStats => {
+                if let Some(directory) = &config.stats_directory {
+                    stats::write(directory, session.token, &host_session.metrics());
+                }
+            }
+            Event::Accepted(_) | Event::WorkerExited(_) | Event::SessionClosed(_) => unreachable!(),
+        }
+    }
+    host_session.close(config.shutdown_timeout);
+    if let Some(directory) = &config.stats_directory {
+        stats::remove(directory, session.token);
+    }
+}
@@ -4,7 +4,7 @@ use itertools::Itertools;
 use vk_parse::CommandDefinition;
 
 use crate::{
-    function_data::{CommandExt, CommandParamExt},
+    function_data::{CommandExt, CommandParamExt, FunctionType},
     push_indentation, push_param_name, to_rust_type, trace,
     transport::{self, check_if_count_ptr},
     vulkan_types::TypeVulkan,
@@ -117,11 +117,38 @@ fn generate_command(
 
     trace(builder, definition, false);
 
+    // Objects created before device lost does not exist on host, new instance starts from scratch.
+    push_indentation(builder, 1);
+    match definition.function_type() {
+        FunctionType::Entry => {
+            if definition.proto.name == "vkCreateInstance" {
+                builder.push_str("wie_transport_guest::reset_device_lost();\n");
+            }
+        }
+        _ => {
+            builder.push_str("if wie_transport_guest::is_device_lost() {\n");
+            push_device_lost_return(builder, definition, types, 2);
+            push_indentation(builder, 1);
+            builder.push_str("}\n");
+        }
+    }
+
     // Packet creation
     push_indentation(builder, 1);
-    builder.push_str("let mut packet = new_packet(");
-    builder.push_str(&handler_id.to_string());
-    builder.push_str(");\n");
+    match definition.packet_priority() {
+        Some(priority) => {
+            builder.push_str("let mut packet = wie_transport_guest::new_packet_with_priority(");
+            builder.push_str(&handler_id.to_string());
+            builder.push_str(", wie_transport::packet::Priority::");
+            builder.push_str(priority);
+            builder.push_str(");\n");
+        }
+        None => {
+            builder.push_str("let mut packet = new_packet(");
+            builder.push_str(&handler_id.to_string());
+            builder.push_str(");\n");
+        }
+    }
 
     let mut last_is_count = false;
     for param in definition.params.iter().unique_by(|x| &x.definition.name) {
@@ -146,7 +173,12 @@ fn generate_command(
     builder.push('\n');
     push_indentation(builder, 1);
     if definition.is_return_data(types) {
-        builder.push_str("let mut response = packet.send_with_response();\n");
+        builder.push_str("let Ok(mut response) = packet.try_send_with_response() else {\n");
+        push_indentation(builder, 2);
+        builder.push_str("wie_transport_guest::set_device_lost();\n");
+        push_device_lost_return(builder, definition, types, 2);
+        push_indentation(builder, 1);
+        builder.push_str("};\n");
         unpack_response(builder, definition, types);
     } else {
         builder.push_str("packet.send();\n");
@@ -155,6 +187,23 @@ fn generate_command(
     builder.push_str("}\n");
 }
 
+/// Returns from the function with value which reports lost connection to the application.
+fn push_device_lost_return(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    indentation: usize,
+) {
+    push_indentation(builder, indentation);
+    match to_rust_type(&definition.proto, types).as_str() {
+        "std::ffi::c_void" => builder.push_str("return;\n"),
+        "VkResult" => {
+            builder.push_str("return vk::Result::ERROR_DEVICE_LOST.as_raw() as VkResult;\n")
+        }
+        _ => builder.push_str("return std::mem::zeroed();\n"),
+    }
+}
+
 fn unpack_response(builder: &mut String, definition: &CommandDefinition, types: &TypeVulkan) {
     let mut last_is_count = false;
     for param in definition
@@ -26,7 +26,7 @@ pub fn generate(
 }
 
 fn generate_struct(builder: &mut String, commands: &[&CommandDefinition]) {
-    builder.push_str("\npub struct FunctionAddressTable {\n");
+    builder.push_str("\n#[derive(Clone)]\npub struct FunctionAddressTable {\n");
 
     for command in commands {
         push_indentation(builder, 1);
@@ -50,9 +50,6 @@ fn generate_impl(
     generate_new(builder, commands, types);
     generate_set_address(builder, commands, required_commands);
     builder.push_str("}\n");
-
-    builder.push_str("\nunsafe impl Sync for FunctionAddressTable {}\n");
-    builder.push_str("\nunsafe impl Send for FunctionAddressTable {}\n");
 }
 
 fn generate_new(builder: &mut String, commands: &[&CommandDefinition], types: &TypeVulkan) {
@@ -14,6 +14,8 @@ pub trait CommandExt {
     fn function_type(&self) -> FunctionType;
     fn is_return_data(&self, types: &TypeVulkan) -> bool;
     fn get_alias(&self, required_commands: &HashSet<&str>) -> Option<String>;
+    fn packet_priority(&self) -> Option<&'static str>;
+    fn is_queue_handler(&self) -> bool;
 }
 
 impl CommandExt for vk_parse::CommandDefinition {
@@ -81,6 +83,44 @@ impl CommandExt for vk_parse::CommandDefinition {
         }
         None
     }
+
+    /// Returns priority class of the packet, when it differs from the default one.
+    fn packet_priority(&self) -> Option<&'static str> {
+        match self.proto.name.as_str() {
+            // Synchronization and status queries, which are usually called in the middle of a frame.
+            "vkGetFenceStatus"
+            | "vkWaitForFences"
+            | "vkResetFences"
+            | "vkGetEventStatus"
+            | "vkGetSemaphoreCounterValue"
+            | "vkGetSemaphoreCounterValueKHR"
+            | "vkWaitSemaphores"
+            | "vkWaitSemaphoresKHR"
+            | "vkSignalSemaphore"
+            | "vkSignalSemaphoreKHR"
+            | "vkGetQueryPoolResults"
+            | "vkAcquireNextImageKHR"
+            | "vkAcquireNextImage2KHR"
+            | "vkQueuePresentKHR" => Some("High"),
+            // Commands which carry big blobs of data and wait for their result. Recorded commands like
+            // `vkCmdUpdateBuffer` stay in the normal lane, as they are not answered and would be reordered against other
+            // commands of their command buffer.
+            "vkCreateShaderModule"
+            | "vkCreatePipelineCache"
+            | "vkMergePipelineCaches"
+            | "vkFlushMappedMemoryRanges" => Some("Bulk"),
+            _ => None,
+        }
+    }
+
+    /// Tells if the command runs on the queue thread of its session, as it can be delayed by the quotas and the
+    /// scheduler.
+    fn is_queue_handler(&self) -> bool {
+        matches!(
+            self.proto.name.as_str(),
+            "vkQueueSubmit" | "vkQueueSubmit2" | "vkQueueSubmit2KHR" | "vkQueuePresentKHR"
+        )
+    }
 }
 
 pub trait CommandParamExt {
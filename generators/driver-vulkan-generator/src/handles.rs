@@ -0,0 +1,492 @@
+use std::{collections::HashSet, fs, path::Path};
+
+use vk_parse::{CommandDefinition, CommandParam, Type, TypeMemberDefinition};
+
+use crate::{
+    function_data::CommandParamExt, push_element_name, push_indentation, push_param_name,
+    to_screaming_snake_case, to_snake_case, transport::check_if_count_ptr,
+    vulkan_types::TypeVulkan,
+};
+
+const VK_STRUCTURE_TYPE: &str = "VK_STRUCTURE_TYPE_";
+
+/// Structures which contain handles, directly, through other structures or through `pNext` chain. The listener
+/// translates handles in them between guest IDs and host handles with generated walkers.
+pub struct HandleFields<'a> {
+    walked: HashSet<&'a str>,
+    /// Structures which can be chained by `pNext`, with their `VkStructureType`.
+    chained: Vec<(&'a str, &'a str)>,
+}
+
+pub(crate) struct Member<'a> {
+    pub name: &'a str,
+    pub type_name: &'a str,
+    pub definition: &'a TypeMemberDefinition,
+}
+
+impl<'a> HandleFields<'a> {
+    pub fn new(types: &'a TypeVulkan, structure_types: &'a [(String, String)]) -> Self {
+        // Unions are walked only when they contain handles, other structures also for their chains.
+        let with_handles = walked_types(types, |_| false, |_| false);
+        let walked = walked_types(
+            types,
+            |ty| ty.category.as_deref() == Some("union") && !with_handles.contains(name(ty)),
+            |member| member.name == "pNext",
+        );
+
+        let chained = structure_types
+            .iter()
+            .filter(|(_, type_name)| walked.contains(type_name.as_str()))
+            .map(|(structure_type, type_name)| (structure_type.as_str(), type_name.as_str()))
+            .collect();
+
+        Self { walked, chained }
+    }
+
+    /// Returns true, when the input parameter is a handle passed by value, so it is translated in place.
+    pub fn is_translated_value(&self, param: &CommandParam, types: &TypeVulkan) -> bool {
+        !param.definition.code.contains('*')
+            && is_handle(&param.definition.name, param_type(param), types)
+    }
+}
+
+/// Returns types which contain handles, directly or in members of other returned types.
+fn walked_types<'a>(
+    types: &TypeVulkan<'a>,
+    skip: impl Fn(&Type) -> bool,
+    is_walked: impl Fn(&Member) -> bool,
+) -> HashSet<&'a str> {
+    let mut walked = HashSet::new();
+    loop {
+        let mut changed = false;
+        for ty in types.types.iter().copied() {
+            if walked.contains(name(ty)) || skip(ty) {
+                continue;
+            }
+
+            let contains_handles = members(ty).iter().any(|member| {
+                is_walked(member)
+                    || is_handle(member.name, member.type_name, types)
+                    || walked.contains(member.type_name)
+            });
+            if contains_handles {
+                walked.insert(name(ty));
+                changed = true;
+            }
+        }
+
+        if !changed {
+            return walked;
+        }
+    }
+}
+
+/// Generates walkers, which call the map for every handle in the structure with its object type, and walk its `pNext`
+/// chain.
+pub fn generate(project_directory: &Path, fields: &HandleFields, types: &TypeVulkan) {
+    let mut builder = String::new();
+    builder.push_str("//! THIS FILE IS GENERATED BY TOOL, DO NOT MODIFY.\n\nuse ash::vk;\nuse std::ffi::c_void;\nuse wie_driver_common_vulkan::generated::vulkan_types::*;\nuse crate::handles::{slice_mut, Map};\n");
+
+    generate_p_next(&mut builder, fields);
+    for ty in &types.types {
+        if fields.walked.contains(ty.name.as_deref().unwrap()) {
+            generate_walker(&mut builder, ty, fields, types);
+        }
+    }
+
+    let path =
+        project_directory.join("crates/driver-listener-vulkan/src/generated/handle_walkers.rs");
+    fs::create_dir_all(path.parent().unwrap()).expect("create directories");
+    fs::write(path, builder).expect("write to a file");
+}
+
+fn generate_p_next(builder: &mut String, fields: &HandleFields) {
+    builder.push_str(
+        "\npub(crate) unsafe fn p_next(p_next: *const c_void, map: &mut Map) -> bool {\n",
+    );
+    push_indentation(builder, 1);
+    builder.push_str("if p_next.is_null() {\n");
+    push_indentation(builder, 2);
+    builder.push_str("return true;\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n\n");
+    push_indentation(builder, 1);
+    builder.push_str("let base = &*(p_next as *const vk::BaseInStructure);\n");
+    push_indentation(builder, 1);
+    builder.push_str("match base.s_type {\n");
+
+    for (structure_type, type_name) in &fields.chained {
+        push_indentation(builder, 2);
+        builder.push_str("vk::StructureType::");
+        builder.push_str(&structure_type[VK_STRUCTURE_TYPE.len()..]);
+        builder.push_str(" => ");
+        to_snake_case(builder, type_name);
+        builder.push_str("(p_next as *mut ");
+        builder.push_str(type_name);
+        builder.push_str(", map),\n");
+    }
+
+    push_indentation(builder, 2);
+    builder.push_str("_ => p_next(base.p_next as *const c_void, map),\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n}\n");
+}
+
+fn generate_walker(builder: &mut String, ty: &Type, fields: &HandleFields, types: &TypeVulkan) {
+    let type_name = ty.name.as_deref().unwrap();
+
+    builder.push_str("\npub(crate) unsafe fn ");
+    to_snake_case(builder, type_name);
+    builder.push_str("(x: *mut ");
+    builder.push_str(type_name);
+    builder.push_str(", map: &mut Map) -> bool {\n");
+    push_indentation(builder, 1);
+    builder.push_str("if x.is_null() {\n");
+    push_indentation(builder, 2);
+    builder.push_str("return true;\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+
+    if ty.category.as_deref() == Some("union") {
+        // Active member of the union is not known, so handles in it cannot be translated.
+        push_indentation(builder, 1);
+        builder.push_str("let _ = map;\n");
+        push_indentation(builder, 1);
+        builder.push_str("false\n}\n");
+        return;
+    }
+
+    push_indentation(builder, 1);
+    builder.push_str("let mut known = true;\n");
+
+    let members = members(ty);
+    for (index, member) in members.iter().enumerate() {
+        let is_handle = is_handle(member.name, member.type_name, types);
+        if member.name == "pNext" {
+            push_indentation(builder, 1);
+            builder.push_str("known &= p_next((*x).p_next as *const c_void, map);\n");
+            continue;
+        }
+        if !is_handle && !fields.walked.contains(member.type_name) {
+            continue;
+        }
+
+        let code = &member.definition.code;
+        let element = match is_handle {
+            true => {
+                let mut element = "known &= map(element, ".to_owned();
+                push_object_type(&mut element, member.type_name, types);
+                element.push_str(");\n");
+                element
+            }
+            false => {
+                let mut element = "known &= ".to_owned();
+                to_snake_case(&mut element, member.type_name);
+                element.push_str("(element, map);\n");
+                element
+            }
+        };
+
+        push_indentation(builder, 1);
+        match code.matches('*').count() {
+            0 if code.contains('[') => {
+                builder.push_str("for element in (*x).");
+                push_element_name(builder, member.name);
+                builder.push_str(".iter_mut()");
+                // Fixed arrays are usually preceded by count, like `physicalDevices` of device group properties.
+                if let Some(count) = index
+                    .checked_sub(1)
+                    .map(|x| &members[x])
+                    .filter(|x| x.name.ends_with("Count"))
+                {
+                    builder.push_str(".take((*x).");
+                    push_element_name(builder, count.name);
+                    builder.push_str(" as usize)");
+                }
+                builder.push_str(" {\n");
+                push_indentation(builder, 2);
+                builder.push_str(&element);
+                push_indentation(builder, 1);
+                builder.push_str("}\n");
+            }
+            0 => {
+                builder.push_str("let element = &mut (*x).");
+                push_element_name(builder, member.name);
+                builder.push_str(";\n");
+                push_indentation(builder, 1);
+                builder.push_str(&element);
+            }
+            1 => match len_expression(member, &members) {
+                Some(len) => {
+                    builder.push_str("for element in slice_mut((*x).");
+                    push_element_name(builder, member.name);
+                    builder.push_str(", ");
+                    builder.push_str(&len);
+                    builder.push_str(") {\n");
+                    push_indentation(builder, 2);
+                    builder.push_str(&element);
+                    push_indentation(builder, 1);
+                    builder.push_str("}\n");
+                }
+                None => {
+                    builder.push_str("// Length of `");
+                    builder.push_str(member.name);
+                    builder.push_str("` is not supported.\n");
+                    push_indentation(builder, 1);
+                    builder.push_str("known = false;\n");
+                }
+            },
+            _ => {
+                builder.push_str("// Arrays of pointers are not supported.\n");
+                push_indentation(builder, 1);
+                builder.push_str("known = false;\n");
+            }
+        }
+    }
+
+    push_indentation(builder, 1);
+    builder.push_str("known\n}\n");
+}
+
+/// Writes code which translates guest IDs in parameters to host handles, before the command is called. Sets `known`
+/// to false, when the guest passed an unknown ID. Returns false, when the command does not take any handles.
+pub fn translate_inputs(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    fields: &HandleFields,
+    types: &TypeVulkan,
+) -> bool {
+    let params: Vec<_> = unique_params(definition)
+        .filter(|x| !x.is_return_data(types))
+        .filter(|x| {
+            fields.is_translated_value(x, types)
+                || (x.definition.code.contains('*')
+                    && (is_handle(&x.definition.name, param_type(x), types)
+                        || fields.walked.contains(param_type(x))))
+        })
+        .collect();
+    if params.is_empty() {
+        return false;
+    }
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("let handles = &crate::session::current().handles;\n");
+    push_indentation(builder, 1);
+    builder.push_str("let mut known = true;\n");
+
+    for param in params {
+        push_indentation(builder, 1);
+        if fields.is_translated_value(param, types) {
+            builder.push_str("known &= handles.to_host(&mut ");
+            push_param_name(builder, param);
+            builder.push_str(", ");
+            push_object_type(builder, param_type(param), types);
+            builder.push_str(");\n");
+            continue;
+        }
+
+        if param.definition.code.matches('*').count() > 1 {
+            builder.push_str("// Arrays of pointers are not supported.\n");
+            push_indentation(builder, 1);
+            builder.push_str("known = false;\n");
+            continue;
+        }
+
+        builder.push_str("known &= unsafe { ");
+        push_walk(builder, param, "1", "handles.to_host", types);
+        builder.push_str(" };\n");
+    }
+
+    push_indentation(builder, 1);
+    builder.push_str("if !known {\n");
+    push_indentation(builder, 2);
+    builder.push_str("warn!(\"");
+    builder.push_str(&definition.proto.name);
+    builder.push_str(" rejected, guest passed unknown handle\");\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+    true
+}
+
+/// Writes code which replaces host handles returned by the command with guest IDs. It is placed after the call, handles
+/// are written only when the command was called, see `called` of `listener::generate_command`, and succeeded.
+pub fn translate_outputs(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    fields: &HandleFields,
+    types: &TypeVulkan,
+    checked: bool,
+    called: Option<&str>,
+) {
+    let mut outputs = Vec::new();
+    let mut count = None;
+    for param in unique_params(definition) {
+        if check_if_count_ptr(param) {
+            count = Some(param);
+            continue;
+        }
+
+        let ty = param_type(param);
+        if param.is_return_data(types)
+            && (is_handle(&param.definition.name, ty, types) || fields.walked.contains(ty))
+        {
+            outputs.push((param, count));
+        }
+        count = None;
+    }
+    if outputs.is_empty() {
+        return;
+    }
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("if ");
+    if let Some(called) = called {
+        builder.push_str(called);
+        builder.push_str(" && ");
+    }
+    match definition.proto.type_name.as_deref() {
+        Some("VkResult") => builder.push_str("(result as i32) >= 0"),
+        _ => builder.push_str("true"),
+    }
+    builder.push_str(" {\n");
+    if !checked {
+        push_indentation(builder, 2);
+        builder.push_str("let handles = &crate::session::current().handles;\n");
+    }
+
+    for (param, count) in outputs {
+        let mut len = String::new();
+        match count {
+            Some(count) => {
+                push_param_name(&mut len, count);
+                len.push_str(" as usize");
+            }
+            None => len.push('1'),
+        }
+
+        push_indentation(builder, 2);
+        builder.push_str("unsafe { ");
+        push_walk(builder, param, &len, "handles.to_guest", types);
+        builder.push_str(" };\n");
+    }
+
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+}
+
+/// Writes expression which maps handles of the parameter with the `map` method, which takes the handle and its object
+/// type. Packets carry a single element of arrays which are not preceded by count pointer, see
+/// `transport::read_packet_param`, so only that one is mapped.
+fn push_walk(builder: &mut String, param: &CommandParam, len: &str, map: &str, types: &TypeVulkan) {
+    let ty = param_type(param);
+    builder.push_str("slice_mut(");
+    push_param_name(builder, param);
+    builder.push_str(", ");
+    builder.push_str(len);
+    builder.push_str(").iter_mut().all(|x| ");
+    builder.push_str(map);
+    match is_handle(&param.definition.name, ty, types) {
+        true => {
+            builder.push_str("(x, ");
+            push_object_type(builder, ty, types);
+            builder.push(')');
+        }
+        false => {
+            builder.push_str("crate::generated::handle_walkers::");
+            to_snake_case(builder, ty);
+            builder.push_str("(x, &mut |x, ty| ");
+            builder.push_str(map);
+            builder.push_str("(x, ty))");
+        }
+    }
+    builder.push(')');
+}
+
+fn len_expression(member: &Member, members: &[Member]) -> Option<String> {
+    let definition = member.definition;
+    if definition.altlen.is_some() {
+        return None;
+    }
+
+    let Some(len) = definition.len.as_deref() else {
+        return Some("1".to_owned());
+    };
+    let len = len.split(',').next().unwrap().trim();
+    let count = members.iter().find(|x| x.name == len)?;
+
+    let mut expression = "(*x).".to_owned();
+    push_element_name(&mut expression, count.name);
+    expression.push_str(" as usize");
+    Some(expression)
+}
+
+/// Writes `vk::ObjectType` of the handle type, like `vk::ObjectType::BUFFER` for `VkBuffer`. Object handles passed as
+/// `uint64_t` get `UNKNOWN`, which accepts any object, as their type is a separate parameter.
+pub(crate) fn push_object_type(builder: &mut String, type_name: &str, types: &TypeVulkan) {
+    builder.push_str("vk::ObjectType::");
+    match types.contains_handle(type_name) {
+        true => to_screaming_snake_case(builder, &type_name["Vk".len()..]),
+        false => builder.push_str("UNKNOWN"),
+    }
+}
+
+/// Handles, and object handles of debug names and private data, which are passed as `uint64_t` with their type.
+pub(crate) fn is_handle(name: &str, type_name: &str, types: &TypeVulkan) -> bool {
+    types.contains_handle(type_name)
+        || (type_name == "uint64_t" && matches!(name, "object" | "objectHandle"))
+}
+
+pub(crate) fn members(ty: &Type) -> Vec<Member> {
+    let vk_parse::TypeSpec::Members(members) = &ty.spec else {
+        return Vec::new();
+    };
+
+    let mut result: Vec<Member> = Vec::new();
+    for member in members {
+        let vk_parse::TypeMember::Definition(definition) = member else {
+            continue;
+        };
+
+        let mut name = None;
+        let mut type_name = "";
+        for markup in &definition.markup {
+            match markup {
+                vk_parse::TypeMemberMarkup::Name(x) => name = Some(x.as_str()),
+                vk_parse::TypeMemberMarkup::Type(x) => type_name = x.as_str(),
+                _ => {}
+            }
+        }
+
+        let Some(name) = name else {
+            continue;
+        };
+        // Same as in generated types, members defined for more APIs are listed repeatedly.
+        if result.last().is_some_and(|x| x.name == name) {
+            continue;
+        }
+        result.push(Member {
+            name,
+            type_name,
+            definition,
+        });
+    }
+    result
+}
+
+pub(crate) fn unique_params(definition: &CommandDefinition) -> impl Iterator<Item = &CommandParam> {
+    let mut names = HashSet::new();
+    definition
+        .params
+        .iter()
+        .filter(move |x| names.insert(x.definition.name.as_str()))
+}
+
+pub(crate) fn name(ty: &Type) -> &str {
+    ty.name.as_deref().unwrap()
+}
+
+pub(crate) fn param_type(param: &CommandParam) -> &str {
+    param.definition.type_name.as_deref().unwrap_or_default()
+}
@@ -5,14 +5,24 @@ use vk_parse::CommandDefinition;
 
 use crate::{
     function_data::{CommandExt, CommandParamExt},
+    handles::{self, HandleFields},
+    objects::{self, ObjectLifetimes},
     push_indentation, push_param_name, to_rust_type, to_rust_type_without_ptr, to_snake_case,
     trace,
     transport::{self, check_if_count_ptr},
+    validators::{self, Validators},
     vulkan_types::TypeVulkan,
     VULKAN_HANDLERS_BEGIN,
 };
 
-pub fn generate(project_directory: &Path, commands: &[&CommandDefinition], types: &TypeVulkan) {
+pub fn generate(
+    project_directory: &Path,
+    commands: &[&CommandDefinition],
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+    fields: &HandleFields,
+    validators: &Validators,
+) {
     let overrided_commands = OverridedCommands::new(project_directory);
 
     let mut builder = String::new();
@@ -23,7 +33,15 @@ pub fn generate(project_directory: &Path, commands: &[&CommandDefinition], types
     generate_function_handler_map(&mut builder, commands);
 
     for definition in commands {
-        generate_command(&mut builder, definition, types, &overrided_commands);
+        generate_command(
+            &mut builder,
+            definition,
+            types,
+            &overrided_commands,
+            lifetimes,
+            fields,
+            validators,
+        );
     }
 
     let path = project_directory.join("crates/driver-listener-vulkan/src/generated/handlers.rs");
@@ -47,6 +65,15 @@ fn generate_function_handler_map(builder: &mut String, commands: &[&CommandDefin
     }
 
     builder.push_str("}\n");
+
+    builder.push_str("\n/// Handlers which run on the queue thread of their session.\npub(crate) const QUEUE_HANDLERS: &[u64] = &[");
+    let queue_handlers = (VULKAN_HANDLERS_BEGIN..)
+        .zip(commands)
+        .filter(|(_, definition)| definition.is_queue_handler())
+        .map(|(i, _)| i.to_string())
+        .join(", ");
+    builder.push_str(&queue_handlers);
+    builder.push_str("];\n");
 }
 
 fn generate_command(
@@ -54,6 +81,9 @@ fn generate_command(
     definition: &CommandDefinition,
     types: &TypeVulkan,
     overrided_commands: &OverridedCommands,
+    lifetimes: &ObjectLifetimes,
+    fields: &HandleFields,
+    validators: &Validators,
 ) {
     builder.push_str(
         "\n#[doc = \"<https://registry.khronos.org/vulkan/specs/1.3-extensions/man/html/",
@@ -63,13 +93,36 @@ fn generate_command(
     to_snake_case(builder, &definition.proto.name);
     builder.push_str("(mut packet: Packet) {\n");
 
-    unpack_packet(builder, definition, types);
+    unpack_packet(builder, definition, types, fields);
     trace(builder, definition, true);
+    let validated = validators::validate(builder, definition, validators, types);
+    let checked = handles::translate_inputs(builder, definition, fields, types);
+    let limited = objects::check_quota(builder, definition, types, lifetimes);
+    // Expression which tells if the command is called, rejected commands are not.
+    let conditions: Vec<_> = [
+        (validated, "valid"),
+        (checked, "known"),
+        (limited, "within_quota"),
+    ]
+    .into_iter()
+    .filter_map(|(emitted, condition)| emitted.then_some(condition))
+    .collect();
+    let called = (!conditions.is_empty()).then(|| conditions.join(" && "));
+    let called = called.as_deref();
 
     let return_type = to_rust_type(&definition.proto, types);
     let is_void = return_type == "std::ffi::c_void";
 
-    call_vulkan_function(builder, definition, is_void, overrided_commands);
+    call_vulkan_function(
+        builder,
+        definition,
+        is_void,
+        called,
+        limited,
+        overrided_commands,
+    );
+    objects::track(builder, definition, types, lifetimes, called);
+    handles::translate_outputs(builder, definition, fields, types, checked, called);
     if definition.is_return_data(types) {
         write_response(builder, definition, is_void, types);
     }
@@ -77,7 +130,12 @@ fn generate_command(
     builder.push_str("}\n");
 }
 
-fn unpack_packet(builder: &mut String, definition: &CommandDefinition, types: &TypeVulkan) {
+fn unpack_packet(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    fields: &HandleFields,
+) {
     let mut last_is_count = false;
     for param in definition.params.iter().unique_by(|x| &x.definition.name) {
         let is_count = check_if_count_ptr(param);
@@ -99,6 +157,10 @@ fn unpack_packet(builder: &mut String, definition: &CommandDefinition, types: &T
                 push_param_name(builder, param);
                 builder.push_str(", ");
             } else {
+                // Handles are replaced with host ones in place.
+                if fields.is_translated_value(param, types) {
+                    builder.push_str("mut ");
+                }
                 push_param_name(builder, param);
                 builder.push_str(": ");
                 builder.push_str(&to_rust_type(&param.definition, types));
@@ -115,19 +177,43 @@ fn call_vulkan_function(
     builder: &mut String,
     definition: &CommandDefinition,
     is_void: bool,
+    called: Option<&str>,
+    limited: bool,
     overrided_commands: &OverridedCommands,
 ) {
     push_indentation(builder, 1);
     if !is_void {
         builder.push_str("let result = ");
     }
+    // Rejected command is not called, result tells the guest that it failed.
+    if let Some(called) = called {
+        builder.push_str("match ");
+        builder.push_str(called);
+        builder.push_str(" {\n");
+        push_indentation(builder, 2);
+        builder.push_str("false => ");
+        match (is_void, definition.proto.type_name.as_deref()) {
+            (true, _) => builder.push_str("()"),
+            // Commands over the object quota fail like the host ran out of memory.
+            (false, Some("VkResult")) if limited => builder.push_str(
+                "match within_quota { true => vk::Result::ERROR_VALIDATION_FAILED_EXT, false => vk::Result::ERROR_OUT_OF_HOST_MEMORY }.as_raw() as _",
+            ),
+            (false, Some("VkResult")) => {
+                builder.push_str("vk::Result::ERROR_VALIDATION_FAILED_EXT.as_raw() as _")
+            }
+            (false, _) => builder.push_str("Default::default()"),
+        }
+        builder.push_str(",\n");
+        push_indentation(builder, 2);
+        builder.push_str("true => ");
+    }
     builder.push_str("unsafe {\n");
 
     push_indentation(builder, 2);
     if overrided_commands.is_overrided(&definition.proto.name) {
         call_vulkan_overrided_function(builder, definition);
     } else {
-        builder.push_str("(crate::FUNCTION_ADDRESS_TABLE.");
+        builder.push_str("(crate::session::current().function_table().");
         to_snake_case(builder, &definition.proto.name);
         builder.push_str(")(\n");
     }
@@ -146,6 +232,10 @@ fn call_vulkan_function(
     builder.push_str(")\n");
 
     push_indentation(builder, 1);
+    if called.is_some() {
+        builder.push_str("},\n");
+        push_indentation(builder, 1);
+    }
     builder.push_str("};\n");
 }
 
@@ -231,9 +321,12 @@ impl OverridedCommands {
         }
     }
 
+    /// Overrided functions are re-exported one per line, like `pub use instance::vk_create_instance;`, so names which
+    /// start with another one, like `vk_queue_submit2`, are not confused.
     fn is_overrided(&self, name: &str) -> bool {
-        let mut snake_case = String::new();
+        let mut snake_case = String::from("::");
         to_snake_case(&mut snake_case, name);
+        snake_case.push(';');
         self.mod_file.contains(&snake_case)
     }
 }
@@ -11,11 +11,14 @@ mod driver;
 pub mod enums;
 mod function_address_table;
 pub mod function_data;
+mod handles;
 mod listener;
+mod objects;
 mod p_next;
 mod pfn_functions;
 mod transport;
 mod utils;
+mod validators;
 mod vulkan_bitmasks;
 mod vulkan_types;
 
@@ -86,7 +89,24 @@ fn generate(vk_headers_path: &Path, project_directory: &Path) {
     println!("Generating function address table...");
     function_address_table::generate(project_directory, &commands, &required_commands, &types);
     println!("Generating transport...");
-    listener::generate(project_directory, &commands, &types);
+    let lifetimes = objects::ObjectLifetimes::new(&commands, &types);
+    let structure_types = p_next::get_structure_types(&spec, &registry, &types);
+    let fields = handles::HandleFields::new(&types, &structure_types);
+    let validators = validators::Validators::new(&types, &structure_types);
+    listener::generate(
+        project_directory,
+        &commands,
+        &types,
+        &lifetimes,
+        &fields,
+        &validators,
+    );
+    println!("Generating object destroyers...");
+    objects::generate(project_directory, &lifetimes);
+    println!("Generating handle walkers...");
+    handles::generate(project_directory, &fields, &types);
+    println!("Generating validators...");
+    validators::generate(project_directory, &commands, &validators, &types);
 }
 
 fn get_required_types_commands_and_extensions(
@@ -0,0 +1,493 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use vk_parse::{CommandDefinition, CommandParam};
+
+use crate::{
+    handles::push_object_type, push_element_name, push_indentation, push_param_name,
+    to_rust_expression, to_screaming_snake_case, to_snake_case, vulkan_types::TypeVulkan,
+};
+
+/// Commands which create and destroy objects of every handle type, read from the registry, so the listener can track
+/// objects of the guest and destroy them when it disappears.
+pub struct ObjectLifetimes<'a> {
+    /// Commands which destroy a single object, keyed by its handle type.
+    destroyers: HashMap<&'a str, Destroyer<'a>>,
+    /// Handle types which are freed in arrays, like command buffers. They are destroyed together with their pool.
+    freed_with_parent: HashSet<&'a str>,
+    /// Handle type from which objects are created, from `parent` attribute of the handle.
+    parents: HashMap<&'a str, &'a str>,
+}
+
+struct Destroyer<'a> {
+    command: &'a str,
+    /// Instance or device passed to the command, missing when the command destroys the instance or device itself.
+    owner: Option<&'a str>,
+}
+
+impl<'a> ObjectLifetimes<'a> {
+    pub fn new(commands: &[&'a CommandDefinition], types: &'a TypeVulkan) -> Self {
+        let mut destroyers = HashMap::new();
+        let mut freed_with_parent = HashSet::new();
+
+        for command in commands.iter().filter(|x| is_destroy_command(x)) {
+            let params = &command.params;
+            let is_allocator = |param: &CommandParam| {
+                param.definition.type_name.as_deref() == Some("VkAllocationCallbacks")
+            };
+
+            match params.len() {
+                // vkDestroyInstance(instance, pAllocator)
+                2 if is_allocator(&params[1]) => {
+                    destroyers
+                        .entry(param_type(&params[0]))
+                        .or_insert(Destroyer {
+                            command: &command.proto.name,
+                            owner: None,
+                        });
+                }
+                // vkDestroyBuffer(device, buffer, pAllocator)
+                3 if is_allocator(&params[2]) => {
+                    destroyers
+                        .entry(param_type(&params[1]))
+                        .or_insert(Destroyer {
+                            command: &command.proto.name,
+                            owner: Some(param_type(&params[0])),
+                        });
+                }
+                // vkFreeCommandBuffers(device, commandPool, commandBufferCount, pCommandBuffers)
+                _ => {
+                    if let Some(param) = params.iter().find(|x| is_handle_array(x, types)) {
+                        freed_with_parent.insert(param_type(param));
+                    }
+                }
+            }
+        }
+
+        let parents = types
+            .handles
+            .iter()
+            .filter_map(|handle| {
+                let parent = handle.parent.as_deref()?;
+                // Some handles have more parents, the first one is the one which they are created from.
+                let parent = parent.split(',').next()?.trim();
+                Some((handle.name.as_str(), parent))
+            })
+            .collect();
+
+        Self {
+            destroyers,
+            freed_with_parent,
+            parents,
+        }
+    }
+
+    /// Objects which can be destroyed are tracked, and also objects which they are created from, like physical devices,
+    /// so every object is linked to its instance.
+    fn is_tracked(&self, handle_type: &str) -> bool {
+        self.destroyers.contains_key(handle_type)
+            || self.freed_with_parent.contains(handle_type)
+            || self.parents.values().any(|x| *x == handle_type)
+    }
+}
+
+/// Generates destroyers of every handle type, which are called for objects left by the guest.
+pub fn generate(project_directory: &Path, lifetimes: &ObjectLifetimes) {
+    let mut builder = String::new();
+    builder.push_str("//! THIS FILE IS GENERATED BY TOOL, DO NOT MODIFY.\n\nuse wie_driver_common_vulkan::NonDisposableHandle;\nuse crate::{generated::function_address_table::FunctionAddressTable, objects::Destroyer};\n");
+
+    let mut destroyers: Vec<_> = lifetimes.destroyers.iter().collect();
+    destroyers.sort_by_key(|(handle_type, _)| *handle_type);
+
+    for (handle_type, destroyer) in destroyers {
+        builder.push_str("\npub(crate) const ");
+        to_screaming_snake_case(&mut builder, handle_type);
+        builder.push_str(": Destroyer = Destroyer {\n");
+        push_indentation(&mut builder, 1);
+        builder.push_str("name: c\"");
+        builder.push_str(destroyer.command);
+        builder.push_str("\",\n");
+        push_indentation(&mut builder, 1);
+        builder.push_str("call: ");
+        to_snake_case(&mut builder, handle_type);
+        builder.push_str(",\n};\n");
+
+        builder.push_str("\nunsafe fn ");
+        to_snake_case(&mut builder, handle_type);
+        builder.push_str("(table: &FunctionAddressTable, ");
+        if destroyer.owner.is_none() {
+            builder.push('_');
+        }
+        builder.push_str("owner: NonDisposableHandle, handle: NonDisposableHandle) {\n");
+        push_indentation(&mut builder, 1);
+        builder.push_str("(table.");
+        to_snake_case(&mut builder, destroyer.command);
+        builder.push_str(")(");
+        if destroyer.owner.is_some() {
+            builder.push_str("owner, ");
+        }
+        builder.push_str("handle, std::ptr::null());\n");
+        builder.push_str("}\n");
+    }
+
+    let path = project_directory.join("crates/driver-listener-vulkan/src/generated/destroyers.rs");
+    fs::create_dir_all(path.parent().unwrap()).expect("create directories");
+    fs::write(path, builder).expect("write to a file");
+}
+
+/// Writes code which registers objects created by the command, or forgets objects destroyed by it. It is placed after
+/// the call, where `result` holds the result of commands which return it.
+/// Writes code which tracks objects created or destroyed by the command. Commands which can be rejected before they
+/// are called pass the expression which tells if it was called, so rejected destroys do not forget their objects.
+pub fn track(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+    called: Option<&str>,
+) {
+    let name = definition.proto.name.as_str();
+    // Physical devices are not created, but devices are created from them, which links devices to their instance.
+    if is_create_command(definition) || name == "vkEnumeratePhysicalDevices" {
+        track_created(builder, definition, types, lifetimes);
+    } else if is_destroy_command(definition) {
+        let mut tracking = String::new();
+        track_destroyed(&mut tracking, definition, types, lifetimes);
+        push_when_called(builder, &tracking, called);
+    } else if name == "vkResetDescriptorPool" {
+        // Resetting the pool frees all descriptor sets allocated from it.
+        let mut tracking = String::new();
+        push_indentation(&mut tracking, 1);
+        tracking.push_str("crate::session::current().forget_children(descriptor_pool);\n");
+        push_when_called(builder, &tracking, called);
+    } else if let Some((info, object)) = match name {
+        "vkSetDebugUtilsObjectNameEXT" => Some(("p_name_info", "object_handle")),
+        "vkDebugMarkerSetObjectNameEXT" => Some(("p_name_info", "object")),
+        _ => None,
+    } {
+        track_name(builder, info, object);
+    }
+}
+
+/// Writes code which tells if the guest is within its quota of live objects, for commands which create tracked objects
+/// and can fail. Sets `within_quota`, returns false when the command is not limited.
+pub fn check_quota(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+) -> bool {
+    if !is_create_command(definition)
+        || definition.proto.type_name.as_deref() != Some("VkResult")
+        || created_output(definition, types, lifetimes).is_none()
+    {
+        return false;
+    }
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("let within_quota = crate::session::current().within_object_quota();\n");
+    true
+}
+
+fn push_when_called(builder: &mut String, code: &str, called: Option<&str>) {
+    let Some(called) = called.filter(|_| !code.is_empty()) else {
+        builder.push_str(code);
+        return;
+    };
+
+    push_indentation(builder, 1);
+    builder.push_str("if ");
+    builder.push_str(called);
+    builder.push_str(" {\n");
+    for line in code.lines() {
+        push_indentation(builder, 1);
+        builder.push_str(line);
+        builder.push('\n');
+    }
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+}
+
+fn track_name(builder: &mut String, info: &str, object: &str) {
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("if (result as i32) >= 0 {\n");
+    push_indentation(builder, 2);
+    builder.push_str("unsafe {\n");
+    push_indentation(builder, 3);
+    builder.push_str("crate::session::current().objects.set_name((*");
+    builder.push_str(info);
+    builder.push_str(").");
+    builder.push_str(object);
+    builder.push_str(", unpack_cstr((*");
+    builder.push_str(info);
+    builder.push_str(").p_object_name));\n");
+    push_indentation(builder, 2);
+    builder.push_str("}\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+}
+
+fn track_created(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+) {
+    let Some(output) = created_output(definition, types, lifetimes) else {
+        return;
+    };
+    let handle_type = param_type(output);
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    // Pipelines which failed to compile are null, but the others are created, so every non-error result counts.
+    match definition.proto.type_name.as_deref() {
+        Some("VkResult") => builder.push_str("if (result as i32) >= 0 {\n"),
+        _ => builder.push_str("{\n"),
+    }
+    push_indentation(builder, 2);
+    builder.push_str("let session = crate::session::current();\n");
+
+    push_indentation(builder, 2);
+    match output.len.as_deref() {
+        Some(len) => {
+            builder.push_str("for &handle in unsafe { unpack_vk_array(");
+            push_param_name(builder, output);
+            builder.push_str(", (");
+            to_rust_expression(builder, len, &definition.params, true);
+            builder.push_str(") as usize) }.unwrap_or_default() {\n");
+        }
+        None => {
+            builder.push_str("for handle in [unsafe { *");
+            push_param_name(builder, output);
+            builder.push_str(" }] {\n");
+        }
+    }
+
+    // Physical devices are enumerated again and again, only created objects hold their IDs.
+    if is_create_command(definition) {
+        push_indentation(builder, 3);
+        builder.push_str("session.handles.retain(handle, ");
+        push_object_type(builder, handle_type, types);
+        builder.push_str(");\n");
+    }
+    push_indentation(builder, 3);
+    builder.push_str("session.objects.insert(handle, crate::objects::Object {\n");
+    push_indentation(builder, 4);
+    builder.push_str("type_name: \"");
+    builder.push_str(handle_type);
+    builder.push_str("\",\n");
+    push_indentation(builder, 4);
+    builder.push_str("object_type: ");
+    push_object_type(builder, handle_type, types);
+    builder.push_str(",\n");
+    push_indentation(builder, 4);
+    builder.push_str("command: \"");
+    builder.push_str(&definition.proto.name);
+    builder.push_str("\",\n");
+
+    let parent = lifetimes
+        .parents
+        .get(handle_type)
+        .and_then(|parent| find_handle_expression(definition, parent, types));
+    let destroyer = lifetimes.destroyers.get(handle_type);
+    let owner = destroyer
+        .and_then(|x| x.owner)
+        .and_then(|owner| find_handle_expression(definition, owner, types));
+
+    push_indentation(builder, 4);
+    builder.push_str("owner: ");
+    builder.push_str(owner.as_deref().unwrap_or("0"));
+    builder.push_str(",\n");
+    push_indentation(builder, 4);
+    builder.push_str("parent: ");
+    builder.push_str(parent.or(owner).as_deref().unwrap_or("0"));
+    builder.push_str(",\n");
+    push_indentation(builder, 4);
+    builder.push_str("destroyer: ");
+    match destroyer {
+        Some(_) => {
+            builder.push_str("Some(crate::generated::destroyers::");
+            to_screaming_snake_case(builder, handle_type);
+            builder.push_str("),\n");
+        }
+        None => builder.push_str("None,\n"),
+    }
+    push_indentation(builder, 3);
+    builder.push_str("});\n");
+    push_indentation(builder, 2);
+    builder.push_str("}\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+}
+
+/// Returns parameter with objects created by the command, which are tracked.
+fn created_output<'d>(
+    definition: &'d CommandDefinition,
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+) -> Option<&'d CommandParam> {
+    definition
+        .params
+        .iter()
+        .rev()
+        .find(|x| is_output_handle(x, types) && lifetimes.is_tracked(param_type(x)))
+}
+
+fn track_destroyed(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    types: &TypeVulkan,
+    lifetimes: &ObjectLifetimes,
+) {
+    let params = &definition.params;
+    // Destroyed object follows its owner, or it is the only handle of instance and device destroy commands.
+    let Some(param) = params
+        .iter()
+        .skip(1)
+        .find(|x| is_handle_array(x, types))
+        .or_else(|| params.get(1).filter(|x| is_handle(x, types)))
+        .or_else(|| params.first().filter(|x| is_handle(x, types)))
+    else {
+        return;
+    };
+    let handle_type = param_type(param);
+    if !lifetimes.is_tracked(handle_type) {
+        return;
+    }
+
+    // Instance and device must outlive every object created from them, the others are leaks of the guest.
+    if lifetimes
+        .destroyers
+        .get(handle_type)
+        .is_some_and(|x| x.owner.is_none())
+    {
+        push_indentation(builder, 1);
+        builder.push_str("crate::session::current().report_leaks(");
+        push_param_name(builder, param);
+        builder.push_str(", \"");
+        builder.push_str(handle_type);
+        builder.push_str("\");\n");
+    }
+
+    push_indentation(builder, 1);
+    match param.len.is_some() {
+        // Packets carry a single element of arrays which are not preceded by count pointer.
+        true => {
+            builder.push_str("if let Some(handle) = unsafe { ");
+            push_param_name(builder, param);
+            builder.push_str(".as_ref() } {\n");
+            push_indentation(builder, 2);
+            builder.push_str("crate::session::current().forget(*handle, ");
+            push_object_type(builder, handle_type, types);
+            builder.push_str(");\n");
+            push_indentation(builder, 1);
+            builder.push_str("}\n");
+        }
+        false => {
+            builder.push_str("crate::session::current().forget(");
+            push_param_name(builder, param);
+            builder.push_str(", ");
+            push_object_type(builder, handle_type, types);
+            builder.push_str(");\n");
+        }
+    }
+}
+
+/// Finds expression of the handle passed to the command, directly or as a member of its create info.
+fn find_handle_expression(
+    definition: &CommandDefinition,
+    handle_type: &str,
+    types: &TypeVulkan,
+) -> Option<String> {
+    let mut expression = String::new();
+
+    if let Some(param) = definition
+        .params
+        .iter()
+        .find(|x| is_handle(x, types) && param_type(x) == handle_type)
+    {
+        push_param_name(&mut expression, param);
+        return Some(expression);
+    }
+
+    for param in definition
+        .params
+        .iter()
+        .filter(|x| x.definition.code.starts_with("const ") && x.len.is_none())
+    {
+        let Some(ty) = types
+            .types
+            .iter()
+            .find(|x| x.name.as_deref() == Some(param_type(param)))
+        else {
+            continue;
+        };
+        let vk_parse::TypeSpec::Members(members) = &ty.spec else {
+            continue;
+        };
+
+        for member in members {
+            let vk_parse::TypeMember::Definition(member) = member else {
+                continue;
+            };
+            if member.code.contains('*') {
+                continue;
+            }
+
+            let is_handle_type = member
+                .markup
+                .iter()
+                .any(|x| matches!(x, vk_parse::TypeMemberMarkup::Type(t) if t == handle_type));
+            let name = member.markup.iter().find_map(|x| match x {
+                vk_parse::TypeMemberMarkup::Name(name) => Some(name),
+                _ => None,
+            });
+            if let (true, Some(name)) = (is_handle_type, name) {
+                expression.push_str("unsafe { (*");
+                push_param_name(&mut expression, param);
+                expression.push_str(").");
+                push_element_name(&mut expression, name);
+                expression.push_str(" }");
+                return Some(expression);
+            }
+        }
+    }
+
+    None
+}
+
+fn param_type(param: &CommandParam) -> &str {
+    param.definition.type_name.as_deref().unwrap_or_default()
+}
+
+fn is_handle(param: &CommandParam, types: &TypeVulkan) -> bool {
+    !param.definition.code.contains('*') && types.contains_handle(param_type(param))
+}
+
+fn is_handle_array(param: &CommandParam, types: &TypeVulkan) -> bool {
+    param.definition.code.contains('*')
+        && param.len.is_some()
+        && types.contains_handle(param_type(param))
+}
+
+fn is_output_handle(param: &CommandParam, types: &TypeVulkan) -> bool {
+    param.definition.code.contains('*')
+        && !param.definition.code.starts_with("const ")
+        && types.contains_handle(param_type(param))
+}
+
+fn is_create_command(definition: &CommandDefinition) -> bool {
+    let name = definition.proto.name.as_str();
+    name.starts_with("vkCreate") || name.starts_with("vkAllocate") || name.starts_with("vkRegister")
+}
+
+fn is_destroy_command(definition: &CommandDefinition) -> bool {
+    let name = definition.proto.name.as_str();
+    name.starts_with("vkDestroy") || name.starts_with("vkFree")
+}
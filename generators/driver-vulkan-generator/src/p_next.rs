@@ -30,7 +30,8 @@ pub fn generate(
         use ash::vk::StructureType;
         use cdump::{CDumpReader, CDumpWriter, CSerialize, CDeserialize};
         use crate::generated::vulkan_types::*;
-        use std::{slice, mem, ptr, ffi::c_void, fmt::Debug};
+        use std::{slice, mem, panic, ptr, ffi::c_void, fmt::Debug};
+        use wie_transport::errors::MalformedPacket;
 
         #serializer
         #deserializer
@@ -83,19 +84,15 @@ fn deserializer(structure_types: &[(String, String)]) -> TokenStream {
         |quotes| {
             quote! {
                 pub(crate) unsafe fn p_next_deserializer<T: CDumpReader>(buf: &mut T) -> *mut c_void {
-                    unsafe fn unimplemented<T: CDumpReader>(buf: &mut T) -> *mut c_void {
-                        buf.add_read(mem::size_of::<StructureType>());
-                        ptr::null_mut()
-                    }
-
+                    // Structure type is read through the reader, so a packet which ends before it is rejected.
                     buf.align::<StructureType>();
-                    let ptr = buf.as_mut_ptr_at::<c_void>(buf.get_read());
+                    let ptr = buf.read_raw_slice(mem::size_of::<StructureType>()) as *mut c_void;
                     let ty = *(ptr as *const StructureType);
                     match ty {
                         #quotes
-                        StructureType::LOADER_INSTANCE_CREATE_INFO => return unimplemented(buf),
-                        StructureType::LOADER_DEVICE_CREATE_INFO => return unimplemented(buf),
-                        _ => panic!("Unknown structure type: {:?}", ty),
+                        StructureType::LOADER_INSTANCE_CREATE_INFO => return ptr::null_mut(),
+                        StructureType::LOADER_DEVICE_CREATE_INFO => return ptr::null_mut(),
+                        _ => panic::panic_any(MalformedPacket),
                     }
                     ptr
                 }
@@ -105,7 +102,7 @@ fn deserializer(structure_types: &[(String, String)]) -> TokenStream {
             quote! {
                 StructureType::#id => {
                     let dst = &mut *(ptr as *mut #type_name);
-                    buf.add_read(mem::size_of::<#type_name>());
+                    buf.add_read(mem::size_of::<#type_name>() - mem::size_of::<StructureType>());
                     #type_name::deserialize_to_without_shallow_copy(buf, dst);
                 },
             }
@@ -153,7 +150,7 @@ where
     body(quotes)
 }
 
-fn get_structure_types(
+pub(crate) fn get_structure_types(
     spec: &vk_parse::Registry,
     registry: &vkxml::Registry,
     types: &TypeVulkan,
@@ -0,0 +1,639 @@
+use std::{
+    collections::{HashMap, HashSet},
+    fs,
+    path::Path,
+};
+
+use vk_parse::{CommandDefinition, CommandParam, Type};
+
+use crate::{
+    function_data::CommandParamExt,
+    handles::{self, Member},
+    push_element_name, push_indentation, push_param_name, to_rust_type, to_snake_case,
+    vulkan_types::TypeVulkan,
+};
+
+const VK_STRUCTURE_TYPE: &str = "VK_STRUCTURE_TYPE_";
+
+/// Rules of the registry which hardened mode of the listener checks before commands are called, see
+/// `crates/driver-listener-vulkan/src/validation.rs`.
+pub struct Validators<'a> {
+    /// Structures which have something to check, directly or in their members.
+    checked: HashSet<&'a str>,
+    /// `VkStructureType` of structures, without prefix.
+    structure_types: HashMap<&'a str, &'a str>,
+    /// Structure types which may be chained to the structure, from `structextends` of the chained ones.
+    extended_by: HashMap<&'a str, Vec<&'a str>>,
+}
+
+impl<'a> Validators<'a> {
+    pub fn new(types: &'a TypeVulkan, structure_types: &'a [(String, String)]) -> Self {
+        let structure_types: HashMap<_, _> = structure_types
+            .iter()
+            .map(|(structure_type, type_name)| {
+                (
+                    type_name.as_str(),
+                    &structure_type[VK_STRUCTURE_TYPE.len()..],
+                )
+            })
+            .collect();
+
+        let mut extended_by: HashMap<_, Vec<_>> = HashMap::new();
+        for ty in types.types.iter().copied() {
+            let Some(structure_type) = structure_types.get(handles::name(ty)) else {
+                continue;
+            };
+            for extended in ty.structextends.as_deref().unwrap_or_default().split(',') {
+                if !extended.is_empty() {
+                    extended_by
+                        .entry(extended)
+                        .or_default()
+                        .push(*structure_type);
+                }
+            }
+        }
+
+        let mut checked = HashSet::new();
+        loop {
+            let mut changed = false;
+            for ty in types.types.iter().copied() {
+                let name = handles::name(ty);
+                if checked.contains(name) || !is_input_structure(ty) {
+                    continue;
+                }
+
+                let has_rules = structure_types.contains_key(name)
+                    || handles::members(ty).iter().any(|member| {
+                        member.name == "pNext"
+                            || rule(member, types).is_some()
+                            || checked.contains(member.type_name)
+                    });
+                if has_rules {
+                    checked.insert(name);
+                    changed = true;
+                }
+            }
+
+            if !changed {
+                break;
+            }
+        }
+
+        Self {
+            checked,
+            structure_types,
+            extended_by,
+        }
+    }
+
+    /// Returns true, when the command has parameters to check.
+    pub fn is_validated(&self, definition: &CommandDefinition, types: &TypeVulkan) -> bool {
+        !command_checks(definition, self, types).is_empty()
+    }
+}
+
+/// Check of a single member or parameter.
+enum Rule<'a> {
+    Handle,
+    Pointer,
+    /// Pointer which must not be null when the length is not zero.
+    Array(&'a str),
+}
+
+/// Generates validators of structures and commands, and tests which feed them malformed inputs.
+pub fn generate(
+    project_directory: &Path,
+    commands: &[&CommandDefinition],
+    validators: &Validators,
+    types: &TypeVulkan,
+) {
+    let mut builder = String::new();
+    builder.push_str("//! THIS FILE IS GENERATED BY TOOL, DO NOT MODIFY.\n\nuse ash::vk;\nuse std::ffi::{c_char, c_void};\nuse wie_driver_common_vulkan::{*, generated::vulkan_types::*, generated::vulkan_bitmasks::*};\nuse crate::validation::{self, Invalid};\n");
+
+    generate_p_next(&mut builder, validators);
+    for ty in &types.types {
+        if validators.checked.contains(handles::name(ty)) {
+            generate_structure(&mut builder, ty, validators, types);
+        }
+    }
+
+    builder.push_str("\npub(crate) mod commands {\n");
+    push_indentation(&mut builder, 1);
+    builder.push_str("use super::*;\n");
+    for definition in commands {
+        generate_command(&mut builder, definition, validators, types);
+    }
+    builder.push_str("}\n");
+
+    generate_tests(&mut builder, commands, validators, types);
+
+    let path = project_directory.join("crates/driver-listener-vulkan/src/generated/validators.rs");
+    fs::create_dir_all(path.parent().unwrap()).expect("create directories");
+    fs::write(path, builder).expect("write to a file");
+}
+
+/// Writes code which checks parameters of the command, it runs in the handler before handles are translated.
+/// Returns false, when the command does not have anything to check.
+pub fn validate(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    validators: &Validators,
+    types: &TypeVulkan,
+) -> bool {
+    if !validators.is_validated(definition, types) {
+        return false;
+    }
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("let valid = crate::validation::check(\"");
+    builder.push_str(&definition.proto.name);
+    builder.push_str("\", || unsafe {\n");
+    push_indentation(builder, 2);
+    builder.push_str("crate::generated::validators::commands::");
+    to_snake_case(builder, &definition.proto.name);
+    builder.push('(');
+    for (index, param) in input_params(definition, types).enumerate() {
+        if index != 0 {
+            builder.push_str(", ");
+        }
+        push_param_name(builder, param);
+    }
+    builder.push_str(")\n");
+    push_indentation(builder, 1);
+    builder.push_str("});\n");
+    true
+}
+
+fn generate_p_next(builder: &mut String, validators: &Validators) {
+    builder.push_str("\n/// Checks members of a chained structure, the rest of the chain is checked by its root.\nunsafe fn p_next(element: *const c_void) -> Result<(), Invalid> {\n");
+    push_indentation(builder, 1);
+    builder.push_str("match (*(element as *const vk::BaseInStructure)).s_type {\n");
+
+    let mut chained: Vec<_> = validators
+        .structure_types
+        .iter()
+        .filter(|(type_name, _)| validators.checked.contains(*type_name))
+        .collect();
+    chained.sort();
+    for (type_name, structure_type) in chained {
+        push_indentation(builder, 2);
+        builder.push_str("vk::StructureType::");
+        builder.push_str(structure_type);
+        builder.push_str(" => members_");
+        to_snake_case(builder, type_name);
+        builder.push_str("(element as *const ");
+        builder.push_str(type_name);
+        builder.push_str("),\n");
+    }
+
+    push_indentation(builder, 2);
+    builder.push_str("_ => Ok(()),\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n}\n");
+}
+
+fn generate_structure(
+    builder: &mut String,
+    ty: &Type,
+    validators: &Validators,
+    types: &TypeVulkan,
+) {
+    let type_name = handles::name(ty);
+    let members = handles::members(ty);
+    let has_chain = members.iter().any(|x| x.name == "pNext");
+
+    // Structure with its chain.
+    builder.push_str("\npub(crate) unsafe fn ");
+    to_snake_case(builder, type_name);
+    builder.push_str("(x: *const ");
+    builder.push_str(type_name);
+    builder.push_str(") -> Result<(), Invalid> {\n");
+    push_indentation(builder, 1);
+    builder.push_str("if x.is_null() {\n");
+    push_indentation(builder, 2);
+    builder.push_str("return Ok(());\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+    if let Some(structure_type) = validators.structure_types.get(type_name) {
+        push_indentation(builder, 1);
+        builder.push_str("validation::structure_type((*x).s_type, vk::StructureType::");
+        builder.push_str(structure_type);
+        builder.push_str(", \"");
+        builder.push_str(type_name);
+        builder.push_str("\")?;\n");
+    }
+    if has_chain {
+        push_indentation(builder, 1);
+        builder.push_str("validation::chain((*x).p_next as *const c_void, \"");
+        builder.push_str(type_name);
+        builder.push_str("\", ");
+        match validators.extended_by.get(type_name) {
+            Some(extended_by) => {
+                builder.push_str("|s_type| matches!(s_type, ");
+                for (index, structure_type) in extended_by.iter().enumerate() {
+                    if index != 0 {
+                        builder.push_str(" | ");
+                    }
+                    builder.push_str("vk::StructureType::");
+                    builder.push_str(structure_type);
+                }
+                builder.push(')');
+            }
+            None => builder.push_str("|_| false"),
+        }
+        builder.push_str(", p_next)?;\n");
+    }
+    push_indentation(builder, 1);
+    builder.push_str("members_");
+    to_snake_case(builder, type_name);
+    builder.push_str("(x)\n}\n");
+
+    // Members, without the chain.
+    builder.push_str("\nunsafe fn members_");
+    to_snake_case(builder, type_name);
+    builder.push_str("(x: *const ");
+    builder.push_str(type_name);
+    builder.push_str(") -> Result<(), Invalid> {\n");
+    for member in &members {
+        if member.name == "sType" || member.name == "pNext" {
+            continue;
+        }
+
+        let mut name = String::new();
+        name.push_str(type_name);
+        name.push_str("::");
+        name.push_str(member.name);
+        let mut field = "(*x).".to_owned();
+        push_element_name(&mut field, member.name);
+
+        if let Some(rule) = rule(member, types) {
+            push_indentation(builder, 1);
+            match rule {
+                Rule::Handle => push_check(builder, "handle", &field, None, &name),
+                Rule::Pointer => push_check(builder, "pointer", &field, None, &name),
+                Rule::Array(len) => {
+                    let mut len_field = "(*x).".to_owned();
+                    push_element_name(&mut len_field, len);
+                    push_check(builder, "elements", &field, Some(&len_field), &name);
+                }
+            }
+        }
+
+        if !validators.checked.contains(member.type_name) {
+            continue;
+        }
+        let code = &member.definition.code;
+        push_indentation(builder, 1);
+        match (code.matches('*').count(), array_len(member)) {
+            (0, _) if !code.contains('[') => {
+                to_snake_case(builder, member.type_name);
+                builder.push_str("(&");
+                builder.push_str(&field);
+                builder.push_str(")?;\n");
+            }
+            (0, _) => {
+                builder.push_str("for element in &");
+                builder.push_str(&field);
+                builder.push_str(" {\n");
+                push_indentation(builder, 2);
+                to_snake_case(builder, member.type_name);
+                builder.push_str("(element)?;\n");
+                push_indentation(builder, 1);
+                builder.push_str("}\n");
+            }
+            (1, Some(Some(len))) => {
+                builder.push_str("for element in unpack_vk_array(");
+                builder.push_str(&field);
+                builder.push_str(", (*x).");
+                push_element_name(builder, len);
+                builder.push_str(" as usize).unwrap_or_default() {\n");
+                push_indentation(builder, 2);
+                to_snake_case(builder, member.type_name);
+                builder.push_str("(element)?;\n");
+                push_indentation(builder, 1);
+                builder.push_str("}\n");
+            }
+            (1, None) => {
+                to_snake_case(builder, member.type_name);
+                builder.push('(');
+                builder.push_str(&field);
+                builder.push_str(")?;\n");
+            }
+            _ => builder.push_str("// Length of the member is not supported.\n"),
+        }
+    }
+    push_indentation(builder, 1);
+    builder.push_str("Ok(())\n}\n");
+}
+
+fn generate_command(
+    builder: &mut String,
+    definition: &CommandDefinition,
+    validators: &Validators,
+    types: &TypeVulkan,
+) {
+    let checks = command_checks(definition, validators, types);
+    if checks.is_empty() {
+        return;
+    }
+
+    builder.push('\n');
+    push_indentation(builder, 1);
+    builder.push_str("pub(crate) unsafe fn ");
+    to_snake_case(builder, &definition.proto.name);
+    builder.push('(');
+    for (index, param) in input_params(definition, types).enumerate() {
+        if index != 0 {
+            builder.push_str(", ");
+        }
+        push_param_name(builder, param);
+        builder.push_str(": ");
+        builder.push_str(&to_rust_type(&param.definition, types));
+    }
+    builder.push_str(") -> Result<(), Invalid> {\n");
+    for line in checks.lines() {
+        push_indentation(builder, 2);
+        builder.push_str(line);
+        builder.push('\n');
+    }
+    push_indentation(builder, 2);
+    builder.push_str("Ok(())\n");
+    push_indentation(builder, 1);
+    builder.push_str("}\n");
+}
+
+/// Returns body of the command validator, without the final result.
+fn command_checks(
+    definition: &CommandDefinition,
+    validators: &Validators,
+    types: &TypeVulkan,
+) -> String {
+    let params: Vec<_> = input_params(definition, types).collect();
+    let mut builder = String::new();
+    for param in &params {
+        let code = &param.definition.code;
+        let ty = handles::param_type(param);
+        let name = &param.definition.name;
+        let mut field = String::new();
+        push_param_name(&mut field, param);
+
+        if let Some(error) = null_error(param, types) {
+            let check = match error {
+                "NullHandle" => "handle",
+                _ => "pointer",
+            };
+            push_check(&mut builder, check, &field, None, name);
+        }
+        if code.matches('*').count() != 1 || ty == "void" {
+            continue;
+        }
+
+        match param.len.as_deref() {
+            None | Some("null-terminated") => {}
+            // Packets carry a single element of arrays which are not preceded by count pointer, longer arrays would
+            // be read beyond the packet by the host driver.
+            Some(len) => {
+                let Some(len) = params
+                    .iter()
+                    .find(|x| x.definition.name == len && !x.definition.code.contains('*'))
+                    .filter(|_| param.altlen.is_none())
+                else {
+                    continue;
+                };
+                builder.push_str("validation::array(");
+                builder.push_str(&field);
+                builder.push_str(", ");
+                push_param_name(&mut builder, len);
+                builder.push_str(" as u64, \"");
+                builder.push_str(name);
+                builder.push_str("\", ");
+                builder.push_str(match is_optional(param.optional.as_deref()) {
+                    true => "true",
+                    false => "false",
+                });
+                builder.push_str(")?;\n");
+            }
+        }
+
+        if validators.checked.contains(ty) {
+            to_snake_case(&mut builder, ty);
+            builder.push('(');
+            builder.push_str(&field);
+            builder.push_str(")?;\n");
+        }
+    }
+    builder
+}
+
+/// Writes tables of malformed inputs, which validators must reject with the expected error.
+fn generate_tests(
+    builder: &mut String,
+    commands: &[&CommandDefinition],
+    validators: &Validators,
+    types: &TypeVulkan,
+) {
+    builder.push_str("\n#[cfg(all(test, debug_assertions))]\nmod tests {\n");
+    push_indentation(builder, 1);
+    builder.push_str("use std::{mem::zeroed, ptr};\n\n");
+    push_indentation(builder, 1);
+    builder.push_str("use super::*;\n\n");
+    push_indentation(builder, 1);
+    builder.push_str("type Case = (fn() -> Result<(), Invalid>, Invalid);\n");
+
+    // Every parameter is null or zero, so the first required handle or pointer is reported.
+    builder.push_str("\n    const NULL_PARAMETERS: &[Case] = &[\n");
+    for definition in commands {
+        let Some((param, error)) = first_null_error(definition, validators, types) else {
+            continue;
+        };
+        push_indentation(builder, 2);
+        builder.push_str("(|| unsafe { commands::");
+        to_snake_case(builder, &definition.proto.name);
+        builder.push('(');
+        for index in 0..input_params(definition, types).count() {
+            if index != 0 {
+                builder.push_str(", ");
+            }
+            builder.push_str("zeroed()");
+        }
+        builder.push_str(") }, Invalid::");
+        builder.push_str(error);
+        builder.push_str("(\"");
+        builder.push_str(&param.definition.name);
+        builder.push_str("\")),\n");
+    }
+    push_indentation(builder, 1);
+    builder.push_str("];\n");
+
+    let mut structures: Vec<_> = validators
+        .structure_types
+        .iter()
+        .filter(|(type_name, _)| validators.checked.contains(*type_name))
+        .collect();
+    structures.sort();
+
+    builder.push_str("\n    const WRONG_STRUCTURE_TYPES: &[Case] = &[\n");
+    for (type_name, structure_type) in &structures {
+        let wrong = match **structure_type {
+            "APPLICATION_INFO" => "INSTANCE_CREATE_INFO",
+            _ => "APPLICATION_INFO",
+        };
+        push_indentation(builder, 2);
+        builder.push_str("(|| unsafe { let mut x: ");
+        builder.push_str(type_name);
+        builder.push_str(" = zeroed(); x.s_type = vk::StructureType::");
+        builder.push_str(wrong);
+        builder.push_str(".as_raw() as _; ");
+        to_snake_case(builder, type_name);
+        builder.push_str("(&x) }, Invalid::StructureType { structure: \"");
+        builder.push_str(type_name);
+        builder.push_str("\", found: vk::StructureType::");
+        builder.push_str(wrong);
+        builder.push_str(" }),\n");
+    }
+    push_indentation(builder, 1);
+    builder.push_str("];\n");
+
+    // Structure chained to itself, no structure extends itself.
+    builder.push_str("\n    const FOREIGN_CHAINS: &[Case] = &[\n");
+    for (type_name, structure_type) in &structures {
+        let ty = types.types.iter().find(|x| handles::name(x) == **type_name);
+        if !ty.is_some_and(|ty| handles::members(ty).iter().any(|x| x.name == "pNext")) {
+            continue;
+        }
+        push_indentation(builder, 2);
+        builder.push_str(
+            "(|| unsafe { let foreign = vk::BaseInStructure { s_type: vk::StructureType::",
+        );
+        builder.push_str(structure_type);
+        builder.push_str(", p_next: ptr::null(), ..Default::default() }; let mut x: ");
+        builder.push_str(type_name);
+        builder.push_str(" = zeroed(); x.s_type = vk::StructureType::");
+        builder.push_str(structure_type);
+        builder.push_str(".as_raw() as _; x.p_next = &foreign as *const _ as _; ");
+        to_snake_case(builder, type_name);
+        builder.push_str("(&x) }, Invalid::Chain { structure: \"");
+        builder.push_str(type_name);
+        builder.push_str("\", found: vk::StructureType::");
+        builder.push_str(structure_type);
+        builder.push_str(" }),\n");
+    }
+    push_indentation(builder, 1);
+    builder.push_str("];\n");
+
+    for (test, cases) in [
+        ("commands_reject_null_parameters", "NULL_PARAMETERS"),
+        (
+            "structures_reject_wrong_structure_type",
+            "WRONG_STRUCTURE_TYPES",
+        ),
+        ("structures_reject_foreign_chain", "FOREIGN_CHAINS"),
+    ] {
+        builder.push_str("\n    #[test]\n    fn ");
+        builder.push_str(test);
+        builder.push_str("() {\n        for (validate, expected) in ");
+        builder.push_str(cases);
+        builder.push_str(
+            " {\n            assert_eq!(Err(*expected), validate());\n        }\n    }\n",
+        );
+    }
+    builder.push_str("}\n");
+}
+
+/// Returns the parameter which is reported when every parameter is null, with the name of the error.
+fn first_null_error<'d>(
+    definition: &'d CommandDefinition,
+    validators: &Validators,
+    types: &TypeVulkan,
+) -> Option<(&'d CommandParam, &'static str)> {
+    if !validators.is_validated(definition, types) {
+        return None;
+    }
+
+    input_params(definition, types)
+        .find_map(|param| null_error(param, types).map(|error| (param, error)))
+}
+
+/// Returns name of the error which is reported for the parameter when it is null, when it is required.
+fn null_error(param: &CommandParam, types: &TypeVulkan) -> Option<&'static str> {
+    let code = &param.definition.code;
+    let ty = handles::param_type(param);
+    if is_optional(param.optional.as_deref()) || param.noautovalidity.is_some() {
+        None
+    } else if !code.contains('*') && !code.contains('[') && types.contains_handle(ty) {
+        Some("NullHandle")
+    } else if code.matches('*').count() == 1
+        && ty != "void"
+        && matches!(param.len.as_deref(), None | Some("null-terminated"))
+    {
+        Some("NullPointer")
+    } else {
+        None
+    }
+}
+
+/// Returns check of the member, required handles and pointers must not be null.
+fn rule<'m>(member: &Member<'m>, types: &TypeVulkan) -> Option<Rule<'m>> {
+    let definition = member.definition;
+    if definition.noautovalidity.is_some() || is_optional(definition.optional.as_deref()) {
+        return None;
+    }
+
+    let code = &definition.code;
+    match code.matches('*').count() {
+        0 if !code.contains('[') && types.contains_handle(member.type_name) => Some(Rule::Handle),
+        0 => None,
+        _ if member.type_name == "void" => None,
+        count => match array_len(member) {
+            Some(Some(len)) => Some(Rule::Array(len)),
+            None if count == 1 => Some(Rule::Pointer),
+            _ => None,
+        },
+    }
+}
+
+/// Returns member which holds length of the array, `None` when the member is a single element or a string, and
+/// `Some(None)` when the length is an expression.
+fn array_len<'m>(member: &Member<'m>) -> Option<Option<&'m str>> {
+    let definition = member.definition;
+    let len = definition.len.as_deref()?;
+    let len = len.split(',').next().unwrap().trim();
+    match len {
+        "null-terminated" => None,
+        _ if definition.altlen.is_some() || len.contains(['-', '>', ' ', '(']) => Some(None),
+        _ => Some(Some(len)),
+    }
+}
+
+fn push_check(builder: &mut String, check: &str, field: &str, len: Option<&str>, name: &str) {
+    builder.push_str("validation::");
+    builder.push_str(check);
+    builder.push('(');
+    builder.push_str(field);
+    if let Some(len) = len {
+        builder.push_str(", ");
+        builder.push_str(len);
+        builder.push_str(" as u64");
+    }
+    builder.push_str(", \"");
+    builder.push_str(name);
+    builder.push_str("\")?;\n");
+}
+
+fn input_params<'d>(
+    definition: &'d CommandDefinition,
+    types: &'d TypeVulkan,
+) -> impl Iterator<Item = &'d CommandParam> {
+    handles::unique_params(definition).filter(|x| !x.is_return_data(types))
+}
+
+/// Structures which are passed to the host, returned only structures are written by it.
+fn is_input_structure(ty: &Type) -> bool {
+    ty.category.as_deref() == Some("struct") && ty.returnedonly.as_deref() != Some("true")
+}
+
+/// First value of `optional` tells if the pointer or handle itself may be null.
+fn is_optional(optional: Option<&str>) -> bool {
+    optional.is_some_and(|x| x.split(',').next() == Some("true"))
+}
@@ -179,7 +179,7 @@ impl<'r> TypeVulkan<'r> {
 
 pub fn generate_vulkan_types(project_directory: &Path, types: &TypeVulkan) {
     let mut builder = String::new();
-    builder.push_str("//! THIS FILE IS GENERATED BY TOOL, DO NOT MODIFY.\n\nuse std::ffi::{c_char, c_void};\nuse crate::{NonDisposableHandle, unimplemented_serializer, unimplemented_deserializer, unimplemented_deserializer_mut, generated::vulkan_enums::*, generated::vulkan_pfn_functions::*, generated::vulkan_bitmasks::*, generated::p_next::*};\nuse cdump::{CDeserialize, CSerialize, CDebug};\nuse ash::vk;\n");
+    builder.push_str("//! THIS FILE IS GENERATED BY TOOL, DO NOT MODIFY.\n\nuse std::ffi::{c_char, c_void};\nuse crate::{NonDisposableHandle, unimplemented_serializer, unimplemented_deserializer, unimplemented_deserializer_mut, opaque_serializer, opaque_deserializer_mut, generated::vulkan_enums::*, generated::vulkan_pfn_functions::*, generated::vulkan_bitmasks::*, generated::p_next::*};\nuse cdump::{CDeserialize, CSerialize, CDebug};\nuse ash::vk;\n");
 
     for ty in &types.types {
         generate_type(&mut builder, ty, types);
@@ -246,6 +246,9 @@ pub fn generate_type(builder: &mut String, ty: &Type, types: &TypeVulkan) {
             push_indentation(builder, 1);
             if member_name == "pNext" {
                 builder.push_str("#[cdump(dynamic(serializer = p_next_serializer, deserializer = p_next_deserializer, cdebugger = p_next_cdebugger))]\n");
+            } else if member_name == "pUserData" && ty == "*mut c_void" {
+                // User data is passed back to the side which owns it, e.g. in callbacks.
+                builder.push_str("#[cdump(dynamic(serializer = opaque_serializer, deserializer = opaque_deserializer_mut))]\n");
             } else {
                 builder.push_str("#[cdump(dynamic(serializer = unimplemented_serializer, deserializer = unimplemented_deserializer");
                 match ty == "*mut c_void" {